@@ -0,0 +1,20 @@
+//! Records the commit the preprocessor is built from, for `version`
+
+use std::process::Command;
+
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .filter(|commit| !commit.is_empty());
+    // A build from a packaged crate has no repository to ask
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=PLANTUML_RENDERER_COMMIT={}", commit);
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,237 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{copy, create_dir_all, read, read_dir, write};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::html;
+use crate::manifest::MANIFEST_FILENAME;
+use crate::render::{HASH_EXTENSION, PROVENANCE_EXTENSION};
+
+static PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// An image in the book which doesn't match what its diagram renders to now
+#[derive(Debug)]
+pub enum Mismatch {
+    Missing(PathBuf),
+    Changed(PathBuf),
+    /// An image in a diagram directory of the book which nothing renders to anymore
+    Removed(PathBuf),
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Missing(path) => write!(f, "missing: {}", path.display()),
+            Mismatch::Changed(path) => write!(f, "changed: {}", path.display()),
+            Mismatch::Removed(path) => write!(f, "removed: {}", path.display()),
+        }
+    }
+}
+
+/// Compares every image rendered into `fresh` with the one at the same place
+/// under `committed`. Images in `committed` which nothing renders to anymore
+/// aren't a mismatch, they're merely unused.
+pub fn compare(fresh: &Path, committed: &Path) -> io::Result<Vec<Mismatch>> {
+    let mut mismatches = Vec::new();
+    for relative in image_files(fresh, Path::new(""))? {
+        let committed_image = committed.join(&relative);
+        if !committed_image.is_file() {
+            mismatches.push(Mismatch::Missing(committed_image));
+        } else if read(fresh.join(&relative))? != read(&committed_image)? {
+            mismatches.push(Mismatch::Changed(committed_image));
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Like `compare`, but SVGs only have to match in structure, leaving out the
+/// whitespace between their elements, and images in a `diagram_directory`
+/// of `committed` which nothing renders to are `Removed`. With
+/// `ignore_metadata` SVG comments and processing instructions, and PNG chunks
+/// other than the image's own, don't count either.
+pub fn compare_all(
+    fresh: &Path,
+    committed: &Path,
+    diagram_directory: &Path,
+    ignore_metadata: bool,
+) -> io::Result<Vec<Mismatch>> {
+    let mut mismatches = Vec::new();
+    for relative in image_files(fresh, Path::new(""))? {
+        let committed_image = committed.join(&relative);
+        if !committed_image.is_file() {
+            mismatches.push(Mismatch::Missing(committed_image));
+            continue;
+        }
+        let fresh_image = comparable(&relative, read(fresh.join(&relative))?, ignore_metadata);
+        if fresh_image != comparable(&relative, read(&committed_image)?, ignore_metadata) {
+            mismatches.push(Mismatch::Changed(committed_image));
+        }
+    }
+    for directory in diagram_directories(committed, Path::new(""), diagram_directory)? {
+        for relative in image_files(committed, &directory)? {
+            if !fresh.join(&relative).is_file() {
+                mismatches.push(Mismatch::Removed(committed.join(relative)));
+            }
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Writes `index.html` into `report_directory`, showing each changed image as
+/// it's in the book next to how it renders now. Both are copied into the
+/// report, which is returned.
+pub fn write_report(
+    changed: &[PathBuf],
+    fresh: &Path,
+    committed: &Path,
+    report_directory: &Path,
+) -> io::Result<PathBuf> {
+    let mut rows = String::new();
+    for committed_image in changed {
+        let relative = committed_image
+            .strip_prefix(committed)
+            .expect("Changed images are within the committed directory");
+        for (side, image) in &[
+            ("cached", committed_image.clone()),
+            ("fresh", fresh.join(relative)),
+        ] {
+            let copied = report_directory.join(side).join(relative);
+            if let Some(parent) = copied.parent() {
+                create_dir_all(parent)?;
+            }
+            copy(image, copied)?;
+        }
+        let relative = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        rows.push_str(&format!(
+            "<tr><td>{0}</td><td><img src=\"cached/{0}\" /></td><td><img src=\"fresh/{0}\" /></td></tr>\n",
+            html::escape(&relative)
+        ));
+    }
+    let report = report_directory.join("index.html");
+    write(
+        &report,
+        format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Changed diagrams</title></head>\n\
+             <body>\n<table>\n<tr><th>Image</th><th>Cached</th><th>Fresh</th></tr>\n{}</table>\n</body>\n</html>\n",
+            rows
+        ),
+    )?;
+    Ok(report)
+}
+
+/// What's compared of an image
+fn comparable(path: &Path, image: Vec<u8>, ignore_metadata: bool) -> Vec<u8> {
+    match path.extension().and_then(OsStr::to_str) {
+        Some("svg") => {
+            svg_structure(&String::from_utf8_lossy(&image), ignore_metadata).into_bytes()
+        }
+        Some("png") if ignore_metadata => png_pixels(&image).unwrap_or(image),
+        _ => image,
+    }
+}
+
+/// The SVG's markup and text, without the whitespace around them
+fn svg_structure(svg: &str, ignore_metadata: bool) -> String {
+    let mut structure = String::new();
+    let mut rest = svg;
+    while !rest.is_empty() {
+        if !rest.starts_with('<') {
+            let end = rest.find('<').unwrap_or(rest.len());
+            structure.push_str(rest[..end].trim());
+            rest = &rest[end..];
+            continue;
+        }
+        let (close, metadata) = if rest.starts_with("<!--") {
+            ("-->", true)
+        } else if rest.starts_with("<![CDATA[") {
+            ("]]>", false)
+        } else if rest.starts_with("<?") {
+            ("?>", true)
+        } else {
+            (">", false)
+        };
+        let end = rest.find(close).map_or(rest.len(), |end| end + close.len());
+        if !(metadata && ignore_metadata) {
+            structure.push_str(&rest[..end]);
+        }
+        rest = &rest[end..];
+    }
+    structure
+}
+
+/// The PNG with only its critical chunks, those naming the image's size,
+/// palette and pixels, or nothing if it isn't a PNG
+fn png_pixels(png: &[u8]) -> Option<Vec<u8>> {
+    if !png.starts_with(PNG_SIGNATURE) {
+        return None;
+    }
+    let mut pixels = PNG_SIGNATURE.to_vec();
+    let mut rest = &png[PNG_SIGNATURE.len()..];
+    while !rest.is_empty() {
+        let header = rest.get(..4)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        // The length, the type, the data and the CRC
+        let chunk = rest.get(..12 + length as usize)?;
+        // Ancillary chunks have a type starting with a lowercase letter
+        if chunk[4].is_ascii_uppercase() {
+            pixels.extend_from_slice(chunk);
+        }
+        rest = &rest[chunk.len()..];
+    }
+    Some(pixels)
+}
+
+/// The directories below `root` ending in `diagram_directory`, relative to it
+fn diagram_directories(
+    root: &Path,
+    relative: &Path,
+    diagram_directory: &Path,
+) -> io::Result<Vec<PathBuf>> {
+    let mut directories = Vec::new();
+    for entry in read_dir(root.join(relative))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = relative.join(entry.file_name());
+        if path.ends_with(diagram_directory) {
+            directories.push(path);
+        } else {
+            directories.extend(diagram_directories(root, &path, diagram_directory)?);
+        }
+    }
+    directories.sort();
+    Ok(directories)
+}
+
+/// Relative paths of the images below `root`, leaving out the PlantUML
+/// sources, their provenance and hashes and the manifest
+fn image_files(root: &Path, relative: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut images = Vec::new();
+    for entry in read_dir(root.join(relative))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            images.extend(image_files(root, &path)?);
+        } else if path.extension() != Some(OsStr::new("puml"))
+            && ![PROVENANCE_EXTENSION, HASH_EXTENSION]
+                .iter()
+                .any(|extension| {
+                    entry
+                        .file_name()
+                        .to_string_lossy()
+                        .ends_with(&format!(".{}", extension))
+                })
+            && entry.file_name() != MANIFEST_FILENAME
+        {
+            images.push(path);
+        }
+    }
+    images.sort();
+    Ok(images)
+}
@@ -0,0 +1,1109 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::path::{Component, PathBuf};
+
+use log::LevelFilter;
+use mdbook::errors::Error;
+use mdbook::preprocess::PreprocessorContext;
+use serde_derive::Deserialize;
+
+/// What `deterministic` has PlantUML read diagrams in, and the locale it runs in
+static DETERMINISTIC_CHARSET: &str = "UTF-8";
+static DETERMINISTIC_LOCALE: &str = "C";
+/// What PlantUML reads `security_profile` from, as a Java property or, as it's
+/// given, from the environment
+pub static SECURITY_PROFILE_VARIABLE: &str = "PLANTUML_SECURITY_PROFILE";
+/// The language of PlantUML blocks, unless `language` names another
+pub const PLANTUML_LANGUAGE: &str = "plantuml";
+/// How many hex characters a diagram's SHA-1 hash has, at most `hash_length`
+pub static HASH_LENGTH: usize = 40;
+/// The colors PlantUML draws with unless told otherwise, both those of its
+/// current theme and of the one before 1.2022, and the variables `css_theming`
+/// turns them into
+static DEFAULT_CSS_THEME_COLORS: &[(&str, &str)] = &[
+    ("#000000", "--diagram-fg"),
+    ("#181818", "--diagram-fg"),
+    ("#A80036", "--diagram-border"),
+    ("#E2E2F0", "--diagram-fill"),
+    ("#F1F1F1", "--diagram-fill"),
+    ("#FEFECE", "--diagram-fill"),
+    ("#FFFFFF", "--diagram-bg"),
+];
+
+/// Options read from the `[preprocessor.plantuml-renderer]` table of the book.toml.
+/// Every option has a default, see `Config::default`, so one can also be built
+/// with only the options of interest, e.g. `Config { strict: true, ..Config::default() }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Run PlantUML inside a container instead of looking for it on the host
+    pub container: Option<ContainerConfig>,
+    /// PlantUML commands tried in order, e.g. `["tools/plantuml", "/opt/plantuml/bin/plantuml"]`,
+    /// the first which runs `-version` is used, then `plantuml` on the `PATH`
+    pub commands: Vec<String>,
+    /// The PlantUML command with the arguments it starts with, e.g.
+    /// `["java", "-jar", "/opt/plantuml.jar"]`, used instead of looking for it.
+    /// A single string is a command without arguments. It isn't `command`, which
+    /// is mdbook's for running the preprocessor itself.
+    pub plantuml_command: Vec<String>,
+    /// Log a warning when the same diagram appears more than once in the book, which
+    /// is usually an accidental copy-paste
+    pub warn_duplicates: bool,
+    /// A fixed modification time for generated files, in seconds since the Unix epoch
+    pub mtime: Option<i64>,
+    /// Take the modification time for generated files from the `SOURCE_DATE_EPOCH`
+    /// environment variable when it's set, overriding `mtime`
+    pub source_date_epoch: bool,
+    /// Octal permissions for generated files, e.g. `"644"`. Only supported on unix.
+    pub file_mode: Option<String>,
+    /// Fail the build on unrecognized fence attributes and front matter options
+    /// instead of only warning about them, and on a `build-dir` inside `src`.
+    /// A diagram PlantUML fails on fails it even with `error_images`, as does
+    /// one PlantUML reports an error for on stderr while exiting successfully.
+    pub strict: bool,
+    /// Warn about diagrams PlantUML fails on and show the error image it draws
+    /// for them, rather than failing the build. Ignored with `strict`.
+    pub error_images: bool,
+    /// The image format diagrams are rendered to. `auto` is resolved for the
+    /// renderer when the config is read, so it's never seen past that.
+    pub output_format: OutputFormat,
+    /// What `auto` picks for each renderer, on top of the defaults
+    pub auto_formats: BTreeMap<String, OutputFormat>,
+    /// The format for each renderer, taking the place of `output_format` for
+    /// the renderers it lists, e.g. `{ html = "svg", epub = "png" }`
+    pub formats: BTreeMap<String, OutputFormat>,
+    /// Scales PNG and WebP images are rendered at as well, for an `<img srcset>`
+    /// which high-DPI displays pick a sharper one from, e.g. `[1, 2]`
+    pub srcset_scales: Vec<f64>,
+    /// The `cwebp` binary used to convert PlantUML's PNGs for WebP output
+    pub cwebp: String,
+    /// Feed diagrams to PlantUML over stdin and read the image from stdout,
+    /// rather than writing `.puml` files for it to read
+    pub pipe: bool,
+    /// Only log errors, like `--quiet`
+    pub quiet: bool,
+    /// How log lines are written to stderr and the log file
+    pub log_format: LogFormat,
+    /// The most detailed log lines written, unless `RUST_LOG` says otherwise
+    pub log_level: LogLevel,
+    /// Globs matched against chapter paths, e.g. `guide/*.md`. When any are given
+    /// only the matching chapters have their diagrams rendered.
+    pub include_chapters: Vec<String>,
+    /// Globs for chapters which are passed through untouched, e.g. ones showing
+    /// PlantUML source as an example
+    pub exclude_chapters: Vec<String>,
+    /// Globs of chapters whose diagrams only warn when they fail, even with
+    /// `strict`, e.g. one being written
+    pub lenient_chapters: Vec<String>,
+    /// A `.puml` fragment, relative to the book root, added to diagrams with
+    /// a `legend` attribute and those of the `legend_diagram_types`
+    pub legend: Option<PathBuf>,
+    /// The diagram types, like `uml` or `component`, which always get the legend
+    pub legend_diagram_types: Vec<String>,
+    /// Footer text added to every diagram, `{build_id}` is replaced with the
+    /// `BUILD_ID` environment variable
+    pub watermark: Option<String>,
+    /// Hash the watermark with its build id filled in, so every new build id
+    /// re-renders every diagram. Otherwise only the watermark's template is hashed.
+    pub watermark_in_hash: bool,
+    /// Crop the whitespace around diagrams. SVGs have their `viewBox` tightened,
+    /// raster images are cropped by `trim_tool`.
+    pub trim: bool,
+    /// An ImageMagick `mogrify` compatible binary, run as `<trim_tool> -trim +repage <image>`
+    pub trim_tool: String,
+    /// A command run on every image once it's rendered, with the image's path
+    /// appended to it, e.g. `["svgo", "--multipass"]`
+    pub post_render: Vec<String>,
+    /// A command mapping the file of an include directive to its path, with
+    /// the include appended to it, e.g. `["./scripts/resolve-include"]`
+    pub include_resolver: Vec<String>,
+    /// An image, relative to the book's `src`, shown in place of a diagram
+    /// which PlantUML didn't produce
+    pub placeholder: Option<String>,
+    /// HTML for each image instead of the `<img>`, with `{src}`, `{alt}` and
+    /// `{class}` in it, e.g. for a theme's zoom plugin to hook into
+    pub wrapper_template: Option<String>,
+    /// The language a diagram PlantUML didn't produce is shown in as a code
+    /// block instead, when there's no `placeholder`
+    pub fallback_language: String,
+    /// A directory, relative to the book root, PlantUML runs from and searches
+    /// for includes, e.g. a shared `diagrams` folder
+    pub base_dir: Option<PathBuf>,
+    /// Wrap every diagram in an element with an id derived from its hash, so it
+    /// can be linked to
+    pub anchors: bool,
+    /// What anchor ids start with, followed by the first 8 characters of the hash
+    pub anchor_prefix: String,
+    /// Put `<!-- plantuml-renderer:begin hash=<hash> -->` and
+    /// `<!-- plantuml-renderer:end -->` around every diagram, for other tools to find
+    pub emit_markers: bool,
+    /// Put a visible notice saying why in front of every diagram which isn't
+    /// rendered, for while the book is being written
+    pub show_skips: bool,
+    /// Show diagrams as numbered figures, with their title in the caption
+    pub figure_numbering: Option<FigureNumbering>,
+    /// Emit markdown images as `![alt][label]`, with the labels defined at the
+    /// end of the chapter, keeping long URLs out of the text
+    pub reference_links: bool,
+    /// Make every image a link to itself, opening in a new tab. A fence's
+    /// `downloadable` attribute overrides this for its diagram.
+    pub downloadable: bool,
+    /// Put the diagram's PlantUML source in a `data-plantuml-source` attribute
+    /// of its image, for scripts to render it again in the page
+    pub embed_source_attr: bool,
+    /// Fonts appended to every `font-family` of SVG output, e.g. `"Arial, sans-serif"`
+    pub svg_font_family: Option<String>,
+    /// An XML comment put at the top of every SVG, e.g. a copyright notice.
+    /// It's not part of the hash, cached images get it too.
+    pub svg_comment: Option<String>,
+    /// Parse every SVG PlantUML renders as XML, failing the diagram if it's not
+    /// well-formed, e.g. cut off by an interrupted render
+    pub validate_svg: bool,
+    /// Pragmas added to every diagram right after its `@start` directive, e.g. `"teoz true"`
+    pub pragmas: Vec<String>,
+    /// Values defined with `!define` in every diagram, e.g. `{ VERSION = "1.2" }`
+    pub definitions: BTreeMap<String, String>,
+    /// Diagrams shared by the whole book, by name, e.g. a legend. They're
+    /// shown with `{{#plantuml <name>}}` like diagrams with an id.
+    pub diagrams: BTreeMap<String, String>,
+    /// The PlantUML theme of every diagram, e.g. `cerulean`, for a `!theme`
+    /// line ahead of the `preamble`
+    pub theme: Option<String>,
+    /// Lines added to every diagram after its pragmas and definitions, e.g. `skinparam` settings
+    pub preamble: Vec<String>,
+    /// Added after `preamble` when PlantUML renders SVG
+    pub preamble_svg: Vec<String>,
+    /// Added after `preamble` when PlantUML renders PNG, which includes WebP
+    pub preamble_png: Vec<String>,
+    /// Leave every diagram as source, with a warning, rather than failing the
+    /// build when PlantUML (or the container runtime) isn't installed
+    pub skip_if_missing: bool,
+    /// Fail the build when it has diagrams but PlantUML can't be run, the
+    /// opposite of `skip_if_missing`
+    pub require_renderer: bool,
+    /// Render every diagram again, even when its image already exists. A fence's
+    /// `nocache` attribute overrides this for its diagram.
+    pub no_cache: bool,
+    /// How links to images are written
+    pub url_style: UrlStyle,
+    /// How SVG images are put on the page. Other formats are always `img`.
+    pub embed: Embed,
+    /// Make the relative links of SVGs, e.g. `[[other.md]]` on an element, lead
+    /// where they would from the diagram's chapter, with chapters' markdown
+    /// leading to their pages
+    pub rewrite_svg_links: bool,
+    /// Make the colors of inlined SVGs CSS variables, so they follow the
+    /// book's theme
+    pub css_theming: bool,
+    /// The variables of colors, added to the defaults and taking precedence
+    pub css_theme_colors: BTreeMap<String, String>,
+    /// Write a `.gitattributes` into `plantuml-diagrams` which stores its PNG
+    /// and WebP images with Git LFS
+    pub lfs_gitattributes: bool,
+    /// Remove the diagram directory before every build other than those of
+    /// `mdbook serve` and `mdbook watch`, so no stale image is left in it
+    pub clean_before_build: bool,
+    /// After a build, remove the images in `diagram_directory` which none of
+    /// the book's diagrams is shown as anymore
+    pub prune: bool,
+    /// In builds of `mdbook serve` and `mdbook watch`, show the diagrams
+    /// already rendered right away and render the others in the background
+    pub serve_async: bool,
+    /// Renderers, e.g. `markdown`, whose output links to images where they are
+    /// in `src`, rather than where the renderer would have copied them
+    pub images_in_place: Vec<String>,
+    /// Renderers for which nothing is rendered and chapters are left as they
+    /// are. `test` is what `mdbook test` runs preprocessors for.
+    pub skip_renderers: Vec<String>,
+    /// Give images wider than `page_width_px` a width and height which fit,
+    /// for print renderers which ignore CSS
+    pub fit_to_page: bool,
+    /// The width images are fitted to with `fit_to_page`
+    pub page_width_px: u32,
+    /// Write images into a directory named after the first two characters of
+    /// their hash, e.g. `plantuml-diagrams/3f/3f9a….svg`
+    pub shard: bool,
+    /// Name images after only the first this many characters of their hash,
+    /// the whole hash when unset
+    pub hash_length: Option<usize>,
+    /// Write a JSON `.puml.meta` file next to every image rendered, saying
+    /// what it was rendered from, with what and when
+    pub write_provenance: bool,
+    /// Write each chapter's diagrams to a directory of their own, mirroring
+    /// the chapter's path, e.g. `plantuml-diagrams/guide/intro/` for `guide/intro.md`
+    pub mirror_chapters: bool,
+    /// Where in `src` images are written, and so where mdbook's HTML renderer,
+    /// which copies `src` as it is, has them in its output
+    pub diagram_directory: PathBuf,
+    /// Which code blocks are diagrams to render
+    pub trigger_style: TriggerStyle,
+    /// The fence language of PlantUML blocks, e.g. `puml`, besides `plantuml`
+    pub language: String,
+    /// Only render blocks whose info string starts with exactly
+    /// `plantuml,render`, `json,render` or `yaml,render`
+    pub strict_trigger: bool,
+    /// Lay out Graphviz-backed diagrams with PlantUML's built-in port of
+    /// Graphviz, so the layout doesn't depend on which Graphviz is installed
+    pub stable_layout: bool,
+    /// The charset PlantUML reads diagrams in, passed as `-charset`, e.g. `"UTF-8"`.
+    /// Otherwise it's the JVM's default, which depends on the machine's locale.
+    pub charset: Option<String>,
+    /// The encoding of diagram files the preprocessor reads, and of the source
+    /// it hands PlantUML, which the files it includes are read along with
+    pub input_charset: InputCharset,
+    /// What PlantUML's diagrams may read and reach, its
+    /// `PLANTUML_SECURITY_PROFILE`, e.g. `SANDBOX`. PlantUML's own default
+    /// when unset.
+    pub security_profile: Option<SecurityProfile>,
+    /// Render the same everywhere: `stable_layout`, `charset = "UTF-8"` unless
+    /// another is set, and the `C` locale for `LANG` and `LC_ALL` unless `env`
+    /// sets them
+    pub deterministic: bool,
+    /// What to do when PlantUML writes more than one page for a diagram, only
+    /// the first of which ends up in the book
+    pub extra_pages: ExtraPages,
+    /// `managed` renders through a PlantUML `-picoweb` server the preprocessor
+    /// starts once and leaves running for later builds, rather than a JVM per run
+    pub server_mode: ServerMode,
+    /// A PlantUML server elsewhere which renders the diagrams, e.g.
+    /// `http://plantuml:8080`, instead of PlantUML on this machine
+    pub server_url: Option<String>,
+    /// Environment variables PlantUML runs with, e.g. `GRAPHVIZ_DOT` or `JAVA_HOME`
+    pub env: BTreeMap<String, String>,
+    /// Hash diagrams without their comments, blank lines and extra whitespace,
+    /// so reformatting one doesn't render it again
+    pub semantic_cache: bool,
+    /// Mixed into every diagram's hash, to keep apart caches of books whose
+    /// identical diagrams shouldn't share images
+    pub cache_salt: Option<String>,
+    /// Where the book's files are browsable, e.g. a GitHub blob URL. Diagrams
+    /// read from a `file:` get a link to their source, the file's path relative
+    /// to the book root appended to this.
+    pub source_link_base: Option<String>,
+    /// How many PlantUML processes may run at once. By default one per CPU, as
+    /// far as the available memory allows.
+    pub max_processes: Option<usize>,
+    /// Render the diagrams of the whole book ahead of its chapters, on as many
+    /// threads as `max_processes` allows
+    pub parallel: bool,
+    /// How many different diagrams the book may have before the build fails,
+    /// e.g. for a generated chapter gone wrong. No limit by default.
+    pub max_diagrams: Option<usize>,
+    /// How long PlantUML may take to render a diagram before it's stopped, in
+    /// seconds, not counting how long it takes to start. No limit by default.
+    pub timeout_secs: Option<u64>,
+    /// How long a `batch` run may take, by default `timeout_secs` for every
+    /// diagram in it
+    pub batch_timeout_secs: Option<u64>,
+    /// How long the whole run may render diagrams for, those left once it's up
+    /// are shown as not rendered yet
+    pub global_timeout_secs: Option<u64>,
+    /// Whether a run which ran out of `global_timeout_secs` warns or fails
+    pub on_global_timeout: GlobalTimeout,
+    /// Fail the build on diagrams without a `description`, so none goes without alt text
+    pub require_alt: bool,
+    /// Use a diagram's `title` as its alt text when it has no `description`,
+    /// the same as `alt_fallback = "title"`
+    pub alt_from_title: bool,
+    /// The alt text of diagrams without a `description`: `none`, their `title`,
+    /// or any other text as a template, e.g. `"Diagram: {title}"`. See `Config::alt_fallback`.
+    pub alt_fallback: String,
+    /// Title diagrams read from a `file:` without a `title` of their own after
+    /// the file's name, e.g. `Login Sequence` for `login-sequence.puml`, which
+    /// is then their alt text as well
+    pub title_from_filename: bool,
+    /// Render each chapter's diagrams with a single PlantUML run instead of one
+    /// per diagram. Has no effect with `pipe` or `strict_stderr`.
+    pub batch: bool,
+    /// Fail the build when PlantUML writes to stderr, even when it exits
+    /// successfully. Diagrams are then rendered one at a time, so what PlantUML
+    /// reports is known to be about the diagram being rendered.
+    pub strict_stderr: bool,
+    /// Lines of PlantUML's stderr `strict_stderr` lets through, those containing
+    /// any of these, e.g. `"Font not found"`
+    pub stderr_ignore: Vec<String>,
+    /// What a `plantuml,render` block without a `@start` directive is wrapped
+    /// as, e.g. `gantt` for `@startgantt`
+    pub default_diagram_type: String,
+    /// Append a chapter showing every diagram of the book, each with links to
+    /// the chapters it's in
+    pub gallery: bool,
+    /// The name of the gallery chapter
+    pub gallery_title: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            container: None,
+            commands: Vec::new(),
+            plantuml_command: Vec::new(),
+            warn_duplicates: false,
+            mtime: None,
+            source_date_epoch: false,
+            file_mode: None,
+            strict: false,
+            error_images: false,
+            output_format: OutputFormat::Svg,
+            auto_formats: BTreeMap::new(),
+            formats: BTreeMap::new(),
+            srcset_scales: Vec::new(),
+            cwebp: "cwebp".into(),
+            pipe: false,
+            quiet: false,
+            log_format: LogFormat::Text,
+            log_level: LogLevel::Info,
+            include_chapters: Vec::new(),
+            exclude_chapters: Vec::new(),
+            lenient_chapters: Vec::new(),
+            legend: None,
+            legend_diagram_types: Vec::new(),
+            watermark: None,
+            watermark_in_hash: false,
+            trim: false,
+            trim_tool: "mogrify".into(),
+            post_render: Vec::new(),
+            include_resolver: Vec::new(),
+            placeholder: None,
+            wrapper_template: None,
+            fallback_language: "plantuml".into(),
+            base_dir: None,
+            anchors: false,
+            emit_markers: false,
+            show_skips: false,
+            figure_numbering: None,
+            reference_links: false,
+            anchor_prefix: "diagram-".into(),
+            downloadable: false,
+            embed_source_attr: false,
+            svg_font_family: None,
+            validate_svg: false,
+            svg_comment: None,
+            pragmas: Vec::new(),
+            definitions: BTreeMap::new(),
+            diagrams: BTreeMap::new(),
+            theme: None,
+            preamble: Vec::new(),
+            preamble_svg: Vec::new(),
+            preamble_png: Vec::new(),
+            skip_if_missing: false,
+            require_renderer: false,
+            no_cache: false,
+            url_style: UrlStyle::Absolute,
+            embed: Embed::Img,
+            rewrite_svg_links: false,
+            css_theming: false,
+            css_theme_colors: BTreeMap::new(),
+            lfs_gitattributes: false,
+            clean_before_build: false,
+            prune: false,
+            serve_async: false,
+            images_in_place: Vec::new(),
+            skip_renderers: vec!["test".into()],
+            fit_to_page: false,
+            page_width_px: 700,
+            mirror_chapters: false,
+            diagram_directory: PathBuf::from("plantuml-diagrams"),
+            shard: false,
+            hash_length: None,
+            write_provenance: false,
+            trigger_style: TriggerStyle::Language,
+            language: PLANTUML_LANGUAGE.into(),
+            strict_trigger: false,
+            stable_layout: false,
+            charset: None,
+            input_charset: InputCharset::Utf8,
+            security_profile: None,
+            deterministic: false,
+            extra_pages: ExtraPages::Warn,
+            server_mode: ServerMode::Off,
+            server_url: None,
+            env: BTreeMap::new(),
+            semantic_cache: false,
+            cache_salt: None,
+            source_link_base: None,
+            max_processes: None,
+            parallel: false,
+            max_diagrams: None,
+            timeout_secs: None,
+            batch_timeout_secs: None,
+            global_timeout_secs: None,
+            on_global_timeout: GlobalTimeout::Warn,
+            require_alt: false,
+            alt_from_title: false,
+            alt_fallback: "none".into(),
+            title_from_filename: false,
+            batch: false,
+            strict_stderr: false,
+            stderr_ignore: Vec::new(),
+            default_diagram_type: "uml".into(),
+            gallery: false,
+            gallery_title: "Diagram Gallery".into(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_context(context: &PreprocessorContext, name: &str) -> Result<Config, Error> {
+        Config::from_book_config(&context.config, name, &context.renderer)
+    }
+
+    /// Reads the `[preprocessor.<name>]` table of a book loaded outside of mdbook's
+    /// pipeline, for output going to `renderer`
+    pub fn from_book_config(
+        book_config: &mdbook::Config,
+        name: &str,
+        renderer: &str,
+    ) -> Result<Config, Error> {
+        let table = book_config
+            .get_preprocessor(name)
+            .cloned()
+            .unwrap_or_default();
+        Config::from_table(table, name, renderer)
+    }
+
+    /// Reads the options from the `[preprocessor.<name>]` table itself, for
+    /// output going to `renderer`. Options which aren't given keep their defaults.
+    pub fn from_table(
+        mut table: toml::value::Table,
+        name: &str,
+        renderer: &str,
+    ) -> Result<Config, Error> {
+        if let Some(toml::Value::String(command)) = table.get("plantuml_command") {
+            let command = vec![toml::Value::String(command.clone())];
+            table.insert("plantuml_command".into(), toml::Value::Array(command));
+        }
+        let mut config: Config = toml::Value::Table(table).try_into().map_err(|err| {
+            Error::from(format!(
+                "Invalid [preprocessor.{}] configuration: {}",
+                name, err
+            ))
+        })?;
+        if config.default_diagram_type.is_empty()
+            || !config
+                .default_diagram_type
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric())
+        {
+            return Err(Error::from(format!(
+                "default_diagram_type has to be a diagram type like `uml` or `gantt`, not `{}`",
+                config.default_diagram_type
+            )));
+        }
+        if let Some(theme) = &config.theme {
+            let theme = theme.trim();
+            if theme.is_empty() || theme.contains('\n') {
+                return Err(Error::from(format!("`{}` isn't a theme name", theme)));
+            }
+        }
+        let within_src = config
+            .diagram_directory
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if config.diagram_directory.as_os_str().is_empty() || !within_src {
+            return Err(Error::from(format!(
+                "diagram_directory has to be a directory within `src`, like `assets/diagrams`, \
+                 not `{}`",
+                config.diagram_directory.display()
+            )));
+        }
+        if let Some(template) = &config.wrapper_template {
+            if !template.contains("{src}") {
+                return Err(Error::from(format!(
+                    "wrapper_template needs a `{{src}}` for the image, `{}` has none",
+                    template
+                )));
+            }
+            // It would end the HTML block, leaving the rest to markdown
+            if template.lines().any(|line| line.trim().is_empty()) {
+                return Err(Error::from("wrapper_template can't have blank lines"));
+            }
+        }
+        if config.require_renderer && config.skip_if_missing {
+            return Err(Error::from(
+                "require_renderer fails the build where skip_if_missing leaves diagrams as \
+                 source, only one of them can be set",
+            ));
+        }
+        if config.strict_trigger && config.trigger_style == TriggerStyle::Attribute {
+            return Err(Error::from(
+                "strict_trigger only takes `plantuml,render` for a diagram, it can't go \
+                 with trigger_style = \"attribute\"",
+            ));
+        }
+        // Anything else would be split off of it, or taken for another marker
+        let reserved = ["json", "yaml", "render", "def"];
+        if config.language.is_empty()
+            || config
+                .language
+                .contains(|c: char| c.is_whitespace() || ",{}`~".contains(c))
+            || reserved.contains(&config.language.as_str())
+        {
+            return Err(Error::from(format!(
+                "`{}` can't be the language of PlantUML blocks, it has to be one word \
+                 other than {}",
+                config.language,
+                reserved.join(", ")
+            )));
+        }
+        if config.security_profile.is_some() && config.env.contains_key(SECURITY_PROFILE_VARIABLE) {
+            return Err(Error::from(format!(
+                "security_profile sets {} itself, leave it out of `env`",
+                SECURITY_PROFILE_VARIABLE
+            )));
+        }
+        if let Some(length) = config.hash_length {
+            if length == 0 || length > HASH_LENGTH {
+                return Err(Error::from(format!(
+                    "hash_length has to be between 1 and {}, not {}",
+                    HASH_LENGTH, length
+                )));
+            }
+        }
+        if let Some(definition) = config.definitions.keys().find(|name| !is_identifier(name)) {
+            return Err(Error::from(format!(
+                "The definition `{}` isn't a name PlantUML can define",
+                definition
+            )));
+        }
+        if let Some(scale) = config
+            .srcset_scales
+            .iter()
+            .find(|scale| !scale.is_finite() || **scale <= 0.0)
+        {
+            return Err(Error::from(format!(
+                "The srcset scale {} has to be a positive number",
+                scale
+            )));
+        }
+        match config.plantuml_command.first() {
+            Some(_) if !config.commands.is_empty() => {
+                return Err(Error::from(
+                    "plantuml_command is the one PlantUML command, it can't be used with commands",
+                ))
+            }
+            Some(_) if config.container.is_some() => {
+                return Err(Error::from(
+                    "plantuml_command runs PlantUML on the host, it can't be used with a container",
+                ))
+            }
+            Some(program) if program.trim().is_empty() => {
+                return Err(Error::from("plantuml_command has to start with a program"))
+            }
+            _ => {}
+        }
+        if config.parallel && config.batch {
+            return Err(Error::from(
+                "parallel and batch both save on PlantUML's startup, only one of them can be set",
+            ));
+        }
+        if config.server_mode == ServerMode::Managed && config.container.is_some() {
+            return Err(Error::from(
+                "server_mode = \"managed\" runs PlantUML on the host, it can't be used with a container",
+            ));
+        }
+        if let Some(server_url) = &config.server_url {
+            if !server_url.starts_with("http://") {
+                return Err(Error::from(format!(
+                    "server_url has to be an http:// URL, like `http://localhost:8080`, not `{}`",
+                    server_url
+                )));
+            }
+            if config.server_mode == ServerMode::Managed || config.container.is_some() {
+                return Err(Error::from(
+                    "server_url renders on a server elsewhere, it can't be used with \
+                     server_mode = \"managed\" or a container",
+                ));
+            }
+        }
+        if config.embed.is_inline() && config.rewrite_svg_links {
+            return Err(Error::from(
+                "rewrite_svg_links is for SVG files shown on their own, the links of SVGs \
+                 embedded with embed = \"inline\" or \"inline-with-fallback\" already lead \
+                 from the chapter",
+            ));
+        }
+        if config.css_theming && !config.embed.is_inline() {
+            return Err(Error::from(
+                "css_theming needs embed = \"inline\" or \"inline-with-fallback\", the book's \
+                 CSS doesn't reach into SVGs shown as images",
+            ));
+        }
+        for (color, variable) in &config.css_theme_colors {
+            if normalize_color(color).is_none() {
+                return Err(Error::from(format!(
+                    "The css_theme_colors color `{}` has to be a hex color like `#181818`",
+                    color
+                )));
+            }
+            let name = variable.strip_prefix("--").unwrap_or("");
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(Error::from(format!(
+                    "The css_theme_colors variable `{}` has to be a custom property like \
+                     `--diagram-fg`",
+                    variable
+                )));
+            }
+        }
+        if config.alt_fallback.trim().is_empty() {
+            return Err(Error::from(
+                "alt_fallback has to be `none`, `title` or a template like `\"Diagram: {title}\"`",
+            ));
+        }
+        if config.deterministic {
+            config.stable_layout = true;
+            // Legacy sources are read in their own charset
+            let charset = match config.input_charset {
+                InputCharset::Utf8 => DETERMINISTIC_CHARSET,
+                input_charset => input_charset.name(),
+            };
+            config.charset.get_or_insert_with(|| charset.to_string());
+            for variable in &["LANG", "LC_ALL"] {
+                config
+                    .env
+                    .entry(variable.to_string())
+                    .or_insert_with(|| DETERMINISTIC_LOCALE.to_string());
+            }
+        }
+        if let Some(format) = config.formats.get(renderer) {
+            config.output_format = *format;
+        }
+        if config.output_format == OutputFormat::Auto {
+            config.output_format = config.auto_format(renderer);
+        }
+        Ok(config)
+    }
+
+    /// The options, other than the diagram source, which change how an image
+    /// comes out, mixed into every diagram's hash so changing one renders them
+    /// again. It's empty with the defaults, leaving hashes as they'd be without
+    /// it. Options which only change what's logged or how fast it goes, or
+    /// what's done to cached images anyway like `svg_comment`, are left out.
+    pub fn render_fingerprint(&self) -> String {
+        let mut fingerprint = Vec::new();
+        if self.trim {
+            fingerprint.push("trim".to_string());
+            if self.output_format != OutputFormat::Svg {
+                fingerprint.push(format!("trim_tool={}", self.trim_tool));
+            }
+        }
+        if self.rewrite_svg_links && self.output_format == OutputFormat::Svg {
+            fingerprint.push("rewrite_svg_links".to_string());
+        }
+        if !self.post_render.is_empty() {
+            fingerprint.push(format!("post_render={}", self.post_render.join(" ")));
+        }
+        if let Some(svg_font_family) = &self.svg_font_family {
+            fingerprint.push(format!("svg_font_family={}", svg_font_family));
+        }
+        if self.output_format == OutputFormat::Webp && self.cwebp != Config::default().cwebp {
+            fingerprint.push(format!("cwebp={}", self.cwebp));
+        }
+        if let Some(charset) = &self.charset {
+            fingerprint.push(format!("charset={}", charset));
+        }
+        // Diagrams rendered as an error under a stricter profile aren't kept
+        if let Some(profile) = self.security_profile {
+            fingerprint.push(format!("security_profile={}", profile.name()));
+        }
+        for (key, value) in &self.env {
+            fingerprint.push(format!("env.{}={}", key, value));
+        }
+        if let Some(container) = &self.container {
+            fingerprint.push(format!("container.image={}", container.image));
+        }
+        fingerprint.join("\n")
+    }
+
+    /// The variable each color of `css_theming` becomes, by its upper case
+    /// `#RRGGBB`
+    pub fn css_theme_colors(&self) -> BTreeMap<String, String> {
+        let mut colors: BTreeMap<String, String> = DEFAULT_CSS_THEME_COLORS
+            .iter()
+            .map(|(color, variable)| (color.to_string(), variable.to_string()))
+            .collect();
+        for (color, variable) in &self.css_theme_colors {
+            if let Some(color) = normalize_color(color) {
+                colors.insert(color, variable.clone());
+            }
+        }
+        colors
+    }
+
+    /// The charset PlantUML reads diagrams in: `charset`, or else that of the
+    /// source it's handed when it isn't UTF-8
+    pub fn plantuml_charset(&self) -> Option<&str> {
+        match (&self.charset, self.input_charset) {
+            (Some(charset), _) => Some(charset),
+            (None, InputCharset::Utf8) => None,
+            (None, input_charset) => Some(input_charset.name()),
+        }
+    }
+
+    /// What `trigger_style` asks for, made exact by `strict_trigger`
+    pub fn trigger_style(&self) -> TriggerStyle {
+        match self.trigger_style {
+            TriggerStyle::Language if self.strict_trigger => TriggerStyle::Exact,
+            trigger_style => trigger_style,
+        }
+    }
+
+    /// What `alt_fallback` asks for, with `alt_from_title` taken as `title`
+    pub fn alt_fallback(&self) -> AltFallback<'_> {
+        match self.alt_fallback.as_str() {
+            "none" if self.alt_from_title => AltFallback::Title,
+            "none" => AltFallback::None,
+            "title" => AltFallback::Title,
+            template => AltFallback::Template(template),
+        }
+    }
+
+    /// SVG for the web, PNG for everything else, since e-book readers and LaTeX
+    /// are much less likely to handle SVG. `auto` in `auto_formats` is ignored.
+    fn auto_format(&self, renderer: &str) -> OutputFormat {
+        match self.auto_formats.get(renderer) {
+            Some(format) if *format != OutputFormat::Auto => *format,
+            _ if renderer == "html" => OutputFormat::Svg,
+            _ => OutputFormat::Png,
+        }
+    }
+}
+
+/// Letters, digits and underscores, not starting with a digit
+fn is_identifier(name: &str) -> bool {
+    let mut characters = name.chars();
+    match characters.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            characters.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Picked by the renderer the book is built for, see `Config::auto_formats`
+    Auto,
+    Svg,
+    Png,
+    /// Rendered as PNG by PlantUML, then converted with `cwebp`
+    Webp,
+    /// PlantUML's ASCII art, shown in a code block rather than as an image
+    Txt,
+}
+
+impl OutputFormat {
+    /// The PlantUML flag selecting the format it renders
+    pub fn plantuml_flag(self) -> &'static str {
+        match self {
+            OutputFormat::Auto => unreachable!("auto is resolved when the config is read"),
+            OutputFormat::Svg => "-tsvg",
+            OutputFormat::Png | OutputFormat::Webp => "-tpng",
+            OutputFormat::Txt => "-ttxt",
+        }
+    }
+
+    /// The extension of the file PlantUML itself writes
+    pub fn rendered_extension(self) -> &'static str {
+        match self {
+            OutputFormat::Auto => unreachable!("auto is resolved when the config is read"),
+            OutputFormat::Svg => "svg",
+            OutputFormat::Png | OutputFormat::Webp => "png",
+            OutputFormat::Txt => "atxt",
+        }
+    }
+
+    /// The extension of the image embedded in the book
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Auto => unreachable!("auto is resolved when the config is read"),
+            OutputFormat::Svg => "svg",
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Txt => "atxt",
+        }
+    }
+}
+
+/// The alt text given to diagrams without a `description`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AltFallback<'a> {
+    /// Nothing, the alt text stays empty
+    None,
+    /// The diagram's PlantUML `title`, when it has one
+    Title,
+    /// The text with `{title}` replaced by the diagram's title, or by the name
+    /// of its chapter when it has none
+    Template(&'a str),
+}
+
+impl AltFallback<'_> {
+    /// The alt text of a diagram with `title`, in the chapter named `chapter_name`
+    pub fn alt_text(self, title: Option<&str>, chapter_name: &str) -> Option<String> {
+        match self {
+            AltFallback::None => None,
+            AltFallback::Title => title.map(String::from),
+            AltFallback::Template(template) => {
+                Some(template.replace("{title}", title.unwrap_or(chapter_name)))
+            }
+        }
+    }
+}
+
+/// How the link to a diagram's image is written
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UrlStyle {
+    /// Relative to the book's source directory, e.g. `plantuml-diagrams/<hash>.svg`
+    Absolute,
+    /// Relative to the chapter, e.g. `../plantuml-diagrams/<hash>.svg` for `guide/intro.md`
+    Relative,
+    /// From the root of the site, e.g. `/plantuml-diagrams/<hash>.svg`
+    Root,
+}
+
+/// The element an SVG image is shown with
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Embed {
+    /// A markdown image, or an `<img>` when it needs attributes
+    Img,
+    /// An `<object>`, in which the links and scripts of the SVG work, with
+    /// an `<img>` inside for browsers which can't show it
+    Object,
+    /// The SVG's markup put into the page, where the page's styles and
+    /// scripts reach it, with an `<img>` of the file in a `<noscript>`
+    #[serde(rename = "inline-with-fallback")]
+    InlineWithFallback,
+    /// The SVG's markup alone, for pages which have to do without the files
+    Inline,
+}
+
+impl Embed {
+    /// Whether the SVG's markup is put into the page
+    pub fn is_inline(self) -> bool {
+        matches!(self, Embed::InlineWithFallback | Embed::Inline)
+    }
+}
+
+/// What marks a code block as a diagram to render
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerStyle {
+    /// A language of `plantuml,render`, `json,render` or `yaml,render`
+    Language,
+    /// A `{render}` attribute on a plain `plantuml`, `json` or `yaml` block as
+    /// well, which syntax highlighters take for the language it is
+    Attribute,
+    /// Only an info string starting with `plantuml,render`, what `strict_trigger`
+    /// makes of `language`
+    #[serde(skip)]
+    Exact,
+}
+
+/// How a diagram split into pages with `newpage` is reported
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtraPages {
+    Warn,
+    /// Fail the build, so no page goes missing unnoticed
+    Error,
+}
+
+/// The encoding of diagram files on disk. They're turned into UTF-8 when read,
+/// so the hash of a diagram doesn't depend on it.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum InputCharset {
+    #[serde(rename = "UTF-8", alias = "utf-8")]
+    Utf8,
+    /// Latin-1, in which every byte is the character with its code point
+    #[serde(rename = "ISO-8859-1", alias = "iso-8859-1", alias = "latin-1")]
+    Latin1,
+}
+
+impl InputCharset {
+    /// The name Java knows it by, for PlantUML's `-charset`
+    pub fn name(self) -> &'static str {
+        match self {
+            InputCharset::Utf8 => "UTF-8",
+            InputCharset::Latin1 => "ISO-8859-1",
+        }
+    }
+
+    pub fn decode(self, bytes: Vec<u8>) -> Result<String, String> {
+        match self {
+            InputCharset::Utf8 => String::from_utf8(bytes)
+                .map_err(|_| "it isn't valid UTF-8, see input_charset".to_string()),
+            InputCharset::Latin1 => Ok(bytes.into_iter().map(char::from).collect()),
+        }
+    }
+
+    pub fn encode(self, text: &str) -> Result<Vec<u8>, String> {
+        match self {
+            InputCharset::Utf8 => Ok(text.as_bytes().to_vec()),
+            InputCharset::Latin1 => text
+                .chars()
+                .map(|c| {
+                    u8::try_from(u32::from(c))
+                        .map_err(|_| format!("`{}` can't be written in {}", c, self.name()))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// What comes of a run which ran out of `global_timeout_secs`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GlobalTimeout {
+    /// Build the book with what was rendered in time
+    Warn,
+    /// Fail the build, once every chapter has been gone through
+    Error,
+}
+
+/// How diagrams' figures are numbered
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FigureNumbering {
+    /// Figure 1, 2, 3 throughout the book
+    Continuous,
+    /// Figure 2.1, 2.2 in chapter 2, starting over in every chapter
+    PerChapter,
+}
+
+/// PlantUML's security profiles, from the most to the least restrictive
+/// (https://plantuml.com/security)
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SecurityProfile {
+    /// No files, no network and no environment variables
+    Sandbox,
+    /// Only files and URLs on `plantuml.allowlist.path` and
+    /// `plantuml.allowlist.url`
+    Allowlist,
+    /// Only URLs on the internet, no local files
+    Internet,
+    /// What PlantUML allowed before it had profiles
+    Legacy,
+    /// Anything
+    Unsecure,
+}
+
+impl SecurityProfile {
+    /// The value of `PLANTUML_SECURITY_PROFILE`
+    pub fn name(self) -> &'static str {
+        match self {
+            SecurityProfile::Sandbox => "SANDBOX",
+            SecurityProfile::Allowlist => "ALLOWLIST",
+            SecurityProfile::Internet => "INTERNET",
+            SecurityProfile::Legacy => "LEGACY",
+            SecurityProfile::Unsecure => "UNSECURE",
+        }
+    }
+}
+
+/// Whether diagrams are rendered by a PlantUML server
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerMode {
+    /// PlantUML is run for the diagrams of every build
+    Off,
+    /// A `-picoweb` server is started on the first diagram and left running,
+    /// found again by later builds through a pidfile in the book root
+    Managed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, with `timestamp`, `level`, `target` and `message`
+    /// fields plus `chapter` and `diagram` when they're known
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    /// What's being rendered and why, the default
+    Info,
+    /// Hashes, file names and PlantUML's output, for every diagram
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn filter(self) -> LevelFilter {
+        match self {
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// ```toml
+/// [preprocessor.plantuml-renderer.container]
+/// runtime = "docker"
+/// image = "plantuml/plantuml"
+/// mounts = [{ host = "diagrams", container = "/diagrams" }]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ContainerConfig {
+    /// The container runtime binary, `docker` or anything CLI compatible with it (e.g. `podman`)
+    pub runtime: String,
+    /// An image whose entrypoint is PlantUML
+    pub image: String,
+    /// Where the book's root directory is mounted inside the container
+    pub workdir: String,
+    /// Extra arguments given to `<runtime> run`, before the image name (e.g. `["--user", "1000:1000"]`)
+    pub run_args: Vec<String>,
+    /// Additional host directories to make available inside the container
+    pub mounts: Vec<Mount>,
+}
+
+impl Default for ContainerConfig {
+    fn default() -> Self {
+        ContainerConfig {
+            runtime: "docker".into(),
+            image: "plantuml/plantuml".into(),
+            workdir: "/plantuml".into(),
+            run_args: Vec::new(),
+            mounts: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Mount {
+    /// Directory on the host, relative to the book root
+    pub host: PathBuf,
+    /// Absolute path the directory is mounted at inside the container
+    pub container: String,
+}
+
+/// A hex color as upper case `#RRGGBB`, which `#rgb` is spelled out to
+pub fn normalize_color(color: &str) -> Option<String> {
+    let digits = color.trim().strip_prefix('#')?;
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let digits = match digits.len() {
+        3 => digits.chars().flat_map(|c| vec![c, c]).collect(),
+        6 => digits.to_string(),
+        _ => return None,
+    };
+    Some(format!("#{}", digits.to_ascii_uppercase()))
+}
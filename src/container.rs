@@ -0,0 +1,131 @@
+use std::fs::canonicalize;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::warn;
+use mdbook::errors::Error;
+
+use crate::config::ContainerConfig;
+
+/// Runs PlantUML through a container runtime such as docker or podman. The
+/// container only sees the directories which are mounted into it, so any path
+/// handed to PlantUML has to be translated into its in-container equivalent.
+pub struct Container {
+    runtime: String,
+    image: String,
+    run_args: Vec<String>,
+    workdir: String,
+    /// Pairs of absolute host directories and where they're mounted in the container
+    mounts: Vec<(PathBuf, String)>,
+}
+
+impl Container {
+    pub fn new(config: &ContainerConfig, book_root: &Path) -> Result<Container, Error> {
+        // Mounting the whole book keeps every diagram output directory, and
+        // anything a diagram might include, visible to PlantUML
+        let mut mounts = vec![(absolute(book_root)?, config.workdir.clone())];
+        for mount in &config.mounts {
+            mounts.push((
+                absolute(&book_root.join(&mount.host))?,
+                mount.container.clone(),
+            ));
+        }
+        Ok(Container {
+            runtime: config.runtime.clone(),
+            image: config.image.clone(),
+            run_args: config.run_args.clone(),
+            workdir: config.workdir.clone(),
+            mounts,
+        })
+    }
+
+    pub fn runtime(&self) -> &str {
+        &self.runtime
+    }
+
+    /// Creates a `<runtime> run` command with every mount bound. PlantUML's
+    /// own arguments are expected to be appended by the caller. Interactive
+    /// containers keep stdin open, which is needed to pipe diagrams in.
+    /// PlantUML starts in `working_directory`, an in-container path, or the
+    /// mounted book root, like it does on the host.
+    pub fn command(
+        &self,
+        interactive: bool,
+        working_directory: Option<&str>,
+        environment: &[(&str, String)],
+    ) -> Command {
+        let mut command = Command::new(&self.runtime);
+        command
+            .arg("run")
+            .arg("--rm")
+            .arg("-w")
+            .arg(working_directory.unwrap_or(&self.workdir));
+        if interactive {
+            command.arg("-i");
+        }
+        for (name, value) in environment {
+            command.arg("-e").arg(format!("{}={}", name, value));
+        }
+        for (host, container) in &self.mounts {
+            // Built from the OS string, a host path needn't be valid UTF-8
+            let mut volume = host.as_os_str().to_os_string();
+            volume.push(":");
+            volume.push(container);
+            command.arg("-v").arg(volume);
+        }
+        command.args(&self.run_args).arg(&self.image);
+        command
+    }
+
+    /// Translates a path on the host into the path it is visible at inside the
+    /// container. When mounts are nested the most specific one wins. Returns
+    /// `None` for paths which aren't mounted at all.
+    pub fn translate(&self, host_path: &Path) -> Option<String> {
+        let host_path = canonicalize(host_path).unwrap_or_else(|_| host_path.to_path_buf());
+        self.mounts
+            .iter()
+            .filter_map(|(host, container)| {
+                host_path
+                    .strip_prefix(host)
+                    .ok()
+                    .map(|relative| (host.components().count(), container, relative))
+            })
+            .max_by_key(|(depth, _, _)| *depth)
+            .map(|(_, container, relative)| {
+                // Containers are Linux, so always join with forward slashes
+                // regardless of the host's separator
+                relative.components().fold(
+                    container.trim_end_matches('/').to_string(),
+                    |mut path, component| {
+                        path.push('/');
+                        match component.as_os_str().to_str() {
+                            Some(component) => path.push_str(component),
+                            None => {
+                                let lossy = component.as_os_str().to_string_lossy();
+                                warn!(
+                                    "{} isn't valid UTF-8, PlantUML in the container \
+                                     likely won't find it as {}",
+                                    host_path.display(),
+                                    lossy
+                                );
+                                path.push_str(&lossy);
+                            }
+                        }
+                        path
+                    },
+                )
+            })
+    }
+}
+
+/// Bind mounts need absolute host paths, so resolve them up front. This also
+/// reports mounts pointing at directories that don't exist.
+fn absolute(path: &Path) -> Result<PathBuf, Error> {
+    canonicalize(path).map_err(|err| {
+        Error::from(format!(
+            "Unable to mount {} into the PlantUML container: {}",
+            path.display(),
+            err
+        ))
+    })
+}
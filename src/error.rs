@@ -0,0 +1,87 @@
+//! What goes wrong while rendering a book, with the chapter and diagram it
+//! went wrong for. It's turned into mdbook's error once the book is done.
+
+use std::io;
+use std::path::PathBuf;
+
+use mdbook::errors::Error;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RenderError {
+    /// Something about a chapter as a whole, or before its diagram's hash is known
+    #[error("Chapter `{chapter}`: {message}")]
+    Chapter { chapter: String, message: String },
+    /// A diagram which couldn't be rendered or shown
+    #[error("Chapter `{chapter}`, diagram {hash}: {message}")]
+    Diagram {
+        chapter: String,
+        hash: String,
+        message: String,
+    },
+    /// A rendered file which couldn't be read back
+    #[error("Chapter `{chapter}`, diagram {hash}: unable to read {}: {source}", path.display())]
+    Io {
+        chapter: String,
+        hash: String,
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// Options which can't work together with the rest of the book
+    #[error("{0}")]
+    Config(String),
+    /// A chapter whose processed markdown couldn't be written out
+    #[error("Chapter `{chapter}`: markdown serialization failed: {message}")]
+    Serialization { chapter: String, message: String },
+}
+
+impl RenderError {
+    pub fn chapter(chapter: &str, message: impl ToString) -> RenderError {
+        RenderError::Chapter {
+            chapter: chapter.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn diagram(chapter: &str, hash: &str, message: impl ToString) -> RenderError {
+        RenderError::Diagram {
+            chapter: chapter.to_string(),
+            hash: hash.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// What went wrong, without where
+    pub fn message(&self) -> String {
+        match self {
+            RenderError::Chapter { message, .. } | RenderError::Diagram { message, .. } => {
+                message.clone()
+            }
+            RenderError::Io { path, source, .. } => {
+                format!("unable to read {}: {}", path.display(), source)
+            }
+            RenderError::Config(message) => message.clone(),
+            RenderError::Serialization { message, .. } => {
+                format!("markdown serialization failed: {}", message)
+            }
+        }
+    }
+
+    /// The chapter it went wrong in, which options aren't about
+    pub fn chapter_name(&self) -> Option<&str> {
+        match self {
+            RenderError::Chapter { chapter, .. }
+            | RenderError::Diagram { chapter, .. }
+            | RenderError::Io { chapter, .. }
+            | RenderError::Serialization { chapter, .. } => Some(chapter),
+            RenderError::Config(_) => None,
+        }
+    }
+}
+
+impl From<RenderError> for Error {
+    fn from(err: RenderError) -> Error {
+        Error::from(err.to_string())
+    }
+}
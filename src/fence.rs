@@ -0,0 +1,402 @@
+use crate::config::{Config, TriggerStyle, PLANTUML_LANGUAGE};
+
+/// Every attribute the preprocessor understands on a renderable fence
+pub const KNOWN_ATTRIBUTES: &[&str] = &[
+    "out",
+    "nocache",
+    "description",
+    "long_description",
+    "render",
+    "group",
+    "downloadable",
+    "scale",
+    "use",
+    "width",
+    "height",
+    "legend",
+];
+
+/// The fence languages which get rendered, along with a `render` marker or a
+/// `{render}` attribute, with the diagram type their body is wrapped in when it
+/// doesn't start with a directive of its own. `None` takes the configured
+/// `default_diagram_type`.
+const RENDERABLE_LANGUAGES: &[(&str, Option<&str>)] = &[
+    (PLANTUML_LANGUAGE, None),
+    ("json", Some("json")),
+    ("yaml", Some("yaml")),
+];
+
+/// What marks a fence as one to render in the `language` trigger style
+const RENDER_MARKER: &str = "render";
+/// What marks a fence as a fragment for later diagrams, like `plantuml,def name=base`
+const DEFINITION_MARKER: &str = "def";
+
+/// The info string of a fenced code block, split into the language and any
+/// attributes following it, e.g. `plantuml,render out=assets/arch`.
+/// Attribute values may be double quoted to include whitespace.
+///
+/// The `render` marker may come before or after the language and be separated
+/// from it by a comma or whitespace, like `render,plantuml` or `plantuml render`,
+/// since some markdown tools split info strings on commas.
+///
+/// A `{#id .class key=value}` block holds attributes for the emitted image
+/// rather than the preprocessor, the way other markdown tools write them.
+#[derive(Debug, Default)]
+pub struct FenceInfo {
+    pub language: String,
+    marked: bool,
+    /// Whether the info string starts with exactly `<language>,render`
+    exact: bool,
+    definition: bool,
+    attributes: Vec<(String, Option<String>)>,
+    html_attributes: Vec<(String, String)>,
+}
+
+impl FenceInfo {
+    /// `plantuml_language` is the `language` option, the book's name for
+    /// PlantUML blocks
+    pub fn parse(info: &str, plantuml_language: &str) -> FenceInfo {
+        let (info, braced) = split_braced(info);
+        let mut tokens = tokenize(&info).into_iter();
+        let first = tokens.next().unwrap_or_default();
+        let mut marked = false;
+        let mut definition = false;
+        let mut language = String::new();
+        for part in first.split(',').filter(|part| !part.is_empty()) {
+            if part == RENDER_MARKER {
+                marked = true;
+            } else if part == DEFINITION_MARKER && !language.is_empty() {
+                definition = true;
+            } else if language.is_empty() {
+                language = part.to_string();
+            } else {
+                // Anything else, like `plantuml,render,extra`, isn't a diagram
+                marked = false;
+                definition = false;
+                language = first.clone();
+                break;
+            }
+        }
+        let exact = marked && first == format!("{},{}", language, RENDER_MARKER);
+        // Taken for PlantUML from here on, whatever the book calls it
+        if language == plantuml_language {
+            language = PLANTUML_LANGUAGE.to_string();
+        }
+        let mut attributes: Vec<(String, Option<String>)> = tokens
+            .filter(|token| {
+                let marker = token == RENDER_MARKER && !marked;
+                marked |= marker;
+                !marker
+            })
+            .map(|token| match token.find('=') {
+                Some(index) => (
+                    token[..index].to_string(),
+                    Some(token[index + 1..].to_string()),
+                ),
+                None => (token, None),
+            })
+            .collect();
+        let mut html_attributes = Vec::new();
+        let mut classes = Vec::new();
+        for token in braced.map(tokenize).unwrap_or_default() {
+            if let Some(id) = token.strip_prefix('#') {
+                html_attributes.retain(|(name, _): &(String, String)| name != "id");
+                html_attributes.insert(0, ("id".to_string(), id.to_string()));
+            } else if let Some(class) = token.strip_prefix('.') {
+                classes.push(class.to_string());
+            } else {
+                match token.find('=') {
+                    Some(index) if is_attribute_name(&token[..index]) => html_attributes
+                        .push((token[..index].to_string(), token[index + 1..].to_string())),
+                    // Reported like any other attribute the preprocessor doesn't know
+                    _ => attributes.push((token, None)),
+                }
+            }
+        }
+        if !classes.is_empty() {
+            let position = html_attributes
+                .iter()
+                .take_while(|(name, _)| name == "id")
+                .count();
+            html_attributes.insert(position, ("class".to_string(), classes.join(" ")));
+        }
+        FenceInfo {
+            language,
+            marked,
+            exact,
+            definition,
+            attributes,
+            html_attributes,
+        }
+    }
+
+    /// Attributes from the `{...}` block, to be carried onto the emitted image
+    pub fn html_attributes(&self) -> &[(String, String)] {
+        &self.html_attributes
+    }
+
+    /// The `#id` from the `{...}` block, which also names the diagram for
+    /// `{{#plantuml <id>}}` references
+    pub fn id(&self) -> Option<&str> {
+        self.html_attributes
+            .iter()
+            .find(|(name, _)| name == "id")
+            .map(|(_, id)| id.as_str())
+    }
+
+    /// The `group` attribute, naming a diagram split over several blocks
+    pub fn group(&self) -> Option<&str> {
+        self.get("group").filter(|group| !group.is_empty())
+    }
+
+    /// Whether the fence is marked for rendering in the given trigger style.
+    /// `plantuml,render` always is.
+    pub fn is_renderable(&self, trigger_style: TriggerStyle) -> bool {
+        let triggered = match trigger_style {
+            TriggerStyle::Language => self.marked,
+            TriggerStyle::Attribute => self.marked || self.has_render_attribute(),
+            TriggerStyle::Exact => self.exact,
+        };
+        triggered && !self.definition && self.diagram_type().is_some()
+    }
+
+    /// Whether the fence defines a fragment for later diagrams of the chapter
+    /// rather than a diagram, like `plantuml,def name=base`
+    pub fn is_definition(&self) -> bool {
+        self.definition && self.diagram_type().is_some()
+    }
+
+    /// Turns the body of the fence into PlantUML source, adding the
+    /// `@start`/`@end` directives unless it already starts with any, e.g. `@startmindmap`.
+    /// Plain PlantUML is wrapped as a `default_type` diagram, e.g. `uml` or `gantt`.
+    /// Blank lines around the body are dropped, editors add and remove them
+    /// all the time and they'd change the diagram's hash.
+    pub fn wrap(&self, body: &str, default_type: &str) -> String {
+        let body = &trim_blank_lines(body);
+        match self.diagram_type() {
+            Some(diagram_type) if !body.trim_start().starts_with("@start") => {
+                let diagram_type = diagram_type.unwrap_or(default_type);
+                let newline = if body.ends_with('\n') { "" } else { "\n" };
+                format!(
+                    "@start{}\n{}{}@end{}\n",
+                    diagram_type, body, newline, diagram_type
+                )
+            }
+            _ => body.to_string(),
+        }
+    }
+
+    fn diagram_type(&self) -> Option<Option<&'static str>> {
+        RENDERABLE_LANGUAGES
+            .iter()
+            .find(|(renderable, _)| *renderable == self.language)
+            .map(|(_, diagram_type)| *diagram_type)
+    }
+
+    /// A bare `render` in the `{...}` block or among the attributes
+    fn has_render_attribute(&self) -> bool {
+        self.attributes
+            .iter()
+            .any(|(name, value)| name == "render" && value.is_none())
+    }
+
+    /// The value of a `key=value` attribute
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(name, _)| name == key)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    /// A boolean attribute, which is true when given without a value
+    pub fn flag(&self, key: &str) -> Result<Option<bool>, String> {
+        match self.attributes.iter().find(|(name, _)| name == key) {
+            None => Ok(None),
+            Some((_, None)) => Ok(Some(true)),
+            Some((_, Some(value))) => match value.as_str() {
+                "true" => Ok(Some(true)),
+                "false" => Ok(Some(false)),
+                _ => Err(format!(
+                    "The fence attribute `{}` is either true or false, not `{}`",
+                    key, value
+                )),
+            },
+        }
+    }
+
+    /// The `scale` directive the `scale` attribute asks for, like `scale 1.5`,
+    /// `scale 2/3`, `scale 800 width` or `scale max 1024x768`
+    pub fn scale(&self) -> Result<Option<String>, String> {
+        self.get("scale").map(scale_directive).transpose()
+    }
+
+    /// The `width` and `height` attributes as they're given to the image, in
+    /// pixels like `300` or `300px`, which loses its unit, or a percentage like
+    /// `50%`
+    pub fn dimensions(&self) -> Result<Vec<(String, String)>, String> {
+        ["width", "height"]
+            .iter()
+            .filter_map(|name| self.get(name).map(|value| (*name, value)))
+            .map(|(name, value)| {
+                dimension(value)
+                    .map(|value| (name.to_string(), value))
+                    .ok_or_else(|| {
+                        format!(
+                            "`{}={}` isn't a size in pixels or a percentage, like `300` or `50%`",
+                            name, value
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    /// Attributes which aren't in `KNOWN_ATTRIBUTES`, most likely typos
+    pub fn unknown_attributes(&self) -> impl Iterator<Item = &str> {
+        self.attributes
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| !KNOWN_ATTRIBUTES.contains(name))
+    }
+}
+
+/// A number of pixels, with or without `px`, or a percentage
+fn dimension(value: &str) -> Option<String> {
+    let (number, percent) = match value.strip_suffix('%') {
+        Some(number) => (number, true),
+        None => (value.strip_suffix("px").unwrap_or(value), false),
+    };
+    let valid = !number.is_empty()
+        && number.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && number.parse::<f64>().is_ok_and(|number| number > 0.0);
+    match (valid, percent) {
+        (false, _) => None,
+        (true, true) => Some(format!("{}%", number)),
+        (true, false) => Some(number.to_string()),
+    }
+}
+
+/// Whether `content` might hold a diagram, or a block defining a fragment for
+/// one, which takes one of the renderable languages in a fence's info string.
+/// It's a plain search of the text, which is all most of a big generated
+/// chapter gets.
+pub fn may_have_diagrams(content: &str, config: &Config) -> bool {
+    content.contains(config.language.as_str())
+        || RENDERABLE_LANGUAGES
+            .iter()
+            .any(|(language, _)| content.contains(language))
+}
+
+/// The file named by a body of nothing but `file: <path>`, for diagrams whose
+/// source is kept outside of the chapter
+pub fn source_file(body: &str) -> Option<&str> {
+    body.trim()
+        .strip_prefix("file:")
+        .map(str::trim)
+        .filter(|path| !path.is_empty() && !path.contains('\n'))
+}
+
+/// The file named by a body of nothing but `datafile: <path>`, for TOML, JSON
+/// or YAML data rendered as a tree
+pub fn data_file(body: &str) -> Option<&str> {
+    body.trim()
+        .strip_prefix("datafile:")
+        .map(str::trim)
+        .filter(|path| !path.is_empty() && !path.contains('\n'))
+}
+
+/// Whether raw HTML swallowed a renderable fence, which happens when it
+/// directly follows a tag like `<details>` without a blank line in between.
+/// It's then just text to the markdown parser.
+pub fn hidden_in_html(html: &str, config: &Config) -> bool {
+    html.lines().any(|line| {
+        let line = line.trim_start();
+        let info = line
+            .strip_prefix("```")
+            .or_else(|| line.strip_prefix("~~~"))
+            .map(|info| info.trim_start_matches(['`', '~']));
+        matches!(
+            info,
+            Some(info) if FenceInfo::parse(info, &config.language).is_renderable(config.trigger_style())
+        )
+    })
+}
+
+/// Drops the blank lines before and after the code, leaving the lines in
+/// between untouched
+fn trim_blank_lines(code: &str) -> String {
+    let mut lines: Vec<&str> = code
+        .split_inclusive('\n')
+        .skip_while(|line| line.trim().is_empty())
+        .collect();
+    while matches!(lines.last(), Some(line) if line.trim().is_empty()) {
+        lines.pop();
+    }
+    let mut trimmed = lines.concat();
+    if !trimmed.is_empty() && !trimmed.ends_with('\n') {
+        trimmed.push('\n');
+    }
+    trimmed
+}
+
+/// Takes the `{...}` block out of the info string
+fn split_braced(info: &str) -> (String, Option<&str>) {
+    match (info.find('{'), info.rfind('}')) {
+        (Some(start), Some(end)) if start < end => (
+            format!("{} {}", &info[..start], &info[end + 1..]),
+            Some(&info[start + 1..end]),
+        ),
+        _ => (info.to_string(), None),
+    }
+}
+
+/// Anything else could break out of the emitted tag
+fn is_attribute_name(name: &str) -> bool {
+    let mut characters = name.chars();
+    match characters.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => characters
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ':' || c == '.'),
+        _ => false,
+    }
+}
+
+/// Splits on whitespace, except where it's inside double quotes. The quotes
+/// themselves are dropped.
+fn tokenize(info: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for character in info.chars() {
+        match character {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+/// The `scale` line for a scale like `1.5`, `2/3`, `800 width` or `max 1024x768`
+pub fn scale_directive(scale: &str) -> Result<String, String> {
+    let scale = scale.trim();
+    let valid = !scale.is_empty()
+        && scale.split_whitespace().all(|word| {
+            ["max", "width", "height"].contains(&word)
+                || word
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || ".*/x".contains(c))
+        });
+    if !valid {
+        return Err(format!(
+            "`scale={}` isn't a scale PlantUML understands, like `1.5`, `2/3` or `800 width`",
+            scale
+        ));
+    }
+    Ok(format!("scale {}", scale))
+}
@@ -0,0 +1,67 @@
+//! Figure numbers for `figure_numbering`. Chapters are rendered in whatever
+//! order mdbook visits them, so captions get a placeholder which is numbered
+//! once the whole book is done, going through it in reading order.
+
+use mdbook::book::{Book, BookItem, Chapter};
+
+use crate::config::FigureNumbering;
+use crate::html;
+
+/// Where a caption's number goes, until the book is numbered
+static PLACEHOLDER: &str = "<!-- plantuml-renderer:figure -->";
+
+/// The caption of a diagram, numbered later by `number`, e.g. `Figure 2.3:
+/// Login` with its title
+pub fn caption(title: Option<&str>) -> String {
+    match title {
+        Some(title) => format!("Figure {}: {}", PLACEHOLDER, html::escape(title)),
+        None => format!("Figure {}", PLACEHOLDER),
+    }
+}
+
+/// Numbers the captions of every chapter in reading order. With `per_chapter`
+/// the count starts over for every top level chapter, which its nested ones
+/// share, and the number is prefixed with the chapter's. Unnumbered chapters,
+/// like a preface, count their figures on their own, without a prefix.
+pub fn number(book: &mut Book, numbering: FigureNumbering) {
+    let mut count = 0;
+    let mut current: Option<String> = None;
+    in_reading_order(&mut book.sections, &mut |chapter: &mut Chapter| {
+        let (group, prefix) = match (numbering, chapter.number.as_ref().and_then(|n| n.first())) {
+            (FigureNumbering::Continuous, _) => (String::new(), String::new()),
+            (FigureNumbering::PerChapter, Some(number)) => {
+                (number.to_string(), format!("{}.", number))
+            }
+            (FigureNumbering::PerChapter, None) => (chapter.name.clone(), String::new()),
+        };
+        if current.as_ref() != Some(&group) {
+            current = Some(group);
+            if numbering == FigureNumbering::PerChapter {
+                count = 0;
+            }
+        }
+        if !chapter.content.contains(PLACEHOLDER) {
+            return;
+        }
+        let mut numbered = String::with_capacity(chapter.content.len());
+        let mut rest = chapter.content.as_str();
+        while let Some(start) = rest.find(PLACEHOLDER) {
+            count += 1;
+            numbered.push_str(&rest[..start]);
+            numbered.push_str(&format!("{}{}", prefix, count));
+            rest = &rest[start + PLACEHOLDER.len()..];
+        }
+        numbered.push_str(rest);
+        chapter.content = numbered;
+    });
+}
+
+/// Unlike `Book::for_each_mut`, a chapter comes before the ones nested in it
+fn in_reading_order(items: &mut [BookItem], visit: &mut impl FnMut(&mut Chapter)) {
+    for item in items {
+        if let BookItem::Chapter(chapter) = item {
+            visit(chapter);
+            in_reading_order(&mut chapter.sub_items, visit);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::path::Path;
+
+use glob::Pattern;
+use mdbook::errors::Error;
+
+use crate::config::Config;
+
+/// Decides which chapters get their diagrams rendered, and in which of them a
+/// diagram failing doesn't fail the build, based on globs matched against each
+/// chapter's path relative to the book's source directory
+#[derive(Debug, Default)]
+pub struct ChapterFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    lenient: Vec<Pattern>,
+}
+
+impl ChapterFilter {
+    pub fn from_config(config: &Config) -> Result<ChapterFilter, Error> {
+        Ok(ChapterFilter {
+            include: compile("include_chapters", &config.include_chapters)?,
+            exclude: compile("exclude_chapters", &config.exclude_chapters)?,
+            lenient: compile("lenient_chapters", &config.lenient_chapters)?,
+        })
+    }
+
+    /// With no `include_chapters` every chapter is included, unless it's excluded
+    pub fn includes(&self, chapter_path: &Path) -> bool {
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| pattern.matches_path(chapter_path));
+        included
+            && !self
+                .exclude
+                .iter()
+                .any(|pattern| pattern.matches_path(chapter_path))
+    }
+
+    /// Whether every chapter's diagrams are rendered
+    pub fn includes_all(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether what goes wrong in the chapter is only warned about
+    pub fn is_lenient(&self, chapter_path: &Path) -> bool {
+        self.lenient
+            .iter()
+            .any(|pattern| pattern.matches_path(chapter_path))
+    }
+}
+
+fn compile(option: &str, globs: &[String]) -> Result<Vec<Pattern>, Error> {
+    globs
+        .iter()
+        .map(|glob| {
+            Pattern::new(glob)
+                .map_err(|err| Error::from(format!("Invalid {} glob `{}`: {}", option, glob, err)))
+        })
+        .collect()
+}
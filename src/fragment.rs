@@ -0,0 +1,52 @@
+//! Fragments of PlantUML a chapter defines once, in `plantuml,def name=<name>`
+//! blocks which aren't rendered themselves, for its later diagrams to start
+//! with by naming them in a `use` attribute
+
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct Fragments {
+    bodies: HashMap<String, String>,
+}
+
+impl Fragments {
+    /// Defines `name`, replacing any fragment defined under it before
+    pub fn define(&mut self, name: &str, body: &str) {
+        let mut body = body.trim_matches('\n').to_string();
+        body.push('\n');
+        self.bodies.insert(name.to_string(), body);
+    }
+
+    /// `body` starting with the fragments `uses` names, comma separated and in
+    /// that order. They go after an `@start` line the body has of its own.
+    pub fn apply(&self, uses: &str, body: &str) -> Result<String, String> {
+        let mut fragments = String::new();
+        for name in uses
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            match self.bodies.get(name) {
+                Some(fragment) => fragments.push_str(fragment),
+                None => {
+                    return Err(format!(
+                        "`use={}` names no `plantuml,def name={}` block before it in the chapter",
+                        uses, name
+                    ))
+                }
+            }
+        }
+        let start = if body.trim_start().starts_with("@start") {
+            body.find('\n').map_or(body.len(), |end| end + 1)
+        } else {
+            0
+        };
+        let (directive, rest) = body.split_at(start);
+        let newline = if directive.is_empty() || directive.ends_with('\n') {
+            ""
+        } else {
+            "\n"
+        };
+        Ok(format!("{}{}{}{}", directive, newline, fragments, rest))
+    }
+}
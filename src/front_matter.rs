@@ -0,0 +1,169 @@
+//! Options a chapter sets for its own diagrams, in front matter at the very top
+//! of it: TOML between `+++` lines or YAML between `---` lines
+
+use crate::config::{Config, OutputFormat};
+use crate::fence;
+
+static TOML_DELIMITER: &str = "+++";
+static YAML_DELIMITER: &str = "---";
+/// The options a chapter can set
+static OPTIONS: &[&str] = &["output_format", "scale", "theme"];
+
+/// A chapter's own options, on top of the book's
+#[derive(Debug, Default)]
+pub struct FrontMatter {
+    output_format: Option<OutputFormat>,
+    /// The `scale` line for diagrams without a `scale` of their own
+    scale: Option<String>,
+    theme: Option<String>,
+    /// Keys which aren't options a chapter can set
+    unknown: Vec<String>,
+}
+
+/// Splits the front matter off the start of a chapter, returning it along with
+/// the rest of the chapter. YAML front matter is only told apart from a
+/// thematic break by holding nothing but `key: value` lines of the options a
+/// chapter can set, and comments. It's left alone otherwise, so `Note: draft`
+/// between two rules isn't taken off the page.
+pub fn split(content: &str) -> Result<Option<(FrontMatter, &str)>, String> {
+    let mut lines = content.split_inclusive('\n');
+    let delimiter = match lines.next().map(str::trim_end) {
+        Some(line) if line == TOML_DELIMITER || line == YAML_DELIMITER => line,
+        _ => return Ok(None),
+    };
+    let mut length = content.find('\n').map_or(content.len(), |end| end + 1);
+    let mut body = String::new();
+    let mut closed = false;
+    for line in lines {
+        length += line.len();
+        if line.trim_end() == delimiter {
+            closed = true;
+            break;
+        }
+        body.push_str(line);
+    }
+    if !closed {
+        return Ok(None);
+    }
+    let options = if delimiter == TOML_DELIMITER {
+        toml_options(&body)?
+    } else {
+        match yaml_options(&body) {
+            Some(options)
+                if options
+                    .iter()
+                    .all(|(key, _)| OPTIONS.contains(&key.as_str())) =>
+            {
+                options
+            }
+            _ => return Ok(None),
+        }
+    };
+    let front_matter = FrontMatter::from_options(options)?;
+    Ok(Some((front_matter, &content[length..])))
+}
+
+/// Like `split`, with the default front matter for a chapter which has none
+pub fn split_or_default(content: &str) -> Result<(FrontMatter, &str), String> {
+    Ok(split(content)?.unwrap_or_else(|| (FrontMatter::default(), content)))
+}
+
+/// The values of a TOML table's keys, which have to be strings or numbers
+fn toml_options(body: &str) -> Result<Vec<(String, String)>, String> {
+    let table: toml::value::Table =
+        toml::from_str(body).map_err(|err| format!("invalid front matter: {}", err))?;
+    table
+        .into_iter()
+        .map(|(key, value)| match value {
+            toml::Value::String(value) => Ok((key, value)),
+            toml::Value::Integer(_) | toml::Value::Float(_) => Ok((key, value.to_string())),
+            _ => Err(format!(
+                "the front matter option `{}` has to be a string or a number",
+                key
+            )),
+        })
+        .collect()
+}
+
+/// Flat `key: value` pairs, with the quotes around values taken off. Anything
+/// else means it isn't front matter.
+fn yaml_options(body: &str) -> Option<Vec<(String, String)>> {
+    let mut options = Vec::new();
+    for line in body.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line.split_at(line.find(':')?);
+        let key = key.trim();
+        let valid_key = !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_key {
+            return None;
+        }
+        let value = value[1..].trim();
+        let unquoted = [('"', '"'), ('\'', '\'')]
+            .iter()
+            .find(|(open, close)| {
+                value.len() >= 2 && value.starts_with(*open) && value.ends_with(*close)
+            })
+            .map_or(value, |_| &value[1..value.len() - 1]);
+        options.push((key.to_string(), unquoted.to_string()));
+    }
+    Some(options)
+}
+
+impl FrontMatter {
+    fn from_options(options: Vec<(String, String)>) -> Result<FrontMatter, String> {
+        let mut front_matter = FrontMatter::default();
+        for (key, value) in options {
+            match key.as_str() {
+                "output_format" => {
+                    let format = toml::Value::String(value.clone())
+                        .try_into()
+                        .map_err(|_| format!("`{}` isn't an output format", value))?;
+                    if format == OutputFormat::Auto {
+                        return Err("a chapter can't pick the `auto` output format, \
+                                    it's picked for the whole book"
+                            .to_string());
+                    }
+                    front_matter.output_format = Some(format);
+                }
+                "scale" => front_matter.scale = Some(fence::scale_directive(&value)?),
+                "theme" => {
+                    let theme = value.trim();
+                    if theme.is_empty() || theme.contains('\n') {
+                        return Err(format!("`{}` isn't a theme name", value));
+                    }
+                    front_matter.theme = Some(theme.to_string());
+                }
+                _ => front_matter.unknown.push(key),
+            }
+        }
+        Ok(front_matter)
+    }
+
+    /// The book's options with the chapter's output format and theme, which
+    /// replaces the book's ahead of the preamble
+    pub fn config(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        if let Some(format) = self.output_format {
+            config.output_format = format;
+        }
+        if self.theme.is_some() {
+            config.theme = self.theme.clone();
+        }
+        config
+    }
+
+    /// The `scale` line of diagrams without a `scale` attribute
+    pub fn scale(&self) -> Option<&str> {
+        self.scale.as_deref()
+    }
+
+    /// Keys other than `output_format`, `scale` and `theme`, most likely typos
+    pub fn unknown_options(&self) -> &[String] {
+        &self.unknown
+    }
+}
@@ -0,0 +1,84 @@
+//! The generated appendix showing every diagram of the book at a glance
+
+use std::path::Path;
+
+use mdbook::book::Chapter;
+
+/// Where the gallery chapter is written to, relative to `src`
+pub static GALLERY_PATH: &str = "plantuml-gallery.md";
+
+/// Every rendered diagram, in the order they first appear in the book
+#[derive(Debug, Default)]
+pub struct Gallery {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// The image, or ASCII art, as it's shown in the gallery
+    figure: String,
+    title: String,
+    /// The name of each chapter the diagram is in, with the link to it
+    chapters: Vec<(String, String)>,
+}
+
+impl Gallery {
+    /// Adds a diagram found in a chapter. `figure` is the markdown showing it,
+    /// which also tells apart diagrams, one used by several chapters is listed once.
+    pub fn add(&mut self, figure: String, title: &str, chapter_name: &str, chapter_path: &Path) {
+        let chapter = (chapter_name.to_string(), chapter_link(chapter_path));
+        match self.entries.iter_mut().find(|entry| entry.figure == figure) {
+            Some(entry) => {
+                if !entry.chapters.contains(&chapter) {
+                    entry.chapters.push(chapter);
+                }
+            }
+            None => self.entries.push(Entry {
+                figure,
+                title: title.to_string(),
+                chapters: vec![chapter],
+            }),
+        }
+    }
+
+    /// The chapter listing every diagram, with links to where each is used
+    pub fn chapter(&self, name: &str) -> Chapter {
+        let mut content = format!("# {}\n", escape(name));
+        for entry in &self.entries {
+            content.push_str(&format!(
+                "\n## {}\n\n{}\n\n",
+                escape(&entry.title),
+                entry.figure
+            ));
+            let links = entry
+                .chapters
+                .iter()
+                .map(|(name, link)| format!("[{}]({})", escape(name), link))
+                .collect::<Vec<_>>();
+            content.push_str(&format!("Used in {}\n", links.join(", ")));
+        }
+        Chapter::new(name, content, GALLERY_PATH, Vec::new())
+    }
+}
+
+/// The chapter's source file, which mdbook turns into a link to its page.
+/// The gallery is at the top of `src`, so that's the chapter's path.
+fn chapter_link(chapter_path: &Path) -> String {
+    chapter_path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().replace(' ', "%20"))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Escapes what markdown would otherwise read as formatting in titles and link text
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        if "\\`*_[]<>#!|".contains(character) {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+use crate::fence::FenceInfo;
+
+/// Diagrams built up over several code blocks of a chapter sharing a `group`
+/// attribute. Their bodies are joined in order and rendered as one diagram,
+/// where the last of them is.
+#[derive(Debug, Default)]
+pub struct Groups {
+    /// How many blocks of each group haven't been seen yet
+    remaining: HashMap<String, usize>,
+    bodies: HashMap<String, String>,
+}
+
+impl Groups {
+    /// Counts the blocks of each group among the chapter's diagrams
+    pub fn new<'a>(fences: impl IntoIterator<Item = &'a FenceInfo>) -> Groups {
+        let mut remaining = HashMap::new();
+        let diagrams = fences.into_iter().filter(|fence| !fence.is_definition());
+        for group in diagrams.filter_map(FenceInfo::group) {
+            *remaining.entry(group.to_string()).or_insert(0) += 1;
+        }
+        Groups {
+            remaining,
+            bodies: HashMap::new(),
+        }
+    }
+
+    /// Adds the body of one of the group's blocks, returning the whole diagram
+    /// once its last block has been added
+    pub fn add(&mut self, group: &str, body: &str) -> Option<String> {
+        let joined = self.bodies.entry(group.to_string()).or_default();
+        joined.push_str(body);
+        if !joined.is_empty() && !joined.ends_with('\n') {
+            joined.push('\n');
+        }
+        let remaining = self.remaining.entry(group.to_string()).or_insert(1);
+        *remaining = remaining.saturating_sub(1);
+        if *remaining > 0 {
+            return None;
+        }
+        self.remaining.remove(group);
+        self.bodies.remove(group)
+    }
+}
@@ -0,0 +1,137 @@
+//! HTML emitted in place of the markdown image, for when the image has to
+//! carry more than its link
+
+/// Keeps an element out of sight but in the accessibility tree
+static VISUALLY_HIDDEN_STYLE: &str = "position: absolute; width: 1px; height: 1px; \
+     overflow: hidden; clip: rect(0 0 0 0); white-space: nowrap;";
+
+/// Escapes text for a double quoted attribute value. Line breaks are escaped
+/// too, the HTML has to stay on one line for markdown to leave it be.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// An `<img>` with the given attributes following its `src` and `alt`
+pub fn img(src: &str, alt: &str, attributes: &[(String, String)]) -> String {
+    let mut html = format!("<img src=\"{}\" alt=\"{}\"", escape(src), escape(alt));
+    for (name, value) in attributes {
+        html.push_str(&format!(" {}=\"{}\"", name, escape(value)));
+    }
+    html.push_str(" />");
+    html
+}
+
+/// The `wrapper_template` filled in for an image, with its `{src}`, `{alt}` and
+/// `{class}` replaced by the image's, escaped for attribute values
+pub fn wrapper(template: &str, src: &str, alt: &str, class: &str) -> String {
+    let placeholders = [("{src}", src), ("{alt}", alt), ("{class}", class)];
+    let mut html = String::with_capacity(template.len() + src.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        html.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let placeholder = placeholders
+            .iter()
+            .find(|(placeholder, _)| rest.starts_with(placeholder));
+        match placeholder {
+            Some((placeholder, value)) => {
+                html.push_str(&escape(value));
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                html.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    html.push_str(rest);
+    html
+}
+
+/// Text for screen readers only. It's hidden with an inline style, the theme
+/// can't be relied on to have a class for it.
+pub fn visually_hidden(id: &str, text: &str) -> String {
+    format!(
+        "<span id=\"{}\" style=\"{}\">{}</span>",
+        escape(id),
+        VISUALLY_HIDDEN_STYLE,
+        escape(text)
+    )
+}
+
+/// Wraps an image in an element readers can link to with `#id`
+pub fn anchored(id: &str, html: &str) -> String {
+    format!("<div id=\"{}\">{}</div>", escape(id), html)
+}
+
+/// The notice `show_skips` puts in front of a diagram which isn't rendered,
+/// on a line of its own with a blank one after it, so the block ends there
+pub fn skip_notice(reason: &str) -> String {
+    format!(
+        "<div class=\"plantuml-skipped\" role=\"note\">⚠ Diagram not rendered: {}</div>\n\n",
+        escape(reason)
+    )
+}
+
+/// A plain link, e.g. to a diagram's source
+pub fn link(href: &str, text: &str) -> String {
+    format!("<a href=\"{}\">{}</a>", escape(href), escape(text))
+}
+
+/// Makes `html`, e.g. an image, a link opening `href` in a new tab
+pub fn new_tab_link(href: &str, html: &str) -> String {
+    format!(
+        "<a href=\"{}\" target=\"_blank\" rel=\"noopener\">{}</a>",
+        escape(href),
+        html
+    )
+}
+
+/// An `<object>` showing the SVG at `data` as a document of its own, so its
+/// links work, with `fallback` for browsers which don't show it
+pub fn object(data: &str, alt: &str, attributes: &[(String, String)], fallback: &str) -> String {
+    let mut html = format!("<object type=\"image/svg+xml\" data=\"{}\"", escape(data));
+    if !alt.is_empty() {
+        html.push_str(&format!(" aria-label=\"{}\"", escape(alt)));
+    }
+    for (name, value) in attributes {
+        html.push_str(&format!(" {}=\"{}\"", name, escape(value)));
+    }
+    html.push_str(&format!(">{}</object>", fallback));
+    html
+}
+
+/// An SVG put into the page itself, so the page's styles and scripts reach it,
+/// followed by `fallback`, e.g. an `<img>` of the same file, in a `<noscript>`
+/// for readers which don't take inline SVG. It has to end up in a `block`.
+pub fn inline_with_fallback(svg: &str, fallback: &str) -> String {
+    format!("{}\n<noscript>{}</noscript>", svg, fallback)
+}
+
+/// A `<figure>` of an image with its caption, which takes the image's anchor
+/// when it has one. `caption` is HTML already.
+pub fn figure(id: Option<&str>, html: &str, caption: &str) -> String {
+    let id = id.map_or_else(String::new, |id| format!(" id=\"{}\"", escape(id)));
+    format!(
+        "<figure{}>{}<figcaption>{}</figcaption></figure>",
+        id, html, caption
+    )
+}
+
+/// Wraps `html` in a `<div>`, which markdown leaves alone as an HTML block
+/// however many lines the markup inside takes, up to a blank line
+pub fn block(html: &str) -> String {
+    format!("<div>{}</div>", html)
+}
@@ -0,0 +1,132 @@
+use std::collections::HashSet;
+use std::fs::{read, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// The byte order mark some editors put at the start of UTF-8 files
+static UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// The include directives naming files, longest first so `!include` doesn't
+/// take the others. `!includeurl` fetches over the network and is left out.
+static INCLUDE_DIRECTIVES: &[&str] = &["!include_many", "!include_once", "!includesub", "!include"];
+
+/// The files a diagram includes, as they're written. Standard library includes
+/// like `<C4/C4_Container>` and URLs aren't files and are left out.
+pub fn included_files(plantuml_code: &str) -> Vec<&str> {
+    plantuml_code
+        .lines()
+        .filter_map(included_file)
+        .map(|(_, file)| file)
+        .collect()
+}
+
+/// The code with the file of every include directive replaced by what
+/// `replace` makes of it, the rest of each line left as it is
+pub fn replace_included_files(
+    plantuml_code: &str,
+    mut replace: impl FnMut(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut replaced = String::with_capacity(plantuml_code.len());
+    for line in plantuml_code.split_inclusive('\n') {
+        match included_file(line) {
+            Some((start, file)) => {
+                replaced.push_str(&line[..start]);
+                replaced.push_str(&replace(file)?);
+                replaced.push_str(&line[start + file.len()..]);
+            }
+            None => replaced.push_str(line),
+        }
+    }
+    Ok(replaced)
+}
+
+/// The file a line includes, along with where in the line it starts
+fn included_file(line: &str) -> Option<(usize, &str)> {
+    let indent = line.len() - line.trim_start().len();
+    let (directive, rest) = INCLUDE_DIRECTIVES.iter().find_map(|directive| {
+        line.trim()
+            .strip_prefix(directive)
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+            .map(|rest| (directive, rest))
+    })?;
+    let spacing = rest.len() - rest.trim_start().len();
+    // `file!2` and `!includesub file!PART` pick part of the file
+    let file = rest.trim_start().split('!').next()?.trim_end();
+    if file.is_empty() || file.starts_with('<') || file.contains("://") {
+        return None;
+    }
+    Some((indent + directive.len() + spacing, file))
+}
+
+/// Finds an included file the way PlantUML would, in the first of `directories` it's in
+pub fn resolve(file: &str, directories: &[&Path]) -> Option<PathBuf> {
+    directories
+        .iter()
+        .map(|directory| directory.join(file))
+        .find(|path| path.is_file())
+}
+
+/// What the files a diagram includes hold, and those they include in turn, in
+/// the order they're included, so editing any of them changes the diagram's
+/// hash. A nested include is looked for next to the file including it first.
+/// Files which aren't found are left for PlantUML to report, each file only
+/// counts once.
+pub fn included_contents(plantuml_code: &str, directories: &[&Path]) -> Vec<u8> {
+    let mut contents = Vec::new();
+    add_included_contents(
+        plantuml_code,
+        None,
+        directories,
+        &mut HashSet::new(),
+        &mut contents,
+    );
+    contents
+}
+
+fn add_included_contents(
+    code: &str,
+    including_directory: Option<&Path>,
+    directories: &[&Path],
+    seen: &mut HashSet<PathBuf>,
+    contents: &mut Vec<u8>,
+) {
+    let mut search: Vec<&Path> = including_directory.into_iter().collect();
+    search.extend(directories);
+    for file in included_files(code) {
+        let path = match resolve(file, &search) {
+            Some(path) => path,
+            None => continue,
+        };
+        if !seen.insert(path.canonicalize().unwrap_or_else(|_| path.clone())) {
+            continue;
+        }
+        let included = match read(&path) {
+            Ok(included) => included,
+            Err(_) => continue,
+        };
+        contents.extend_from_slice(&included);
+        add_included_contents(
+            &String::from_utf8_lossy(&included),
+            path.parent(),
+            directories,
+            seen,
+            contents,
+        );
+    }
+}
+
+/// Whether a file starts with a UTF-8 byte order mark. PlantUML can take it
+/// for part of the first line and then misread that line's directive.
+pub fn starts_with_bom(path: &Path) -> bool {
+    let mut start = [0; 3];
+    match File::open(path).and_then(|mut file| file.read_exact(&mut start)) {
+        Ok(()) => start == UTF8_BOM,
+        Err(_) => false,
+    }
+}
+
+/// Source read by the preprocessor itself, with the byte order mark dropped and
+/// Windows line endings normalized, so neither changes the diagram's hash
+pub fn normalize(source: &str) -> String {
+    source.trim_start_matches('\u{feff}').replace("\r\n", "\n")
+}
@@ -0,0 +1,88 @@
+//! A shared key added to diagrams from a `.puml` fragment the book configures,
+//! so teams don't paste the same `legend` block into every diagram
+
+use std::fs::read;
+use std::path::Path;
+
+use mdbook::errors::Error;
+
+use crate::config::Config;
+use crate::include;
+
+pub struct Legend {
+    fragment: String,
+    diagram_types: Vec<String>,
+}
+
+impl Legend {
+    /// Reads the configured fragment, relative to the book root
+    pub fn from_config(config: &Config, book_root: &Path) -> Result<Option<Legend>, Error> {
+        let path = match &config.legend {
+            Some(path) => book_root.join(path),
+            None => return Ok(None),
+        };
+        let fragment = read(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|fragment| config.input_charset.decode(fragment));
+        let fragment = fragment.map_err(|err| {
+            Error::from(format!(
+                "Unable to read the legend {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        let mut fragment = include::normalize(&fragment);
+        if !fragment.ends_with('\n') {
+            fragment.push('\n');
+        }
+        Ok(Some(Legend {
+            fragment,
+            diagram_types: config.legend_diagram_types.clone(),
+        }))
+    }
+
+    /// Whether a diagram gets the legend: when its fence asks with `legend`,
+    /// or else when it's one of the `legend_diagram_types`
+    pub fn applies_to(&self, asked: Option<bool>, plantuml_code: &str) -> bool {
+        asked.unwrap_or_else(|| {
+            diagram_type(plantuml_code)
+                .is_some_and(|diagram_type| self.diagram_types.iter().any(|t| t == diagram_type))
+        })
+    }
+
+    /// Adds the fragment right before the last `@end...` directive, which puts
+    /// it on the last page of a diagram split with `newpage`
+    pub fn apply(&self, plantuml_code: &str) -> String {
+        let lines: Vec<&str> = plantuml_code.split_inclusive('\n').collect();
+        let end = lines
+            .iter()
+            .rposition(|line| line.trim_start().starts_with("@end"));
+        let mut applied = String::with_capacity(plantuml_code.len() + self.fragment.len());
+        for (index, line) in lines.iter().enumerate() {
+            if Some(index) == end {
+                applied.push_str(&self.fragment);
+            }
+            applied.push_str(line);
+        }
+        if end.is_none() {
+            if !applied.is_empty() && !applied.ends_with('\n') {
+                applied.push('\n');
+            }
+            applied.push_str(&self.fragment);
+        }
+        applied
+    }
+}
+
+/// The type of the diagram's first `@start` directive, e.g. `uml` for
+/// `@startuml` or `gantt` for `@startgantt(id=plan)`
+fn diagram_type(plantuml_code: &str) -> Option<&str> {
+    let directive = plantuml_code
+        .lines()
+        .map(str::trim_start)
+        .find_map(|line| line.strip_prefix("@start"))?;
+    let end = directive
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(directive.len());
+    Some(&directive[..end])
+}
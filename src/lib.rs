@@ -0,0 +1,5 @@
+//! The parts of the preprocessor which are of use outside of it. For now that's
+//! the configuration, so the options of a `[preprocessor.plantuml-renderer]`
+//! table can be read, or built up in code, without going through mdbook.
+
+pub mod config;
@@ -0,0 +1,41 @@
+//! Diagrams defined once, with a `{#id}` on their fence, and shown wherever
+//! `{{#plantuml <id>}}` refers to them
+
+static REFERENCE_START: &str = "{{#plantuml";
+static REFERENCE_END: &str = "}}";
+
+/// Replaces every `{{#plantuml <id>}}` with what `image` makes of the id. Like
+/// mdbook's own `{{#include}}`, references are found anywhere in the text.
+pub fn replace_references(
+    content: &str,
+    mut image: impl FnMut(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut replaced = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find(REFERENCE_START) {
+        let after = &rest[start + REFERENCE_START.len()..];
+        let end = match after.find(REFERENCE_END) {
+            Some(end) if after.starts_with(char::is_whitespace) => end,
+            // Something else starting alike, like `{{#plantumlx}}`
+            _ => {
+                replaced.push_str(&rest[..start + REFERENCE_START.len()]);
+                rest = after;
+                continue;
+            }
+        };
+        let id = after[..end].trim();
+        if id.is_empty() || id.contains(char::is_whitespace) {
+            return Err(format!(
+                "`{}{}{}` has to name exactly one diagram id",
+                REFERENCE_START,
+                &after[..end],
+                REFERENCE_END
+            ));
+        }
+        replaced.push_str(&rest[..start]);
+        replaced.push_str(&image(id)?);
+        rest = &after[end + REFERENCE_END.len()..];
+    }
+    replaced.push_str(rest);
+    Ok(replaced)
+}
@@ -0,0 +1,91 @@
+use std::fs::read_to_string;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+use log::{debug, info};
+
+/// What a PlantUML JVM is assumed to take, in kB as `/proc/meminfo` counts
+static KB_PER_PROCESS: usize = 512 * 1024;
+
+/// A semaphore keeping more than `limit` PlantUML processes from running at
+/// once, each one is a JVM of its own
+#[derive(Debug)]
+pub struct ProcessLimit {
+    limit: usize,
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+/// Holds one of the limited places until it's dropped
+pub struct Permit<'a> {
+    limit: &'a ProcessLimit,
+}
+
+impl ProcessLimit {
+    pub fn new(limit: usize) -> ProcessLimit {
+        ProcessLimit {
+            limit,
+            running: Mutex::new(0),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// One process per CPU, but no more than the available memory holds
+    pub fn default_limit() -> usize {
+        let cpus = thread::available_parallelism().map_or(1, |cpus| cpus.get());
+        let limit = match available_memory() {
+            Some(kb) => cpus.min(kb / KB_PER_PROCESS).max(1),
+            None => cpus,
+        };
+        debug!("Running up to {} PlantUML processes at once", limit);
+        limit
+    }
+
+    /// How many processes may run at once
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Waits until a process may be started
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut running = self
+            .running
+            .lock()
+            .expect("The process limit lock is poisoned");
+        if *running >= self.limit {
+            info!(
+                "{} PlantUML processes are running, waiting for one to finish",
+                self.limit
+            );
+        }
+        while *running >= self.limit {
+            running = self
+                .finished
+                .wait(running)
+                .expect("The process limit lock is poisoned");
+        }
+        *running += 1;
+        Permit { limit: self }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let mut running = self
+            .limit
+            .running
+            .lock()
+            .expect("The process limit lock is poisoned");
+        *running -= 1;
+        self.limit.finished.notify_one();
+    }
+}
+
+/// The memory available for new processes in kB, only known on Linux
+fn available_memory() -> Option<usize> {
+    let meminfo = read_to_string("/proc/meminfo").ok()?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
@@ -0,0 +1,33 @@
+//! mdbook's `{{#include}}` directives, which its `links` preprocessor replaces
+//! with the files they name. Diagrams in those files are only seen by this
+//! preprocessor once that's happened.
+
+static INCLUDE_START: &str = "{{#include";
+static INCLUDE_END: &str = "}}";
+
+/// The files the chapter's `{{#include path}}` directives name, relative to the
+/// chapter, with any `:anchor` or line range taken off. Escaped ones, like
+/// `\{{#include}}`, are left out, as mdbook leaves them be.
+pub fn included_files(content: &str) -> Vec<&str> {
+    let mut files = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find(INCLUDE_START) {
+        let escaped = rest[..start].ends_with('\\');
+        let after = &rest[start + INCLUDE_START.len()..];
+        rest = after;
+        // Something else starting alike, like `{{#include_code}}`
+        if escaped || !after.starts_with(char::is_whitespace) {
+            continue;
+        }
+        let end = match after.find(INCLUDE_END) {
+            Some(end) => end,
+            None => break,
+        };
+        let file = after[..end].trim().split(':').next().unwrap_or_default();
+        if !file.is_empty() {
+            files.push(file);
+        }
+        rest = &after[end + INCLUDE_END.len()..];
+    }
+    files
+}
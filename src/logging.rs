@@ -0,0 +1,94 @@
+use std::cell::RefCell;
+use std::env;
+use std::error::Error;
+use std::fmt::Arguments;
+use std::io::stderr;
+use std::thread::LocalKey;
+
+use log::{LevelFilter, Record};
+use serde_json::{Map, Value};
+
+use crate::config::LogFormat;
+
+thread_local! {
+    /// What the preprocessor is currently working on, attached to JSON log lines
+    static CHAPTER: RefCell<Option<String>> = const { RefCell::new(None) };
+    static DIAGRAM: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub fn setup(format: LogFormat, level: LevelFilter) -> Result<(), Box<dyn Error>> {
+    let dispatch = fern::Dispatch::new();
+    let dispatch = match format {
+        LogFormat::Text => dispatch.format(|out, message, record| {
+            out.finish(format_args!(
+                "{} [{}] ({}): {}",
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                record.level(),
+                record.target(),
+                message
+            ))
+        }),
+        LogFormat::Json => dispatch.format(|out, message, record| {
+            out.finish(format_args!("{}", json_line(message, record)))
+        }),
+    };
+    dispatch
+        .level(level)
+        .chain(stderr())
+        .chain(fern::log_file("plantuml-renderer-output.log")?)
+        .apply()?;
+    Ok(())
+}
+
+/// The level `RUST_LOG` sets for the preprocessor, the last of a bare level like
+/// `warn` and one for its target like `mdbook_plantuml_renderer=debug`. Those for
+/// other targets and anything unparseable don't count.
+pub fn env_level() -> Option<LevelFilter> {
+    let directives = env::var("RUST_LOG").ok()?;
+    directives
+        .rsplit(',')
+        .find_map(|directive| match directive.trim().split_once('=') {
+            Some((target, level)) if target.trim() == env!("CARGO_CRATE_NAME") => {
+                level.trim().parse().ok()
+            }
+            Some(_) => None,
+            None => directive.trim().parse().ok(),
+        })
+}
+
+/// One self-contained JSON object per line, so CI can pick out diagram failures
+fn json_line(message: &Arguments, record: &Record) -> Value {
+    let mut line = Map::new();
+    line.insert("timestamp".into(), chrono::Local::now().to_rfc3339().into());
+    line.insert("level".into(), record.level().to_string().into());
+    line.insert("target".into(), record.target().into());
+    line.insert("message".into(), message.to_string().into());
+    for (field, key) in &[("chapter", &CHAPTER), ("diagram", &DIAGRAM)] {
+        if let Some(value) = key.with(|value| value.borrow().clone()) {
+            line.insert(field.to_string(), value.into());
+        }
+    }
+    Value::Object(line)
+}
+
+/// Attaches a field to log lines until it's dropped
+pub struct Scope(&'static LocalKey<RefCell<Option<String>>>);
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        self.0.with(|value| value.borrow_mut().take());
+    }
+}
+
+fn scope(key: &'static LocalKey<RefCell<Option<String>>>, value: &str) -> Scope {
+    key.with(|current| *current.borrow_mut() = Some(value.to_string()));
+    Scope(key)
+}
+
+pub fn chapter(name: &str) -> Scope {
+    scope(&CHAPTER, name)
+}
+
+pub fn diagram(plantuml_hash_sum: &str) -> Scope {
+    scope(&DIAGRAM, plantuml_hash_sum)
+}
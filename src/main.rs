@@ -1,17 +1,58 @@
 extern crate crypto;
 
-use std::fs::{create_dir_all, File};
-use std::io::{stderr, stdin, stdout, Read, Write};
-use std::path::{Path, PathBuf};
-use std::process::Command;
+mod check;
+mod container;
+mod error;
+mod fence;
+mod figure;
+mod filter;
+mod fragment;
+mod front_matter;
+mod gallery;
+mod group;
+mod html;
+mod include;
+mod legend;
+mod library;
+mod limit;
+mod links;
+mod logging;
+mod manifest;
+mod markdown;
+mod pending;
+mod preamble;
+mod prune;
+mod region;
+mod remote;
+mod render;
+mod resolver;
+mod semantic;
+mod server;
+mod stamp;
+mod svg;
+mod timeout;
+mod title;
+mod validate;
+mod watermark;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{create_dir_all, read, read_to_string, remove_dir_all, write};
+use std::io::{stdin, stdout, Read, Write};
+use std::iter;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 use log::{debug, error, info, trace, warn};
 
 use log::LevelFilter;
-use mdbook::book::{Book, BookItem};
+use mdbook::book::{Book, BookItem, Chapter};
 use mdbook::errors::Error;
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor, PreprocessorContext};
+use mdbook::MDBook;
 
 use markedit::{rewrite_between, Matcher, Rewriter};
 use pulldown_cmark::{CodeBlockKind, CowStr, Event, LinkType, Parser, Tag};
@@ -20,217 +61,2869 @@ use pulldown_cmark_to_cmark::cmark;
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
 
-static PLANTUML_RENDERABLE_LANGUAGE: &str = "plantuml,render";
-static RENDER_DIRECTORY_NAME: &str = "plantuml-diagrams";
+// The configuration is part of the library, for reuse, the rest refers to it as `crate::config`
+use mdbook_plantuml_renderer::config;
 
-fn main() -> Result<(), Box<std::error::Error>> {
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{} [{}] ({}): {}",
-                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-                record.level(),
-                record.target(),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Trace)
-        .chain(stderr())
-        .chain(fern::log_file("plantuml-renderer-output.log")?)
-        .apply()?;
+use crate::config::{
+    AltFallback, Config, Embed, GlobalTimeout, InputCharset, LogFormat, LogLevel, OutputFormat,
+    UrlStyle,
+};
+use crate::error::RenderError;
+use crate::fence::FenceInfo;
+use crate::filter::ChapterFilter;
+use crate::fragment::Fragments;
+use crate::front_matter::FrontMatter;
+use crate::gallery::Gallery;
+use crate::group::Groups;
+use crate::legend::Legend;
+use crate::preamble::Preamble;
+use crate::region::Marker;
+use crate::render::{Diagram, Renderer, Summary};
+use crate::resolver::IncludeResolver;
+use crate::watermark::Watermark;
 
-    let preprocessor = PlantumlRendererPreprocessor::default();
+static VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The commit the binary was built from, the build script leaves it out when
+/// there's no repository to ask
+static COMMIT: Option<&str> = option_env!("PLANTUML_RENDERER_COMMIT");
+/// Where the `check`, `ci-check` and `compare` subcommands render to, within the book root
+static CHECK_DIRECTORY_NAME: &str = ".plantuml-renderer-check";
+/// Where the `validate` subcommand writes diagrams for PlantUML to read
+static VALIDATE_DIRECTORY_NAME: &str = ".plantuml-renderer-validate";
+/// Where the `render-one` subcommand renders its diagram, within the book root
+static RENDER_ONE_DIRECTORY_NAME: &str = ".plantuml-renderer-render-one";
+/// What the `render-one` subcommand's diagram is said to come from in errors
+static RENDER_ONE_ORIGIN: &str = "render-one";
+/// Subcommands prepare diagrams for the usual build, which `output_format = "auto"` is resolved for
+static SUBCOMMAND_RENDERER: &str = "html";
+/// The language of the code blocks ASCII art is shown in
+static ASCII_ART_LANGUAGE: &str = "text";
+/// What the comments of `emit_markers` start with
+static MARKER_BEGIN: &str = "plantuml-renderer:begin";
+static MARKER_END: &str = "plantuml-renderer:end";
+/// What the link to a diagram's `file:` says
+static SOURCE_LINK_TEXT: &str = "source";
+static GITATTRIBUTES_FILENAME: &str = ".gitattributes";
+/// The image formats `lfs_gitattributes` has Git LFS store, SVGs and ASCII art
+/// are text which Git diffs well
+static LFS_EXTENSIONS: &[&str] = &["png", "webp"];
+/// The resolution PlantUML draws raster images at, which `srcset_scales` multiply
+static BASE_DPI: f64 = 96.0;
+/// How many characters of the hash name the directory of an image with `shard`
+static SHARD_LENGTH: usize = 2;
+/// Where the diagrams of the `diagrams` option are said to come from
+static NAMED_DIAGRAMS_ORIGIN: &str = "book.toml";
+/// Options of mdbook's commands followed by a value, which isn't the book's directory
+static MDBOOK_VALUE_OPTIONS: &[&str] = &[
+    "-d",
+    "--dest-dir",
+    "-n",
+    "--hostname",
+    "-p",
+    "--port",
+    "--websocket-hostname",
+    "--websocket-port",
+    "-L",
+    "--library-path",
+];
+/// The `[preprocessor.<name>]` table read unless `--name` or the environment say otherwise
+pub const PREPROCESSOR_NAME: &str = "plantuml-renderer";
+/// Like a usage error, set apart from the preprocessor failing on a book
+static NOT_RUN_BY_MDBOOK_EXIT_CODE: i32 = 2;
+/// Lets the same binary be registered under several names, e.g. one per output format
+static PREPROCESSOR_NAME_VARIABLE: &str = "MDBOOK_PLANTUML_RENDERER_NAME";
+
+fn main() -> Result<(), Box<std::error::Error>> {
     let matches = get_clap().get_matches();
-    if let Some(_support_subcommand) = matches.subcommand_matches("supports") {
-        // if preprocessor.supports_renderer(renderer) {
-        //     return Ok(());
-        // }
-        warn!("We're returning true to all renderers, this hasn't been tested");
+    let quiet = is_quiet(&matches);
+    let preprocessor = match matches.value_of("name") {
+        Some(name) => PlantumlRendererPreprocessor::new(name),
+        None => match std::env::var(PREPROCESSOR_NAME_VARIABLE) {
+            Ok(name) if !name.is_empty() => PlantumlRendererPreprocessor::new(&name),
+            _ => PlantumlRendererPreprocessor::default(),
+        },
+    };
+    if let Some(supports_subcommand) = matches.subcommand_matches("supports") {
+        logging::setup(LogFormat::Text, log_level(quiet, None))?;
+        let renderer = supports_subcommand
+            .value_of("renderer")
+            .expect("The renderer is required");
+        let book_root = supports_subcommand
+            .value_of("root")
+            .map_or_else(mdbook_book_root, PathBuf::from);
+        // mdbook takes any other exit status for not supported
+        process::exit(if preprocessor.supports_renderer_of(&book_root, renderer) {
+            0
+        } else {
+            1
+        });
+    }
+    if let Some(version_subcommand) = matches.subcommand_matches("version") {
+        logging::setup(LogFormat::Text, log_level(quiet, None))?;
+        let book_root = Path::new(version_subcommand.value_of("root").unwrap_or("."));
+        // Outside of a book PlantUML is looked for the default way
+        let config = match MDBook::load(book_root) {
+            Ok(book) => {
+                Config::from_book_config(&book.config, preprocessor.name(), SUBCOMMAND_RENDERER)?
+            }
+            Err(_) => Config::default(),
+        };
+        println!(
+            "mdbook-plantuml-renderer {} ({})",
+            VERSION,
+            COMMIT.unwrap_or("unknown commit")
+        );
+        let render_directory =
+            determine_plantuml_output_directory(&determine_build_directory(book_root), &config);
+        let renderer = Renderer::new(&config, book_root, &render_directory)?;
+        match renderer.plantuml_version() {
+            Some(plantuml_version) => println!("{}", plantuml_version),
+            None => println!("PlantUML wasn't found or didn't run"),
+        }
+        return Ok(());
+    }
+    if let Some(stop_server_subcommand) = matches.subcommand_matches("stop-server") {
+        logging::setup(LogFormat::Text, log_level(quiet, None))?;
+        let book_root = Path::new(stop_server_subcommand.value_of("root").unwrap_or("."));
+        if server::stop(&book_root.join(server::PIDFILE_NAME))? {
+            println!("Stopped the PlantUML server");
+        } else {
+            println!("No PlantUML server was running");
+        }
+        return Ok(());
+    }
+    if let Some(prerender_subcommand) = matches.subcommand_matches("prerender") {
+        let book_root = prerender_subcommand.value_of("root").unwrap_or(".");
+        let book = MDBook::load(book_root)?;
+        let config =
+            Config::from_book_config(&book.config, preprocessor.name(), SUBCOMMAND_RENDERER)?;
+        logging::setup(config.log_format, log_level(quiet, Some(&config)))?;
+        check_build_directory(&book.root, &book.config.build.build_dir, &config)?;
+        let source_directory = determine_build_directory(&book.root);
+        let (_, summary) =
+            preprocessor.render_book(&book.root, &source_directory, &config, None, book.book)?;
+        println!(
+            "Rendered {} diagrams, {} were already rendered",
+            summary.rendered, summary.cached
+        );
+        return Ok(());
+    }
+    if let Some(check_subcommand) = matches.subcommand_matches("check") {
+        let book_root = check_subcommand.value_of("root").unwrap_or(".");
+        let book = MDBook::load(book_root)?;
+        let config =
+            Config::from_book_config(&book.config, preprocessor.name(), SUBCOMMAND_RENDERER)?;
+        logging::setup(config.log_format, log_level(quiet, Some(&config)))?;
+        let mismatches = preprocessor.render_afresh(book, &config, |fresh, committed| {
+            check::compare(fresh, committed).map_err(Error::from)
+        })?;
+        if mismatches.is_empty() {
+            println!("Every diagram is up to date");
+            return Ok(());
+        }
+        for mismatch in &mismatches {
+            println!("{}", mismatch);
+        }
+        println!("{} diagrams are out of date", mismatches.len());
+        process::exit(1);
+    }
+    if let Some(ci_check_subcommand) = matches.subcommand_matches("ci-check") {
+        let book_root = ci_check_subcommand.value_of("root").unwrap_or(".");
+        let ignore_metadata = ci_check_subcommand.is_present("ignore-metadata");
+        let book = MDBook::load(book_root)?;
+        let config =
+            Config::from_book_config(&book.config, preprocessor.name(), SUBCOMMAND_RENDERER)?;
+        logging::setup(config.log_format, log_level(quiet, Some(&config)))?;
+        let mismatches = preprocessor.render_afresh(book, &config, |fresh, committed| {
+            check::compare_all(fresh, committed, &config.diagram_directory, ignore_metadata)
+                .map_err(Error::from)
+        })?;
+        if mismatches.is_empty() {
+            println!("Every committed diagram is up to date");
+            return Ok(());
+        }
+        for mismatch in &mismatches {
+            println!("{}", mismatch);
+        }
+        let count = |kind: fn(&check::Mismatch) -> bool| {
+            mismatches.iter().filter(|mismatch| kind(mismatch)).count()
+        };
+        println!(
+            "{} missing, {} changed, {} removed",
+            count(|mismatch| matches!(mismatch, check::Mismatch::Missing(_))),
+            count(|mismatch| matches!(mismatch, check::Mismatch::Changed(_))),
+            count(|mismatch| matches!(mismatch, check::Mismatch::Removed(_)))
+        );
+        process::exit(1);
+    }
+    if let Some(compare_subcommand) = matches.subcommand_matches("compare") {
+        let book_root = compare_subcommand.value_of("root").unwrap_or(".");
+        let book = MDBook::load(book_root)?;
+        let config =
+            Config::from_book_config(&book.config, preprocessor.name(), SUBCOMMAND_RENDERER)?;
+        logging::setup(config.log_format, log_level(quiet, Some(&config)))?;
+        let report_directory = compare_subcommand.value_of("report").map(PathBuf::from);
+        let (changed, report) = preprocessor.render_afresh(book, &config, |fresh, committed| {
+            // Diagrams without a cached image have nothing to be compared with
+            let changed: Vec<PathBuf> = check::compare(fresh, committed)?
+                .into_iter()
+                .filter_map(|mismatch| match mismatch {
+                    check::Mismatch::Changed(path) => Some(path),
+                    check::Mismatch::Missing(_) | check::Mismatch::Removed(_) => None,
+                })
+                .collect();
+            let report = match &report_directory {
+                Some(directory) if !changed.is_empty() => {
+                    Some(check::write_report(&changed, fresh, committed, directory)?)
+                }
+                _ => None,
+            };
+            Ok((changed, report))
+        })?;
+        for path in &changed {
+            println!("changed: {}", path.display());
+        }
+        if changed.is_empty() {
+            println!("Every cached diagram renders the same");
+        } else {
+            println!("{} diagrams render differently", changed.len());
+        }
+        if let Some(report) = report {
+            println!("Side by side: {}", report.display());
+        }
+        return Ok(());
+    }
+    if let Some(validate_subcommand) = matches.subcommand_matches("validate") {
+        let book_root = validate_subcommand.value_of("root").unwrap_or(".");
+        let book = MDBook::load(book_root)?;
+        let config =
+            Config::from_book_config(&book.config, preprocessor.name(), SUBCOMMAND_RENDERER)?;
+        logging::setup(config.log_format, log_level(quiet, Some(&config)))?;
+        let (checked, failures) = preprocessor.validate_book(&book.root, &config, &book.book)?;
+        if failures.is_empty() {
+            println!("All {} diagrams are valid", checked);
+            return Ok(());
+        }
+        for failure in &failures {
+            println!("{}", failure);
+        }
+        println!("{} of {} diagrams have errors", failures.len(), checked);
+        process::exit(1);
+    }
+    if let Some(render_one_subcommand) = matches.subcommand_matches("render-one") {
+        let book_root = Path::new(render_one_subcommand.value_of("root").unwrap_or("."));
+        // Outside of a book the diagram is rendered with the defaults
+        let mut config = match MDBook::load(book_root) {
+            Ok(book) => {
+                Config::from_book_config(&book.config, preprocessor.name(), SUBCOMMAND_RENDERER)?
+            }
+            Err(_) => Config::default(),
+        };
+        if let Some(format) = render_one_subcommand.value_of("format") {
+            config.output_format = match toml::Value::String(format.to_string()).try_into() {
+                Ok(OutputFormat::Auto) | Err(_) => {
+                    return Err(format!("`{}` isn't an output format", format).into())
+                }
+                Ok(format) => format,
+            };
+        }
+        logging::setup(config.log_format, log_level(quiet, Some(&config)))?;
+        // Includes are looked for next to the file, like next to a chapter
+        let (source, include_directory) = match render_one_subcommand.value_of("input") {
+            Some(input) if input != "-" => {
+                let input = Path::new(input);
+                let directory = match input.parent() {
+                    Some(directory) if !directory.as_os_str().is_empty() => directory,
+                    _ => Path::new("."),
+                };
+                let source = config
+                    .input_charset
+                    .decode(read(input)?)
+                    .map_err(|err| format!("Unable to read {}: {}", input.display(), err))?;
+                (source, directory.to_path_buf())
+            }
+            _ => {
+                let mut source = String::new();
+                stdin().read_to_string(&mut source)?;
+                (source, PathBuf::from("."))
+            }
+        };
+        let image = preprocessor.render_one(book_root, &config, &source, &include_directory)?;
+        match render_one_subcommand.value_of("output") {
+            Some(output) => write(output, image)?,
+            None => stdout().write_all(&image)?,
+        }
         return Ok(());
     }
 
-    // let mut buffer = String::new();
-    // stdin().read_to_string(&mut buffer);
-    // let mut f = File::create("foo.txt")?;
-    // write!(f, "{}", buffer);
-
-    let (context, book) = CmdPreprocessor::parse_input(stdin())?;
+    // Run by hand rather than by mdbook, there's either a terminal or nothing
+    // at all on stdin, and serde's complaints about it don't help anyone
+    if atty::is(atty::Stream::Stdin) {
+        not_run_by_mdbook(preprocessor.name(), None);
+    }
+    let mut input = String::new();
+    stdin().read_to_string(&mut input)?;
+    if input.trim().is_empty() {
+        not_run_by_mdbook(preprocessor.name(), None);
+    }
+    let (context, book) = match CmdPreprocessor::parse_input(input.as_bytes()) {
+        Ok(parsed) => parsed,
+        Err(err) => not_run_by_mdbook(preprocessor.name(), Some(&err)),
+    };
+    // The log format is part of the book's configuration, so logging can only
+    // start once the book has been read
+    let config = Config::from_context(&context, preprocessor.name())?;
+    logging::setup(config.log_format, log_level(quiet, Some(&config)))?;
     info!("Initiated");
     let resulting_book = preprocessor.run(&context, book)?;
     serde_json::to_writer(stdout(), &resulting_book)?;
     Ok(())
 }
 
-fn get_clap() -> App<'static, 'static> {
-    const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+/// `--quiet` can be given before or after a subcommand
+fn is_quiet(matches: &ArgMatches<'_>) -> bool {
+    matches.is_present("quiet")
+        || matches
+            .subcommand()
+            .1
+            .is_some_and(|subcommand| subcommand.is_present("quiet"))
+}
+
+/// Only errors with `--quiet` or `quiet = true`, which beats anything else,
+/// then `RUST_LOG`, then `log_level`
+fn log_level(quiet: bool, config: Option<&Config>) -> LevelFilter {
+    if quiet || config.is_some_and(|config| config.quiet) {
+        return LevelFilter::Error;
+    }
+    logging::env_level().unwrap_or_else(|| {
+        config
+            .map_or(LogLevel::Info, |config| config.log_level)
+            .filter()
+    })
+}
+
+/// Explains how the preprocessor is meant to be run, and exits
+fn not_run_by_mdbook(name: &str, err: Option<&Error>) -> ! {
+    if let Some(err) = err {
+        eprintln!("The input isn't a book passed on by mdbook: {}\n", err);
+    }
+    eprintln!(
+        "mdbook-plantuml-renderer is an mdbook preprocessor, mdbook runs it and passes \
+         it the book on stdin. Register it in book.toml:\n\n\
+         [preprocessor.{}]\n\
+         command = \"mdbook-plantuml-renderer\"\n\n\
+         and run `mdbook build`. See `mdbook-plantuml-renderer --help` for the \
+         subcommands which run on their own.",
+        name
+    );
+    process::exit(NOT_RUN_BY_MDBOOK_EXIT_CODE);
+}
 
+fn get_clap() -> App<'static, 'static> {
     App::new("mdbook-plantuml-renderer")
         .version(VERSION)
         .author("Paul Freakn Baker")
         .about("A preprocessor that will replace some inline codeblocks with rendered PlantUML")
+        .arg(
+            Arg::with_name("name")
+                .long("name")
+                .takes_value(true)
+                .help("The [preprocessor.<name>] table to read, defaults to plantuml-renderer"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .global(true)
+                .help("Only log errors"),
+        )
         .subcommand(
             SubCommand::with_name("supports")
                 .arg(Arg::with_name("renderer").required(true))
+                .arg(Arg::with_name("root").help(
+                    "The book's root directory, defaults to the one mdbook builds, \
+                     or else the current directory",
+                ))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            SubCommand::with_name("prerender")
+                .arg(
+                    Arg::with_name("root")
+                        .help("The book's root directory, defaults to the current directory"),
+                )
+                .about("Render every diagram of a book which isn't rendered yet, e.g. to warm a CI cache"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .arg(
+                    Arg::with_name("root")
+                        .help("The book's root directory, defaults to the current directory"),
+                )
+                .about("Render every diagram afresh and fail if the images in the book differ"),
+        )
+        .subcommand(
+            SubCommand::with_name("ci-check")
+                .arg(
+                    Arg::with_name("root")
+                        .help("The book's root directory, defaults to the current directory"),
+                )
+                .arg(
+                    Arg::with_name("ignore-metadata")
+                        .long("ignore-metadata")
+                        .help("Ignore SVG comments and processing instructions, and PNG metadata"),
+                )
+                .about(
+                    "Render every diagram afresh and fail if any committed image is missing, \
+                     differs or is no longer used",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compare")
+                .arg(
+                    Arg::with_name("root")
+                        .help("The book's root directory, defaults to the current directory"),
+                )
+                .arg(
+                    Arg::with_name("report")
+                        .long("report")
+                        .takes_value(true)
+                        .help("A directory to write a side by side HTML report of the changes to"),
+                )
+                .about("Render every diagram afresh and list the cached images which would change"),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .arg(
+                    Arg::with_name("root")
+                        .help("The book's root directory, defaults to the current directory"),
+                )
+                .about("Check the syntax of every diagram with PlantUML, without rendering any"),
+        )
+        .subcommand(
+            SubCommand::with_name("render-one")
+                .arg(Arg::with_name("input").help(
+                    "A file of PlantUML source, read from stdin when it's missing or `-`",
+                ))
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("Where to write the image, stdout by default"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .help("The output format, by default the book's: svg, png, webp or txt"),
+                )
+                .arg(
+                    Arg::with_name("root")
+                        .long("root")
+                        .takes_value(true)
+                        .help("The book's root directory, whose options are used, defaults to the current directory"),
+                )
+                .about("Render a single diagram with the book's options, outside of mdbook"),
+        )
+        .subcommand(
+            SubCommand::with_name("stop-server")
+                .arg(
+                    Arg::with_name("root")
+                        .help("The book's root directory, defaults to the current directory"),
+                )
+                .about("Stop the PlantUML server `server_mode = \"managed\"` left running"),
+        )
+        .subcommand(
+            SubCommand::with_name("version")
+                .arg(Arg::with_name("root").help(
+                    "The book's root directory, whose PlantUML options are used, defaults to \
+                     the current directory",
+                ))
+                .about("Print the preprocessor's version and commit, and the PlantUML it runs"),
+        )
 }
 
-#[derive(Default)]
-struct PlantumlRendererPreprocessor {}
+struct PlantumlRendererPreprocessor {
+    name: String,
+}
 
-impl Preprocessor for PlantumlRendererPreprocessor {
-    fn name(&self) -> &str {
-        "plantuml-renderer"
+impl PlantumlRendererPreprocessor {
+    fn new(name: &str) -> PlantumlRendererPreprocessor {
+        PlantumlRendererPreprocessor {
+            name: name.to_string(),
+        }
     }
 
-    fn run(&self, context: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
-        let plantuml_build_directory = determine_plantuml_output_directory(&context);
+    /// Renders every diagram of the book under `render_root`, normally the
+    /// book's source directory, returning the book with diagrams replaced by their images.
+    /// `in_place` leads from the renderer's output to the source directory, when
+    /// images are linked where they are.
+    fn render_book(
+        &self,
+        book_root: &Path,
+        render_root: &Path,
+        config: &Config,
+        in_place: Option<&Path>,
+        mut book: Book,
+    ) -> Result<(Book, Summary), Error> {
+        let source_directory = determine_build_directory(book_root);
+        let plantuml_build_directory = determine_plantuml_output_directory(render_root, config);
         create_dir_all(&plantuml_build_directory)?;
-        debug!(
-            "Output Directory: {}",
-            &plantuml_build_directory.to_str().unwrap()
+        if config.lfs_gitattributes {
+            write_gitattributes(&plantuml_build_directory)?;
+        }
+        debug!("Output Directory: {}", plantuml_build_directory.display());
+        let renderer = Renderer::new(config, book_root, &plantuml_build_directory)?;
+        if config.require_renderer
+            && has_diagrams(&book, config)?
+            && renderer.plantuml_version().is_none()
+        {
+            let reason = if renderer.is_installed() {
+                "doesn't run"
+            } else {
+                "isn't installed"
+            };
+            return Err(RenderError::Config(format!(
+                "The book has diagrams but PlantUML {}, require_renderer is set. \
+                 Run `mdbook-plantuml-renderer version` to see which PlantUML is looked for.",
+                reason
+            ))
+            .into());
+        }
+        if config.skip_if_missing && !renderer.is_installed() {
+            warn!(
+                "PlantUML isn't installed, so no diagram is rendered and they're all left \
+                 as source. Install it to see them, skip_if_missing is set."
+            );
+            strip_front_matter(&mut book)?;
+            let chapter_filter = ChapterFilter::from_config(config)?;
+            if config.show_skips {
+                let mut errors = Vec::new();
+                book.for_each_mut(|item: &mut BookItem| {
+                    if let BookItem::Chapter(chapter) = item {
+                        if chapter_filter.includes(&chapter.path) {
+                            show_skipped(chapter, config, false, "PlantUML isn't installed")
+                                .unwrap_or_else(|err| errors.push(err));
+                        }
+                    }
+                });
+                if let Some(err) = errors.into_iter().next() {
+                    return Err(err.into());
+                }
+            }
+            replace_references_with_source(&mut book, config, &chapter_filter)?;
+            return Ok((book, renderer.summary()));
+        }
+        let chapter_filter = ChapterFilter::from_config(config)?;
+        // With serve_async, diagrams which aren't rendered yet are left to a
+        // render in the background, so the page shows up without waiting on them
+        let deferring = config.serve_async
+            && matches!(mdbook_command().as_deref(), Some("serve") | Some("watch"));
+        let mut deferred = 0;
+        // Diagrams left unrendered once global_timeout_secs ran out
+        let mut out_of_time = 0;
+        let watermark = Watermark::from_config(config);
+        let legend = Legend::from_config(config, book_root)?;
+        let include_resolver = IncludeResolver::from_config(config, book_root);
+        let preamble = Preamble::from_config(config);
+        // The rewrite closures can't return errors, so they're collected and
+        // the first one is reported once every chapter has been visited
+        let mut errors: Vec<RenderError> = Vec::new();
+        // Every chapter each diagram hash was seen in, in book order
+        let mut occurrences: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        // The image, its format and the chapter of each diagram with an id, for references to it
+        let mut definitions: HashMap<String, (PathBuf, OutputFormat, String)> = HashMap::new();
+        let mut gallery = Gallery::default();
+        // Set once there are more diagrams than max_diagrams, the chapters left aren't rendered
+        let mut too_many_diagrams = false;
+        // The names of the chapters whose errors are only warned about
+        let mut lenient_chapters: HashSet<String> = HashSet::new();
+        // Chapters looked through for diagrams, and those which plainly had none
+        let mut chapters_processed = 0;
+        let mut chapters_skipped = 0;
+        // Those of the book.toml, which chapters take theirs from
+        let book_diagrams = ChapterDiagrams {
+            book_root,
+            render_root,
+            config,
+            preamble: &preamble,
+            watermark: watermark.as_ref(),
+            legend: legend.as_ref(),
+            include_resolver: include_resolver.as_ref(),
+            chapter_name: NAMED_DIAGRAMS_ORIGIN,
+            chapter_path: Path::new(""),
+            scale: None,
+        };
+        render_named_diagrams(
+            &book_diagrams,
+            &renderer,
+            &source_directory,
+            &mut definitions,
+            &mut errors,
         );
 
+        if config.parallel && !deferring {
+            render_in_parallel(
+                &book,
+                &book_diagrams,
+                &chapter_filter,
+                &renderer,
+                &source_directory,
+            );
+        }
+
         book.for_each_mut(|current_item: &mut BookItem| {
             if let BookItem::Chapter(ref mut current_chapter) = *current_item {
+                let lenient = chapter_filter.is_lenient(&current_chapter.path);
+                if lenient {
+                    lenient_chapters.insert(current_chapter.name.clone());
+                }
+                // It's meant for the preprocessor, so it's taken off every chapter
+                let front_matter = match front_matter::split_or_default(&current_chapter.content) {
+                    Ok((front_matter, rest)) => {
+                        if rest.len() < current_chapter.content.len() {
+                            current_chapter.content = rest.to_string();
+                        }
+                        front_matter
+                    }
+                    Err(err) => {
+                        errors.push(RenderError::chapter(&current_chapter.name, err));
+                        return;
+                    }
+                };
+                if !chapter_filter.includes(&current_chapter.path) {
+                    debug!("Skipping Chapter: {}", &current_chapter.name);
+                    if config.show_skips {
+                        let reason = "the chapter is left out by include_chapters or \
+                                      exclude_chapters";
+                        if let Err(err) = show_skipped(current_chapter, config, false, reason) {
+                            errors.push(err);
+                        }
+                    }
+                    return;
+                }
+                if too_many_diagrams {
+                    return;
+                }
                 info!("Working Chapter: {}", &current_chapter.name);
+                let chapter_name = current_chapter.name.clone();
+                let _chapter_scope = logging::chapter(&chapter_name);
+                if lenient {
+                    info!(
+                        "Chapter `{}` is in lenient_chapters, its diagrams only warn when they fail",
+                        chapter_name
+                    );
+                }
+                let setup = ChapterSetup::new(
+                    front_matter,
+                    config,
+                    &source_directory,
+                    &current_chapter.path,
+                );
+                for option in setup.front_matter.unknown_options() {
+                    let err = RenderError::chapter(
+                        &chapter_name,
+                        format!("unknown front matter option `{}`", option),
+                    );
+                    if config.strict {
+                        errors.push(err);
+                        return;
+                    }
+                    warn!("{}", err);
+                }
+                // The chapter's own options win over the book's
+                let config = &setup.config;
+                let chapter_path = current_chapter.path.clone();
+                let chapter_directory = setup.directory.as_path();
+                // Parsing and writing out a huge generated chapter takes a while,
+                // it's only done for chapters which may have a diagram at all
+                let may_have_diagrams = fence::may_have_diagrams(&current_chapter.content, config);
 
-                let events_iterator = markedit::parse(&current_chapter.content);
+                if may_have_diagrams {
+                    for event in markdown::parse(&current_chapter.content) {
+                        if let Event::Html(html) = &event {
+                            if fence::hidden_in_html(html, config) {
+                                warn!(
+                                    "Chapter `{}`: a diagram inside an HTML block isn't \
+                                     rendered, leave a blank line between the HTML and the \
+                                     code block",
+                                    chapter_name
+                                );
+                            }
+                        }
+                    }
+                }
+                // Left for mdbook's `links` preprocessor, which hasn't run yet
+                for file in links::included_files(&current_chapter.content) {
+                    let has_diagrams =
+                        read_to_string(chapter_directory.join(file)).is_ok_and(|included| {
+                            markdown::parse(&included).any(|event| {
+                                renderable_plantuml_start(&event, config)
+                            })
+                        });
+                    if has_diagrams {
+                        warn!(
+                            "Chapter `{}`: the diagrams of the included `{}` aren't rendered, \
+                             mdbook's links preprocessor has to run first, add \
+                             `after = [\"links\"]` to [preprocessor.{}]",
+                            chapter_name,
+                            file,
+                            self.name()
+                        );
+                    }
+                }
+                if !may_have_diagrams {
+                    debug!(
+                        "Chapter `{}` has no diagrams, it's left as it is",
+                        chapter_name
+                    );
+                    chapters_skipped += 1;
+                    return;
+                }
+                chapters_processed += 1;
+                let layout = match ChapterLayout::of(&current_chapter.content, config) {
+                    Ok(layout) => layout,
+                    Err(err) => {
+                        errors.push(RenderError::chapter(&chapter_name, err));
+                        return;
+                    }
+                };
+                if config.show_skips && layout.has_regions {
+                    let reason = "it's outside of the chapter's `plantuml:begin` and \
+                                  `plantuml:end` markers";
+                    if let Err(err) = show_skipped(current_chapter, config, true, reason) {
+                        errors.push(err);
+                        return;
+                    }
+                }
+                let chapter_diagrams = setup.diagrams(&book_diagrams, &chapter_name, &chapter_path);
+                if config.batch && !deferring && !renderer.is_out_of_time() {
+                    let prepared = layout.prepare(&current_chapter.content, &chapter_diagrams);
+                    let new_diagrams = prepared
+                        .iter()
+                        .map(|diagram| &diagram.plantuml_hash_sum)
+                        .filter(|hash| !occurrences.contains_key(*hash))
+                        .collect::<HashSet<_>>()
+                        .len();
+                    if let Some(max) = config.max_diagrams {
+                        if occurrences.len() + new_diagrams > max {
+                            errors.push(too_many_diagrams_error(&chapter_name, max));
+                            too_many_diagrams = true;
+                            return;
+                        }
+                    }
+                    let batch = images_to_render(&prepared).collect::<Vec<_>>();
+                    if let Err(err) = renderer.render_batch(&batch, chapter_directory) {
+                        errors.push(RenderError::chapter(&chapter_name, err));
+                    }
+                }
+
+                let events_iterator = markdown::parse(&current_chapter.content);
+                // Element ids handed out on this page, with how often each was asked for
+                let mut ids: HashMap<String, usize> = HashMap::new();
+                let mut groups = Groups::new(&layout.fences);
+                let mut fragments = Fragments::default();
+                // With reference_links, the label of every image URL, in order
+                let mut reference_definitions: Vec<(String, String)> = Vec::new();
 
                 // let plantuml_renderer = create_render_plantuml_renderer(&plantuml_build_directory);
                 let mutated_events_iterator = rewrite_between(
                     events_iterator,
-                    renderable_plantuml_start,
-                    renderable_plantuml_end,
+                    diagram_start_matcher(layout.has_regions, config),
+                    |event: &Event<'_>| renderable_plantuml_end(event, config),
                     |events: &mut Vec<Event<'_>>| {
-                        // Intentionally consume and remove all events by mapping them into
-                        // a single string of code. This helps strip out the opening/closing
-                        // code-fences before and after the codeblock.
-                        let plantuml_code = events
+                        let PreparedDiagram {
+                            fence,
+                            relative_directory,
+                            output_directory,
+                            source_file,
+                            plantuml_hash_sum,
+                            plantuml_code,
+                            image_name,
+                            format,
+                            no_cache,
+                            downloadable,
+                            link_base,
+                            variants,
+                            caption,
+                        } = match chapter_diagrams.prepare(events, &mut groups, &mut fragments) {
+                            Ok(Some(diagram)) => diagram,
+                            // Shown along with the rest of its group, or a fragment for later ones
+                            Ok(None) => {
+                                events.clear();
+                                return;
+                            }
+                            Err(err) => {
+                                errors.push(err);
+                                return;
+                            }
+                        };
+                        let _diagram_scope = logging::diagram(&plantuml_hash_sum);
+                        if let Some(max) = config.max_diagrams {
+                            if !occurrences.contains_key(&plantuml_hash_sum)
+                                && occurrences.len() >= max
+                            {
+                                if !too_many_diagrams {
+                                    errors.push(too_many_diagrams_error(&chapter_name, max));
+                                    too_many_diagrams = true;
+                                }
+                                return;
+                            }
+                        }
+                        occurrences
+                            .entry(plantuml_hash_sum.clone())
+                            .or_default()
+                            .push(chapter_name.clone());
+                        let mut unknown_attribute = false;
+                        for key in fence.unknown_attributes() {
+                            let err = RenderError::diagram(
+                                &chapter_name,
+                                &plantuml_hash_sum,
+                                format!("unknown fence attribute `{}`", key),
+                            );
+                            if config.strict {
+                                errors.push(err);
+                                unknown_attribute = true;
+                            } else {
+                                warn!("{}", err);
+                            }
+                        }
+                        if unknown_attribute {
+                            return;
+                        }
+                        // A caption describes the diagram too, unless it's described already
+                        let description = match fence.get("description") {
+                            Some(description) if !description.trim().is_empty() => {
+                                Some(description.to_string())
+                            }
+                            _ => caption.clone(),
+                        };
+                        let file_title = source_file
+                            .as_deref()
+                            .filter(|_| config.title_from_filename)
+                            .and_then(title::from_filename);
+                        let title = caption
+                            .clone()
+                            .or_else(|| title::extract(&plantuml_code))
+                            .or_else(|| file_title.clone());
+                        let gallery_title = description
+                            .clone()
+                            .or_else(|| title.clone())
+                            .unwrap_or_else(|| chapter_name.clone());
+                        // A file's name is taken as the title without asking for titles
+                        let alt_fallback = match config.alt_fallback() {
+                            AltFallback::None if file_title.is_some() => AltFallback::Title,
+                            alt_fallback => alt_fallback,
+                        };
+                        let description = description
+                            .or_else(|| alt_fallback.alt_text(title.as_deref(), &chapter_name));
+                        let described = description.is_some();
+                        if config.require_alt && !described {
+                            errors.push(RenderError::diagram(
+                                &chapter_name,
+                                &plantuml_hash_sum,
+                                "a `description` is required, require_alt is set",
+                            ));
+                            return;
+                        }
+                        let diagram = Diagram {
+                            plantuml_code: &plantuml_code,
+                            plantuml_hash_sum: &plantuml_hash_sum,
+                            output_directory: &output_directory,
+                            name: &image_name,
+                            format,
+                            no_cache,
+                            link_base: link_base.as_deref(),
+                        };
+                        // Why it's shown as not rendered yet rather than rendered now
+                        let postponed = if renderer.is_rendered(&diagram) {
+                            None
+                        } else if deferring {
+                            deferred += 1;
+                            Some("it's still rendering in the background")
+                        } else if renderer.is_out_of_time() {
+                            out_of_time += 1;
+                            Some("global_timeout_secs ran out before it was rendered")
+                        } else {
+                            None
+                        };
+                        let image_filename = if postponed.is_some() {
+                            renderer.image_filename(&diagram)
+                        } else {
+                            match renderer.render(&diagram, chapter_directory) {
+                                Ok(image_filename) => image_filename,
+                                Err(err) => {
+                                    errors.push(RenderError::diagram(
+                                        &chapter_name,
+                                        &plantuml_hash_sum,
+                                        err,
+                                    ));
+                                    return;
+                                }
+                            }
+                        };
+                        // The image at each of the other `srcset_scales`, those rendered
+                        let mut rendered_variants = Vec::new();
+                        for variant in variants.iter().filter(|_| postponed.is_none()) {
+                            let variant_diagram = Diagram {
+                                plantuml_code: &variant.plantuml_code,
+                                name: &variant.name,
+                                ..diagram
+                            };
+                            match renderer.render(&variant_diagram, chapter_directory) {
+                                Ok(filename) if filename.exists() => {
+                                    rendered_variants.push((variant.scale, filename))
+                                }
+                                Ok(_) => {}
+                                Err(err) => errors.push(RenderError::diagram(
+                                    &chapter_name,
+                                    &plantuml_hash_sum,
+                                    err,
+                                )),
+                            }
+                        }
+                        let dimensions = renderer.dimensions(format, &image_filename);
+                        if let Some(dimensions) = &dimensions {
+                            debug!(
+                                "Diagram dimensions: {}x{}",
+                                dimensions.width, dimensions.height
+                            );
+                        }
+                        // What the author wrote, for when there's no image to show
+                        let fence_source: String = events
                             .iter()
-                            .map(|e| match e {
-                                Event::Text(plantuml_text) => plantuml_text.to_string(),
-                                _ => "".into(),
+                            .filter_map(|event| match event {
+                                Event::Text(text) => Some(text.as_ref()),
+                                _ => None,
                             })
-                            .collect::<String>();
+                            .collect();
                         events.clear();
-                        trace!("Found plantuml:\n{}", plantuml_code);
-                        // Generate the SHA sum. This lets us be lazy. If the diagram already exists
-                        // it doesn't need to be re-created, merely referenced.
-                        let mut hasher = Sha1::new();
-                        hasher.input_str(&plantuml_code);
-                        let plantuml_hash_sum = hasher.result_str();
-                        debug!("Plantuml SHA1 hash sum: {}", &plantuml_hash_sum);
-                        let mut plantuml_svg_filename = PathBuf::new();
-                        plantuml_svg_filename.push(&plantuml_build_directory);
-                        plantuml_svg_filename.push(&plantuml_hash_sum);
-                        plantuml_svg_filename.set_extension("svg");
-                        debug!("Filename: {}", plantuml_svg_filename.to_str().unwrap());
-                        // If the SVG doesn't exist, dump the PUML file for plantuml to parse
-                        if !&plantuml_svg_filename.exists() {
-                            let mut puml_filename = PathBuf::new();
-                            puml_filename.push(&plantuml_build_directory);
-                            puml_filename.push(&plantuml_hash_sum);
-                            puml_filename.set_extension("puml");
+                        if config.emit_markers {
+                            events.push(marker(MARKER_BEGIN, Some(&plantuml_hash_sum)));
+                        }
+                        // Create the relative filename to use, and then place it programatically
+                        // as an image to be re-introduced to the mdbook
+                        let empty_str = "";
+                        let mut relative_url = PathBuf::new();
+                        relative_url.push(&relative_directory);
+                        relative_url.push(&image_name);
+                        relative_url.set_extension(format.extension());
+                        // Nothing was produced, e.g. PlantUML crashed. It's retried on the next
+                        // build, until then the page gets the placeholder instead of a broken image.
+                        let missing = !image_filename.exists();
+                        if let Some(reason) = postponed {
                             debug!(
-                                "SVG doesn't exist, writing PUML data: {}",
-                                puml_filename.to_str().unwrap()
+                                "Chapter `{}`, diagram {}: not rendered, {}",
+                                chapter_name, plantuml_hash_sum, reason
+                            );
+                            if config.show_skips {
+                                let notice = html::skip_notice(reason);
+                                events.push(Event::Html(CowStr::Boxed(notice.into())));
+                            }
+                            if let Some(placeholder) = &config.placeholder {
+                                relative_url = PathBuf::from(placeholder);
+                            }
+                        } else if missing {
+                            warn!(
+                                "Chapter `{}`, diagram {}: PlantUML didn't produce {}",
+                                chapter_name,
+                                plantuml_hash_sum,
+                                image_filename.display()
+                            );
+                            if config.show_skips {
+                                let notice = html::skip_notice("PlantUML didn't produce an image");
+                                events.push(Event::Html(CowStr::Boxed(notice.into())));
+                            }
+                            if let Some(placeholder) = &config.placeholder {
+                                relative_url = PathBuf::from(placeholder);
+                            }
+                        }
+                        if let Some(id) = fence.id() {
+                            match definitions.get(id) {
+                                Some((_, _, defined_in)) => errors.push(RenderError::chapter(
+                                    &chapter_name,
+                                    format!(
+                                        "the diagram id `{}` is already defined in `{}`",
+                                        id, defined_in
+                                    ),
+                                )),
+                                None => {
+                                    definitions.insert(
+                                        id.to_string(),
+                                        (relative_url.clone(), format, chapter_name.clone()),
+                                    );
+                                }
+                            }
+                        }
+                        // The source is more use to a reader than a broken image
+                        if missing && config.placeholder.is_none() {
+                            events.extend(code_block(&config.fallback_language, fence_source));
+                            if config.emit_markers {
+                                events.push(marker(MARKER_END, None));
+                            }
+                            return;
+                        }
+                        if format == OutputFormat::Txt && !missing {
+                            match read_to_string(&image_filename) {
+                                Ok(text) => {
+                                    if config.gallery {
+                                        gallery.add(
+                                            ascii_art_block(&text),
+                                            &gallery_title,
+                                            &chapter_name,
+                                            &chapter_path,
+                                        );
+                                    }
+                                    events.extend(ascii_art(text));
+                                    events.push(Event::SoftBreak);
+                                }
+                                Err(source) => errors.push(RenderError::Io {
+                                    chapter: chapter_name.clone(),
+                                    hash: plantuml_hash_sum.clone(),
+                                    path: image_filename.clone(),
+                                    source,
+                                }),
+                            }
+                            if config.emit_markers {
+                                events.push(marker(MARKER_END, None));
+                            }
+                            return;
+                        }
+
+                        let alt_text = match description.as_deref().or(fence.get("description")) {
+                            Some(description) => description,
+                            None if missing => "Diagram not rendered yet",
+                            None => empty_str,
+                        };
+                        if config.gallery && !missing {
+                            let gallery_url = image_url(
+                                config.url_style,
+                                in_place,
+                                Path::new(gallery::GALLERY_PATH),
+                                &relative_url,
                             );
-                            create_dir_all(&plantuml_build_directory).unwrap();
-                            let mut puml_file = File::create(&puml_filename).unwrap();
-                            write!(puml_file, "{}", plantuml_code).unwrap();
-                            drop(puml_file);
-                            // Call plantuml and generate the SVG
-                            let output = Command::new("plantuml")
-                                .arg("-tsvg")
-                                .arg("-o")
-                                .arg(&plantuml_build_directory.to_str().unwrap())
-                                .arg(&puml_filename.to_str().unwrap())
-                                .output()
-                                .expect("Failed to run PlantUML");
-                            if !output.status.success() {
-                                warn!("PlantUML failure occurred!");
-                                debug!(
-                                    "PlantUML stdout: {}",
-                                    String::from_utf8(output.stdout).unwrap()
+                            let figure =
+                                format!("![{}]({})", gallery::escape(alt_text), gallery_url);
+                            gallery.add(figure, &gallery_title, &chapter_name, &chapter_path);
+                        }
+                        let url =
+                            image_url(config.url_style, in_place, &chapter_path, &relative_url);
+                        let url = url.as_str();
+                        let anchor = if config.anchors {
+                            let id = format!("{}{}", config.anchor_prefix, &plantuml_hash_sum[..8]);
+                            Some(unique_id(&mut ids, id))
+                        } else {
+                            None
+                        };
+                        let source_link = match (&config.source_link_base, &source_file) {
+                            (Some(base), Some(source_file)) => Some(format!(
+                                "{}/{}",
+                                base.trim_end_matches('/'),
+                                url_path(source_file)
+                            )),
+                            _ => None,
+                        };
+                        // Screen readers announce it along with the image, for what
+                        // doesn't fit into alt text
+                        let long_description = fence.get("long_description").map(|text| {
+                            let id = match &anchor {
+                                Some(anchor) => format!("{}-description", anchor),
+                                None => unique_id(
+                                    &mut ids,
+                                    format!("diagram-description-{}", &plantuml_hash_sum[..8]),
+                                ),
+                            };
+                            (id, text)
+                        });
+                        let mut attributes = fence.html_attributes().to_vec();
+                        let dimensions_asked = match fence.dimensions() {
+                            Ok(dimensions) => dimensions,
+                            Err(err) => {
+                                errors.push(RenderError::diagram(
+                                    &chapter_name,
+                                    &plantuml_hash_sum,
+                                    err,
+                                ));
+                                return;
+                            }
+                        };
+                        // Ones in the `{...}` block are just as much the fence's own
+                        for (name, value) in dimensions_asked {
+                            if !attributes.iter().any(|(attribute, _)| *attribute == name) {
+                                attributes.push((name, value));
+                            }
+                        }
+                        // Print renderers don't honour CSS max-width, so an image which
+                        // doesn't fit is given a size which does. The fence's own wins.
+                        let sized = attributes
+                            .iter()
+                            .any(|(name, _)| name == "width" || name == "height");
+                        if let (true, false, Some(dimensions)) =
+                            (config.fit_to_page, sized, dimensions)
+                        {
+                            let page_width = f64::from(config.page_width_px);
+                            if dimensions.width > page_width {
+                                let height = dimensions.height * page_width / dimensions.width;
+                                attributes.push(("width".to_string(), page_width.to_string()));
+                                attributes.push(("height".to_string(), height.round().to_string()));
+                            }
+                        }
+                        if !missing && !rendered_variants.is_empty() {
+                            let srcset = config
+                                .srcset_scales
+                                .iter()
+                                .filter_map(|scale| {
+                                    let filename = if *scale == 1.0 {
+                                        &image_filename
+                                    } else {
+                                        rendered_variants
+                                            .iter()
+                                            .find(|(rendered, _)| rendered == scale)
+                                            .map(|(_, filename)| filename)?
+                                    };
+                                    let variant_url =
+                                        relative_directory.join(filename.file_name()?);
+                                    let variant_url = image_url(
+                                        config.url_style,
+                                        in_place,
+                                        &chapter_path,
+                                        &variant_url,
+                                    );
+                                    Some(format!("{} {}x", variant_url, scale))
+                                })
+                                .collect::<Vec<_>>()
+                                .join(", ");
+                            attributes.push(("srcset".to_string(), srcset));
+                        }
+                        if config.embed_source_attr {
+                            attributes
+                                .push(("data-plantuml-source".to_string(), plantuml_code.clone()));
+                        }
+                        let object = config.embed == Embed::Object && format == OutputFormat::Svg;
+                        // A missing image has no markup to inline, its placeholder is shown
+                        let inline_svg = match (config.embed.is_inline(), format, missing) {
+                            (true, OutputFormat::Svg, false) => {
+                                match read_to_string(&image_filename) {
+                                    Ok(svg) if config.css_theming => {
+                                        svg::inline(&svg, alt_text).map(|svg| {
+                                            svg::theme_colors(&svg, &config.css_theme_colors())
+                                        })
+                                    }
+                                    Ok(svg) => svg::inline(&svg, alt_text),
+                                    Err(source) => {
+                                        errors.push(RenderError::Io {
+                                            chapter: chapter_name.clone(),
+                                            hash: plantuml_hash_sum.clone(),
+                                            path: image_filename.clone(),
+                                            source,
+                                        });
+                                        return;
+                                    }
+                                }
+                            }
+                            _ => None,
+                        };
+                        // A markdown image can't be told to open in a new tab
+                        let plain = attributes.is_empty()
+                            && anchor.is_none()
+                            && long_description.is_none()
+                            && !downloadable
+                            && !object
+                            && inline_svg.is_none()
+                            && config.figure_numbering.is_none()
+                            && config.wrapper_template.is_none();
+                        if plain {
+                            if config.reference_links {
+                                let label = reference_label(
+                                    &mut reference_definitions,
+                                    url,
+                                    &plantuml_hash_sum,
                                 );
-                                debug!(
-                                    "PlantUML stderr: {}",
-                                    String::from_utf8(output.stderr).unwrap()
+                                // Written out as it is, to-cmark only writes inline links
+                                let image = format!("![{}][{}]", markdown_escape(alt_text), label);
+                                events.push(Event::Html(CowStr::Boxed(image.into())));
+                            } else {
+                                let image_title = match &caption {
+                                    Some(caption) => CowStr::Boxed(caption.as_str().into()),
+                                    None => CowStr::Borrowed(empty_str),
+                                };
+                                events.push(Event::Start(Tag::Image(
+                                    LinkType::Inline,
+                                    CowStr::Boxed(url.into()),
+                                    image_title.clone(),
+                                )));
+                                if !alt_text.is_empty() {
+                                    events.push(Event::Text(CowStr::Boxed(alt_text.into())));
+                                }
+                                events.push(Event::End(Tag::Image(
+                                    LinkType::Inline,
+                                    CowStr::Boxed(url.into()),
+                                    image_title,
+                                )));
+                            }
+                            if let Some(source_link) = &source_link {
+                                let link = Tag::Link(
+                                    LinkType::Inline,
+                                    CowStr::Boxed(source_link.as_str().into()),
+                                    CowStr::Borrowed(empty_str),
                                 );
+                                events.push(Event::Text(CowStr::Borrowed(" ")));
+                                events.push(Event::Start(link.clone()));
+                                events.push(Event::Text(CowStr::Borrowed(SOURCE_LINK_TEXT)));
+                                events.push(Event::End(link));
                             }
+                        } else {
+                            // A markdown image has nowhere to keep the fence's attributes
+                            if let Some((id, _)) = &long_description {
+                                attributes.push(("aria-describedby".to_string(), id.clone()));
+                            }
+                            let mut img = match &config.wrapper_template {
+                                Some(template) => {
+                                    let class = attributes
+                                        .iter()
+                                        .find(|(name, _)| name == "class")
+                                        .map_or("", |(_, class)| class.as_str());
+                                    html::wrapper(template, url, alt_text, class)
+                                }
+                                None => html::img(url, alt_text, &attributes),
+                            };
+                            if object {
+                                img = html::object(url, alt_text, &attributes, &img);
+                            }
+                            if let Some(inline_svg) = &inline_svg {
+                                img = match config.embed {
+                                    Embed::Inline => inline_svg.clone(),
+                                    _ => html::inline_with_fallback(inline_svg, &img),
+                                };
+                            }
+                            if downloadable {
+                                img = html::new_tab_link(url, &img);
+                            }
+                            if let Some((id, text)) = &long_description {
+                                img.push_str(&html::visually_hidden(id, text));
+                            }
+                            if let Some(source_link) = &source_link {
+                                img = format!(
+                                    "{} {}",
+                                    img,
+                                    html::link(source_link, SOURCE_LINK_TEXT)
+                                );
+                            }
+                            if config.figure_numbering.is_some() {
+                                let caption = figure::caption(title.as_deref());
+                                img = html::figure(anchor.as_deref(), &img, &caption);
+                            } else if let Some(anchor) = &anchor {
+                                img = html::anchored(anchor, &img);
+                            } else if inline_svg.is_some() {
+                                img = html::block(&img);
+                            }
+                            events.push(Event::Html(CowStr::Boxed(format!("{}\n", img).into())));
                         }
-                        // Create the relative filename to use, and then place it programatically
-                        // as an image to be re-introduced to the mdbook
-                        let empty_str = "";
-                        let mut relative_url = PathBuf::new();
-                        relative_url.push(&RENDER_DIRECTORY_NAME);
-                        relative_url.push(&plantuml_hash_sum);
-                        relative_url.set_extension("svg");
-
-                        events.push(Event::Start(Tag::Image(
-                            LinkType::Inline,
-                            CowStr::Boxed(relative_url.to_str().unwrap().into()),
-                            CowStr::Borrowed(empty_str),
-                        )));
-                        events.push(Event::End(Tag::Image(
-                            LinkType::Inline,
-                            CowStr::Boxed(relative_url.to_str().unwrap().into()),
-                            CowStr::Borrowed(empty_str),
-                        )));
                         events.push(Event::SoftBreak);
+                        if config.emit_markers {
+                            events.push(marker(MARKER_END, None));
+                        }
                     },
                 );
 
                 let mut content_buffer = String::with_capacity(current_chapter.content.len());
-                current_chapter.content = cmark(mutated_events_iterator, &mut content_buffer, None)
-                    .map(|_| content_buffer)
-                    .map_err(|err| Error::from(format!("Markdown serialization failed: {}", err)))
-                    .unwrap();
+                // The chapter keeps its source if it can't be written out, the build fails anyway
+                let events = markdown::keep_block_quotes(mutated_events_iterator);
+                match cmark(events.into_iter(), &mut content_buffer, None) {
+                    Ok(_) => {
+                        if !reference_definitions.is_empty() {
+                            content_buffer.push_str("\n\n");
+                        }
+                        for (label, url) in &reference_definitions {
+                            content_buffer.push_str(&format!("[{}]: <{}>\n", label, url));
+                        }
+                        current_chapter.content = content_buffer;
+                    }
+                    Err(err) => errors.push(RenderError::Serialization {
+                        chapter: chapter_name.clone(),
+                        message: err.to_string(),
+                    }),
+                }
+            }
+        });
+
+        info!(
+            "Looked for diagrams in {} chapters, {} more couldn't have any and were skipped",
+            chapters_processed, chapters_skipped
+        );
+
+        // References can come before the diagram they refer to, so they're only
+        // replaced once every diagram has been rendered
+        let mut referenced: HashSet<String> = HashSet::new();
+        book.for_each_mut(|current_item: &mut BookItem| {
+            if let BookItem::Chapter(ref mut current_chapter) = *current_item {
+                let chapter_name = &current_chapter.name;
+                let chapter_path = &current_chapter.path;
+                let replaced = library::replace_references(&current_chapter.content, |id| {
+                    referenced.insert(id.to_string());
+                    match definitions.get(id) {
+                        Some((relative_url, OutputFormat::Txt, _)) => {
+                            read_to_string(render_root.join(relative_url))
+                                .map(|text| ascii_art_block(&text))
+                                .map_err(|err| format!("unable to read diagram `{}`: {}", id, err))
+                        }
+                        Some((relative_url, _, _)) => Ok(format!(
+                            "![]({})",
+                            image_url(config.url_style, in_place, chapter_path, relative_url)
+                        )),
+                        None => Err(format!("no diagram has the id `{}`", id)),
+                    }
+                });
+                match replaced {
+                    Ok(content) => current_chapter.content = content,
+                    Err(err) => errors.push(RenderError::chapter(chapter_name, err)),
+                }
+            }
+        });
+
+        if let Some(numbering) = config.figure_numbering {
+            figure::number(&mut book, numbering);
+        }
+
+        for name in config.diagrams.keys() {
+            if !referenced.contains(name) {
+                warn!(
+                    "The diagram `{}` of the book.toml isn't referred to by any chapter",
+                    name
+                );
+            }
+        }
+
+        // It's made up afresh every build, from what was just rendered
+        if config.gallery {
+            book.push_item(gallery.chapter(&config.gallery_title));
+        }
+        if config.warn_duplicates {
+            warn_about_duplicates(&occurrences);
+        }
+        // Only what every diagram of the book is shown as is known to be used
+        if config.prune {
+            let incomplete = if !chapter_filter.includes_all() {
+                Some("include_chapters or exclude_chapters leave chapters out")
+            } else if deferred > 0 || out_of_time > 0 {
+                Some("some diagrams aren't rendered yet")
+            } else if !errors.is_empty() {
+                Some("the build has errors")
+            } else {
+                None
+            };
+            match incomplete {
+                Some(reason) => info!("Not pruning the diagram directory, {}", reason),
+                None => {
+                    let pruned = renderer.prune(&plantuml_build_directory)?;
+                    if pruned > 0 {
+                        info!("Pruned {} images no diagram uses anymore", pruned);
+                    }
+                }
+            }
+        }
+        renderer.save_manifest()?;
+        if deferred > 0 {
+            match pending::render_in_background(book_root) {
+                Ok(true) => info!("Rendering {} more diagrams in the background", deferred),
+                Ok(false) => info!(
+                    "{} diagrams are left to the background render still going",
+                    deferred
+                ),
+                Err(err) => warn!("Unable to render diagrams in the background: {}", err),
+            }
+        }
+        if out_of_time > 0 {
+            let message = format!(
+                "global_timeout_secs ran out, {} diagrams weren't rendered and are shown \
+                 as not rendered yet. The next build renders them.",
+                out_of_time
+            );
+            if config.on_global_timeout == GlobalTimeout::Error {
+                return Err(RenderError::Config(message).into());
             }
+            warn!("{}", message);
+        }
+
+        let (lenient, errors): (Vec<_>, Vec<_>) = errors.into_iter().partition(|err| {
+            err.chapter_name()
+                .is_some_and(|chapter| lenient_chapters.contains(chapter))
         });
+        for err in lenient {
+            warn!("{}", err);
+        }
+        let mut errors = errors.into_iter();
+        if let Some(first_error) = errors.next() {
+            for err in errors {
+                error!("{}", err);
+            }
+            return Err(first_error.into());
+        }
+        Ok((book, renderer.summary()))
+    }
+
+    /// Renders every diagram into a directory of its own and hands it to
+    /// `inspect` along with the book's source directory, to compare the two.
+    /// It's within the book, so a container sees it too, and only there while
+    /// `inspect` runs. The book isn't changed.
+    fn render_afresh<T>(
+        &self,
+        book: MDBook,
+        config: &Config,
+        inspect: impl FnOnce(&Path, &Path) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let source_directory = determine_build_directory(&book.root);
+        let fresh_directory = book.root.join(CHECK_DIRECTORY_NAME);
+        if fresh_directory.exists() {
+            remove_dir_all(&fresh_directory)?;
+        }
+        let inspected = self
+            .render_book(&book.root, &fresh_directory, config, None, book.book)
+            .and_then(|_| inspect(&fresh_directory, &source_directory));
+        remove_dir_all(&fresh_directory)?;
+        inspected
+    }
+
+    /// Has PlantUML check every diagram which would be rendered, without
+    /// rendering anything. Returns how many diagrams were checked and a report
+    /// for each one which doesn't parse.
+    fn validate_book(
+        &self,
+        book_root: &Path,
+        config: &Config,
+        book: &Book,
+    ) -> Result<(usize, Vec<String>), Error> {
+        let source_directory = determine_build_directory(book_root);
+        let scratch_directory = book_root.join(VALIDATE_DIRECTORY_NAME);
+        let renderer = Renderer::new(config, book_root, &scratch_directory)?;
+        let chapter_filter = ChapterFilter::from_config(config)?;
+        let watermark = Watermark::from_config(config);
+        let legend = Legend::from_config(config, book_root)?;
+        let include_resolver = IncludeResolver::from_config(config, book_root);
+        let preamble = Preamble::from_config(config);
+        let book_diagrams = ChapterDiagrams {
+            book_root,
+            render_root: &source_directory,
+            config,
+            preamble: &preamble,
+            watermark: watermark.as_ref(),
+            legend: legend.as_ref(),
+            include_resolver: include_resolver.as_ref(),
+            chapter_name: NAMED_DIAGRAMS_ORIGIN,
+            chapter_path: Path::new(""),
+            scale: None,
+        };
+        let mut checked = 0;
+        let mut failures = Vec::new();
+        for item in book.iter() {
+            let chapter = match item {
+                BookItem::Chapter(chapter) if chapter_filter.includes(&chapter.path) => chapter,
+                _ => continue,
+            };
+            let (front_matter, content) = match front_matter::split_or_default(&chapter.content) {
+                Ok(split) => split,
+                Err(err) => {
+                    failures.push(format!("Chapter `{}`: {}", chapter.name, err));
+                    continue;
+                }
+            };
+            let setup = ChapterSetup::new(front_matter, config, &source_directory, &chapter.path);
+            // Lines are counted from the top of the chapter, front matter included
+            let front_matter_lines = chapter.content[..chapter.content.len() - content.len()]
+                .lines()
+                .count();
+            let diagrams = match validate::diagrams(content, &setup.config) {
+                Ok(diagrams) => diagrams,
+                Err(err) => {
+                    failures.push(format!("Chapter `{}`: {}", chapter.name, err));
+                    continue;
+                }
+            };
+            let layout = match ChapterLayout::of(content, &setup.config) {
+                Ok(layout) => layout,
+                Err(err) => {
+                    failures.push(format!("Chapter `{}`: {}", chapter.name, err));
+                    continue;
+                }
+            };
+            // Each block is prepared the way the build does it, so what's checked
+            // is the code PlantUML would be given
+            let chapter_diagrams = setup.diagrams(&book_diagrams, &chapter.name, &chapter.path);
+            let prepared = layout.prepare_each(content, &chapter_diagrams);
+            for (diagram, prepared) in diagrams.iter().zip(prepared) {
+                let line = diagram.line + front_matter_lines;
+                let prepared = match prepared {
+                    Ok(Some(prepared)) => prepared,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        failures.push(format!(
+                            "Chapter `{}`, line {}: {}",
+                            chapter.name,
+                            line,
+                            err.message()
+                        ));
+                        continue;
+                    }
+                };
+                checked += 1;
+                let report = renderer.validate(
+                    &prepared.plantuml_code,
+                    &scratch_directory,
+                    &setup.directory,
+                );
+                if let Some(report) = report? {
+                    failures.push(format!(
+                        "Chapter `{}`, line {}: {}",
+                        chapter.name, line, report
+                    ));
+                }
+            }
+        }
+        if scratch_directory.exists() {
+            remove_dir_all(&scratch_directory)?;
+        }
+        Ok((checked, failures))
+    }
+}
+
+impl PlantumlRendererPreprocessor {
+    /// Renders `source` as a diagram of the book would be, with its preamble,
+    /// wrapping and `config`, returning the image. It's rendered afresh into a
+    /// directory of its own, which is gone again afterwards, and the book's
+    /// images and manifest are left alone.
+    fn render_one(
+        &self,
+        book_root: &Path,
+        config: &Config,
+        source: &str,
+        include_directory: &Path,
+    ) -> Result<Vec<u8>, Error> {
+        let scratch_directory = book_root.join(RENDER_ONE_DIRECTORY_NAME);
+        let renderer = Renderer::new(config, book_root, &scratch_directory)?;
+        let watermark = Watermark::from_config(config);
+        let legend = Legend::from_config(config, book_root)?;
+        let include_resolver = IncludeResolver::from_config(config, book_root);
+        let preamble = Preamble::from_config(config);
+        let chapter_diagrams = ChapterDiagrams {
+            book_root,
+            render_root: &scratch_directory,
+            config,
+            preamble: &preamble,
+            watermark: watermark.as_ref(),
+            legend: legend.as_ref(),
+            include_resolver: include_resolver.as_ref(),
+            chapter_name: RENDER_ONE_ORIGIN,
+            chapter_path: Path::new(""),
+            scale: None,
+        };
+        let info = CowStr::Borrowed("plantuml,render");
+        let events = [
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info.clone()))),
+            Event::Text(CowStr::Borrowed(source)),
+            Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(info))),
+        ];
+        let prepared = chapter_diagrams
+            .prepare(&events, &mut Groups::new(&[]), &mut Fragments::default())?
+            .ok_or_else(|| Error::from("There's no diagram to render"))?;
+        let diagram = Diagram {
+            plantuml_code: &prepared.plantuml_code,
+            plantuml_hash_sum: &prepared.plantuml_hash_sum,
+            output_directory: &prepared.output_directory,
+            name: &prepared.image_name,
+            format: prepared.format,
+            no_cache: true,
+            link_base: None,
+        };
+        let image = renderer
+            .render(&diagram, include_directory)
+            .and_then(|image_filename| read(image_filename).map_err(Error::from));
+        if scratch_directory.exists() {
+            remove_dir_all(&scratch_directory)?;
+        }
+        image
+    }
+}
+
+impl Default for PlantumlRendererPreprocessor {
+    fn default() -> Self {
+        PlantumlRendererPreprocessor::new(PREPROCESSOR_NAME)
+    }
+}
+
+impl Preprocessor for PlantumlRendererPreprocessor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&self, context: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
+        let config = Config::from_context(context, self.name())?;
+        // `mdbook test` runs preprocessors too, only for the book's Rust code
+        if config.skip_renderers.contains(&context.renderer) {
+            info!(
+                "Leaving diagrams as they are for `{}`, it's in skip_renderers",
+                context.renderer
+            );
+            strip_front_matter(&mut book)?;
+            return Ok(book);
+        }
+        check_build_directory(&context.root, &context.config.build.build_dir, &config)?;
+        let source_directory = determine_build_directory(&context.root);
+        if config.clean_before_build {
+            clean_diagram_directory(&source_directory, &config)?;
+        }
+        let in_place = if config.images_in_place.contains(&context.renderer) {
+            Some(relative_path(
+                &renderer_destination(context),
+                &source_directory,
+            ))
+        } else {
+            None
+        };
+        let (book, summary) = self.render_book(
+            &context.root,
+            &source_directory,
+            &config,
+            in_place.as_deref(),
+            book,
+        )?;
+        info!(
+            "Rendered {} diagrams, {} were already rendered",
+            summary.rendered, summary.cached
+        );
         Ok(book)
     }
 
+    /// Every renderer but those of `skip_renderers`, which mdbook then doesn't
+    /// run the preprocessor for at all. It's asked without being told the book,
+    /// which is found like mdbook finds it, see `mdbook_book_root`.
     fn supports_renderer(&self, renderer: &str) -> bool {
-        warn!(
-            "Just returning true for all backends. This has been untested with: {}",
-            renderer
+        self.supports_renderer_of(&mdbook_book_root(), renderer)
+    }
+}
+
+impl PlantumlRendererPreprocessor {
+    /// Whether the preprocessor runs for `renderer` in the book at `book_root`,
+    /// which only its `skip_renderers` can rule out. Options which can't be read
+    /// are reported and taken to support it, so the build fails on them rather
+    /// than mdbook quietly leaving the preprocessor out.
+    fn supports_renderer_of(&self, book_root: &Path, renderer: &str) -> bool {
+        let book_toml = book_root.join("book.toml");
+        let config = if book_toml.is_file() {
+            let config = mdbook::Config::from_disk(&book_toml).and_then(|book_config| {
+                Config::from_book_config(&book_config, self.name(), renderer)
+            });
+            match config {
+                Ok(config) => config,
+                Err(err) => {
+                    error!("{}", err);
+                    return true;
+                }
+            }
+        } else {
+            warn!(
+                "There's no {}, the default skip_renderers are taken",
+                book_toml.display()
+            );
+            Config::default()
+        };
+        let supported = !config
+            .skip_renderers
+            .iter()
+            .any(|skipped| skipped == renderer);
+        if !supported {
+            debug!("`{}` is in skip_renderers, it isn't supported", renderer);
+        }
+        supported
+    }
+}
+
+/// What a chapter's diagrams are prepared with
+struct ChapterDiagrams<'a> {
+    book_root: &'a Path,
+    render_root: &'a Path,
+    config: &'a Config,
+    preamble: &'a Preamble,
+    watermark: Option<&'a Watermark>,
+    legend: Option<&'a Legend>,
+    include_resolver: Option<&'a IncludeResolver>,
+    chapter_name: &'a str,
+    chapter_path: &'a Path,
+    /// The chapter's `scale` line, for diagrams without a `scale` of their own
+    scale: Option<&'a str>,
+}
+
+/// A chapter's own options, those of its front matter on top of the book's,
+/// and where it is. The chapter loop and `render_in_parallel` read chapters
+/// alike through it and `ChapterLayout`, so the diagrams rendered ahead are
+/// the ones the chapter then shows.
+struct ChapterSetup {
+    front_matter: FrontMatter,
+    config: Config,
+    preamble: Preamble,
+    /// The directory the chapter is in, where its includes are looked for first
+    directory: PathBuf,
+}
+
+impl ChapterSetup {
+    fn new(
+        front_matter: FrontMatter,
+        book_config: &Config,
+        source_directory: &Path,
+        chapter_path: &Path,
+    ) -> ChapterSetup {
+        let config = front_matter.config(book_config);
+        let preamble = Preamble::from_config(&config);
+        let directory = source_directory
+            .join(chapter_path)
+            .parent()
+            .map_or_else(|| source_directory.to_path_buf(), Path::to_path_buf);
+        ChapterSetup {
+            front_matter,
+            config,
+            preamble,
+            directory,
+        }
+    }
+
+    /// What the chapter's diagrams are prepared with, the book's with the
+    /// chapter's options
+    fn diagrams<'a>(
+        &'a self,
+        book_diagrams: &ChapterDiagrams<'a>,
+        chapter_name: &'a str,
+        chapter_path: &'a Path,
+    ) -> ChapterDiagrams<'a> {
+        ChapterDiagrams {
+            config: &self.config,
+            preamble: &self.preamble,
+            chapter_name,
+            chapter_path,
+            scale: self.front_matter.scale(),
+            ..*book_diagrams
+        }
+    }
+}
+
+/// Which of a chapter's code blocks are its diagrams
+struct ChapterLayout {
+    /// Whether only what's between its `plantuml:begin` and `plantuml:end`
+    /// markers is rendered
+    has_regions: bool,
+    /// The fences of its diagrams, which the blocks of each group are counted among
+    fences: Vec<FenceInfo>,
+}
+
+impl ChapterLayout {
+    fn of(content: &str, config: &Config) -> Result<ChapterLayout, String> {
+        let has_regions = region::has_regions(content)?;
+        let fences = match validate::diagrams(content, config) {
+            Ok(diagrams) => diagrams.into_iter().map(|diagram| diagram.fence).collect(),
+            Err(_) => Vec::new(),
+        };
+        Ok(ChapterLayout {
+            has_regions,
+            fences,
+        })
+    }
+
+    /// The chapter's diagrams, prepared as they are when the chapter is shown,
+    /// for rendering them ahead of it. Whatever is wrong with a diagram is
+    /// reported when it's shown, until then it's left out.
+    fn prepare(
+        &self,
+        content: &str,
+        chapter_diagrams: &ChapterDiagrams<'_>,
+    ) -> Vec<PreparedDiagram> {
+        self.prepare_each(content, chapter_diagrams)
+            .into_iter()
+            .filter_map(|diagram| diagram.ok().flatten())
+            .collect()
+    }
+
+    /// What each of the chapter's blocks is prepared into, in the order of
+    /// `validate::diagrams`, for `validate` to point at them
+    fn prepare_each(
+        &self,
+        content: &str,
+        chapter_diagrams: &ChapterDiagrams<'_>,
+    ) -> Vec<Result<Option<PreparedDiagram>, RenderError>> {
+        let config = chapter_diagrams.config;
+        let mut groups = Groups::new(&self.fences);
+        let mut fragments = Fragments::default();
+        let mut prepared = Vec::new();
+        rewrite_between(
+            markdown::parse(content),
+            diagram_start_matcher(self.has_regions, config),
+            |event: &Event<'_>| renderable_plantuml_end(event, config),
+            |events: &mut Vec<Event<'_>>| {
+                prepared.push(chapter_diagrams.prepare(events, &mut groups, &mut fragments));
+            },
+        )
+        .for_each(drop);
+        prepared
+    }
+}
+
+/// A diagram drawn at one of the `srcset_scales` other than 1, for high-DPI
+/// displays. It's named after the diagram's image, e.g. `<hash>@2x.png`.
+struct Variant {
+    scale: f64,
+    name: String,
+    plantuml_code: String,
+}
+
+/// A diagram ready to be rendered, with where it goes and its hash
+struct PreparedDiagram {
+    fence: FenceInfo,
+    relative_directory: PathBuf,
+    output_directory: PathBuf,
+    source_file: Option<PathBuf>,
+    plantuml_hash_sum: String,
+    plantuml_code: String,
+    /// The image's filename without its extension
+    image_name: String,
+    /// The way from the image to the chapter's directory, for `rewrite_svg_links`
+    link_base: Option<PathBuf>,
+    format: OutputFormat,
+    no_cache: bool,
+    /// Whether the image links to itself, to be opened on its own
+    downloadable: bool,
+    variants: Vec<Variant>,
+    /// What the block's `'# title:` comment says, see `title::split_caption`
+    caption: Option<String>,
+}
+
+impl ChapterDiagrams<'_> {
+    /// Turns the events of a code block into the diagram PlantUML gets to see.
+    /// A block which isn't the last of its group gives nothing, the diagram is
+    /// returned for the last one. Neither does a fragment's definition, which
+    /// is kept in `fragments` for the blocks after it. Errors already name the
+    /// chapter.
+    fn prepare(
+        &self,
+        events: &[Event<'_>],
+        groups: &mut Groups,
+        fragments: &mut Fragments,
+    ) -> Result<Option<PreparedDiagram>, RenderError> {
+        let fence = match events.first() {
+            Some(Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info)))) => {
+                FenceInfo::parse(info, &self.config.language)
+            }
+            _ => FenceInfo::default(),
+        };
+        // An `out` naming a file, e.g. `out=assets/login.png`, gives the image its
+        // name and format as well
+        let output_file = match fence.get("out") {
+            Some(out) => {
+                output_file(out).map_err(|err| RenderError::chapter(self.chapter_name, err))?
+            }
+            None => None,
+        };
+        // Diagrams share one directory unless the fence asks for its own, or
+        // each chapter gets one mirroring where it is in `src`
+        let mut relative_directory = match fence.get("out") {
+            Some(out) => {
+                let directory = output_file.map_or(out, |(directory, _, _)| directory);
+                confine_relative_directory(directory)
+                    .map_err(|err| RenderError::chapter(self.chapter_name, err))?
+            }
+            None if self.config.mirror_chapters => {
+                let mirrored = normalize_relative(&self.chapter_path.with_extension(""));
+                match mirrored {
+                    Some(mirrored) => self.config.diagram_directory.join(mirrored),
+                    None => self.config.diagram_directory.clone(),
+                }
+            }
+            None => self.config.diagram_directory.clone(),
+        };
+        // Intentionally consume and remove all events by mapping them into
+        // a single string of code. This helps strip out the opening/closing
+        // code-fences before and after the codeblock.
+        let body = events
+            .iter()
+            .map(|e| match e {
+                Event::Text(plantuml_text) => plantuml_text.to_string(),
+                _ => "".into(),
+            })
+            .collect::<String>();
+        if fence.is_definition() {
+            let name = fence
+                .get("name")
+                .filter(|name| !name.trim().is_empty())
+                .ok_or_else(|| {
+                    RenderError::chapter(
+                        self.chapter_name,
+                        "a `plantuml,def` block needs a `name=` to be used by",
+                    )
+                })?;
+            fragments.define(name, &body);
+            return Ok(None);
+        }
+        // Not part of the diagram, so not of its hash either
+        let (caption, body) = title::split_caption(&body);
+        let body = match fence.group() {
+            Some(group) => match groups.add(group, &body) {
+                Some(body) => body,
+                None => return Ok(None),
+            },
+            None => body,
+        };
+        let (body, source_file) = diagram_body(
+            self.book_root,
+            self.chapter_path,
+            body,
+            self.config.input_charset,
+        )
+        .map_err(|err| RenderError::chapter(self.chapter_name, err))?;
+        // Part of the code, so of the hash too
+        let body = match fence.get("use") {
+            Some(uses) => fragments
+                .apply(uses, &body)
+                .map_err(|err| RenderError::chapter(self.chapter_name, err))?,
+            None => body,
+        };
+        let scale = fence
+            .scale()
+            .map_err(|err| RenderError::chapter(self.chapter_name, err))?
+            .or_else(|| self.scale.map(String::from));
+        let wrapped = fence.wrap(&body, &self.config.default_diagram_type);
+        let plantuml_code = match scale {
+            Some(scale) => self.preamble.with_line(scale).apply(&wrapped),
+            None => self.preamble.apply(&wrapped),
+        };
+        let asked_for_legend = fence
+            .flag("legend")
+            .map_err(|err| RenderError::chapter(self.chapter_name, err))?;
+        // Part of the code, so of the hash too
+        let plantuml_code = match self.legend {
+            Some(legend) if legend.applies_to(asked_for_legend, &plantuml_code) => {
+                legend.apply(&plantuml_code)
+            }
+            _ => plantuml_code,
+        };
+        // Where includes are resolved to is part of the code, what they hold is
+        // hashed along with it below
+        let plantuml_code = match self.include_resolver {
+            Some(include_resolver) => include_resolver
+                .apply(&plantuml_code)
+                .map_err(|err| RenderError::chapter(self.chapter_name, err))?,
+            None => plantuml_code,
+        };
+        trace!("Found plantuml:\n{}", plantuml_code);
+        // Generate the SHA sum. This lets us be lazy. If the diagram already exists
+        // it doesn't need to be re-created, merely referenced.
+        let mut hasher = Sha1::new();
+        if self.config.semantic_cache {
+            hasher.input_str(&semantic::normalize(&plantuml_code));
+        } else {
+            hasher.input_str(&plantuml_code);
+        }
+        if let Some(watermark) = self.watermark {
+            hasher.input_str(watermark.hash_input());
+        }
+        if let Some(cache_salt) = &self.config.cache_salt {
+            hasher.input_str(cache_salt);
+        }
+        // Found along the include path PlantUML is given, see `Renderer::include_directories`
+        let chapter_source = determine_build_directory(self.book_root)
+            .join(self.chapter_path)
+            .parent()
+            .map(Path::to_path_buf);
+        let base_directory = self
+            .config
+            .base_dir
+            .as_ref()
+            .map(|base_dir| self.book_root.join(base_dir));
+        let include_directories: Vec<&Path> = chapter_source
+            .iter()
+            .chain(base_directory.iter())
+            .map(PathBuf::as_path)
+            .collect();
+        hasher.input(&include::included_contents(
+            &plantuml_code,
+            &include_directories,
+        ));
+        let fingerprint = self.config.render_fingerprint();
+        if !fingerprint.is_empty() {
+            hasher.input_str(&fingerprint);
+        }
+        let format = output_file.map_or(self.config.output_format, |(_, _, format)| format);
+        let rewrite_links = self.config.rewrite_svg_links && format == OutputFormat::Svg;
+        let chapter_directory = self.chapter_path.parent().unwrap_or_else(|| Path::new(""));
+        // The same links lead elsewhere from another directory
+        if rewrite_links && plantuml_code.contains("[[") {
+            hasher.input_str(&url_path(chapter_directory));
+        }
+        let plantuml_hash_sum = hasher.result_str();
+        debug!("Plantuml SHA1 hash sum: {}", &plantuml_hash_sum);
+        // Like Git's objects, so no directory ends up with thousands of images
+        if self.config.shard && fence.get("out").is_none() {
+            relative_directory.push(&plantuml_hash_sum[..SHARD_LENGTH]);
+        }
+        let output_directory = self.render_root.join(&relative_directory);
+        let link_base = if rewrite_links {
+            Some(relative_path(&relative_directory, chapter_directory))
+        } else {
+            None
+        };
+        let plantuml_code = match self.watermark {
+            Some(watermark) => watermark.apply(&plantuml_code),
+            None => plantuml_code,
+        };
+        // The fence's own setting beats the global one
+        let no_cache = fence
+            .flag("nocache")
+            .map_err(|err| RenderError::diagram(self.chapter_name, &plantuml_hash_sum, err))?
+            .unwrap_or(self.config.no_cache);
+        let downloadable = fence
+            .flag("downloadable")
+            .map_err(|err| RenderError::diagram(self.chapter_name, &plantuml_hash_sum, err))?
+            .unwrap_or(self.config.downloadable);
+        let image_name = match (output_file, self.config.hash_length) {
+            (Some((_, name, _)), _) => name.to_string(),
+            // The renderer tells when two diagrams end up with the same name
+            (None, Some(length)) => plantuml_hash_sum[..length].to_string(),
+            (None, None) => plantuml_hash_sum.clone(),
+        };
+        // Only raster images get blurry, an SVG is sharp at any size
+        let raster = format == OutputFormat::Png || format == OutputFormat::Webp;
+        let variants = self
+            .config
+            .srcset_scales
+            .iter()
+            .filter(|scale| raster && **scale != 1.0)
+            .map(|scale| Variant {
+                scale: *scale,
+                // A `.` would be taken for the start of the extension
+                name: format!("{}@{}x", image_name, scale.to_string().replace('.', "_")),
+                plantuml_code: Preamble::default()
+                    .with_line(format!("skinparam dpi {}", (BASE_DPI * scale).round()))
+                    .apply(&plantuml_code),
+            })
+            .collect();
+        Ok(Some(PreparedDiagram {
+            fence,
+            relative_directory,
+            output_directory,
+            source_file,
+            plantuml_hash_sum,
+            plantuml_code,
+            image_name,
+            format,
+            no_cache,
+            downloadable,
+            link_base,
+            variants,
+            caption,
+        }))
+    }
+}
+
+/// Renders the diagrams of the `diagrams` option, adding each to `definitions`
+/// under its name, as if it had that id in a chapter at the top of `src`
+fn render_named_diagrams(
+    chapter_diagrams: &ChapterDiagrams<'_>,
+    renderer: &Renderer<'_>,
+    source_directory: &Path,
+    definitions: &mut HashMap<String, (PathBuf, OutputFormat, String)>,
+    errors: &mut Vec<RenderError>,
+) {
+    let info = CowStr::Borrowed("plantuml,render");
+    for (name, source) in &chapter_diagrams.config.diagrams {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            errors.push(RenderError::Config(format!(
+                "The diagram name `{}` can't be referred to, it has to be a single word",
+                name
+            )));
+            continue;
+        }
+        let events = [
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info.clone()))),
+            Event::Text(CowStr::Borrowed(source)),
+            Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(info.clone()))),
+        ];
+        let prepared = match chapter_diagrams.prepare(
+            &events,
+            &mut Groups::new(&[]),
+            &mut Fragments::default(),
+        ) {
+            Ok(Some(prepared)) => prepared,
+            Ok(None) => continue,
+            Err(err) => {
+                errors.push(err);
+                continue;
+            }
+        };
+        let diagram = Diagram {
+            plantuml_code: &prepared.plantuml_code,
+            plantuml_hash_sum: &prepared.plantuml_hash_sum,
+            output_directory: &prepared.output_directory,
+            name: &prepared.image_name,
+            format: prepared.format,
+            no_cache: prepared.no_cache,
+            link_base: prepared.link_base.as_deref(),
+        };
+        if let Err(err) = renderer.render(&diagram, source_directory) {
+            errors.push(RenderError::diagram(
+                NAMED_DIAGRAMS_ORIGIN,
+                &prepared.plantuml_hash_sum,
+                err,
+            ));
+            continue;
+        }
+        let mut relative_url = prepared.relative_directory.join(&prepared.image_name);
+        relative_url.set_extension(prepared.format.extension());
+        definitions.insert(
+            name.clone(),
+            (
+                relative_url,
+                prepared.format,
+                NAMED_DIAGRAMS_ORIGIN.to_string(),
+            ),
         );
-        true
     }
 }
 
-fn renderable_plantuml_start(event: &Event<'_>) -> bool {
+/// Every image of the diagrams, those of their `srcset_scales` included
+fn images_to_render(prepared: &[PreparedDiagram]) -> impl Iterator<Item = Diagram<'_>> {
+    prepared.iter().flat_map(|diagram| {
+        let variants = diagram
+            .variants
+            .iter()
+            .map(move |variant| (&variant.plantuml_code, &variant.name));
+        iter::once((&diagram.plantuml_code, &diagram.image_name))
+            .chain(variants)
+            .map(move |(plantuml_code, name)| Diagram {
+                plantuml_code,
+                plantuml_hash_sum: &diagram.plantuml_hash_sum,
+                output_directory: &diagram.output_directory,
+                name,
+                format: diagram.format,
+                no_cache: diagram.no_cache,
+                link_base: diagram.link_base.as_deref(),
+            })
+    })
+}
+
+/// With `parallel`, renders the diagrams of every chapter which aren't
+/// rendered yet ahead of the chapters, on `max_processes` threads. The chapters
+/// then find them rendered. Each image is only taken by one thread, so none is
+/// written twice at once. Whatever is wrong with a diagram, or its chapter, is
+/// reported when the chapter is rendered, which tries it again.
+fn render_in_parallel(
+    book: &Book,
+    book_diagrams: &ChapterDiagrams<'_>,
+    chapter_filter: &ChapterFilter,
+    renderer: &Renderer<'_>,
+    source_directory: &Path,
+) {
+    // The diagrams of each chapter, with the directory its includes are in.
+    // What's wrong with a chapter is the chapter loop's to report.
+    let mut chapters = Vec::new();
+    for item in book.iter() {
+        let chapter = match item {
+            BookItem::Chapter(chapter) if chapter_filter.includes(&chapter.path) => chapter,
+            _ => continue,
+        };
+        let (front_matter, content) = match front_matter::split_or_default(&chapter.content) {
+            Ok(split) => split,
+            Err(_) => continue,
+        };
+        let setup = ChapterSetup::new(
+            front_matter,
+            book_diagrams.config,
+            source_directory,
+            &chapter.path,
+        );
+        if !fence::may_have_diagrams(content, &setup.config) {
+            continue;
+        }
+        let layout = match ChapterLayout::of(content, &setup.config) {
+            Ok(layout) => layout,
+            Err(_) => continue,
+        };
+        let prepared = layout.prepare(
+            content,
+            &setup.diagrams(book_diagrams, &chapter.name, &chapter.path),
+        );
+        chapters.push((setup.directory, prepared));
+    }
+    let mut taken = HashSet::new();
+    let images: Vec<(Diagram<'_>, &Path)> = chapters
+        .iter()
+        .flat_map(|(chapter_directory, prepared)| {
+            images_to_render(prepared).map(move |diagram| (diagram, chapter_directory.as_path()))
+        })
+        .filter(|(diagram, _)| {
+            taken.insert(renderer.image_filename(diagram)) && !renderer.is_rendered(diagram)
+        })
+        .collect();
+    if images.is_empty() {
+        return;
+    }
+    let threads = renderer.max_processes().min(images.len());
+    info!(
+        "Rendering {} diagrams of the book on {} threads",
+        images.len(),
+        threads
+    );
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                while let Some((diagram, chapter_directory)) =
+                    images.get(next.fetch_add(1, Ordering::Relaxed))
+                {
+                    if renderer.is_out_of_time() {
+                        break;
+                    }
+                    let _diagram_scope = logging::diagram(diagram.plantuml_hash_sum);
+                    if let Err(err) = renderer.render(diagram, chapter_directory) {
+                        debug!("Rendering ahead failed, it's tried again: {}", err);
+                    }
+                }
+            });
+        }
+    });
+}
+
+/// The build's failure once the chapter brought the book over max_diagrams,
+/// which is about the whole book, so a lenient chapter can't let it pass
+fn too_many_diagrams_error(chapter_name: &str, max: usize) -> RenderError {
+    RenderError::Config(format!(
+        "Chapter `{}`: the book has more than {} different diagrams, the max_diagrams \
+         limit, so none of the chapters from here on are rendered. Raise max_diagrams if \
+         they're all meant to be there, or check the chapter and the trigger for code \
+         blocks taken as diagrams by mistake.",
+        chapter_name, max
+    ))
+}
+
+/// A lint for diagrams which show up more than once, it doesn't fail the build
+fn warn_about_duplicates(occurrences: &BTreeMap<String, Vec<String>>) {
+    for (plantuml_hash_sum, chapters) in occurrences {
+        if chapters.len() > 1 {
+            warn!(
+                "Diagram {} appears {} times, in chapters: {}",
+                plantuml_hash_sum,
+                chapters.len(),
+                chapters.join(", ")
+            );
+        }
+    }
+}
+
+fn renderable_plantuml_start(event: &Event<'_>, config: &Config) -> bool {
     match event {
-        Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(language))) => {
-            language.to_string() == PLANTUML_RENDERABLE_LANGUAGE.to_string()
+        Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+            FenceInfo::parse(info, &config.language).is_renderable(config.trigger_style())
         }
         _ => false,
     }
 }
 
-fn renderable_plantuml_end(event: &Event<'_>) -> bool {
+/// Matches the start of a diagram, inside the chapter's regions if it has any
+fn diagram_start_matcher(
+    has_regions: bool,
+    config: &Config,
+) -> impl FnMut(&Event<'_>) -> bool + '_ {
+    // Without any markers the whole chapter is rendered
+    let mut in_region = !has_regions;
+    move |event: &Event<'_>| match region::marker(event) {
+        Some(marker) => {
+            in_region = marker == Marker::Begin;
+            false
+        }
+        None => in_region && rewritten_fence(event, config),
+    }
+}
+
+/// Puts `show_skips`' notice in front of each of the chapter's diagrams, those
+/// within its marked regions, or else those outside of them. A chapter without
+/// markers is all one region.
+fn show_skipped(
+    chapter: &mut Chapter,
+    config: &Config,
+    outside_regions: bool,
+    reason: &str,
+) -> Result<(), RenderError> {
+    let has_regions = region::has_regions(&chapter.content)
+        .map_err(|err| RenderError::chapter(&chapter.name, err))?;
+    let mut in_region = !has_regions;
+    let notice = html::skip_notice(reason);
+    let events = rewrite_between(
+        markdown::parse(&chapter.content),
+        |event: &Event<'_>| match region::marker(event) {
+            Some(marker) => {
+                in_region = marker == Marker::Begin;
+                false
+            }
+            None => in_region != outside_regions && renderable_plantuml_start(event, config),
+        },
+        |event: &Event<'_>| renderable_plantuml_end(event, config),
+        |events: &mut Vec<Event<'_>>| {
+            events.insert(0, Event::Html(CowStr::Boxed(notice.clone().into())));
+        },
+    );
+    let mut content = String::with_capacity(chapter.content.len());
+    let events = markdown::keep_block_quotes(events);
+    cmark(events.into_iter(), &mut content, None).map_err(|err| RenderError::Serialization {
+        chapter: chapter.name.clone(),
+        message: err.to_string(),
+    })?;
+    chapter.content = content;
+    Ok(())
+}
+
+fn renderable_plantuml_end(event: &Event<'_>, config: &Config) -> bool {
     match event {
-        Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(language))) => {
-            language.to_string() == PLANTUML_RENDERABLE_LANGUAGE.to_string()
+        Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+            let fence = FenceInfo::parse(info, &config.language);
+            fence.is_renderable(config.trigger_style()) || fence.is_definition()
         }
         _ => false,
     }
 }
 
+/// The start of a block the chapter's rewrite replaces: a diagram, or the
+/// definition of a fragment, which is taken out
+fn rewritten_fence(event: &Event<'_>, config: &Config) -> bool {
+    match event {
+        Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+            let fence = FenceInfo::parse(info, &config.language);
+            fence.is_renderable(config.trigger_style()) || fence.is_definition()
+        }
+        _ => false,
+    }
+}
+
+/// The label of the image at `url` for reference_links, one of `definitions`
+/// or else a new one named after the diagram's hash. The same diagram can be
+/// shown from different files, each gets a label of its own.
+fn reference_label(definitions: &mut Vec<(String, String)>, url: &str, hash: &str) -> String {
+    if let Some((label, _)) = definitions.iter().find(|(_, defined)| defined == url) {
+        return label.clone();
+    }
+    let base = format!("plantuml-{}", hash);
+    let mut label = base.clone();
+    let mut count = 1;
+    while definitions.iter().any(|(taken, _)| *taken == label) {
+        count += 1;
+        label = format!("{}-{}", base, count);
+    }
+    definitions.push((label.clone(), url.to_string()));
+    label
+}
+
+/// Escapes every ASCII punctuation character, so text is taken as it is
+/// wherever markdown would read something into it, like an image's alt text
+fn markdown_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        if character.is_ascii_punctuation() {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
+
+/// One of the comments `emit_markers` puts around a diagram, on a line of its own
+fn marker(kind: &str, hash: Option<&str>) -> Event<'static> {
+    let comment = match hash {
+        Some(hash) => format!("<!-- {} hash={} -->\n", kind, hash),
+        None => format!("<!-- {} -->\n", kind),
+    };
+    Event::Html(CowStr::Boxed(comment.into_boxed_str()))
+}
+
+/// ASCII art is shown as it is, in a code block
+fn ascii_art(text: String) -> Vec<Event<'static>> {
+    code_block(ASCII_ART_LANGUAGE, text)
+}
+
+/// A fenced code block of `text`, highlighted as `language`
+fn code_block(language: &str, mut text: String) -> Vec<Event<'static>> {
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+    let language = CowStr::Boxed(language.to_string().into_boxed_str());
+    let code_block = Tag::CodeBlock(CodeBlockKind::Fenced(language));
+    vec![
+        Event::Start(code_block.clone()),
+        Event::Text(CowStr::Boxed(text.into())),
+        Event::End(code_block),
+    ]
+}
+
+/// The code block of `ascii_art` as markdown, for references to a diagram
+fn ascii_art_block(text: &str) -> String {
+    markdown_code_block(ASCII_ART_LANGUAGE, text)
+}
+
+/// A fenced code block of `text` as markdown, highlighted as `language`
+fn markdown_code_block(language: &str, text: &str) -> String {
+    let newline = if text.ends_with('\n') { "" } else { "\n" };
+    format!("```{}\n{}{}```", language, text, newline)
+}
+
+/// The body of a diagram, which is read from the file a `file:` directive names.
+/// That file is returned too, relative to the book root. It's relative to the
+/// chapter and has to be within the book, and is read in `input_charset`.
+fn diagram_body(
+    book_root: &Path,
+    chapter_path: &Path,
+    body: String,
+    input_charset: InputCharset,
+) -> Result<(String, Option<PathBuf>), String> {
+    let (file, data) = match (fence::source_file(&body), fence::data_file(&body)) {
+        (Some(file), _) => (file, false),
+        (None, Some(file)) => (file, true),
+        (None, None) => return Ok((body, None)),
+    };
+    let chapter_directory = Path::new("src").join(chapter_path.parent().unwrap_or(Path::new("")));
+    let source_file = normalize_relative(&chapter_directory.join(file))
+        .ok_or_else(|| format!("The diagram file `{}` must be within the book", file))?;
+    let body = read(book_root.join(&source_file))
+        .map_err(|err| err.to_string())
+        .and_then(|body| input_charset.decode(body))
+        .map_err(|err| {
+            format!(
+                "Unable to read the diagram file {}: {}",
+                source_file.display(),
+                err
+            )
+        })?;
+    let body = include::normalize(&body);
+    if data {
+        return Ok((data_diagram(&source_file, &body)?, Some(source_file)));
+    }
+    Ok((body, Some(source_file)))
+}
+
+/// The tree diagram of a data file for `datafile:`, told apart by its
+/// extension. TOML is turned into JSON for PlantUML, which reads JSON and YAML
+/// itself.
+fn data_diagram(path: &Path, data: &str) -> Result<String, String> {
+    let invalid = |err: &dyn std::fmt::Display| {
+        format!("The data file {} is invalid: {}", path.display(), err)
+    };
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    let (diagram_type, data) = match extension.as_deref() {
+        Some("toml") => {
+            let value: toml::Value = toml::from_str(data).map_err(|err| invalid(&err))?;
+            let json = serde_json::to_string_pretty(&value).map_err(|err| invalid(&err))?;
+            ("json", json)
+        }
+        Some("json") => {
+            serde_json::from_str::<serde_json::Value>(data).map_err(|err| invalid(&err))?;
+            ("json", data.trim_end().to_string())
+        }
+        Some("yaml") | Some("yml") => ("yaml", data.trim_end().to_string()),
+        _ => {
+            return Err(format!(
+                "The data file {} has to be a `.toml`, `.json`, `.yaml` or `.yml` file",
+                path.display()
+            ))
+        }
+    };
+    Ok(format!(
+        "@start{}\n{}\n@end{}\n",
+        diagram_type, data, diagram_type
+    ))
+}
+
+/// A relative path as it's written in URLs, with forward slashes
+fn url_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The same diagram twice on a page shares its image but still needs ids of
+/// its own, later ones get a `-2`, `-3` and so on
+fn unique_id(ids: &mut HashMap<String, usize>, id: String) -> String {
+    let occurrence = ids.entry(id.clone()).or_insert(0);
+    *occurrence += 1;
+    match *occurrence {
+        1 => id,
+        occurrence => format!("{}-{}", id, occurrence),
+    }
+}
+
+/// The link to an image at `image_path`, relative to the source directory, from
+/// the chapter at `chapter_path`. URLs always use forward slashes. Images linked
+/// in place are reached from where the renderer writes the chapter, through
+/// `in_place`, whatever the `url_style`.
+fn image_url(
+    url_style: UrlStyle,
+    in_place: Option<&Path>,
+    chapter_path: &Path,
+    image_path: &Path,
+) -> String {
+    let image = url_path(image_path);
+    let depth = chapter_path
+        .parent()
+        .map_or(0, |directory| directory.components().count());
+    if let Some(in_place) = in_place {
+        let image = match url_path(in_place) {
+            in_place if in_place.is_empty() => image,
+            in_place => format!("{}/{}", in_place, image),
+        };
+        return format!("{}{}", "../".repeat(depth), image);
+    }
+    match url_style {
+        UrlStyle::Absolute => image,
+        UrlStyle::Root => format!("/{}", image),
+        UrlStyle::Relative => format!("{}{}", "../".repeat(depth), image),
+    }
+}
+
+/// Where mdbook has the renderer write the book: the build directory, or a
+/// directory of its own in there when the book has more than one output
+fn renderer_destination(context: &PreprocessorContext) -> PathBuf {
+    let build_directory = context.root.join(&context.config.build.build_dir);
+    let outputs = context
+        .config
+        .get("output")
+        .and_then(toml::Value::as_table)
+        .map_or(0, |outputs| outputs.len());
+    if outputs > 1 {
+        build_directory.join(&context.renderer)
+    } else {
+        build_directory
+    }
+}
+
+/// The way from the directory `from` to `to`, both absolute or both relative
+/// to the same directory, without touching the filesystem
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<_> = from
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect();
+    let to: Vec<_> = to
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect();
+    let common = from
+        .iter()
+        .zip(&to)
+        .take_while(|(from, to)| from == to)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    for component in &to[common..] {
+        relative.push(component);
+    }
+    relative
+}
+
 /// Takes the context root of the book and concatinates the build directory.
 /// This works because the build directory is given to us relative to the
 /// project root
-fn determine_build_directory(context: &PreprocessorContext) -> PathBuf {
-    let mut build_directory = PathBuf::from(&context.root.as_path());
+fn determine_build_directory(book_root: &Path) -> PathBuf {
+    let mut build_directory = PathBuf::from(book_root);
     // build_directory.push(&context.config.build.build_dir);
     build_directory.push("src");
     build_directory
 }
 
-fn determine_plantuml_output_directory(context: &PreprocessorContext) -> PathBuf {
-    let mut plantuml_directory = determine_build_directory(context);
-    plantuml_directory.push(&RENDER_DIRECTORY_NAME);
-    plantuml_directory
+/// Has Git LFS store the raster images of `directory`, and those below it.
+/// It's only written when it differs, so `mdbook serve` doesn't see a change.
+fn write_gitattributes(directory: &Path) -> Result<(), Error> {
+    let attributes = LFS_EXTENSIONS
+        .iter()
+        .map(|extension| format!("*.{} filter=lfs diff=lfs merge=lfs -text\n", extension))
+        .collect::<String>();
+    let attributes = format!(
+        "# Written by mdbook-plantuml-renderer, lfs_gitattributes is set\n{}",
+        attributes
+    );
+    let path = directory.join(GITATTRIBUTES_FILENAME);
+    if read_to_string(&path).ok().as_deref() != Some(attributes.as_str()) {
+        write(&path, attributes)?;
+    }
+    Ok(())
+}
+
+/// Takes the front matter off every chapter, as the chapter loop does, for a
+/// book whose diagrams are all left as they are
+fn strip_front_matter(book: &mut Book) -> Result<(), Error> {
+    let mut errors = Vec::new();
+    book.for_each_mut(|item: &mut BookItem| {
+        if let BookItem::Chapter(chapter) = item {
+            match front_matter::split(&chapter.content) {
+                Ok(Some((_, rest))) => chapter.content = rest.to_string(),
+                Ok(None) => {}
+                Err(err) => errors.push(RenderError::chapter(&chapter.name, err)),
+            }
+        }
+    });
+    match errors.into_iter().next() {
+        Some(err) => Err(err.into()),
+        None => Ok(()),
+    }
+}
+
+/// Replaces each `{{#plantuml <id>}}` with the source of the diagram it refers
+/// to, as a diagram PlantUML didn't produce is shown, for a book whose
+/// diagrams aren't rendered
+fn replace_references_with_source(
+    book: &mut Book,
+    config: &Config,
+    chapter_filter: &ChapterFilter,
+) -> Result<(), Error> {
+    let mut sources = config.diagrams.clone();
+    for item in book.iter() {
+        let chapter = match item {
+            BookItem::Chapter(chapter) if chapter_filter.includes(&chapter.path) => chapter,
+            _ => continue,
+        };
+        // A chapter whose diagrams can't be told apart has none to refer to
+        let diagrams = validate::diagrams(&chapter.content, config).unwrap_or_default();
+        for diagram in diagrams {
+            if let Some(id) = diagram.fence.id() {
+                if !diagram.fence.is_definition() {
+                    sources.entry(id.to_string()).or_insert(diagram.body);
+                }
+            }
+        }
+    }
+    let mut errors = Vec::new();
+    book.for_each_mut(|item: &mut BookItem| {
+        if let BookItem::Chapter(chapter) = item {
+            let replaced = library::replace_references(&chapter.content, |id| {
+                sources
+                    .get(id)
+                    .map(|source| markdown_code_block(&config.fallback_language, source))
+                    .ok_or_else(|| format!("no diagram has the id `{}`", id))
+            });
+            match replaced {
+                Ok(content) => chapter.content = content,
+                Err(err) => errors.push(RenderError::chapter(&chapter.name, err)),
+            }
+        }
+    });
+    match errors.into_iter().next() {
+        Some(err) => Err(err.into()),
+        None => Ok(()),
+    }
+}
+
+/// Whether there's anything to render: a diagram of the book.toml, or one in a
+/// chapter which isn't left out. A chapter whose diagrams can't be told apart,
+/// e.g. with an unclosed region, is taken to have some.
+fn has_diagrams(book: &Book, config: &Config) -> Result<bool, Error> {
+    if !config.diagrams.is_empty() {
+        return Ok(true);
+    }
+    let chapter_filter = ChapterFilter::from_config(config)?;
+    Ok(book.iter().any(|item| match item {
+        BookItem::Chapter(chapter) if chapter_filter.includes(&chapter.path) => {
+            match validate::diagrams(&chapter.content, config) {
+                Ok(diagrams) => diagrams
+                    .iter()
+                    .any(|diagram| !diagram.fence.is_definition()),
+                Err(_) => true,
+            }
+        }
+        _ => false,
+    }))
+}
+
+/// Removes the diagram directory for `clean_before_build`, unless mdbook is
+/// serving or watching the book. It has to be a directory within `src`, even
+/// when it's reached through a symlink, for anything to be removed.
+fn clean_diagram_directory(source_directory: &Path, config: &Config) -> Result<(), Error> {
+    match mdbook_command() {
+        Some(command) if command != "serve" && command != "watch" => {}
+        Some(command) => {
+            debug!("Keeping the diagrams of `mdbook {}`", command);
+            return Ok(());
+        }
+        None => {
+            warn!(
+                "clean_before_build only works where mdbook's command is known, on Linux, \
+                 so the diagrams are kept"
+            );
+            return Ok(());
+        }
+    }
+    let directory = determine_plantuml_output_directory(source_directory, config);
+    let (source_directory, resolved) =
+        match (source_directory.canonicalize(), directory.canonicalize()) {
+            (Ok(source_directory), Ok(resolved)) => (source_directory, resolved),
+            // Nothing's been rendered yet
+            _ => return Ok(()),
+        };
+    if resolved == source_directory || !resolved.starts_with(&source_directory) {
+        return Err(RenderError::Config(format!(
+            "clean_before_build won't remove {}, it isn't a directory within {}",
+            resolved.display(),
+            source_directory.display()
+        ))
+        .into());
+    }
+    info!(
+        "Removing {} before the build, clean_before_build is set",
+        directory.display()
+    );
+    remove_dir_all(&resolved)?;
+    Ok(())
+}
+
+/// The root of the book the mdbook running the preprocessor is about, the
+/// directory its command names, like `mdbook build guide`, or else the one it
+/// runs in. Run by anything but mdbook that's the current directory.
+fn mdbook_book_root() -> PathBuf {
+    let directory = parent_command_line().and_then(|command_line| {
+        let mut arguments = command_line
+            .split('\0')
+            .filter(|argument| !argument.is_empty());
+        if Path::new(arguments.next()?).file_stem()? != "mdbook" {
+            return None;
+        }
+        // Past the command, the directory is the one argument which isn't an option's
+        arguments.next()?;
+        let mut directory = None;
+        let mut option_value = false;
+        for argument in arguments {
+            if option_value {
+                option_value = false;
+            } else if argument.starts_with('-') {
+                option_value = MDBOOK_VALUE_OPTIONS.contains(&argument);
+            } else {
+                directory = Some(PathBuf::from(argument));
+                break;
+            }
+        }
+        directory
+    });
+    directory.unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// The mdbook command running the preprocessor, like `build` or `serve`, from
+/// the command line of its parent process. Anything but mdbook, like a test or
+/// a script, is taken for a build. `None` when it can't be told.
+fn mdbook_command() -> Option<String> {
+    let command_line = parent_command_line()?;
+    let mut arguments = command_line.split('\0');
+    // Not a preprocessor like this one, whose background render is a build too
+    if Path::new(arguments.next()?).file_stem()? != "mdbook" {
+        return Some("build".to_string());
+    }
+    // Options like `--help` can't come before the command of a build
+    Some(arguments.next().unwrap_or("build").to_string())
+}
+
+#[cfg(unix)]
+fn parent_command_line() -> Option<String> {
+    let path = format!("/proc/{}/cmdline", std::os::unix::process::parent_id());
+    read(path)
+        .ok()
+        .map(|command_line| String::from_utf8_lossy(&command_line).into_owned())
+}
+
+#[cfg(not(unix))]
+fn parent_command_line() -> Option<String> {
+    None
+}
+
+/// Warns, or fails with `strict`, when the book's `build-dir` is within its
+/// source directory. mdbook would then find the last build among the sources,
+/// and `mdbook serve` would rebuild whenever it writes the book.
+fn check_build_directory(book_root: &Path, build_dir: &Path, config: &Config) -> Result<(), Error> {
+    // The build directory needn't exist yet, so it's resolved without the filesystem
+    let book_root = match book_root.canonicalize() {
+        Ok(book_root) => book_root,
+        Err(_) => return Ok(()),
+    };
+    let mut build_directory = PathBuf::new();
+    for component in book_root.join(build_dir).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                build_directory.pop();
+            }
+            component => build_directory.push(component),
+        }
+    }
+    let source_directory = determine_build_directory(&book_root);
+    if !build_directory.starts_with(&source_directory) {
+        return Ok(());
+    }
+    let message = format!(
+        "The build directory `{}` is inside the source directory `{}`, so every build \
+         would be read back in as part of the book. Move `build-dir` out of `src`.",
+        build_directory.display(),
+        source_directory.display()
+    );
+    if config.strict {
+        return Err(RenderError::Config(message).into());
+    }
+    warn!("{}", message);
+    Ok(())
+}
+
+/// Validates a per-diagram `out=` directory, which is relative to the book's
+/// source directory. Anything absolute or climbing out of the source directory
+/// is refused so a diagram can't be written outside of the project tree.
+fn confine_relative_directory(out: &str) -> Result<PathBuf, String> {
+    normalize_relative(Path::new(out)).ok_or_else(|| {
+        format!(
+            "The diagram output directory `{}` must stay within the book's source directory",
+            out
+        )
+    })
+}
+
+/// Splits an `out` naming a file, e.g. `assets/login.png`, into its directory,
+/// the image's name and the format its extension asks for. Directories, which
+/// have no extension or end in `/`, give nothing.
+fn output_file(out: &str) -> Result<Option<(&str, &str, OutputFormat)>, String> {
+    let path = Path::new(out);
+    let extension = match path.extension().and_then(OsStr::to_str) {
+        Some(extension) if !out.ends_with('/') => extension,
+        _ => return Ok(None),
+    };
+    let format = match extension.to_ascii_lowercase().as_str() {
+        "svg" => OutputFormat::Svg,
+        "png" => OutputFormat::Png,
+        "webp" => OutputFormat::Webp,
+        _ => {
+            return Err(format!(
+                "`out={}` names a `.{}` file, which diagrams can't be rendered to. \
+                 Use `.svg`, `.png` or `.webp`, or end it with `/` for a directory.",
+                out, extension
+            ))
+        }
+    };
+    let name = path.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+    let directory = path.parent().and_then(Path::to_str).unwrap_or_default();
+    Ok(Some((directory, name, format)))
+}
+
+/// Resolves `.` and `..` without touching the filesystem. Nothing is returned
+/// for absolute paths and ones climbing above where they start.
+fn normalize_relative(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {}
+            Component::ParentDir if normalized.pop() => {}
+            _ => return None,
+        }
+    }
+    Some(normalized)
+}
+
+fn determine_plantuml_output_directory(render_root: &Path, config: &Config) -> PathBuf {
+    render_root.join(&config.diagram_directory)
 }
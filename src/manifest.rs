@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+use mdbook::errors::Error;
+use serde_derive::{Deserialize, Serialize};
+
+/// The manifest is kept with the diagrams in the default render directory
+pub static MANIFEST_FILENAME: &str = "manifest.json";
+
+/// The size an image was rendered at, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Dimensions {
+    pub width: f64,
+    pub height: f64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Entry {
+    dimensions: Option<Dimensions>,
+}
+
+/// What's known about rendered images, keyed by their filename, so cache hits
+/// don't have to open and parse the images again
+#[derive(Debug, Default)]
+pub struct Manifest {
+    path: PathBuf,
+    entries: BTreeMap<String, Entry>,
+    changed: bool,
+}
+
+impl Manifest {
+    /// A missing or unreadable manifest only costs a re-read of the images
+    pub fn load(path: &Path) -> Manifest {
+        let entries = match read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|err| {
+                warn!(
+                    "Ignoring the unreadable manifest {}: {}",
+                    path.display(),
+                    err
+                );
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        Manifest {
+            path: path.to_path_buf(),
+            entries,
+            changed: false,
+        }
+    }
+
+    /// Only writes when something changed, a rewrite on every build would keep
+    /// retriggering `mdbook serve`
+    pub fn save(&self) -> Result<(), Error> {
+        if !self.changed {
+            return Ok(());
+        }
+        debug!("Writing manifest: {}", self.path.display());
+        let contents = serde_json::to_string_pretty(&self.entries)?;
+        write(&self.path, contents).map_err(|err| {
+            Error::from(format!(
+                "Unable to write the manifest {}: {}",
+                self.path.display(),
+                err
+            ))
+        })
+    }
+
+    pub fn dimensions(&self, image_filename: &str) -> Option<Dimensions> {
+        self.entries
+            .get(image_filename)
+            .and_then(|entry| entry.dimensions)
+    }
+
+    pub fn record_dimensions(&mut self, image_filename: &str, dimensions: Dimensions) {
+        self.entries
+            .entry(image_filename.to_string())
+            .or_default()
+            .dimensions = Some(dimensions);
+        self.changed = true;
+    }
+
+    /// Drops everything known about an image which is about to be regenerated
+    pub fn forget(&mut self, image_filename: &str) {
+        if self.entries.remove(image_filename).is_some() {
+            self.changed = true;
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use pulldown_cmark::{CowStr, Event, Options, Parser, Tag};
+
+/// The extensions mdbook parses chapters with. Without them a diagram in a
+/// footnote isn't even seen as a code block.
+pub fn options() -> Options {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+    options
+}
+
+/// Parses a chapter the way mdbook will once it's been preprocessed
+pub fn parse(content: &str) -> Parser<'_> {
+    Parser::new_ext(content, options())
+}
+
+/// The events of a chapter, mended for pulldown-cmark-to-cmark to write out
+/// block quotes in one piece, like `> [!NOTE]` alerts holding a diagram. The
+/// line it ends HTML with lacks the quote's `>`, which ends the quote, so a
+/// line break of the quote's own takes its place. An alert's `[!NOTE]` would be
+/// escaped, it's written out as it is.
+pub fn keep_block_quotes<'a>(events: impl Iterator<Item = Event<'a>>) -> Vec<Event<'a>> {
+    let mut kept = Vec::new();
+    let mut depth = 0;
+    let mut events = events.peekable();
+    while let Some(event) = events.next() {
+        match event {
+            Event::Start(Tag::BlockQuote) => depth += 1,
+            Event::End(Tag::BlockQuote) => depth -= 1,
+            _ => {}
+        }
+        let opens_quote = matches!(event, Event::Start(Tag::Paragraph))
+            && matches!(kept.last(), Some(Event::Start(Tag::BlockQuote)));
+        // An HTML block as opposed to inline HTML, which needs a blank line after it
+        let html = match &event {
+            Event::Html(html) => Some(html.ends_with('\n')),
+            _ => None,
+        };
+        kept.push(event);
+        if opens_quote {
+            let mut texts = Vec::new();
+            while let Some(text) = events.next_if(|event| matches!(event, Event::Text(_))) {
+                texts.push(text);
+            }
+            let first_line: String = texts
+                .iter()
+                .filter_map(|text| match text {
+                    Event::Text(text) => Some(text.as_ref()),
+                    _ => None,
+                })
+                .collect();
+            if is_alert_marker(&first_line) {
+                kept.push(Event::Html(CowStr::Boxed(first_line.into())));
+                // Taken for more HTML, so nothing's written after it
+                kept.push(Event::Text(CowStr::Borrowed("")));
+            } else {
+                kept.extend(texts);
+            }
+        }
+        let continues = matches!(
+            events.peek(),
+            None | Some(Event::Html(_)) | Some(Event::Text(_))
+        );
+        if let (Some(block), true, false) = (html, depth > 0, continues) {
+            kept.push(Event::Text(CowStr::Borrowed("")));
+            if block {
+                kept.push(Event::SoftBreak);
+            }
+        }
+    }
+    kept
+}
+
+/// Whether the text is the marker of a GitHub style alert, e.g. `[!NOTE]`
+fn is_alert_marker(text: &str) -> bool {
+    text.strip_prefix("[!")
+        .and_then(|rest| rest.strip_suffix(']'))
+        .is_some_and(|kind| !kind.is_empty() && kind.chars().all(|c| c.is_ascii_alphabetic()))
+}
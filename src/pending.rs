@@ -0,0 +1,51 @@
+//! Diagrams left for later with `serve_async`: a build of `mdbook serve` shows
+//! the images it has and leaves the rest to a `prerender` of the book run in
+//! the background. The images it writes into `src` set off another build,
+//! which finds them rendered. Its process id is kept in the book root, so a
+//! build while it's still going doesn't start another.
+
+use std::env::current_exe;
+use std::fs::{read_to_string, write};
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use log::debug;
+
+/// Where the background render's process id is kept, within the book root
+pub static PIDFILE_NAME: &str = ".plantuml-renderer-pending";
+
+/// Starts rendering the book's diagrams in the background unless it's being
+/// done already, returning whether it was started
+pub fn render_in_background(book_root: &Path) -> io::Result<bool> {
+    let pidfile = book_root.join(PIDFILE_NAME);
+    if let Some(pid) = read_to_string(&pidfile)
+        .ok()
+        .filter(|pid| is_running(pid.trim()))
+    {
+        debug!("The background render {} is still going", pid.trim());
+        return Ok(false);
+    }
+    // Its output would keep mdbook waiting on the preprocessor's, what it logs
+    // goes where the preprocessor's does
+    let child = Command::new(current_exe()?)
+        .arg("prerender")
+        .arg(book_root)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+    write(&pidfile, format!("{}\n", child.id()))?;
+    Ok(true)
+}
+
+fn is_running(pid: &str) -> bool {
+    if pid.is_empty() || !pid.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+    Command::new("kill")
+        .arg("-0")
+        .arg(pid)
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
@@ -0,0 +1,101 @@
+use crate::config::{Config, OutputFormat};
+
+/// Smetana is PlantUML's own port of Graphviz's dot. Its layouts only depend
+/// on the PlantUML version, not on the Graphviz a machine happens to have.
+static STABLE_LAYOUT_PRAGMA: &str = "!pragma layout smetana";
+
+/// Lines injected into every diagram right after its `@start` directive,
+/// which is the only place PlantUML honours pragmas
+#[derive(Debug, Default)]
+pub struct Preamble {
+    lines: Vec<String>,
+}
+
+impl Preamble {
+    /// Pragmas may be configured with or without their `!pragma` keyword. A
+    /// configured layout wins over `stable_layout`. The `definitions` come next,
+    /// so the rest can use them, then the `theme`, the configured `preamble` and
+    /// finally the one for the format PlantUML renders, so its `skinparam`s win
+    /// over the global ones.
+    pub fn from_config(config: &Config) -> Preamble {
+        let mut lines: Vec<String> = config
+            .pragmas
+            .iter()
+            .map(|pragma| {
+                let pragma = pragma.trim();
+                let pragma = pragma.strip_prefix("!pragma").unwrap_or(pragma);
+                format!("!pragma {}", pragma.trim())
+            })
+            .collect();
+        let has_layout = lines
+            .iter()
+            .any(|line| line["!pragma".len()..].trim_start().starts_with("layout"));
+        if config.stable_layout && !has_layout {
+            lines.push(STABLE_LAYOUT_PRAGMA.to_string());
+        }
+        lines.extend(
+            config
+                .definitions
+                .iter()
+                .map(|(name, value)| format!("!define {} {}", name, value.trim())),
+        );
+        // Ahead of the preamble, whose skinparams then adjust it
+        if let Some(theme) = &config.theme {
+            lines.push(format!("!theme {}", theme.trim()));
+        }
+        let format_preamble: &[String] = match config.output_format {
+            OutputFormat::Svg => &config.preamble_svg,
+            OutputFormat::Png | OutputFormat::Webp => &config.preamble_png,
+            OutputFormat::Txt | OutputFormat::Auto => &[],
+        };
+        lines.extend(
+            config
+                .preamble
+                .iter()
+                .chain(format_preamble)
+                .map(|line| line.trim().to_string()),
+        );
+        Preamble { lines }
+    }
+
+    /// This preamble followed by a line of a single diagram's, which then wins
+    /// over the configured ones
+    pub fn with_line(&self, line: String) -> Preamble {
+        let mut lines = self.lines.clone();
+        lines.push(line);
+        Preamble { lines }
+    }
+
+    /// Inserts the preamble after the first `@start` line. Lines the diagram
+    /// already has aren't added a second time.
+    pub fn apply(&self, plantuml_code: &str) -> String {
+        let missing: Vec<&String> = self
+            .lines
+            .iter()
+            .filter(|line| {
+                !plantuml_code
+                    .lines()
+                    .any(|existing| existing.trim() == *line)
+            })
+            .collect();
+        if missing.is_empty() {
+            return plantuml_code.to_string();
+        }
+        let mut applied = String::with_capacity(plantuml_code.len());
+        let mut inserted = false;
+        for line in plantuml_code.split_inclusive('\n') {
+            applied.push_str(line);
+            if !inserted && line.trim_start().starts_with("@start") {
+                if !line.ends_with('\n') {
+                    applied.push('\n');
+                }
+                for preamble_line in &missing {
+                    applied.push_str(preamble_line);
+                    applied.push('\n');
+                }
+                inserted = true;
+            }
+        }
+        applied
+    }
+}
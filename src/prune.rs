@@ -0,0 +1,80 @@
+//! `prune`: removing the images no diagram of the book is shown as anymore,
+//! along with the `.puml` and other files kept next to them. Only files named
+//! after a hash are looked at, so anything else sharing the diagram directory
+//! is left alone.
+
+use std::collections::HashSet;
+use std::fs::{read_dir, remove_dir, remove_file};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, HASH_LENGTH};
+
+/// Removes the files within `directory` named after a hash which isn't that of
+/// one of the `used` images, returning them. Directories left empty, like the
+/// shards only stale images were in, go too.
+pub fn prune(
+    directory: &Path,
+    used: &HashSet<PathBuf>,
+    config: &Config,
+) -> io::Result<Vec<PathBuf>> {
+    let kept = used.iter().map(|image| without_extension(image)).collect();
+    let mut removed = Vec::new();
+    prune_directory(directory, &kept, config, &mut removed)?;
+    Ok(removed)
+}
+
+fn prune_directory(
+    directory: &Path,
+    kept: &HashSet<PathBuf>,
+    config: &Config,
+    removed: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    for entry in read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        // Symlinks lead somewhere else, which isn't for the book to clean up
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            let before = removed.len();
+            prune_directory(&path, kept, config, removed)?;
+            if removed.len() > before && read_dir(&path)?.next().is_none() {
+                remove_dir(&path)?;
+            }
+        } else if file_type.is_file()
+            && is_named_after_hash(&path, config)
+            && !kept.contains(&without_extension(&path))
+        {
+            remove_file(&path)?;
+            removed.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The path up to the file name's first `.`, which the image, its `.puml` and
+/// its `.puml.meta` share
+fn without_extension(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(name.split('.').next().unwrap_or_default())
+}
+
+/// Like `<hash>.svg`, or `<hash>@2x.png` for a `srcset_scales` variant, with
+/// the hash as long as `hash_length` makes it or a whole one
+fn is_named_after_hash(path: &Path, config: &Config) -> bool {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    let (stem, extension) = match name.split_once('.') {
+        Some(split) => split,
+        None => return false,
+    };
+    let hash = stem.split('@').next().unwrap_or_default();
+    !extension.is_empty()
+        && (hash.len() == HASH_LENGTH || Some(hash.len()) == config.hash_length)
+        && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
@@ -0,0 +1,61 @@
+use pulldown_cmark::Event;
+
+use crate::markdown;
+
+/// The HTML comments marking a region of a chapter diagrams are rendered in,
+/// `<!-- plantuml:begin -->` and `<!-- plantuml:end -->`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Marker {
+    Begin,
+    End,
+}
+
+pub fn marker(event: &Event<'_>) -> Option<Marker> {
+    let html = match event {
+        Event::Html(html) => html.trim(),
+        _ => return None,
+    };
+    match html
+        .strip_prefix("<!--")
+        .and_then(|comment| comment.strip_suffix("-->"))
+        .map(str::trim)
+    {
+        Some("plantuml:begin") => Some(Marker::Begin),
+        Some("plantuml:end") => Some(Marker::End),
+        _ => None,
+    }
+}
+
+/// Whether the chapter limits rendering to marked regions. Markers have to
+/// come in pairs and regions can't be nested.
+pub fn has_regions(content: &str) -> Result<bool, String> {
+    let mut open = false;
+    let mut found = false;
+    for event in markdown::parse(content) {
+        match (marker(&event), open) {
+            (Some(Marker::Begin), true) => {
+                return Err(
+                    "`<!-- plantuml:begin -->` inside a region which is already open, \
+                     regions can't be nested"
+                        .into(),
+                )
+            }
+            (Some(Marker::End), false) => {
+                return Err(
+                    "`<!-- plantuml:end -->` without a `<!-- plantuml:begin -->` before it".into(),
+                )
+            }
+            (Some(marker), _) => {
+                open = marker == Marker::Begin;
+                found = true;
+            }
+            (None, _) => {}
+        }
+    }
+    if open {
+        return Err(
+            "`<!-- plantuml:begin -->` is never closed by a `<!-- plantuml:end -->`".into(),
+        );
+    }
+    Ok(found)
+}
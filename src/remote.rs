@@ -0,0 +1,102 @@
+//! A PlantUML server run elsewhere, e.g. `plantuml-server` next to a CI job
+//! which has no Java, set with `server_url`. Each diagram is fetched from
+//! `<server_url>/<format>/<encoded source>`, the way PlantUML links diagrams.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::server::{self, Response};
+
+/// The base64 digits of PlantUML's text encoding, which keep it within a URL
+static ALPHABET: &[u8; 64] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz-_";
+/// The most a stored deflate block holds
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/// Fetches the image of the diagram, waiting for it at most `time_limit`. Only
+/// plain `http://` URLs are read, see `Config::server_url`.
+pub fn render(
+    server_url: &str,
+    format: &str,
+    plantuml_code: &str,
+    time_limit: Option<Duration>,
+) -> io::Result<Response> {
+    let (authority, base_path) = split_url(server_url)?;
+    let mut stream = TcpStream::connect(address(authority))?;
+    stream.set_read_timeout(time_limit)?;
+    stream.set_write_timeout(time_limit)?;
+    // HTTP/1.0, so the answer is neither chunked nor kept alive
+    write!(
+        stream,
+        "GET {}/{}/{} HTTP/1.0\r\nHost: {}\r\n\r\n",
+        base_path.trim_end_matches('/'),
+        format,
+        encode(plantuml_code),
+        authority
+    )?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    server::parse_response(&response)
+}
+
+/// Whether something takes connections at the server's address
+pub fn is_answering(server_url: &str) -> bool {
+    split_url(server_url).is_ok_and(|(authority, _)| TcpStream::connect(address(authority)).is_ok())
+}
+
+/// The host and port of the URL, and the path diagrams are under
+fn split_url(server_url: &str) -> io::Result<(&str, &str)> {
+    let location = server_url
+        .strip_prefix("http://")
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "only http:// URLs are read"))?;
+    Ok(match location.find('/') {
+        Some(slash) => location.split_at(slash),
+        None => (location, ""),
+    })
+}
+
+fn address(authority: &str) -> String {
+    if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    }
+}
+
+/// PlantUML's text encoding: the source deflated, in base64 of its own. It's
+/// deflated into stored blocks, which any inflater reads, as there's nothing
+/// here to compress it with. The URL is that much longer.
+pub fn encode(plantuml_code: &str) -> String {
+    let source = plantuml_code.as_bytes();
+    let mut deflated = Vec::with_capacity(source.len() + 5);
+    let mut blocks = source.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        // An empty source is an empty final block
+        deflated.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+        deflated.push(u8::from(last));
+        deflated.extend_from_slice(&length.to_le_bytes());
+        deflated.extend_from_slice(&(!length).to_le_bytes());
+        deflated.extend_from_slice(block);
+    }
+    // Three bytes to four digits, a short last group padded with zeros
+    let mut encoded = String::with_capacity(deflated.len() / 3 * 4 + 4);
+    for group in deflated.chunks(3) {
+        let bytes = [
+            group[0],
+            group.get(1).copied().unwrap_or(0),
+            group.get(2).copied().unwrap_or(0),
+        ];
+        let digits = [
+            bytes[0] >> 2,
+            ((bytes[0] & 0x3) << 4) | (bytes[1] >> 4),
+            ((bytes[1] & 0xF) << 2) | (bytes[2] >> 6),
+            bytes[2] & 0x3F,
+        ];
+        encoded.extend(digits.iter().map(|digit| ALPHABET[*digit as usize] as char));
+    }
+    encoded
+}
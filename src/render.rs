@@ -0,0 +1,1485 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{create_dir_all, read, read_to_string, remove_file, rename, write, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use mdbook::errors::Error;
+use serde_json::{Map, Value};
+
+use crate::config::{
+    Config, ExtraPages, InputCharset, OutputFormat, ServerMode, SECURITY_PROFILE_VARIABLE,
+};
+use crate::container::Container;
+use crate::include;
+use crate::limit::ProcessLimit;
+use crate::manifest::{Dimensions, Manifest, MANIFEST_FILENAME};
+use crate::prune;
+use crate::remote;
+use crate::server::{self, Server};
+use crate::stamp::Stamp;
+use crate::svg;
+use crate::timeout;
+
+/// PlantUML's `plantuml.include.path` property, as it's read from the environment
+static INCLUDE_PATH_VARIABLE: &str = "PLANTUML_INCLUDE_PATH";
+/// What PlantUML is run as when there's no `plantuml_command` and none of the
+/// `commands` is found, looked up on the `PATH`
+static DEFAULT_PLANTUML: &str = "plantuml";
+
+/// What the JSON file `write_provenance` has next to an image ends in, in
+/// place of the image's extension
+pub static PROVENANCE_EXTENSION: &str = "puml.meta";
+/// What the file with the hash of a named image's diagram ends in, which
+/// unlike its `.puml` covers what the diagram includes
+pub static HASH_EXTENSION: &str = "puml.hash";
+
+/// How long the files of one batch run may be together, well within what
+/// Windows takes for a whole command line
+const MAX_BATCH_SOURCES_LENGTH: usize = 24 * 1024;
+
+/// How long measuring PlantUML's startup time is waited for
+static STARTUP_LIMIT: Duration = Duration::from_secs(120);
+
+/// What the JVM prints on stderr before PlantUML runs at all, which is never
+/// about the diagram
+static JVM_NOISE: &[&str] = &["Picked up _JAVA_OPTIONS", "Picked up JAVA_TOOL_OPTIONS"];
+
+/// What the lines PlantUML reports a diagram's errors with on stderr hold,
+/// which `strict` fails on even when it exits successfully. Its warnings, like
+/// a missing font, don't.
+static PLANTUML_ERRORS: &[&str] = &["Error line ", "ERROR", "Syntax Error"];
+
+/// Turns PlantUML source into image files, named after the hash of the source
+pub struct Renderer<'a> {
+    config: &'a Config,
+    book_root: PathBuf,
+    base_directory: Option<PathBuf>,
+    container: Option<Container>,
+    /// The PlantUML on the host, when it doesn't run in the container
+    program: PathBuf,
+    /// The arguments of `plantuml_command`, which come before any others
+    program_args: Vec<String>,
+    stamp: Stamp,
+    manifest: Mutex<Manifest>,
+    /// Images rendered or found up to date during this run, which aren't
+    /// looked at again when the diagram shows up once more
+    up_to_date: Mutex<HashSet<PathBuf>>,
+    /// The hash of the diagram each image shortened by `hash_length` belongs
+    /// to, so two diagrams sharing a short name are told apart
+    shortened: Mutex<HashMap<PathBuf, String>>,
+    /// Every image a diagram of this run is shown as, rendered or not, which
+    /// `prune` keeps
+    used: Mutex<HashSet<PathBuf>>,
+    process_limit: ProcessLimit,
+    /// How long it takes PlantUML to start, which `timeout_secs` doesn't count.
+    /// It's measured the first time it's needed.
+    startup: Mutex<Option<Duration>>,
+    /// The server of `server_mode = "managed"`, once a diagram needed it
+    server: Mutex<Option<Server>>,
+    /// What PlantUML answers to `-version`, for `write_provenance`, once it's asked
+    version: Mutex<Option<String>>,
+    /// When `global_timeout_secs` runs out, counted from when the renderer was made
+    deadline: Option<Instant>,
+    rendered: AtomicUsize,
+    cached: AtomicUsize,
+}
+
+/// The format of images rendered in one go, and the images with their
+/// `link_base` and the hash of their diagram
+type Batch<'a> = (OutputFormat, Vec<(PathBuf, Option<&'a Path>, &'a str)>);
+
+/// A diagram to render, with where its image goes and what it's rendered as
+pub struct Diagram<'a> {
+    pub plantuml_code: &'a str,
+    pub plantuml_hash_sum: &'a str,
+    pub output_directory: &'a Path,
+    /// The image's filename without its extension, the hash unless the fence names the file
+    pub name: &'a str,
+    pub format: OutputFormat,
+    pub no_cache: bool,
+    /// With `rewrite_svg_links`, the way from the image to the directory the
+    /// diagram's relative links are meant from, e.g. `../guide`
+    pub link_base: Option<&'a Path>,
+}
+
+impl Diagram<'_> {
+    /// Whether the image is named after something other than its hash, which
+    /// then says nothing about the source it was rendered from
+    fn is_named(&self) -> bool {
+        self.name != self.plantuml_hash_sum
+    }
+}
+
+/// How many diagrams a run rendered, and how many it found already rendered
+#[derive(Debug, Clone, Copy)]
+pub struct Summary {
+    pub rendered: usize,
+    pub cached: usize,
+}
+
+impl<'a> Renderer<'a> {
+    /// The manifest is kept in `render_directory`, the default home of diagrams
+    pub fn new(
+        config: &'a Config,
+        book_root: &Path,
+        render_directory: &Path,
+    ) -> Result<Renderer<'a>, Error> {
+        let container = match &config.container {
+            Some(container_config) => Some(Container::new(container_config, book_root)?),
+            None => None,
+        };
+        let base_directory = match &config.base_dir {
+            Some(base_dir) => {
+                let base_directory = book_root.join(base_dir);
+                if !base_directory.is_dir() {
+                    return Err(Error::from(format!(
+                        "The base_dir {} isn't a directory",
+                        base_directory.display()
+                    )));
+                }
+                if let Some(container) = &container {
+                    if container.translate(&base_directory).is_none() {
+                        return Err(Error::from(format!(
+                            "The base_dir {} isn't visible in the container, \
+                             it has to be within the book or one of the mounts",
+                            base_directory.display()
+                        )));
+                    }
+                }
+                Some(base_directory)
+            }
+            None => None,
+        };
+        let process_limit = match config.max_processes {
+            Some(0) => return Err(Error::from("max_processes has to be at least 1")),
+            Some(limit) => limit,
+            None => ProcessLimit::default_limit(),
+        };
+        Ok(Renderer {
+            config,
+            book_root: book_root.to_path_buf(),
+            base_directory,
+            program: match (&container, config.plantuml_command.first()) {
+                (Some(_), _) => PathBuf::from(DEFAULT_PLANTUML),
+                (None, Some(program)) => command_path(program, book_root),
+                (None, None) => select_program(&config.commands, book_root),
+            },
+            program_args: config.plantuml_command.iter().skip(1).cloned().collect(),
+            container,
+            stamp: Stamp::from_config(config)?,
+            manifest: Mutex::new(Manifest::load(&render_directory.join(MANIFEST_FILENAME))),
+            up_to_date: Mutex::new(HashSet::new()),
+            shortened: Mutex::new(HashMap::new()),
+            used: Mutex::new(HashSet::new()),
+            process_limit: ProcessLimit::new(process_limit),
+            startup: Mutex::new(None),
+            server: Mutex::new(None),
+            version: Mutex::new(None),
+            deadline: config
+                .global_timeout_secs
+                .map(|secs| Instant::now() + Duration::from_secs(secs)),
+            rendered: AtomicUsize::new(0),
+            cached: AtomicUsize::new(0),
+        })
+    }
+
+    /// Renders the diagram into its `output_directory` unless it's already there
+    /// and `no_cache` isn't set, returning the filename of the image. Includes are
+    /// searched for in `chapter_directory`, where the diagram's chapter lives, and then `base_dir`.
+    /// A diagram is only rendered once per run, even with `no_cache`.
+    pub fn render(
+        &self,
+        diagram: &Diagram<'_>,
+        chapter_directory: &Path,
+    ) -> Result<PathBuf, Error> {
+        let Diagram {
+            plantuml_code,
+            output_directory,
+            format,
+            ..
+        } = *diagram;
+        let image_filename = image_filename(diagram);
+        debug!("Filename: {}", image_filename.display());
+        self.claim_shortened(diagram, &image_filename)?;
+        self.used().insert(image_filename.clone());
+        if self.up_to_date().contains(&image_filename) {
+            return Ok(image_filename);
+        }
+        if is_cached(diagram, &image_filename, self.config.input_charset) {
+            self.cached.fetch_add(1, Ordering::Relaxed);
+            // Rendered before the comment was configured, or with another one
+            if self.comment_svg(format, &image_filename)? {
+                self.stamp_files(&[&image_filename])?;
+            }
+            self.up_to_date().insert(image_filename.clone());
+            return Ok(image_filename);
+        }
+        self.rendered.fetch_add(1, Ordering::Relaxed);
+        self.manifest().forget(&manifest_key(&image_filename));
+        create_dir_all(output_directory)?;
+        let rendered_filename = image_filename.with_extension(format.rendered_extension());
+        let include_directories = self.include_directories(chapter_directory);
+        self.warn_about_boms(plantuml_code, output_directory, &include_directories);
+        let served =
+            self.config.server_mode == ServerMode::Managed || self.config.server_url.is_some();
+        if served {
+            self.render_through_server(format, plantuml_code, &rendered_filename)?;
+        } else {
+            let permit = self.process_limit.acquire();
+            let output = if self.config.pipe {
+                self.render_through_pipe(
+                    format,
+                    plantuml_code,
+                    &rendered_filename,
+                    &include_directories,
+                )?
+            } else {
+                self.render_through_file(
+                    format,
+                    plantuml_code,
+                    &image_filename,
+                    output_directory,
+                    &include_directories,
+                )?
+            };
+            drop(permit);
+            if !output.status.success() {
+                if !self.shows_error_images() {
+                    remove_images(&[&image_filename, &rendered_filename])?;
+                    return Err(Error::from(format!(
+                        "PlantUML failed. {}",
+                        failure(&output)
+                    )));
+                }
+                warn!("PlantUML failure occurred! {}", failure(&output));
+                log_output(&output);
+            }
+            if let Some(report) = self.stderr_report(&output) {
+                remove_images(&[&image_filename, &rendered_filename])?;
+                return Err(Error::from(format!(
+                    "PlantUML reported on stderr: {}",
+                    report
+                )));
+            }
+        }
+        // What a named image was rendered from is only known from its source
+        if (self.config.pipe || served) && diagram.is_named() {
+            self.write_source(&image_filename.with_extension("puml"), plantuml_code)?;
+        }
+        if diagram.is_named() {
+            write_hash(diagram, &image_filename)?;
+        }
+        self.finish(format, &image_filename, diagram.link_base)?;
+        if let Err(err) = self.write_provenance(format, &image_filename, diagram.plantuml_hash_sum)
+        {
+            self.up_to_date().remove(&image_filename);
+            return Err(err);
+        }
+        Ok(image_filename)
+    }
+
+    /// Whether the diagram's image is there to be used as it is, so rendering it
+    /// takes no time
+    pub fn is_rendered(&self, diagram: &Diagram<'_>) -> bool {
+        let image_filename = image_filename(diagram);
+        self.up_to_date().contains(&image_filename)
+            || is_cached(diagram, &image_filename, self.config.input_charset)
+    }
+
+    /// How many PlantUML processes may run at once, see `max_processes`
+    pub fn max_processes(&self) -> usize {
+        self.process_limit.limit()
+    }
+
+    /// Whether a diagram PlantUML fails on is shown as its error image, with a
+    /// warning, rather than failing, see `error_images`
+    fn shows_error_images(&self) -> bool {
+        self.config.error_images && !self.config.strict
+    }
+
+    /// Whether `global_timeout_secs` ran out, so no more diagrams are to be rendered
+    pub fn is_out_of_time(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Where the diagram's image is, or will be once it's rendered
+    pub fn image_filename(&self, diagram: &Diagram<'_>) -> PathBuf {
+        let image_filename = image_filename(diagram);
+        self.used().insert(image_filename.clone());
+        image_filename
+    }
+
+    /// Removes the images within `directory` which no diagram of this run was
+    /// shown as, with their sources, returning how many images there were
+    pub fn prune(&self, directory: &Path) -> Result<usize, Error> {
+        let removed = prune::prune(directory, &self.used(), self.config)?;
+        let mut manifest = self.manifest();
+        let mut images = 0;
+        for filename in &removed {
+            debug!("Removed {}", filename.display());
+            if filename.extension().is_some_and(|extension| {
+                extension != "puml" && extension != "meta" && extension != "hash"
+            }) {
+                manifest.forget(&manifest_key(filename));
+                images += 1;
+            }
+        }
+        Ok(images)
+    }
+
+    /// Takes the image's name for the diagram when it's its shortened hash,
+    /// failing when another diagram of the book has taken it already
+    fn claim_shortened(&self, diagram: &Diagram<'_>, image_filename: &Path) -> Result<(), Error> {
+        let shortened = diagram.name.len() < diagram.plantuml_hash_sum.len()
+            && diagram.plantuml_hash_sum.starts_with(diagram.name);
+        if !shortened {
+            return Ok(());
+        }
+        let mut claimed = self
+            .shortened
+            .lock()
+            .expect("The shortened images lock is poisoned");
+        match claimed.get(image_filename) {
+            Some(hash) if hash != diagram.plantuml_hash_sum => Err(Error::from(format!(
+                "its hash shares the first {} characters with that of diagram {}, so both \
+                 would be written to {}. Raise hash_length.",
+                diagram.name.len(),
+                hash,
+                image_filename.display()
+            ))),
+            Some(_) => Ok(()),
+            None => {
+                claimed.insert(
+                    image_filename.to_path_buf(),
+                    diagram.plantuml_hash_sum.to_string(),
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Renders every diagram of a chapter which isn't rendered yet with a single
+    /// PlantUML run per output directory, saving a JVM start for every other
+    /// one. `render` then finds them rendered. Piping is one diagram at a time,
+    /// with `pipe` they're left to `render`, and so is telling which diagram
+    /// PlantUML's stderr is about with `strict_stderr`. A server is already
+    /// running, so there's nothing to save.
+    pub fn render_batch(
+        &self,
+        diagrams: &[Diagram<'_>],
+        chapter_directory: &Path,
+    ) -> Result<(), Error> {
+        if self.config.pipe
+            || self.config.strict_stderr
+            || self.config.server_mode == ServerMode::Managed
+            || self.config.server_url.is_some()
+        {
+            return Ok(());
+        }
+        let include_directories = self.include_directories(chapter_directory);
+        // PlantUML is run once for every directory and format
+        let mut batches: BTreeMap<(&Path, &str), Batch<'_>> = BTreeMap::new();
+        for diagram in diagrams {
+            let image_filename = image_filename(diagram);
+            let (_, batch) = batches
+                .entry((diagram.output_directory, diagram.format.extension()))
+                .or_insert_with(|| (diagram.format, Vec::new()));
+            // Rendered together, diagrams named alike would overwrite each other's
+            // images, so those are left to `render`
+            if self.up_to_date().contains(&image_filename)
+                || is_cached(diagram, &image_filename, self.config.input_charset)
+                || batch.iter().any(|(image, _, _)| *image == image_filename)
+                || start_name(diagram.plantuml_code).is_some()
+            {
+                continue;
+            }
+            create_dir_all(diagram.output_directory)?;
+            self.write_source(
+                &image_filename.with_extension("puml"),
+                diagram.plantuml_code,
+            )?;
+            if diagram.is_named() {
+                write_hash(diagram, &image_filename)?;
+            }
+            self.warn_about_boms(
+                diagram.plantuml_code,
+                diagram.output_directory,
+                &include_directories,
+            );
+            self.manifest().forget(&manifest_key(&image_filename));
+            batch.push((image_filename, diagram.link_base, diagram.plantuml_hash_sum));
+        }
+        for ((output_directory, _), (format, images)) in batches {
+            let sources: Vec<OsString> = images
+                .iter()
+                .map(|(image, _, _)| self.plantuml_path(&image.with_extension("puml")))
+                .collect();
+            // A run for as many of them as fit on its command line
+            let mut start = 0;
+            while start < images.len() {
+                let end = start + fitting_on_command_line(&sources[start..]);
+                self.render_together(
+                    format,
+                    output_directory,
+                    &images[start..end],
+                    &sources[start..end],
+                    &include_directories,
+                )?;
+                start = end;
+            }
+        }
+        Ok(())
+    }
+
+    /// One PlantUML run of `render_batch`, for the images whose `.puml` files
+    /// are `sources`
+    fn render_together(
+        &self,
+        format: OutputFormat,
+        output_directory: &Path,
+        images: &[(PathBuf, Option<&Path>, &str)],
+        sources: &[OsString],
+        include_directories: &[&Path],
+    ) -> Result<(), Error> {
+        debug!("Rendering {} diagrams in one go", images.len());
+        let permit = self.process_limit.acquire();
+        let mut command = self.plantuml(false, include_directories);
+        command
+            .arg(format.plantuml_flag())
+            .arg("-o")
+            .arg(self.plantuml_path(output_directory))
+            .args(sources);
+        let output = self.run(&mut command, None, self.batch_timeout(images.len()))?;
+        drop(permit);
+        // So does an error PlantUML reports with `strict`, though it exited successfully
+        let failed = !output.status.success() || self.stderr_report(&output).is_some();
+        if failed && !self.shows_error_images() {
+            // Which of them failed isn't known, `render` tries each on its own
+            debug!(
+                "PlantUML failed on diagrams rendered together: {}",
+                failure(&output)
+            );
+            for (image_filename, _, _) in images {
+                remove_images(&[
+                    image_filename,
+                    &image_filename.with_extension(format.rendered_extension()),
+                ])?;
+            }
+            return Ok(());
+        }
+        if !output.status.success() {
+            // Which of them failed shows when their images are missing
+            warn!(
+                "PlantUML failed on some of the diagrams rendered together. {}",
+                failure(&output)
+            );
+            log_output(&output);
+        }
+        for (image_filename, link_base, plantuml_hash_sum) in images {
+            let finished = self
+                .finish(format, image_filename, *link_base)
+                .and_then(|()| self.write_provenance(format, image_filename, plantuml_hash_sum));
+            if let Err(err) = finished {
+                // `render` tries it again on its own, saying which diagram it was
+                debug!("Finishing {} failed: {}", image_filename.display(), err);
+                self.up_to_date().remove(image_filename);
+                if image_filename.exists() {
+                    remove_file(image_filename)?;
+                }
+                continue;
+            }
+            self.rendered.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Has PlantUML check the diagram's syntax with `-checkonly`, without
+    /// rendering it, returning what PlantUML reported when it doesn't parse.
+    /// The source is written to `scratch_directory`, which has to be visible to
+    /// a container as well.
+    pub fn validate(
+        &self,
+        plantuml_code: &str,
+        scratch_directory: &Path,
+        chapter_directory: &Path,
+    ) -> Result<Option<String>, Error> {
+        create_dir_all(scratch_directory)?;
+        let puml_filename = scratch_directory.join("diagram.puml");
+        self.write_source(&puml_filename, plantuml_code)?;
+        let include_directories = self.include_directories(chapter_directory);
+        let permit = self.process_limit.acquire();
+        let mut command = self.plantuml(false, &include_directories);
+        command
+            .arg("-checkonly")
+            .arg(self.plantuml_path(&puml_filename));
+        let output = self.run(&mut command, None, self.timeout());
+        drop(permit);
+        remove_file(&puml_filename)?;
+        let output = output?;
+        if output.status.success() {
+            return Ok(None);
+        }
+        let report = [&output.stderr, &output.stdout]
+            .iter()
+            .map(|stream| String::from_utf8_lossy(stream).trim().to_string())
+            .filter(|stream| !stream.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        Ok(Some(if report.is_empty() {
+            "PlantUML couldn't parse the diagram".to_string()
+        } else {
+            report
+        }))
+    }
+
+    /// Whether the program running PlantUML, the container runtime if there is
+    /// one, can be found at all. There's nothing to install for a `server_url`.
+    pub fn is_installed(&self) -> bool {
+        if self.config.server_url.is_some() {
+            return true;
+        }
+        let program = match &self.container {
+            Some(container) => Path::new(container.runtime()),
+            None => &self.program,
+        };
+        find_program(program).is_some()
+    }
+
+    /// The first line of what PlantUML answers to `-version`, when it runs. A
+    /// `server_url` doesn't tell, it's named instead when it answers.
+    pub fn plantuml_version(&self) -> Option<String> {
+        if let Some(server_url) = &self.config.server_url {
+            return Some(format!("PlantUML server at {}", server_url))
+                .filter(|_| remote::is_answering(server_url));
+        }
+        let output = self.plantuml(false, &[]).arg("-version").output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(String::from)
+    }
+
+    /// The intrinsic size of a rendered image, read from the manifest when it's
+    /// known and otherwise from the image itself
+    pub fn dimensions(&self, format: OutputFormat, image_filename: &Path) -> Option<Dimensions> {
+        let key = manifest_key(image_filename);
+        if let Some(dimensions) = self.manifest().dimensions(&key) {
+            return Some(dimensions);
+        }
+        let (width, height) = match format {
+            OutputFormat::Svg => svg::dimensions(&read_to_string(image_filename).ok()?)?,
+            OutputFormat::Png => png_dimensions(image_filename)?,
+            OutputFormat::Webp | OutputFormat::Txt | OutputFormat::Auto => return None,
+        };
+        let dimensions = Dimensions { width, height };
+        self.manifest().record_dimensions(&key, dimensions);
+        Some(dimensions)
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary {
+            rendered: self.rendered.load(Ordering::Relaxed),
+            cached: self.cached.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn save_manifest(&self) -> Result<(), Error> {
+        self.manifest().save()
+    }
+
+    fn manifest(&self) -> MutexGuard<'_, Manifest> {
+        self.manifest.lock().expect("The manifest lock is poisoned")
+    }
+
+    /// Included files are handed to PlantUML as they are, so the most that can
+    /// be done about a byte order mark in one is pointing it out. Relative includes
+    /// are looked for where PlantUML looks first, next to the `.puml` file or, when
+    /// piping, where it runs from, and then along the include path.
+    fn warn_about_boms(
+        &self,
+        plantuml_code: &str,
+        output_directory: &Path,
+        include_directories: &[&Path],
+    ) {
+        let mut directories = vec![if self.config.pipe {
+            self.base_directory.as_deref().unwrap_or(&self.book_root)
+        } else {
+            output_directory
+        }];
+        directories.extend(include_directories);
+        for file in include::included_files(plantuml_code) {
+            if let Some(path) = include::resolve(file, &directories) {
+                if include::starts_with_bom(&path) {
+                    warn!(
+                        "The included file {} starts with a byte order mark, which PlantUML \
+                         may misread. Save it as UTF-8 without one.",
+                        path.display()
+                    );
+                }
+            }
+        }
+    }
+
+    /// What PlantUML wrote to stderr with `strict_stderr`, without JVM noise and
+    /// the lines `stderr_ignore` matches, when anything is left. With only
+    /// `strict` it's the errors PlantUML reported.
+    fn stderr_report(&self, output: &Output) -> Option<String> {
+        if !self.config.strict_stderr && !self.config.strict {
+            return None;
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let report = stderr
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .filter(|line| !JVM_NOISE.iter().any(|noise| line.starts_with(noise)))
+            .filter(|line| {
+                self.config.strict_stderr
+                    || PLANTUML_ERRORS.iter().any(|error| line.contains(error))
+            })
+            .filter(|line| {
+                !self
+                    .config
+                    .stderr_ignore
+                    .iter()
+                    .any(|pattern| line.contains(pattern.as_str()))
+            })
+            .collect::<Vec<_>>();
+        if report.is_empty() {
+            None
+        } else {
+            Some(report.join("\n"))
+        }
+    }
+
+    fn used(&self) -> MutexGuard<'_, HashSet<PathBuf>> {
+        self.used.lock().expect("The used images lock is poisoned")
+    }
+
+    fn up_to_date(&self) -> MutexGuard<'_, HashSet<PathBuf>> {
+        self.up_to_date
+            .lock()
+            .expect("The up to date images lock is poisoned")
+    }
+
+    fn include_directories<'b>(&'b self, chapter_directory: &'b Path) -> Vec<&'b Path> {
+        let mut include_directories = vec![chapter_directory];
+        include_directories.extend(self.base_directory.as_deref());
+        include_directories
+    }
+
+    /// Everything done to an image once PlantUML has written it, before it
+    /// goes into the book
+    fn finish(
+        &self,
+        format: OutputFormat,
+        image_filename: &Path,
+        link_base: Option<&Path>,
+    ) -> Result<(), Error> {
+        let rendered_filename = image_filename.with_extension(format.rendered_extension());
+        if !self.config.pipe && !rendered_filename.exists() {
+            self.recover_misplaced(&rendered_filename)?;
+        }
+        if self.config.validate_svg && format == OutputFormat::Svg && rendered_filename.exists() {
+            let svg = read_to_string(&rendered_filename)?;
+            if let Err(err) = svg::check_well_formed(&svg) {
+                // Otherwise the next build would take the image as up to date
+                remove_file(&rendered_filename)?;
+                return Err(Error::from(format!(
+                    "PlantUML wrote malformed SVG: {}",
+                    err
+                )));
+            }
+        }
+        // Piped pages all end up in the one image, only files can be counted
+        let extra_pages = extra_pages(&rendered_filename);
+        if !extra_pages.is_empty() {
+            let message = format!(
+                "PlantUML wrote {} pages, only the first is shown: {}",
+                extra_pages.len() + 1,
+                extra_pages
+                    .iter()
+                    .map(|page| page.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            match self.config.extra_pages {
+                ExtraPages::Warn => warn!("{}", message),
+                ExtraPages::Error => {
+                    // Otherwise the next build would take the image as up to date
+                    let hash_filename = image_filename.with_extension(HASH_EXTENSION);
+                    let mut filenames = vec![image_filename, &rendered_filename, &hash_filename];
+                    filenames.extend(extra_pages.iter().map(PathBuf::as_path));
+                    remove_images(&filenames)?;
+                    return Err(Error::from(message));
+                }
+            }
+        }
+        // ASCII art has no margins to speak of
+        if self.config.trim && format != OutputFormat::Txt && rendered_filename.exists() {
+            self.trim(format, &rendered_filename)?;
+        }
+        if let (OutputFormat::Svg, Some(fallback)) = (format, &self.config.svg_font_family) {
+            if rendered_filename.exists() {
+                let svg = read_to_string(&rendered_filename)?;
+                write(&rendered_filename, svg::add_font_fallback(&svg, fallback))?;
+            }
+        }
+        if let (OutputFormat::Svg, Some(link_base)) = (format, link_base) {
+            if rendered_filename.exists() {
+                let svg = read_to_string(&rendered_filename)?;
+                let rewritten = svg::rewrite_links(&svg, |href| book_link(link_base, href));
+                write(&rendered_filename, rewritten)?;
+            }
+        }
+        if format == OutputFormat::Webp && rendered_filename.exists() {
+            self.convert_to_webp(&rendered_filename, image_filename)?;
+        }
+        self.comment_svg(format, image_filename)?;
+        if !self.config.post_render.is_empty() && image_filename.exists() {
+            if let Err(err) = self.post_render(image_filename) {
+                // Otherwise the next build would take the image as done
+                remove_file(image_filename)?;
+                return Err(err);
+            }
+        }
+        self.stamp_files(&[&image_filename.with_extension("puml"), image_filename])?;
+        // Only once it's passed every check, a failed one is tried again
+        self.up_to_date().insert(image_filename.to_path_buf());
+        Ok(())
+    }
+
+    /// Writes what `write_provenance` keeps next to a freshly rendered image. It's
+    /// left out when telling whether an image is up to date, an image without
+    /// one rendered before the option was set keeps going without it.
+    fn write_provenance(
+        &self,
+        format: OutputFormat,
+        image_filename: &Path,
+        plantuml_hash_sum: &str,
+    ) -> Result<(), Error> {
+        if !self.config.write_provenance || !image_filename.exists() {
+            return Ok(());
+        }
+        let version = self
+            .version
+            .lock()
+            .expect("The PlantUML version lock is poisoned")
+            .get_or_insert_with(|| {
+                self.plantuml_version()
+                    .unwrap_or_else(|| "unknown".to_string())
+            })
+            .clone();
+        let mut provenance = Map::new();
+        provenance.insert("hash".into(), plantuml_hash_sum.into());
+        provenance.insert("plantuml_version".into(), version.into());
+        provenance.insert("rendered_at".into(), chrono::Utc::now().to_rfc3339().into());
+        provenance.insert("format".into(), format.extension().into());
+        provenance.insert(
+            "options_fingerprint".into(),
+            self.config.render_fingerprint().into(),
+        );
+        let provenance_filename = image_filename.with_extension(PROVENANCE_EXTENSION);
+        write(
+            &provenance_filename,
+            serde_json::to_string_pretty(&Value::Object(provenance))?,
+        )?;
+        self.stamp_files(&[&provenance_filename])
+    }
+
+    /// Adds the configured `svg_comment` to an SVG image, returning whether the
+    /// file changed. As it's added after rendering it's not part of the hash.
+    fn comment_svg(&self, format: OutputFormat, image_filename: &Path) -> Result<bool, Error> {
+        let comment = match (&self.config.svg_comment, format) {
+            (Some(comment), OutputFormat::Svg) if image_filename.exists() => comment,
+            _ => return Ok(false),
+        };
+        let svg = read_to_string(image_filename)?;
+        match svg::set_comment(&svg, comment) {
+            Some(commented) => {
+                write(image_filename, commented)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn stamp_files(&self, generated: &[&Path]) -> Result<(), Error> {
+        for generated in generated {
+            if generated.exists() {
+                self.stamp.apply(generated).map_err(|err| {
+                    Error::from(format!(
+                        "Unable to set the metadata of {}: {}",
+                        generated.display(),
+                        err
+                    ))
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Depending on its version PlantUML takes `-o` relative to the input file or
+    /// to its working directory, even when it's absolute. What it wrote is looked
+    /// for in both and moved to where it belongs, or it'd be rendered again on
+    /// every build.
+    fn recover_misplaced(&self, rendered_filename: &Path) -> Result<(), Error> {
+        let (output_directory, file_name) =
+            match (rendered_filename.parent(), rendered_filename.file_name()) {
+                (Some(output_directory), Some(file_name)) => (output_directory, file_name),
+                _ => return Ok(()),
+            };
+        // The `-o` PlantUML was given, appended to either of them
+        let output_argument = PathBuf::from(self.plantuml_path(output_directory));
+        let appended: PathBuf = output_argument
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        let working_directory = self.base_directory.as_deref().unwrap_or(&self.book_root);
+        let misplaced = [output_directory, working_directory]
+            .iter()
+            .map(|root| root.join(&appended).join(file_name))
+            .find(|candidate| candidate.is_file());
+        if let Some(misplaced) = misplaced {
+            info!(
+                "PlantUML wrote {} rather than {}, moving it",
+                misplaced.display(),
+                rendered_filename.display()
+            );
+            rename(&misplaced, rendered_filename)?;
+        }
+        Ok(())
+    }
+
+    /// Dumps the PUML file next to where the image goes, for PlantUML to read
+    fn render_through_file(
+        &self,
+        format: OutputFormat,
+        plantuml_code: &str,
+        image_filename: &Path,
+        output_directory: &Path,
+        include_directories: &[&Path],
+    ) -> Result<Output, Error> {
+        let puml_filename = image_filename.with_extension("puml");
+        debug!(
+            "Image doesn't exist, writing PUML data: {}",
+            puml_filename.display()
+        );
+        self.write_source(&puml_filename, plantuml_code)?;
+        // Call plantuml and generate the image
+        let mut command = self.plantuml(false, include_directories);
+        command
+            .arg(format.plantuml_flag())
+            .arg("-o")
+            .arg(self.plantuml_path(output_directory))
+            .arg(self.plantuml_path(&puml_filename));
+        let output = self.run(&mut command, None, self.timeout())?;
+        recover_start_named(
+            plantuml_code,
+            &image_filename.with_extension(format.rendered_extension()),
+        )?;
+        Ok(output)
+    }
+
+    /// Writes a diagram's source for PlantUML in `input_charset`, the one its
+    /// includes are in too
+    fn write_source(&self, puml_filename: &Path, plantuml_code: &str) -> Result<(), Error> {
+        let source = self
+            .config
+            .input_charset
+            .encode(plantuml_code)
+            .map_err(|err| Error::from(format!("The diagram's source, {}", err)))?;
+        write(puml_filename, source)?;
+        Ok(())
+    }
+
+    /// Streams the diagram through PlantUML's `-pipe` mode, so nothing but the
+    /// image itself is written
+    fn render_through_pipe(
+        &self,
+        format: OutputFormat,
+        plantuml_code: &str,
+        rendered_filename: &Path,
+        include_directories: &[&Path],
+    ) -> Result<Output, Error> {
+        debug!(
+            "Image doesn't exist, piping PUML data to render: {}",
+            rendered_filename.display()
+        );
+        let mut command = self.plantuml(true, include_directories);
+        command.arg("-pipe").arg(format.plantuml_flag());
+        let output = self.run(&mut command, Some(plantuml_code), self.timeout())?;
+        // Like file mode, a failed render still produces PlantUML's error image
+        if !output.stdout.is_empty() {
+            let mut image_file = File::create(rendered_filename)?;
+            image_file.write_all(&output.stdout)?;
+        }
+        Ok(output)
+    }
+
+    /// Has the `server_url` render the diagram, or else the managed server,
+    /// which is started first if it isn't running yet
+    fn render_through_server(
+        &self,
+        format: OutputFormat,
+        plantuml_code: &str,
+        rendered_filename: &Path,
+    ) -> Result<(), Error> {
+        debug!(
+            "Image doesn't exist, having the server render: {}",
+            rendered_filename.display()
+        );
+        let time_limit = self.config.timeout_secs.map(Duration::from_secs);
+        let format = &format.plantuml_flag()[2..];
+        let response = match &self.config.server_url {
+            Some(server_url) => remote::render(server_url, format, plantuml_code, time_limit)
+                .map_err(|err| Error::from(format!("{} failed: {}", server_url, err)))?,
+            None => self
+                .server()?
+                .render(format, plantuml_code, time_limit)
+                .map_err(|err| Error::from(format!("The PlantUML server failed: {}", err)))?,
+        };
+        // Like file mode, a failed render still produces PlantUML's error image
+        if !response.image.is_empty() {
+            write(rendered_filename, &response.image)?;
+        }
+        if let Some(error) = response.error {
+            if !self.shows_error_images() {
+                remove_images(&[rendered_filename])?;
+                return Err(Error::from(format!("PlantUML failed. {}", error)));
+            }
+            warn!("PlantUML failure occurred! {}", error);
+        }
+        Ok(())
+    }
+
+    /// The managed server, started the first time it's needed. It searches
+    /// `base_dir` for includes, there's no telling it the chapter's directory.
+    fn server(&self) -> Result<Server, Error> {
+        let mut server = self.server.lock().expect("The server lock is poisoned");
+        if let Some(server) = *server {
+            return Ok(server);
+        }
+        let include_directories: Vec<&Path> = self.base_directory.as_deref().into_iter().collect();
+        let started = Server::connect_or_start(
+            self.plantuml(false, &include_directories),
+            &self.book_root.join(server::PIDFILE_NAME),
+            STARTUP_LIMIT,
+        )
+        .map_err(|err| Error::from(format!("Unable to start a PlantUML server: {}", err)))?;
+        *server = Some(started);
+        Ok(started)
+    }
+
+    /// The PlantUML executable, either on the host or inside the configured container.
+    /// It runs from `base_dir` or else the book root, which is what includes resolve
+    /// against in pipe mode. PlantUML reads its include search path from the
+    /// environment when it isn't passed as a Java property. The configured `env`
+    /// comes first, so it can't override what the preprocessor sets itself.
+    /// The charset it reads diagrams in is passed along.
+    fn plantuml(&self, interactive: bool, include_directories: &[&Path]) -> Command {
+        let working_directory = self.base_directory.as_deref().unwrap_or(&self.book_root);
+        let mut command = match &self.container {
+            Some(container) => {
+                let include_path = include_directories
+                    .iter()
+                    .filter_map(|directory| container.translate(directory))
+                    .collect::<Vec<_>>()
+                    .join(":");
+                let mut environment: Vec<(&str, String)> = self
+                    .config
+                    .env
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.clone()))
+                    .collect();
+                environment.push((INCLUDE_PATH_VARIABLE, include_path));
+                if let Some(profile) = self.config.security_profile {
+                    environment.push((SECURITY_PROFILE_VARIABLE, profile.name().to_string()));
+                }
+                container.command(
+                    interactive,
+                    container.translate(working_directory).as_deref(),
+                    &environment,
+                )
+            }
+            None => {
+                let mut command = Command::new(&self.program);
+                command
+                    .args(&self.program_args)
+                    .current_dir(working_directory)
+                    .envs(&self.config.env);
+                if let Ok(include_path) = env::join_paths(include_directories) {
+                    command.env(INCLUDE_PATH_VARIABLE, include_path);
+                }
+                if let Some(profile) = self.config.security_profile {
+                    command.env(SECURITY_PROFILE_VARIABLE, profile.name());
+                }
+                command
+            }
+        };
+        if let Some(charset) = self.config.plantuml_charset() {
+            command.arg("-charset").arg(charset);
+        }
+        command
+    }
+
+    /// Runs PlantUML, stopping it once it takes longer than `timeout`
+    fn run(
+        &self,
+        command: &mut Command,
+        input: Option<&str>,
+        timeout: Option<(Duration, Duration)>,
+    ) -> Result<Output, Error> {
+        let time_limit = timeout.map(|(render, startup)| render + startup);
+        let input = match input {
+            Some(input) => Some(self.config.input_charset.encode(input)?),
+            None => None,
+        };
+        match timeout::output(command, input.as_deref(), time_limit) {
+            Ok(Some(output)) => Ok(output),
+            Ok(None) => {
+                let (render, startup) = timeout.expect("Only a limited run is stopped");
+                Err(Error::from(format!(
+                    "PlantUML was stopped after {}s of rendering, and {:.1}s taken to start, \
+                     see timeout_secs",
+                    render.as_secs(),
+                    startup.as_secs_f64()
+                )))
+            }
+            Err(err) => Err(Error::from(format!("Failed to run PlantUML: {}", err))),
+        }
+    }
+
+    /// How long rendering a single diagram may take with `timeout_secs`, along
+    /// with the time starting PlantUML takes on top of it
+    fn timeout(&self) -> Option<(Duration, Duration)> {
+        let render = Duration::from_secs(self.config.timeout_secs?);
+        Some((render, self.startup()))
+    }
+
+    /// What `timeout` is for rendering `diagrams` in a single run, which is
+    /// `batch_timeout_secs` or else `timeout_secs` for each of them
+    fn batch_timeout(&self, diagrams: usize) -> Option<(Duration, Duration)> {
+        let render = match (self.config.batch_timeout_secs, self.config.timeout_secs) {
+            (Some(batch_timeout), _) => Duration::from_secs(batch_timeout),
+            (None, Some(timeout)) => Duration::from_secs(timeout)
+                .saturating_mul(u32::try_from(diagrams).unwrap_or(u32::MAX)),
+            (None, None) => return None,
+        };
+        Some((render, self.startup()))
+    }
+
+    /// How long PlantUML takes to answer `-version`, which is mostly starting
+    /// the JVM. Nothing is rendered, so it can't take long unless PlantUML is
+    /// stuck, then it's given up on after `STARTUP_LIMIT` and counted as nothing.
+    fn startup(&self) -> Duration {
+        let mut startup = self
+            .startup
+            .lock()
+            .expect("The startup time lock is poisoned");
+        *startup.get_or_insert_with(|| {
+            let mut command = self.plantuml(false, &[]);
+            command.arg("-version");
+            let started = Instant::now();
+            match timeout::output(&mut command, None, Some(STARTUP_LIMIT)) {
+                Ok(Some(_)) => {
+                    let startup = started.elapsed();
+                    debug!("PlantUML takes {:.1}s to start", startup.as_secs_f64());
+                    startup
+                }
+                _ => {
+                    warn!("Couldn't measure how long PlantUML takes to start");
+                    Duration::from_secs(0)
+                }
+            }
+        })
+    }
+
+    /// Paths handed to PlantUML have to be translated when it runs in a container
+    fn plantuml_path(&self, path: &Path) -> OsString {
+        match &self.container {
+            Some(container) => container
+                .translate(path)
+                .expect("Diagrams are always written within the mounted book")
+                .into(),
+            None => path.as_os_str().to_os_string(),
+        }
+    }
+
+    /// Crops the whitespace PlantUML leaves around a diagram, before any conversion
+    fn trim(&self, format: OutputFormat, rendered_filename: &Path) -> Result<(), Error> {
+        if format == OutputFormat::Svg {
+            let svg = read_to_string(rendered_filename)?;
+            match svg::trim(&svg) {
+                Some(trimmed) => write(rendered_filename, trimmed)?,
+                None => warn!(
+                    "Nothing to trim in {}, leaving it as is",
+                    rendered_filename.display()
+                ),
+            }
+            return Ok(());
+        }
+        let output = Command::new(&self.config.trim_tool)
+            .arg("-trim")
+            .arg("+repage")
+            .arg(rendered_filename)
+            .output()
+            .map_err(|err| match err.kind() {
+                ErrorKind::NotFound => Error::from(format!(
+                    "Trimming raster images needs `{}`, but it wasn't found. \
+                     Install ImageMagick or point the `trim_tool` option at it.",
+                    self.config.trim_tool
+                )),
+                _ => Error::from(format!(
+                    "Failed to run `{}`: {}",
+                    self.config.trim_tool, err
+                )),
+            })?;
+        if !output.status.success() {
+            return Err(Error::from(format!(
+                "`{}` couldn't trim {}: {}",
+                self.config.trim_tool,
+                rendered_filename.display(),
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Runs the `post_render` command on a freshly rendered image, from the book root
+    fn post_render(&self, image_filename: &Path) -> Result<(), Error> {
+        let (program, args) = match self.config.post_render.split_first() {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        let output = Command::new(program)
+            .args(args)
+            .arg(image_filename)
+            .current_dir(&self.book_root)
+            .output()
+            .map_err(|err| Error::from(format!("Failed to run `{}`: {}", program, err)))?;
+        if !output.status.success() {
+            return Err(Error::from(format!(
+                "`{}` failed on {} with {}: {}",
+                self.config.post_render.join(" "),
+                image_filename.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+
+    /// PlantUML can't produce WebP itself, so its PNG is converted with `cwebp`
+    /// and then discarded
+    fn convert_to_webp(&self, png_filename: &Path, webp_filename: &Path) -> Result<(), Error> {
+        let output = Command::new(&self.config.cwebp)
+            .arg("-quiet")
+            .arg(png_filename)
+            .arg("-o")
+            .arg(webp_filename)
+            .output()
+            .map_err(|err| match err.kind() {
+                ErrorKind::NotFound => Error::from(format!(
+                    "WebP output needs `{}` to convert PlantUML's PNG, but it wasn't found. \
+                     Install it or point the `cwebp` option at it.",
+                    self.config.cwebp
+                )),
+                _ => Error::from(format!("Failed to run `{}`: {}", self.config.cwebp, err)),
+            })?;
+        if !output.status.success() {
+            return Err(Error::from(format!(
+                "`{}` couldn't convert {}: {}",
+                self.config.cwebp,
+                png_filename.display(),
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        remove_file(png_filename)?;
+        Ok(())
+    }
+}
+
+/// How PlantUML exited, with what it wrote to stderr, which names the line a
+/// syntax error is on
+fn failure(output: &Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match stderr.trim() {
+        "" => format!("It exited with {}", output.status),
+        stderr => format!("It exited with {}: {}", output.status, stderr),
+    }
+}
+
+/// PlantUML's output is only of interest when something went wrong
+fn log_output(output: &Output) {
+    debug!(
+        "PlantUML stdout: {}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    debug!(
+        "PlantUML stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Where a relative link in an SVG leads from the image, for a link meant from
+/// the directory at `link_base`. A link to a chapter's markdown leads to its
+/// page, like mdbook's own links. Links with a scheme, absolute ones and those
+/// within the image are left alone.
+fn book_link(link_base: &Path, href: &str) -> Option<String> {
+    let has_scheme = href
+        .split('/')
+        .next()
+        .is_some_and(|first| first.contains(':'));
+    if href.is_empty() || href.starts_with('#') || href.starts_with('/') || has_scheme {
+        return None;
+    }
+    let (path, suffix) = href.split_at(href.find(['#', '?']).unwrap_or(href.len()));
+    let path = match path.strip_suffix(".md") {
+        Some(chapter) => format!("{}.html", chapter),
+        None => path.to_string(),
+    };
+    let mut segments: Vec<String> = link_base
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." if segments.last().is_some_and(|last| last != "..") => {
+                segments.pop();
+            }
+            segment => segments.push(segment.to_string()),
+        }
+    }
+    Some(format!("{}{}", segments.join("/"), suffix))
+}
+
+/// Removes what PlantUML left of a diagram it failed on, which the next build
+/// would otherwise take as up to date
+fn remove_images(filenames: &[&Path]) -> Result<(), Error> {
+    for filename in filenames {
+        if filename.exists() {
+            remove_file(filename)?;
+        }
+    }
+    Ok(())
+}
+
+/// How many of the first `sources` fit on one command line, at least one
+fn fitting_on_command_line(sources: &[OsString]) -> usize {
+    let mut length = 0;
+    let count = sources
+        .iter()
+        .take_while(|source| {
+            length += source.len() + 1;
+            length <= MAX_BATCH_SOURCES_LENGTH
+        })
+        .count();
+    count.max(1)
+}
+
+fn image_filename(diagram: &Diagram<'_>) -> PathBuf {
+    let mut image_filename = diagram.output_directory.join(diagram.name);
+    image_filename.set_extension(diagram.format.extension());
+    image_filename
+}
+
+/// Whether the diagram's image can be used as it is. Ones named after their
+/// hash are up to date as long as they exist, named ones when the `.puml`
+/// they were rendered from, kept next to them, is the diagram's source and
+/// the hash kept with it is the diagram's, so none of its includes changed.
+fn is_cached(diagram: &Diagram<'_>, image_filename: &Path, input_charset: InputCharset) -> bool {
+    if diagram.no_cache || !image_filename.exists() {
+        return false;
+    }
+    !diagram.is_named()
+        || read(image_filename.with_extension("puml"))
+            .map_err(|err| err.to_string())
+            .and_then(|source| input_charset.decode(source))
+            .is_ok_and(|source| source == diagram.plantuml_code)
+            && read_to_string(image_filename.with_extension(HASH_EXTENSION))
+                .is_ok_and(|hash| hash.trim() == diagram.plantuml_hash_sum)
+}
+
+/// Keeps the hash of a named image's diagram next to it, for `is_cached`
+fn write_hash(diagram: &Diagram<'_>, image_filename: &Path) -> Result<(), Error> {
+    write(
+        image_filename.with_extension(HASH_EXTENSION),
+        diagram.plantuml_hash_sum,
+    )?;
+    Ok(())
+}
+
+fn manifest_key(image_filename: &Path) -> String {
+    image_filename
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The width and height are the first fields of the IHDR chunk, which directly
+/// follows the 8 byte signature
+fn png_dimensions(png_filename: &Path) -> Option<(f64, f64)> {
+    let mut header = [0; 24];
+    File::open(png_filename)
+        .ok()?
+        .read_exact(&mut header)
+        .ok()?;
+    if &header[12..16] != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes([header[16], header[17], header[18], header[19]]);
+    let height = u32::from_be_bytes([header[20], header[21], header[22], header[23]]);
+    Some((f64::from(width), f64::from(height)))
+}
+
+/// The pages after the first PlantUML wrote for a diagram split with
+/// `newpage`, which are numbered `<hash>_001.svg`, `<hash>_002.svg` and so on
+fn extra_pages(rendered_filename: &Path) -> Vec<PathBuf> {
+    let (stem, extension) = match (rendered_filename.file_stem(), rendered_filename.extension()) {
+        (Some(stem), Some(extension)) => (stem.to_string_lossy(), extension.to_string_lossy()),
+        _ => return Vec::new(),
+    };
+    (1..)
+        .map(|page| rendered_filename.with_file_name(format!("{}_{:03}.{}", stem, page, extension)))
+        .take_while(|page| page.exists())
+        .collect()
+}
+
+/// The name of a diagram's start directive, e.g. `Named` for `@startuml Named`,
+/// which PlantUML names the image of a file after rather than the file
+fn start_name(plantuml_code: &str) -> Option<&str> {
+    let start = plantuml_code
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("@start"))?;
+    let name = start.split_once(char::is_whitespace)?.1.trim();
+    let name = name
+        .strip_prefix('"')
+        .and_then(|name| name.strip_suffix('"'))
+        .unwrap_or(name);
+    // `@startuml(id=…)` and the like don't name the file
+    if name.is_empty() || name.starts_with('(') || name.contains(['/', '\\']) {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Moves the image PlantUML named after the diagram's start directive, and its
+/// other pages, to where the preprocessor looks for it
+fn recover_start_named(plantuml_code: &str, rendered_filename: &Path) -> Result<(), Error> {
+    let (name, extension) = match (start_name(plantuml_code), rendered_filename.extension()) {
+        (Some(name), Some(extension)) => (name, extension.to_string_lossy()),
+        _ => return Ok(()),
+    };
+    let named = rendered_filename.with_file_name(format!("{}.{}", name, extension));
+    if named == rendered_filename || !named.is_file() {
+        return Ok(());
+    }
+    debug!(
+        "PlantUML named {} after the diagram, moving it to {}",
+        named.display(),
+        rendered_filename.display()
+    );
+    let pages = extra_pages(&named);
+    rename(&named, rendered_filename)?;
+    let stem = rendered_filename
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    for (number, page) in (1..).zip(pages) {
+        let numbered = format!("{}_{:03}.{}", stem, number, extension);
+        rename(&page, rendered_filename.with_file_name(numbered))?;
+    }
+    Ok(())
+}
+
+/// A command with a directory is relative to the book root, one without is
+/// looked up on the `PATH`
+fn command_path(command: &str, book_root: &Path) -> PathBuf {
+    match Path::new(command) {
+        program if program.components().count() > 1 => book_root.join(program),
+        program => program.to_path_buf(),
+    }
+}
+
+/// The first of `commands` which is found and runs `-version` successfully,
+/// then `plantuml` on the `PATH`. Commands with a directory are relative to the
+/// book root. Without any `commands` it's `plantuml`, without running it first.
+fn select_program(commands: &[String], book_root: &Path) -> PathBuf {
+    if commands.is_empty() {
+        return PathBuf::from(DEFAULT_PLANTUML);
+    }
+    let candidates = commands
+        .iter()
+        .map(String::as_str)
+        .chain(std::iter::once(DEFAULT_PLANTUML));
+    for candidate in candidates {
+        let program = match find_program(&command_path(candidate, book_root)) {
+            Some(program) => program,
+            None => {
+                debug!("PlantUML command {} wasn't found", candidate);
+                continue;
+            }
+        };
+        let responds = Command::new(&program)
+            .arg("-version")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if responds {
+            info!("Using PlantUML at {}", program.display());
+            return program;
+        }
+        debug!(
+            "PlantUML command {} didn't run `-version`",
+            program.display()
+        );
+    }
+    warn!(
+        "None of the PlantUML commands {:?} runs, trying `{}` anyway",
+        commands, DEFAULT_PLANTUML
+    );
+    PathBuf::from(DEFAULT_PLANTUML)
+}
+
+/// Looks a program up the way running it would, through `PATH` unless it's a path itself
+fn find_program(program: &Path) -> Option<PathBuf> {
+    if program.components().count() > 1 {
+        return Some(program.to_path_buf()).filter(|path| path.is_file());
+    }
+    // Windows also finds `plantuml.bat` when asked for `plantuml`
+    let extensions: Vec<String> = match env::var("PATHEXT") {
+        Ok(extensions) if cfg!(windows) => extensions.split(';').map(String::from).collect(),
+        _ => Vec::new(),
+    };
+    env::split_paths(&env::var_os("PATH")?)
+        .flat_map(|directory| {
+            let candidate = directory.join(program);
+            let mut candidates = vec![candidate.clone()];
+            for extension in &extensions {
+                let mut with_extension = candidate.clone().into_os_string();
+                with_extension.push(extension);
+                candidates.push(PathBuf::from(with_extension));
+            }
+            candidates
+        })
+        .find(|candidate| candidate.is_file())
+}
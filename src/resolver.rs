@@ -0,0 +1,90 @@
+//! The `include_resolver` command, mapping the files diagrams include, e.g.
+//! `@shared/styles.puml`, to where they are. The paths replace those of the
+//! include directives before the diagram is hashed and rendered.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+use crate::config::Config;
+use crate::include;
+
+pub struct IncludeResolver {
+    command: Vec<String>,
+    book_root: PathBuf,
+    /// What each include was resolved to during this build, `None` for those
+    /// the command left as they are
+    resolved: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl IncludeResolver {
+    pub fn from_config(config: &Config, book_root: &Path) -> Option<IncludeResolver> {
+        if config.include_resolver.is_empty() {
+            return None;
+        }
+        Some(IncludeResolver {
+            command: config.include_resolver.clone(),
+            book_root: book_root.to_path_buf(),
+            resolved: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The code with every included file the command knows replaced by its path
+    pub fn apply(&self, plantuml_code: &str) -> Result<String, String> {
+        include::replace_included_files(plantuml_code, |file| {
+            Ok(self.resolve(file)?.unwrap_or_else(|| file.to_string()))
+        })
+    }
+
+    fn resolve(&self, file: &str) -> Result<Option<String>, String> {
+        let mut resolved = self.resolved.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(path) = resolved.get(file) {
+            return Ok(path.clone());
+        }
+        let path = self.run(file)?;
+        resolved.insert(file.to_string(), path.clone());
+        Ok(path)
+    }
+
+    /// Runs the command from the book root with the include appended, taking the
+    /// first line it prints for the path. A relative one is relative to the book
+    /// root. Nothing printed leaves the include as it is.
+    fn run(&self, file: &str) -> Result<Option<String>, String> {
+        let (program, args) = match self.command.split_first() {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+        let output = Command::new(program)
+            .args(args)
+            .arg(file)
+            .current_dir(&self.book_root)
+            .output()
+            .map_err(|err| format!("Failed to run `{}`: {}", program, err))?;
+        if !output.status.success() {
+            return Err(format!(
+                "`{}` failed to resolve the include `{}` with {}: {}",
+                self.command.join(" "),
+                file,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let path = match stdout.lines().next().map(str::trim) {
+            Some(path) if !path.is_empty() => path,
+            _ => return Ok(None),
+        };
+        // PlantUML doesn't run from the book root
+        let path = self.book_root.join(path);
+        match path.canonicalize() {
+            Ok(resolved) if resolved.is_file() => Ok(Some(resolved.to_string_lossy().into_owned())),
+            _ => Err(format!(
+                "`{}` resolved the include `{}` to {}, which isn't a file",
+                self.command.join(" "),
+                file,
+                path.display()
+            )),
+        }
+    }
+}
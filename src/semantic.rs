@@ -0,0 +1,74 @@
+/// The directives of diagram types whose whitespace is part of the data
+const VERBATIM_DIAGRAMS: &[&str] = &["@startjson", "@startyaml"];
+
+/// What a diagram's hash is taken of with `semantic_cache`: the source without
+/// comments, blank lines and runs of whitespace, so diagrams which only differ
+/// in those share an image. Double quoted strings and `!` preprocessor lines
+/// are kept as they are, and JSON and YAML aren't touched at all.
+pub fn normalize(plantuml_code: &str) -> String {
+    let verbatim = plantuml_code
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("@start"))
+        .is_some_and(|start| {
+            VERBATIM_DIAGRAMS
+                .iter()
+                .any(|directive| start.starts_with(directive))
+        });
+    if verbatim {
+        return plantuml_code.to_string();
+    }
+    let mut normalized = String::with_capacity(plantuml_code.len());
+    let mut in_block_comment = false;
+    for line in plantuml_code.lines() {
+        let line = line.trim();
+        if !in_block_comment && line.starts_with('!') {
+            normalized.push_str(line);
+            normalized.push('\n');
+            continue;
+        }
+        let line = strip_comments(line, &mut in_block_comment);
+        if !line.is_empty() {
+            normalized.push_str(&line);
+            normalized.push('\n');
+        }
+    }
+    normalized
+}
+
+/// Drops `'` line comments and `/' ... '/` block comments, which may span
+/// lines, and collapses whitespace outside of strings
+fn strip_comments(line: &str, in_block_comment: &mut bool) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut in_string = false;
+    let mut characters = line.chars().peekable();
+    while let Some(character) = characters.next() {
+        if *in_block_comment {
+            if character == '\'' && characters.peek() == Some(&'/') {
+                characters.next();
+                *in_block_comment = false;
+            }
+            continue;
+        }
+        match character {
+            '"' => {
+                in_string = !in_string;
+                stripped.push(character);
+            }
+            _ if in_string => stripped.push(character),
+            '/' if characters.peek() == Some(&'\'') => {
+                characters.next();
+                *in_block_comment = true;
+            }
+            // A quote only starts a comment at the start of a line
+            '\'' if stripped.trim().is_empty() => break,
+            c if c.is_whitespace() => {
+                if !stripped.ends_with(' ') {
+                    stripped.push(' ');
+                }
+            }
+            c => stripped.push(c),
+        }
+    }
+    stripped.trim().to_string()
+}
@@ -0,0 +1,198 @@
+//! A PlantUML `-picoweb` server the preprocessor starts itself with
+//! `server_mode = "managed"`, so diagrams are rendered without starting a JVM
+//! for each of them. It's left running for the next build, which finds it
+//! through a pidfile in the book root, until `stop-server` ends it.
+
+use std::fs::{read_to_string, remove_file, write};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+/// Where the server's process id and port are kept, within the book root
+pub static PIDFILE_NAME: &str = ".plantuml-renderer-server";
+/// How often a starting server is tried, to tell whether it's ready
+static POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a server which is up to take a connection is given to do so
+static CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// PlantUML's header saying what's wrong with a diagram it drew an error image for
+static DIAGRAM_ERROR_HEADER: &str = "x-plantuml-diagram-error";
+
+/// A running server, listening on the loopback interface
+#[derive(Debug, Clone, Copy)]
+pub struct Server {
+    port: u16,
+}
+
+/// What the server answered for a diagram
+pub struct Response {
+    pub image: Vec<u8>,
+    /// What PlantUML found wrong with the diagram, the image shows it too
+    pub error: Option<String>,
+}
+
+impl Server {
+    /// The server the pidfile names if it still answers, or else one started
+    /// with `command`, which is PlantUML set up as it is to render files. A new
+    /// server is waited for up to `startup_limit`.
+    pub fn connect_or_start(
+        mut command: Command,
+        pidfile: &Path,
+        startup_limit: Duration,
+    ) -> io::Result<Server> {
+        if let Some(server) = Server::from_pidfile(pidfile) {
+            debug!("Using the PlantUML server on port {}", server.port);
+            return Ok(server);
+        }
+        // A free port, given up again right away for PlantUML to take
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?
+            .port();
+        // Its output would keep mdbook waiting on the preprocessor's
+        let mut child = command
+            .arg(format!("-picoweb:{}:{}", port, Ipv4Addr::LOCALHOST))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        write(pidfile, format!("{} {}\n", child.id(), port))?;
+        info!("Started a PlantUML server on port {}", port);
+        let server = Server { port };
+        let started = Instant::now();
+        while !server.is_answering() {
+            if let Some(status) = child.try_wait()? {
+                remove_file(pidfile)?;
+                return Err(io::Error::other(format!(
+                    "the PlantUML server exited with {}",
+                    status
+                )));
+            }
+            if started.elapsed() > startup_limit {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "the PlantUML server didn't answer on port {} within {}s",
+                        port,
+                        startup_limit.as_secs()
+                    ),
+                ));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(server)
+    }
+
+    /// The server in the pidfile, as long as something answers on its port
+    fn from_pidfile(pidfile: &Path) -> Option<Server> {
+        let contents = read_to_string(pidfile).ok()?;
+        let port = contents.split_whitespace().nth(1)?.parse().ok()?;
+        let server = Server { port };
+        if server.is_answering() {
+            Some(server)
+        } else {
+            None
+        }
+    }
+
+    fn address(self) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, self.port))
+    }
+
+    fn is_answering(self) -> bool {
+        TcpStream::connect_timeout(&self.address(), CONNECT_TIMEOUT).is_ok()
+    }
+
+    /// Posts the diagram to `/plantuml/<format>`, e.g. `svg`, waiting for the
+    /// image at most `time_limit`
+    pub fn render(
+        self,
+        format: &str,
+        plantuml_code: &str,
+        time_limit: Option<Duration>,
+    ) -> io::Result<Response> {
+        let mut stream = TcpStream::connect_timeout(&self.address(), CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(time_limit)?;
+        // HTTP/1.0, so the answer is neither chunked nor kept alive
+        write!(
+            stream,
+            "POST /plantuml/{} HTTP/1.0\r\nHost: {}\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\r\n",
+            format,
+            self.address(),
+            plantuml_code.len()
+        )?;
+        stream.write_all(plantuml_code.as_bytes())?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        parse_response(&response)
+    }
+}
+
+/// The image of an HTTP response, which PlantUML answers with an error image
+/// and a header for a broken diagram. Any other failure is an error, with the
+/// body that came with it.
+pub fn parse_response(response: &[u8]) -> io::Result<Response> {
+    let invalid = |message: String| io::Error::new(ErrorKind::InvalidData, message);
+    let head_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| invalid("the PlantUML server's answer was cut off".to_string()))?;
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    let mut body = &response[head_end + 4..];
+    let mut lines = head.lines();
+    let status_line = lines.next().unwrap_or_default();
+    let status: u16 = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid(format!("the PlantUML server answered `{}`", status_line)))?;
+    let mut error = None;
+    for line in lines {
+        let (name, value) = match line.find(':') {
+            Some(colon) => (line[..colon].trim(), line[colon + 1..].trim()),
+            None => continue,
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            if let Ok(length) = value.parse::<usize>() {
+                body = &body[..length.min(body.len())];
+            }
+        } else if name.eq_ignore_ascii_case(DIAGRAM_ERROR_HEADER) {
+            error = Some(value.to_string());
+        }
+    }
+    if !(200..300).contains(&status) && error.is_none() {
+        let body = String::from_utf8_lossy(body);
+        return Err(invalid(match body.trim() {
+            "" => format!("the PlantUML server answered `{}`", status_line),
+            body => format!("the PlantUML server answered `{}`: {}", status_line, body),
+        }));
+    }
+    Ok(Response {
+        image: body.to_vec(),
+        error,
+    })
+}
+
+/// Ends the server the pidfile names, returning whether there was one
+pub fn stop(pidfile: &Path) -> io::Result<bool> {
+    let contents = match read_to_string(pidfile) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    remove_file(pidfile)?;
+    let pid = match contents.split_whitespace().next() {
+        Some(pid) if pid.chars().all(|c| c.is_ascii_digit()) => pid,
+        _ => return Ok(false),
+    };
+    // It may be gone already, which is just as well
+    let status = Command::new("kill")
+        .arg(pid)
+        .stderr(Stdio::null())
+        .status()?;
+    Ok(status.success())
+}
@@ -0,0 +1,67 @@
+use std::env;
+use std::io;
+use std::path::Path;
+
+use filetime::{set_file_mtime, FileTime};
+use mdbook::errors::Error;
+
+use crate::config::Config;
+
+/// Metadata forced onto every file the preprocessor generates, so that the
+/// output of a build doesn't depend on when or by whom it was produced.
+#[derive(Debug, Default)]
+pub struct Stamp {
+    mtime: Option<FileTime>,
+    mode: Option<u32>,
+}
+
+impl Stamp {
+    pub fn from_config(config: &Config) -> Result<Stamp, Error> {
+        let mut mtime = config.mtime;
+        if config.source_date_epoch {
+            if let Ok(epoch) = env::var("SOURCE_DATE_EPOCH") {
+                mtime = Some(epoch.trim().parse().map_err(|_| {
+                    Error::from(format!(
+                        "SOURCE_DATE_EPOCH must be a number of seconds, not `{}`",
+                        epoch
+                    ))
+                })?);
+            }
+        }
+        let mode = match &config.file_mode {
+            Some(mode) => Some(u32::from_str_radix(mode, 8).map_err(|_| {
+                Error::from(format!("file_mode must be an octal mode, not `{}`", mode))
+            })?),
+            None => None,
+        };
+        Ok(Stamp {
+            mtime: mtime.map(|seconds| FileTime::from_unix_time(seconds, 0)),
+            mode,
+        })
+    }
+
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        if let Some(mode) = self.mode {
+            set_mode(path, mode)?;
+        }
+        if let Some(mtime) = self.mtime {
+            set_file_mtime(path, mtime)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, _mode: u32) -> io::Result<()> {
+    log::warn!(
+        "file_mode is only supported on unix, leaving {} as is",
+        path.display()
+    );
+    Ok(())
+}
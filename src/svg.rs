@@ -0,0 +1,534 @@
+//! Just enough SVG handling for post-processing what PlantUML emits. This isn't
+//! a general purpose parser: it expects well-formed, untransformed markup.
+
+use std::collections::BTreeMap;
+
+use crate::config::normalize_color;
+
+/// Stroke widths aren't part of the geometry, so the trimmed box is padded a little
+const TRIM_PADDING: f64 = 1.0;
+
+/// An element's opening tag
+struct Tag<'a> {
+    name: &'a str,
+    attributes: Vec<(&'a str, &'a str)>,
+    /// Where the tag starts and ends (exclusive) in the document
+    span: (usize, usize),
+}
+
+impl<'a> Tag<'a> {
+    fn attribute(&self, name: &str) -> Option<&'a str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| *value)
+    }
+
+    fn number(&self, name: &str) -> Option<f64> {
+        self.attribute(name).and_then(parse_length)
+    }
+}
+
+/// Every opening (or self-closing) tag in the document, skipping comments,
+/// processing instructions, doctypes and closing tags
+fn tags(svg: &str) -> Vec<Tag<'_>> {
+    let mut tags = Vec::new();
+    let mut position = 0;
+    while let Some(offset) = svg[position..].find('<') {
+        let start = position + offset;
+        let rest = &svg[start..];
+        if rest.starts_with("<!--") {
+            position = rest.find("-->").map_or(svg.len(), |end| start + end + 3);
+            continue;
+        }
+        if rest.starts_with("<![CDATA[") {
+            position = rest.find("]]>").map_or(svg.len(), |end| start + end + 3);
+            continue;
+        }
+        let end = match find_tag_end(rest) {
+            Some(end) => start + end + 1,
+            None => break,
+        };
+        position = end;
+        if rest.starts_with("</") || rest.starts_with("<?") || rest.starts_with("<!") {
+            continue;
+        }
+        let inner = svg[start + 1..end - 1].trim_end_matches('/');
+        let name_end = inner
+            .find(|c: char| c.is_whitespace())
+            .unwrap_or(inner.len());
+        tags.push(Tag {
+            name: &inner[..name_end],
+            attributes: parse_attributes(&inner[name_end..]),
+            span: (start, end),
+        });
+    }
+    tags
+}
+
+/// The closing `>` of a tag, ignoring any inside quoted attribute values
+fn find_tag_end(tag: &str) -> Option<usize> {
+    let mut quote = None;
+    for (index, character) in tag.char_indices() {
+        match (quote, character) {
+            (None, '"') | (None, '\'') => quote = Some(character),
+            (Some(open), c) if c == open => quote = None,
+            (None, '>') => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_attributes(attributes: &str) -> Vec<(&str, &str)> {
+    let mut parsed = Vec::new();
+    let mut rest = attributes.trim_start();
+    while let Some(equals) = rest.find('=') {
+        let name = rest[..equals].trim();
+        let value = rest[equals + 1..].trim_start();
+        let quote = match value.chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => quote,
+            _ => break,
+        };
+        let value_end = match value[1..].find(quote) {
+            Some(end) => end + 1,
+            None => break,
+        };
+        parsed.push((name, &value[1..value_end]));
+        rest = value[value_end + 1..].trim_start();
+    }
+    parsed
+}
+
+/// Lengths like `12`, `12.5px` or `-3e2`. Percentages and other units aren't meaningful here.
+fn parse_length(value: &str) -> Option<f64> {
+    value.trim().trim_end_matches("px").parse().ok()
+}
+
+/// Numbers in `points` lists and path data, which may be separated by
+/// whitespace, commas, or nothing at all before a sign
+fn parse_numbers(data: &str) -> Vec<f64> {
+    let mut numbers = Vec::new();
+    let mut current = String::new();
+    let flush = |current: &mut String, numbers: &mut Vec<f64>| {
+        if let Ok(number) = current.parse() {
+            numbers.push(number);
+        }
+        current.clear();
+    };
+    let mut previous = ' ';
+    for character in data.chars() {
+        match character {
+            '-' | '+' if previous != 'e' && previous != 'E' => {
+                flush(&mut current, &mut numbers);
+                current.push(character);
+            }
+            '.' if current.contains('.') && !current.contains(['e', 'E']) => {
+                flush(&mut current, &mut numbers);
+                current.push(character);
+            }
+            c if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || c == '-' || c == '+' => {
+                current.push(c)
+            }
+            _ => flush(&mut current, &mut numbers),
+        }
+        previous = character;
+    }
+    flush(&mut current, &mut numbers);
+    numbers
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BoundingBox {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+}
+
+impl BoundingBox {
+    fn include(bounds: &mut Option<BoundingBox>, x: f64, y: f64) {
+        let bounding_box = bounds.get_or_insert(BoundingBox {
+            min_x: x,
+            min_y: y,
+            max_x: x,
+            max_y: y,
+        });
+        bounding_box.min_x = bounding_box.min_x.min(x);
+        bounding_box.min_y = bounding_box.min_y.min(y);
+        bounding_box.max_x = bounding_box.max_x.max(x);
+        bounding_box.max_y = bounding_box.max_y.max(y);
+    }
+
+    fn width(&self) -> f64 {
+        self.max_x - self.min_x
+    }
+
+    fn height(&self) -> f64 {
+        self.max_y - self.min_y
+    }
+}
+
+/// The box around everything drawn. Coordinates aren't clamped to zero, so
+/// content PlantUML placed at negative coordinates stays inside it.
+fn content_bounds(svg: &str) -> Option<BoundingBox> {
+    let mut bounds = None;
+    for tag in tags(svg) {
+        let number = |name: &str| tag.number(name).unwrap_or(0.0);
+        match tag.name {
+            "rect" | "image" | "foreignObject" => {
+                let (x, y) = (number("x"), number("y"));
+                BoundingBox::include(&mut bounds, x, y);
+                BoundingBox::include(&mut bounds, x + number("width"), y + number("height"));
+            }
+            "ellipse" | "circle" => {
+                let (cx, cy) = (number("cx"), number("cy"));
+                let rx = tag.number("rx").or_else(|| tag.number("r")).unwrap_or(0.0);
+                let ry = tag.number("ry").or_else(|| tag.number("r")).unwrap_or(0.0);
+                BoundingBox::include(&mut bounds, cx - rx, cy - ry);
+                BoundingBox::include(&mut bounds, cx + rx, cy + ry);
+            }
+            "line" => {
+                BoundingBox::include(&mut bounds, number("x1"), number("y1"));
+                BoundingBox::include(&mut bounds, number("x2"), number("y2"));
+            }
+            "polygon" | "polyline" => {
+                let points = parse_numbers(tag.attribute("points").unwrap_or(""));
+                for point in points.chunks(2).filter(|point| point.len() == 2) {
+                    BoundingBox::include(&mut bounds, point[0], point[1]);
+                }
+            }
+            "path" => {
+                for (x, y) in path_points(tag.attribute("d").unwrap_or("")) {
+                    BoundingBox::include(&mut bounds, x, y);
+                }
+            }
+            "text" => {
+                // Text is anchored at its baseline, PlantUML tells us how wide it is
+                let (x, y) = (number("x"), number("y"));
+                let font_size = tag.number("font-size").unwrap_or(14.0);
+                let width = tag.number("textLength").unwrap_or(0.0);
+                BoundingBox::include(&mut bounds, x, y - font_size);
+                BoundingBox::include(&mut bounds, x + width, y + font_size * 0.3);
+            }
+            _ => {}
+        }
+    }
+    bounds
+}
+
+/// Every point a path passes through, including curve control points. The
+/// control points can lie outside the curve, which only ever makes the box larger.
+fn path_points(data: &str) -> Vec<(f64, f64)> {
+    let mut points = Vec::new();
+    let (mut x, mut y) = (0.0, 0.0);
+    let (mut start_x, mut start_y) = (0.0, 0.0);
+    let mut segments = Vec::new();
+    let mut command = None;
+    let mut parameters = String::new();
+    for character in data.chars().chain(std::iter::once('Z')) {
+        if character.is_ascii_alphabetic() && character != 'e' && character != 'E' {
+            if let Some(command) = command {
+                segments.push((command, parse_numbers(&parameters)));
+            }
+            command = Some(character);
+            parameters.clear();
+        } else {
+            parameters.push(character);
+        }
+    }
+    for (command, numbers) in segments {
+        let relative = command.is_ascii_lowercase();
+        let group = match command.to_ascii_uppercase() {
+            'M' | 'L' | 'T' => 2,
+            'H' | 'V' => 1,
+            'C' => 6,
+            'S' | 'Q' => 4,
+            'A' => 7,
+            _ => {
+                x = start_x;
+                y = start_y;
+                continue;
+            }
+        };
+        for (index, parameters) in numbers.chunks(group).enumerate() {
+            if parameters.len() < group {
+                break;
+            }
+            let (origin_x, origin_y) = if relative { (x, y) } else { (0.0, 0.0) };
+            match command.to_ascii_uppercase() {
+                'H' => x = origin_x + parameters[0],
+                'V' => y = origin_y + parameters[0],
+                'A' => {
+                    x = origin_x + parameters[5];
+                    y = origin_y + parameters[6];
+                }
+                _ => {
+                    for pair in parameters.chunks(2) {
+                        points.push((origin_x + pair[0], origin_y + pair[1]));
+                    }
+                    let last = &parameters[group - 2..];
+                    x = origin_x + last[0];
+                    y = origin_y + last[1];
+                }
+            }
+            points.push((x, y));
+            if command.eq_ignore_ascii_case(&'M') && index == 0 {
+                start_x = x;
+                start_y = y;
+            }
+        }
+    }
+    points
+}
+
+/// Checks that an SVG is well-formed XML with an `svg` root, which the rest of
+/// this module relies on, returning what's wrong with it otherwise
+pub fn check_well_formed(svg: &str) -> Result<(), String> {
+    let document = roxmltree::Document::parse(svg).map_err(|err| err.to_string())?;
+    let root = document.root_element().tag_name().name();
+    if root != "svg" {
+        return Err(format!("the root element is `{}`, not `svg`", root));
+    }
+    Ok(())
+}
+
+/// The intrinsic size of the document, from the root's `width`/`height` or
+/// failing those its `viewBox`
+pub fn dimensions(svg: &str) -> Option<(f64, f64)> {
+    let root = tags(svg).into_iter().find(|tag| tag.name == "svg")?;
+    if let (Some(width), Some(height)) = (root.number("width"), root.number("height")) {
+        return Some((width, height));
+    }
+    match parse_numbers(root.attribute("viewBox")?).as_slice() {
+        [_, _, width, height] => Some((*width, *height)),
+        _ => None,
+    }
+}
+
+/// Appends `fallback` to every `font-family`, so text still renders in a
+/// similar font where the one PlantUML picked isn't installed. The fonts
+/// already given keep precedence.
+pub fn add_font_fallback(svg: &str, fallback: &str) -> String {
+    let mut rewritten = String::with_capacity(svg.len());
+    let mut copied = 0;
+    for tag in tags(svg) {
+        let family = match tag.attribute("font-family") {
+            Some(family) if !family.contains(fallback) => family,
+            _ => continue,
+        };
+        // Attribute values are slices of the document, which gives their position
+        let start = family.as_ptr() as usize - svg.as_ptr() as usize;
+        let end = start + family.len();
+        rewritten.push_str(&svg[copied..end]);
+        rewritten.push_str(", ");
+        rewritten.push_str(fallback);
+        copied = end;
+    }
+    rewritten.push_str(&svg[copied..]);
+    rewritten
+}
+
+/// Replaces the `href` and `xlink:href` of every link with what `rewrite`
+/// makes of it, leaving those it returns `None` for as they are
+pub fn rewrite_links(svg: &str, rewrite: impl Fn(&str) -> Option<String>) -> String {
+    let mut rewritten = String::with_capacity(svg.len());
+    let mut copied = 0;
+    for tag in tags(svg).iter().filter(|tag| tag.name == "a") {
+        for (name, href) in &tag.attributes {
+            if *name != "href" && *name != "xlink:href" {
+                continue;
+            }
+            let replacement = match rewrite(href) {
+                Some(replacement) => replacement,
+                None => continue,
+            };
+            let start = href.as_ptr() as usize - svg.as_ptr() as usize;
+            rewritten.push_str(&svg[copied..start]);
+            rewritten.push_str(&replacement);
+            copied = start + href.len();
+        }
+    }
+    rewritten.push_str(&svg[copied..]);
+    rewritten
+}
+
+/// Presentation attributes and style properties which hold a color
+const COLOR_PROPERTIES: &[&str] = &[
+    "fill",
+    "stroke",
+    "stop-color",
+    "flood-color",
+    "lighting-color",
+    "background",
+    "background-color",
+];
+
+/// Replaces every color `variables` has a CSS custom property for, keyed by
+/// upper case `#RRGGBB`, with `var(--property, #color)`, in attributes and
+/// inline styles alike. The color stays the fallback, so the diagram looks the
+/// same where the page doesn't set the property.
+pub fn theme_colors(svg: &str, variables: &BTreeMap<String, String>) -> String {
+    let themed = |color: &str| {
+        let variable = variables.get(&normalize_color(color)?)?;
+        Some(format!("var({}, {})", variable, color.trim()))
+    };
+    let mut rewritten = String::with_capacity(svg.len());
+    let mut copied = 0;
+    for tag in tags(svg) {
+        for (name, value) in &tag.attributes {
+            let replacement = if *name == "style" {
+                let mut changed = false;
+                let declarations: Vec<String> = value
+                    .split(';')
+                    .map(|declaration| match declaration.split_once(':') {
+                        Some((property, color)) if COLOR_PROPERTIES.contains(&property.trim()) => {
+                            match themed(color) {
+                                Some(color) => {
+                                    changed = true;
+                                    format!("{}:{}", property, color)
+                                }
+                                None => declaration.to_string(),
+                            }
+                        }
+                        _ => declaration.to_string(),
+                    })
+                    .collect();
+                if !changed {
+                    continue;
+                }
+                declarations.join(";")
+            } else if COLOR_PROPERTIES.contains(name) {
+                match themed(value) {
+                    Some(color) => color,
+                    None => continue,
+                }
+            } else {
+                continue;
+            };
+            let start = value.as_ptr() as usize - svg.as_ptr() as usize;
+            rewritten.push_str(&svg[copied..start]);
+            rewritten.push_str(&replacement);
+            copied = start + value.len();
+        }
+    }
+    rewritten.push_str(&svg[copied..]);
+    rewritten
+}
+
+/// Shrinks the canvas to the drawn content by rewriting the root element's
+/// `viewBox`, `width`, `height` and the matching inline style. Returns `None`
+/// when there's no root element or nothing drawn to bound.
+pub fn trim(svg: &str) -> Option<String> {
+    let bounds = content_bounds(svg)?;
+    let root = tags(svg).into_iter().find(|tag| tag.name == "svg")?;
+    let (x, y) = (bounds.min_x - TRIM_PADDING, bounds.min_y - TRIM_PADDING);
+    let width = (bounds.width() + 2.0 * TRIM_PADDING).ceil();
+    let height = (bounds.height() + 2.0 * TRIM_PADDING).ceil();
+    let view_box = format!("{} {} {} {}", x, y, width, height);
+
+    let mut rewritten = String::from("<svg");
+    let mut has_view_box = false;
+    for (name, value) in &root.attributes {
+        let value = match *name {
+            "viewBox" => {
+                has_view_box = true;
+                view_box.clone()
+            }
+            "width" => format!("{}px", width),
+            "height" => format!("{}px", height),
+            "style" => {
+                value
+                    .split(';')
+                    .filter(|declaration| !declaration.trim().is_empty())
+                    .map(
+                        |declaration| match declaration.split(':').next().map(str::trim) {
+                            Some("width") => format!("width:{}px", width),
+                            Some("height") => format!("height:{}px", height),
+                            _ => declaration.to_string(),
+                        },
+                    )
+                    .collect::<Vec<_>>()
+                    .join(";")
+                    + ";"
+            }
+            _ => value.to_string(),
+        };
+        rewritten.push_str(&format!(" {}=\"{}\"", name, value));
+    }
+    if !has_view_box {
+        rewritten.push_str(&format!(" viewBox=\"{}\"", view_box));
+    }
+    let self_closing = svg[..root.span.1].ends_with("/>");
+    rewritten.push_str(if self_closing { "/>" } else { ">" });
+
+    Some(format!(
+        "{}{}{}",
+        &svg[..root.span.0],
+        rewritten,
+        &svg[root.span.1..]
+    ))
+}
+
+/// Puts `comment` right before the root element, replacing any comment already
+/// there, which is somewhere PlantUML never writes one. Returns `None` when the
+/// document already has it, or has no root element.
+pub fn set_comment(svg: &str, comment: &str) -> Option<String> {
+    let root = svg.find("<svg")?;
+    let (prolog, document) = svg.split_at(root);
+    let mut stripped = String::with_capacity(prolog.len());
+    let mut remaining = prolog;
+    while let Some(start) = remaining.find("<!--") {
+        stripped.push_str(&remaining[..start]);
+        remaining = match remaining[start..].find("-->") {
+            Some(end) => &remaining[start + end + "-->".len()..],
+            None => "",
+        };
+    }
+    stripped.push_str(remaining);
+    // `--` can't be part of a comment
+    let comment = format!("<!-- {} -->", comment.replace("--", "- -"));
+    let stripped = stripped.trim_end();
+    let commented = if stripped.is_empty() {
+        format!("{}\n{}", comment, document)
+    } else {
+        format!("{}\n{}\n{}", stripped, comment, document)
+    };
+    if commented == svg {
+        None
+    } else {
+        Some(commented)
+    }
+}
+
+/// The document as markup to put straight into a page: its root element on,
+/// without the XML declaration, doctype and comments before it, with `label`
+/// as its accessible name. Blank lines are dropped, they'd end the HTML block
+/// markdown keeps it in. Returns `None` when there's no root element.
+pub fn inline(svg: &str, label: &str) -> Option<String> {
+    let root = tags(svg).into_iter().find(|tag| tag.name == "svg")?;
+    let (start, end) = root.span;
+    // Before the `>`, or the `/>` of an empty document
+    let mut insert_at = end - 1;
+    if svg[..insert_at].ends_with('/') {
+        insert_at -= 1;
+    }
+    let mut attributes = String::new();
+    if root.attribute("role").is_none() {
+        attributes.push_str(" role=\"img\"");
+    }
+    if !label.is_empty() && root.attribute("aria-label").is_none() {
+        attributes.push_str(&format!(" aria-label=\"{}\"", crate::html::escape(label)));
+    }
+    let document = format!(
+        "{}{}{}",
+        &svg[start..insert_at],
+        attributes,
+        &svg[insert_at..]
+    );
+    let lines: Vec<&str> = document
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    Some(lines.join("\n"))
+}
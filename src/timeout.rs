@@ -0,0 +1,80 @@
+//! Runs PlantUML like `Command::output`, but stops it once it's taken too long
+
+use std::io::{self, Read, Write};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a running process is looked at, to tell whether it's done
+static POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The output of `command`, which is fed `input` on stdin, or `None` when it
+/// was stopped for running longer than `time_limit`. Without a limit it's
+/// waited for however long it takes.
+pub fn output(
+    command: &mut Command,
+    input: Option<&[u8]>,
+    time_limit: Option<Duration>,
+) -> io::Result<Option<Output>> {
+    let mut child = command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Everything goes through threads, otherwise a large image filling up the
+    // stdout pipe could block PlantUML before it has read all its input
+    let writer = match (child.stdin.take(), input) {
+        (Some(mut stdin), Some(input)) => {
+            let input = input.to_vec();
+            Some(thread::spawn(move || stdin.write_all(&input)))
+        }
+        _ => None,
+    };
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    let status = match time_limit {
+        Some(time_limit) => {
+            let deadline = Instant::now() + time_limit;
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break status;
+                }
+                if Instant::now() >= deadline {
+                    // The readers are left behind, whatever PlantUML started
+                    // itself may keep the pipes open for a while
+                    child.kill()?;
+                    child.wait()?;
+                    return Ok(None);
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+        None => child.wait()?,
+    };
+    if let Some(writer) = writer {
+        writer
+            .join()
+            .expect("Writing to PlantUML's stdin panicked")?;
+    }
+    Ok(Some(Output {
+        status,
+        stdout: stdout.join().expect("Reading PlantUML's stdout panicked")?,
+        stderr: stderr.join().expect("Reading PlantUML's stderr panicked")?,
+    }))
+}
+
+fn read_in_background(
+    stream: Option<impl Read + Send + 'static>,
+) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut stream) = stream {
+            stream.read_to_end(&mut bytes)?;
+        }
+        Ok(bytes)
+    })
+}
@@ -0,0 +1,177 @@
+//! Reads a diagram's `title` as plain text, for alt text and the gallery
+
+use std::path::Path;
+
+/// Creole markup around text, dropped from titles
+const CREOLE_MARKERS: &[&str] = &["**", "__", "~~", "\"\""];
+/// What a comment giving a block its caption starts with, after the `'#`
+const CAPTION_KEY: &str = "title:";
+
+/// The plain text of the diagram's first `title`. That's the rest of its line,
+/// without the quotes around it, or the lines up to `end title` after a `title`
+/// on its own. Markup is dropped and `<U+XXXX>` and `&#NNN;` characters are
+/// decoded, whitespace is collapsed into single spaces.
+pub fn extract(plantuml_code: &str) -> Option<String> {
+    let mut lines = plantuml_code.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        let rest = match keyword(line, "title") {
+            Some(rest) => rest,
+            None => continue,
+        };
+        let title = if rest.is_empty() {
+            lines
+                .by_ref()
+                .take_while(|line| !is_end_title(line))
+                .collect::<Vec<_>>()
+                .join("\n")
+        } else {
+            unquote(rest).to_string()
+        };
+        let title = plain_text(&title);
+        if !title.is_empty() {
+            return Some(title);
+        }
+    }
+    None
+}
+
+/// The caption a block's first line gives it as a comment, like
+/// `'# title: Sequence of login flow`, with the block without that line, which
+/// PlantUML never sees. The block is left as it is without one.
+pub fn split_caption(body: &str) -> (Option<String>, String) {
+    let first = match body
+        .split_inclusive('\n')
+        .find(|line| !line.trim().is_empty())
+    {
+        Some(first) => first,
+        None => return (None, body.to_string()),
+    };
+    let caption = first
+        .trim()
+        .strip_prefix("'#")
+        .map(str::trim_start)
+        .filter(|comment| {
+            comment
+                .get(..CAPTION_KEY.len())
+                .is_some_and(|key| key.eq_ignore_ascii_case(CAPTION_KEY))
+        })
+        .map(|comment| plain_text(&comment[CAPTION_KEY.len()..]))
+        .filter(|caption| !caption.is_empty());
+    match caption {
+        Some(caption) => {
+            // The line is a slice of the body, which gives its position
+            let start = first.as_ptr() as usize - body.as_ptr() as usize;
+            let rest = format!("{}{}", &body[..start], &body[start + first.len()..]);
+            (Some(caption), rest)
+        }
+        None => (None, body.to_string()),
+    }
+}
+
+/// A title made of the name of the file a diagram is read from, its words
+/// capitalized, e.g. `Login Sequence` for `diagrams/login-sequence.puml`
+pub fn from_filename(path: &Path) -> Option<String> {
+    let stem = path.file_stem()?.to_string_lossy();
+    let words = stem
+        .split(|c: char| c == '-' || c == '_' || c == '.' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut characters = word.chars();
+            match characters.next() {
+                Some(first) => first.to_uppercase().chain(characters).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>();
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" "))
+    }
+}
+
+/// What follows `word` when the line starts with it, in any case, as a word of its own
+fn keyword<'a>(line: &'a str, word: &str) -> Option<&'a str> {
+    let start = line.get(..word.len())?;
+    let rest = &line[word.len()..];
+    let separated = rest.chars().next().is_none_or(char::is_whitespace);
+    if start.eq_ignore_ascii_case(word) && separated {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+fn is_end_title(line: &str) -> bool {
+    let words = line.split_whitespace().collect::<Vec<_>>().join(" ");
+    words.eq_ignore_ascii_case("end title") || words.eq_ignore_ascii_case("endtitle")
+}
+
+fn unquote(text: &str) -> &str {
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        &text[1..text.len() - 1]
+    } else {
+        text
+    }
+}
+
+/// Decodes special characters and drops markup, then collapses whitespace
+fn plain_text(title: &str) -> String {
+    let mut text = title.replace("\\n", " ");
+    for marker in CREOLE_MARKERS {
+        text = text.replace(marker, "");
+    }
+    let mut plain = String::with_capacity(text.len());
+    let mut rest = text.as_str();
+    while let Some(character) = rest.chars().next() {
+        if let Some((decoded, length)) = special_character(rest) {
+            plain.push(decoded);
+            rest = &rest[length..];
+            continue;
+        }
+        if let Some(length) = tag_length(rest) {
+            rest = &rest[length..];
+            continue;
+        }
+        // Italics, unless it's the `//` of a URL
+        if rest.starts_with("//") && !plain.ends_with(':') {
+            rest = &rest[2..];
+            continue;
+        }
+        plain.push(character);
+        rest = &rest[character.len_utf8()..];
+    }
+    plain.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// A `<U+XXXX>` or `&#NNN;` at the start of `text`, with its length in bytes
+fn special_character(text: &str) -> Option<(char, usize)> {
+    let (digits, radix, end) = if let Some(digits) = text
+        .strip_prefix("<U+")
+        .or_else(|| text.strip_prefix("<u+"))
+    {
+        (digits, 16, '>')
+    } else if let Some(digits) = text.strip_prefix("&#") {
+        (digits, 10, ';')
+    } else {
+        return None;
+    };
+    let length = digits.find(end)?;
+    let code = u32::from_str_radix(&digits[..length], radix).ok()?;
+    let character = std::char::from_u32(code)?;
+    Some((character, text.len() - digits.len() + length + 1))
+}
+
+/// The length of an HTML-like tag such as `<b>`, `</i>` or `<color:red>` at
+/// the start of `text`. A `<` which doesn't start one is kept.
+fn tag_length(text: &str) -> Option<usize> {
+    let mut characters = text.chars();
+    if characters.next() != Some('<') {
+        return None;
+    }
+    match characters.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '/' => {}
+        _ => return None,
+    }
+    text.find('>').map(|end| end + 1)
+}
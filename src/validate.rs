@@ -0,0 +1,51 @@
+use pulldown_cmark::{CodeBlockKind, Event, Tag};
+
+use crate::config::Config;
+use crate::fence::FenceInfo;
+use crate::markdown;
+use crate::region::{self, Marker};
+
+/// A diagram's fence and body, with the line of the chapter the fence opens on
+#[derive(Debug)]
+pub struct Diagram {
+    pub line: usize,
+    pub fence: FenceInfo,
+    pub body: String,
+}
+
+/// Every diagram of a chapter the preprocessor would render, in order, along
+/// with the fragments they can `use`. Unlike rendering this keeps track of
+/// offsets, so each can be pointed at.
+pub fn diagrams(content: &str, config: &Config) -> Result<Vec<Diagram>, String> {
+    // Without any markers the whole chapter is rendered
+    let mut in_region = !region::has_regions(content)?;
+    let mut diagrams = Vec::new();
+    let mut current: Option<(usize, FenceInfo, String)> = None;
+    for (event, range) in markdown::parse(content).into_offset_iter() {
+        if let Some(marker) = region::marker(&event) {
+            in_region = marker == Marker::Begin;
+            continue;
+        }
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) if in_region => {
+                let fence = FenceInfo::parse(&info, &config.language);
+                if fence.is_renderable(config.trigger_style()) || fence.is_definition() {
+                    let line = content[..range.start].matches('\n').count() + 1;
+                    current = Some((line, fence, String::new()));
+                }
+            }
+            Event::Text(text) => {
+                if let Some((_, _, body)) = &mut current {
+                    body.push_str(&text);
+                }
+            }
+            Event::End(Tag::CodeBlock(_)) => {
+                if let Some((line, fence, body)) = current.take() {
+                    diagrams.push(Diagram { line, fence, body });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(diagrams)
+}
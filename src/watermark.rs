@@ -0,0 +1,54 @@
+use std::env;
+
+use crate::config::Config;
+
+/// Text stamped onto every diagram as a PlantUML `footer`, for telling apart
+/// the assets of preview builds
+pub struct Watermark {
+    template: String,
+    text: String,
+    in_hash: bool,
+}
+
+impl Watermark {
+    /// `{build_id}` in the configured text is replaced with the `BUILD_ID` environment variable
+    pub fn from_config(config: &Config) -> Option<Watermark> {
+        config.watermark.as_ref().map(|template| Watermark {
+            template: template.clone(),
+            text: template.replace("{build_id}", &env::var("BUILD_ID").unwrap_or_default()),
+            in_hash: config.watermark_in_hash,
+        })
+    }
+
+    /// What the watermark contributes to a diagram's hash. Unless asked otherwise
+    /// that's only the template, so a new build id alone doesn't re-render everything.
+    pub fn hash_input(&self) -> &str {
+        if self.in_hash {
+            &self.text
+        } else {
+            &self.template
+        }
+    }
+
+    /// Adds the footer right before every `@end...` directive, or at the very end
+    /// when there aren't any
+    pub fn apply(&self, plantuml_code: &str) -> String {
+        let footer = format!("footer {}\n", self.text);
+        let mut watermarked = String::with_capacity(plantuml_code.len() + footer.len());
+        let mut applied = false;
+        for line in plantuml_code.split_inclusive('\n') {
+            if line.trim_start().starts_with("@end") {
+                watermarked.push_str(&footer);
+                applied = true;
+            }
+            watermarked.push_str(line);
+        }
+        if !applied {
+            if !watermarked.is_empty() && !watermarked.ends_with('\n') {
+                watermarked.push('\n');
+            }
+            watermarked.push_str(&footer);
+        }
+        watermarked
+    }
+}
@@ -0,0 +1,249 @@
+//! Reads configurations the way the preprocessor does, without running it
+
+use mdbook_plantuml_renderer::config::{AltFallback, Config, InputCharset, OutputFormat, UrlStyle};
+
+fn read(table: &str, renderer: &str) -> Result<Config, mdbook::errors::Error> {
+    let table = toml::from_str(table).expect("The table should be valid TOML");
+    Config::from_table(table, "plantuml-renderer", renderer)
+}
+
+#[test]
+fn keeps_the_defaults_of_options_which_are_not_given() {
+    let config = read("strict = true", "html").unwrap();
+    assert!(config.strict);
+    assert_eq!(config.output_format, OutputFormat::Svg);
+    assert_eq!(config.url_style, UrlStyle::Absolute);
+    assert_eq!(config.anchor_prefix, Config::default().anchor_prefix);
+}
+
+#[test]
+fn resolves_the_auto_format_for_the_renderer() {
+    let table = "output_format = \"auto\"\nauto_formats = { latex = \"svg\" }";
+    assert_eq!(
+        read(table, "html").unwrap().output_format,
+        OutputFormat::Svg
+    );
+    assert_eq!(
+        read(table, "epub").unwrap().output_format,
+        OutputFormat::Png
+    );
+    assert_eq!(
+        read(table, "latex").unwrap().output_format,
+        OutputFormat::Svg
+    );
+}
+
+#[test]
+fn picks_the_format_listed_for_the_renderer() {
+    let table =
+        "output_format = \"txt\"\n[formats]\nhtml = \"svg\"\nepub = \"png\"\nlatex = \"auto\"";
+    assert_eq!(
+        read(table, "html").unwrap().output_format,
+        OutputFormat::Svg
+    );
+    assert_eq!(
+        read(table, "epub").unwrap().output_format,
+        OutputFormat::Png
+    );
+    assert_eq!(
+        read(table, "latex").unwrap().output_format,
+        OutputFormat::Png
+    );
+    // Renderers it doesn't list get `output_format`
+    assert_eq!(
+        read(table, "markdown").unwrap().output_format,
+        OutputFormat::Txt
+    );
+    let err = read("[formats]\nlatex = \"pdf\"", "latex").unwrap_err();
+    assert!(err.to_string().contains("pdf"), "{}", err);
+}
+
+#[test]
+fn bundles_what_rendering_the_same_everywhere_takes() {
+    let config = read(
+        "deterministic = true\nenv = { LANG = \"de_DE.UTF-8\" }",
+        "html",
+    )
+    .unwrap();
+    assert!(config.stable_layout);
+    assert_eq!(config.charset.as_deref(), Some("UTF-8"));
+    assert_eq!(config.env["LC_ALL"], "C");
+    // What's set explicitly is kept
+    assert_eq!(config.env["LANG"], "de_DE.UTF-8");
+    let config = read("deterministic = true\ncharset = \"ISO-8859-1\"", "html").unwrap();
+    assert_eq!(config.charset.as_deref(), Some("ISO-8859-1"));
+    assert!(read("", "html").unwrap().env.is_empty());
+}
+
+#[test]
+fn reads_sources_in_the_input_charset() {
+    let config = read("input_charset = \"ISO-8859-1\"", "html").unwrap();
+    assert_eq!(config.input_charset, InputCharset::Latin1);
+    assert_eq!(config.plantuml_charset(), Some("ISO-8859-1"));
+    assert_eq!(
+        InputCharset::Latin1.decode(b"Caf\xe9".to_vec()).as_deref(),
+        Ok("Café")
+    );
+    assert_eq!(
+        InputCharset::Latin1.encode("Café").as_deref(),
+        Ok(&b"Caf\xe9"[..])
+    );
+    assert!(InputCharset::Latin1.encode("Καφές").is_err());
+    // PlantUML's charset is its own
+    let config = read("input_charset = \"latin-1\"\ncharset = \"UTF-8\"", "html").unwrap();
+    assert_eq!(config.plantuml_charset(), Some("UTF-8"));
+    let config = read(
+        "deterministic = true\ninput_charset = \"ISO-8859-1\"",
+        "html",
+    )
+    .unwrap();
+    assert_eq!(config.charset.as_deref(), Some("ISO-8859-1"));
+    assert_eq!(read("", "html").unwrap().plantuml_charset(), None);
+    assert!(read("input_charset = \"Shift_JIS\"", "html").is_err());
+}
+
+#[test]
+fn rejects_invalid_options() {
+    let err = read("output_format = \"gif\"", "html").unwrap_err();
+    assert!(
+        err.to_string().contains("[preprocessor.plantuml-renderer]"),
+        "{}",
+        err
+    );
+    assert!(read("default_diagram_type = \"\"", "html").is_err());
+    assert!(read("hash_length = 0", "html").is_err());
+    assert!(read(
+        "strict_trigger = true\ntrigger_style = \"attribute\"",
+        "html"
+    )
+    .is_err());
+    assert!(read("hash_length = 41", "html").is_err());
+    assert!(read("security_profile = \"sandbox\"", "html").is_err());
+    assert!(read(
+        "security_profile = \"SANDBOX\"\nenv = { PLANTUML_SECURITY_PROFILE = \"LEGACY\" }",
+        "html"
+    )
+    .is_err());
+    assert!(read("require_renderer = true\nskip_if_missing = true", "html").is_err());
+    assert!(read("wrapper_template = '<img alt=\"{alt}\">'", "html").is_err());
+    assert!(read("theme = \" \"", "html").is_err());
+    assert!(read("wrapper_template = \"<a href='{src}'>\\n\\n</a>\"", "html").is_err());
+    for language in &["", "puml,render", "plant uml", "json", "render"] {
+        let table = format!("language = \"{}\"", language);
+        assert!(read(&table, "html").is_err(), "{}", language);
+    }
+}
+
+#[test]
+fn can_be_built_in_code() {
+    let config = Config {
+        batch: true,
+        ..Config::default()
+    };
+    assert!(config.batch);
+    assert!(!config.pipe);
+}
+
+#[test]
+fn fingerprints_only_options_which_change_images() {
+    assert_eq!(read("", "html").unwrap().render_fingerprint(), "");
+    let cosmetic = read("quiet = true\nmax_processes = 2\nbatch = true", "html").unwrap();
+    assert_eq!(cosmetic.render_fingerprint(), "");
+    let trimmed = read("trim = true", "html").unwrap().render_fingerprint();
+    let fonts = read("svg_font_family = \"Arial\"", "html")
+        .unwrap()
+        .render_fingerprint();
+    assert!(!trimmed.is_empty());
+    assert_ne!(trimmed, fonts);
+}
+
+#[test]
+fn falls_back_to_the_title_or_a_template_for_alt_text() {
+    assert_eq!(Config::default().alt_fallback(), AltFallback::None);
+    let config = read("alt_from_title = true", "html").unwrap();
+    assert_eq!(config.alt_fallback(), AltFallback::Title);
+    let config = read("alt_fallback = \"Diagram: {title}\"", "html").unwrap();
+    let fallback = config.alt_fallback();
+    assert_eq!(
+        fallback.alt_text(Some("Login"), "Setup").as_deref(),
+        Some("Diagram: Login")
+    );
+    assert_eq!(
+        fallback.alt_text(None, "Setup").as_deref(),
+        Some("Diagram: Setup")
+    );
+    assert_eq!(AltFallback::Title.alt_text(None, "Setup"), None);
+    assert!(read("alt_fallback = \" \"", "html").is_err());
+}
+
+#[test]
+fn maps_colors_to_css_variables() {
+    let inline = "embed = \"inline-with-fallback\"\ncss_theming = true";
+    let config = read(
+        &format!(
+            "{}\ncss_theme_colors = {{ \"#181818\" = \"--ink\", \"#abc\" = \"--accent\" }}",
+            inline
+        ),
+        "html",
+    )
+    .unwrap();
+    let colors = config.css_theme_colors();
+    assert_eq!(colors["#181818"], "--ink");
+    assert_eq!(colors["#AABBCC"], "--accent");
+    // The rest of the defaults are kept
+    assert_eq!(colors["#FFFFFF"], "--diagram-bg");
+    assert!(read(
+        &format!("{}\ncss_theme_colors = {{ red = \"--red\" }}", inline),
+        "html"
+    )
+    .is_err());
+    assert!(read(
+        &format!("{}\ncss_theme_colors = {{ \"#f00\" = \"red\" }}", inline),
+        "html"
+    )
+    .is_err());
+    assert!(read("css_theming = true", "html").is_err());
+}
+
+#[test]
+fn reads_the_plantuml_command_as_a_string_or_a_list() {
+    let config = read("plantuml_command = \"/opt/homebrew/bin/plantuml\"", "html").unwrap();
+    assert_eq!(config.plantuml_command, vec!["/opt/homebrew/bin/plantuml"]);
+    let config = read(
+        "plantuml_command = [\"java\", \"-jar\", \"/opt/plantuml.jar\"]",
+        "html",
+    )
+    .unwrap();
+    assert_eq!(
+        config.plantuml_command,
+        vec!["java", "-jar", "/opt/plantuml.jar"]
+    );
+    assert!(read("", "html").unwrap().plantuml_command.is_empty());
+    assert!(read(
+        "plantuml_command = \"plantuml\"\ncommands = [\"tools/plantuml\"]",
+        "html"
+    )
+    .is_err());
+    assert!(read("plantuml_command = [\"\"]", "html").is_err());
+    // mdbook's own command runs the preprocessor, it's not the PlantUML one
+    let config = read("command = \"mdbook-plantuml-renderer --quiet\"", "html").unwrap();
+    assert!(config.plantuml_command.is_empty());
+}
+
+#[test]
+fn reads_the_server_url() {
+    let config = read("server_url = \"http://plantuml:8080\"", "html").unwrap();
+    assert_eq!(config.server_url.as_deref(), Some("http://plantuml:8080"));
+    assert!(read("server_url = \"https://plantuml.example.com\"", "html").is_err());
+    assert!(read(
+        "server_url = \"http://plantuml:8080\"\nserver_mode = \"managed\"",
+        "html"
+    )
+    .is_err());
+}
+
+#[test]
+fn renders_in_parallel_or_in_batches() {
+    assert!(read("parallel = true", "html").unwrap().parallel);
+    assert!(read("parallel = true\nbatch = true", "html").is_err());
+}
@@ -0,0 +1,3997 @@
+//! Runs the preprocessor binary end-to-end against the fixture book in
+//! `tests/fixtures/book`, with the stub in `tests/fixtures/bin` standing in for PlantUML.
+#![cfg(unix)]
+
+use std::collections::HashSet;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use mdbook::MDBook;
+use pulldown_cmark::Parser;
+use pulldown_cmark_to_cmark::cmark;
+use serde_json::{json, Value};
+
+static FIXTURE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A throwaway copy of the fixture book, so tests can change it and generate
+/// diagrams without stepping on each other
+struct Fixture {
+    root: PathBuf,
+}
+
+impl Fixture {
+    fn new() -> Fixture {
+        let root = env::temp_dir().join(format!(
+            "mdbook-plantuml-renderer-{}-{}",
+            process::id(),
+            FIXTURE_COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        copy_directory(&fixtures().join("book"), &root);
+        Fixture { root }
+    }
+
+    /// Appends to the `[preprocessor.plantuml-renderer]` table, the last one in book.toml
+    fn with_config(self, config: &str) -> Fixture {
+        let mut book_toml = OpenOptions::new()
+            .append(true)
+            .open(self.root.join("book.toml"))
+            .unwrap();
+        writeln!(book_toml, "{}", config).unwrap();
+        self
+    }
+
+    /// Adds a chapter to the end of the book
+    fn with_chapter(self, name: &str, content: &str) -> Fixture {
+        let file_name = format!("{}.md", name.to_lowercase().replace(' ', "_"));
+        self.with_chapter_at(name, &file_name, content)
+    }
+
+    /// Adds a chapter to the end of the book at `file_name`, relative to `src`
+    fn with_chapter_at(self, name: &str, file_name: &str, content: &str) -> Fixture {
+        let path = self.root.join("src").join(file_name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+        let mut summary = OpenOptions::new()
+            .append(true)
+            .open(self.root.join("src/SUMMARY.md"))
+            .unwrap();
+        writeln!(summary, "- [{}]({})", name, file_name).unwrap();
+        self
+    }
+
+    /// Has diagrams PlantUML fails on fail the build, as they do unless the
+    /// fixture's `error_images` says otherwise
+    fn without_error_images(self) -> Fixture {
+        let book_toml = self.root.join("book.toml");
+        let config = fs::read_to_string(&book_toml)
+            .unwrap()
+            .replace("error_images = true\n", "");
+        fs::write(&book_toml, config).unwrap();
+        self
+    }
+
+    fn run(&self) -> Output {
+        self.run_with(|_| {})
+    }
+
+    /// Runs the preprocessor after letting the test adjust its arguments or environment
+    fn run_with(&self, adjust: impl FnOnce(&mut Command)) -> Output {
+        self.run_for("html", adjust)
+    }
+
+    /// Runs the preprocessor as it would be for `renderer`
+    fn run_for(&self, renderer: &str, adjust: impl FnOnce(&mut Command)) -> Output {
+        let book = MDBook::load(&self.root).expect("The fixture book should load");
+        let context = json!({
+            "root": self.root,
+            "config": book.config,
+            "renderer": renderer,
+            "mdbook_version": mdbook::MDBOOK_VERSION,
+            "__non_exhaustive": Value::Null,
+        });
+        let input = serde_json::to_vec(&json!([context, book.book])).unwrap();
+        let mut preprocessor = self.preprocessor();
+        adjust(&mut preprocessor);
+        let mut child = preprocessor
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&input).unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    /// Runs the preprocessor expecting it to succeed, returning the processed book
+    fn render(&self) -> Value {
+        let output = self.run();
+        assert!(
+            output.status.success(),
+            "The preprocessor failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        serde_json::from_slice(&output.stdout).expect("The preprocessor should output a book")
+    }
+
+    fn preprocessor(&self) -> Command {
+        let path = match env::var_os("PATH") {
+            Some(path) => {
+                let mut paths = vec![fixtures().join("bin")];
+                paths.extend(env::split_paths(&path));
+                env::join_paths(paths).unwrap()
+            }
+            None => fixtures().join("bin").into_os_string(),
+        };
+        let mut command = Command::new(env!("CARGO_BIN_EXE_mdbook-plantuml-renderer"));
+        command.current_dir(&self.root).env("PATH", path);
+        command
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+fn copy_directory(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_directory(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+fn chapter(book: &Value, name: &str) -> String {
+    fn find<'a>(items: &'a Value, name: &str) -> Option<&'a Value> {
+        items.as_array().unwrap().iter().find_map(|item| {
+            let chapter = &item["Chapter"];
+            if chapter["name"].as_str() == Some(name) {
+                Some(chapter)
+            } else if chapter.get("sub_items").is_some() {
+                find(&chapter["sub_items"], name)
+            } else {
+                None
+            }
+        })
+    }
+    find(&book["sections"], name)
+        .and_then(|chapter| chapter["content"].as_str())
+        .unwrap_or_else(|| panic!("No chapter named {}", name))
+        .to_string()
+}
+
+/// The targets of every image in the chapter, whether markdown or HTML
+fn images(content: &str) -> Vec<String> {
+    let mut images: Vec<(usize, String)> = Vec::new();
+    for (position, _) in content.match_indices("![") {
+        let image = &content[position..];
+        if let Some(start) = image.find("](").map(|start| start + 2) {
+            if let Some(end) = image[start..].find(')') {
+                // Without the image's title
+                let url = image[start..start + end].split(" \"").next().unwrap();
+                images.push((position, url.to_string()));
+            }
+        }
+    }
+    for (position, _) in content.match_indices("<img src=\"") {
+        let start = position + "<img src=\"".len();
+        if let Some(end) = content[start..].find('"') {
+            images.push((position, content[start..start + end].to_string()));
+        }
+    }
+    images.sort();
+    images.into_iter().map(|(_, image)| image).collect()
+}
+
+#[test]
+fn renders_marked_diagrams() {
+    let fixture = Fixture::new();
+    let content = chapter(&fixture.render(), "Rendered");
+    assert!(!content.contains("@startuml"), "{}", content);
+    let images = images(&content);
+    assert_eq!(images.len(), 1, "{}", content);
+    assert!(images[0].starts_with("plantuml-diagrams/"), "{}", images[0]);
+    assert!(images[0].ends_with(".svg"), "{}", images[0]);
+    assert!(fixture.root.join("src").join(&images[0]).is_file());
+}
+
+#[test]
+fn salts_the_hash() {
+    let image = |fixture: Fixture| images(&chapter(&fixture.render(), "Rendered")).remove(0);
+    let unsalted = image(Fixture::new());
+    let salted = image(Fixture::new().with_config("cache_salt = \"dark-theme\""));
+    assert_ne!(unsalted, salted);
+    assert_eq!(
+        salted,
+        image(Fixture::new().with_config("cache_salt = \"dark-theme\""))
+    );
+}
+
+#[test]
+fn records_dimensions_in_the_manifest() {
+    let fixture = Fixture::new();
+    let image = images(&chapter(&fixture.render(), "Rendered")).remove(0);
+    let manifest = fixture.root.join("src/plantuml-diagrams/manifest.json");
+    let manifest: Value = serde_json::from_str(&fs::read_to_string(manifest).unwrap()).unwrap();
+    let file_name = Path::new(&image).file_name().unwrap().to_str().unwrap();
+    assert_eq!(
+        manifest[file_name]["dimensions"],
+        json!({"width": 20.0, "height": 20.0})
+    );
+}
+
+#[test]
+fn appends_the_svg_font_fallback() {
+    let fixture = Fixture::new().with_config("svg_font_family = \"Arial, Helvetica\"");
+    let image = images(&chapter(&fixture.render(), "Rendered")).remove(0);
+    let svg = fs::read_to_string(fixture.root.join("src").join(image)).unwrap();
+    assert!(
+        svg.contains(r#"font-family="sans-serif, Arial, Helvetica""#),
+        "{}",
+        svg
+    );
+}
+
+#[test]
+fn leaves_unmarked_blocks_alone() {
+    let fixture = Fixture::new();
+    let content = chapter(&fixture.render(), "Skipped");
+    assert!(images(&content).is_empty(), "{}", content);
+    assert!(
+        content.contains("Alice -> Bob: Shown, not rendered"),
+        "{}",
+        content
+    );
+}
+
+#[test]
+fn renders_empty_blocks() {
+    let fixture = Fixture::new();
+    let content = chapter(&fixture.render(), "Empty");
+    assert_eq!(images(&content).len(), 1, "{}", content);
+}
+
+#[test]
+fn ignores_blank_lines_around_diagrams() {
+    let content = "```plantuml,render\nAlice -> Bob: Spaced\n\nBob -> Alice\n```\n\n\
+                   ```plantuml,render\n\n  \n@startuml\nAlice -> Bob: Spaced\n\nBob -> Alice\n@enduml\n\n```\n\n\
+                   ```plantuml,render\n@startuml\nAlice -> Bob: Spaced\nBob -> Alice\n@enduml\n```\n";
+    let fixture = Fixture::new().with_chapter("Spacing", content);
+    let images = images(&chapter(&fixture.render(), "Spacing"));
+    assert_eq!(images[0], images[1]);
+    // The blank line within the diagram still counts
+    assert_ne!(images[0], images[2]);
+}
+
+#[test]
+fn starts_diagrams_with_fragments_defined_earlier() {
+    let content = |color: &str| {
+        format!(
+            "```plantuml,def name=base\nskinparam backgroundColor {}\n```\n\n\
+             ```plantuml,def name=actors\nactor Alice\n```\n\n\
+             ```plantuml,render use=base,actors\nAlice -> Bob\n```\n\n\
+             ```plantuml,render use=base\n@startuml\nBob -> Alice\n@enduml\n```\n",
+            color
+        )
+    };
+    let fixture = Fixture::new().with_chapter("Shared", &content("white"));
+    let rendered = chapter(&fixture.render(), "Shared");
+    assert!(!rendered.contains("skinparam"), "{}", rendered);
+    let shared = images(&rendered);
+    assert_eq!(shared.len(), 2, "{}", rendered);
+    let source = |image: &str| {
+        fs::read_to_string(fixture.root.join("src").join(image).with_extension("puml")).unwrap()
+    };
+    assert_eq!(
+        source(&shared[0]),
+        "@startuml\nskinparam backgroundColor white\nactor Alice\nAlice -> Bob\n@enduml\n"
+    );
+    assert_eq!(
+        source(&shared[1]),
+        "@startuml\nskinparam backgroundColor white\nBob -> Alice\n@enduml\n"
+    );
+    // A changed fragment is a changed diagram
+    fs::write(fixture.root.join("src/shared.md"), content("black")).unwrap();
+    let changed = images(&chapter(&fixture.render(), "Shared"));
+    assert_ne!(changed[0], shared[0]);
+
+    let output = Fixture::new()
+        .with_chapter(
+            "Shared",
+            "```plantuml,render use=base\nAlice -> Bob\n```\n\n\
+             ```plantuml,def name=base\nactor Alice\n```\n",
+        )
+        .run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("`use=base` names no `plantuml,def name=base` block before it"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn wraps_snippets_as_the_default_diagram_type() {
+    let content = "```plantuml,render\n[Design] lasts 5 days\n```\n\n\
+                   ```plantuml,render\n@startuml\nAlice -> Bob: Kept\n@enduml\n```\n\n\
+                   ```json,render\n{ \"kept\": true }\n```\n";
+    let fixture = Fixture::new()
+        .with_config("default_diagram_type = \"gantt\"")
+        .with_chapter("Gantt", content);
+    let sources = images(&chapter(&fixture.render(), "Gantt"))
+        .iter()
+        .map(|image| {
+            fs::read_to_string(fixture.root.join("src").join(image).with_extension("puml")).unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        sources[0],
+        "@startgantt\n[Design] lasts 5 days\n@endgantt\n"
+    );
+    assert_eq!(sources[1], "@startuml\nAlice -> Bob: Kept\n@enduml\n");
+    assert_eq!(sources[2], "@startjson\n{ \"kept\": true }\n@endjson\n");
+
+    let output = Fixture::new()
+        .with_config("default_diagram_type = \"@startgantt\"")
+        .run();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn finds_the_render_marker_anywhere_in_the_info_string() {
+    let diagram = |info: &str| format!("```{}\nAlice -> Bob: {}\n```\n\n", info, info);
+    let content = [
+        "render,plantuml",
+        "plantuml render",
+        "plantuml,render,extra",
+    ]
+    .iter()
+    .map(|info| diagram(info))
+    .collect::<String>();
+    let fixture = Fixture::new().with_chapter("Markers", &content);
+    let rendered = chapter(&fixture.render(), "Markers");
+    assert_eq!(images(&rendered).len(), 2, "{}", rendered);
+    assert!(
+        rendered.contains("Alice -> Bob: plantuml,render,extra"),
+        "{}",
+        rendered
+    );
+}
+
+#[test]
+fn renders_blocks_with_a_render_attribute_in_the_attribute_style() {
+    let content = "```plantuml {render}\nAlice -> Bob: Attribute\n```\n\n\
+                   ```plantuml {#marked render}\nAlice -> Bob: Identified\n```\n\n\
+                   ```plantuml,render\nAlice -> Bob: Language\n```\n\n\
+                   ```plantuml\nAlice -> Bob: Example\n```\n";
+    let fixture = Fixture::new().with_chapter("Triggers", content);
+    let rendered = chapter(&fixture.render(), "Triggers");
+    assert_eq!(images(&rendered).len(), 1, "{}", rendered);
+
+    let fixture = Fixture::new()
+        .with_config("trigger_style = \"attribute\"")
+        .with_chapter("Triggers", content);
+    let output = fixture.run();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("attribute `render`"), "{}", stderr);
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let rendered = chapter(&book, "Triggers");
+    assert_eq!(images(&rendered).len(), 2, "{}", rendered);
+    assert!(rendered.contains(r#"id="marked""#), "{}", rendered);
+    assert!(rendered.contains("Alice -> Bob: Example"), "{}", rendered);
+}
+
+#[test]
+fn shares_images_of_diagrams_differing_in_formatting() {
+    let content = "```plantuml,render\n@startuml\nAlice -> Bob: \"Hi  there\"\n@enduml\n```\n\n\
+                   ```plantuml,render\n@startuml\n' Greets Bob\n  Alice   ->  Bob: \"Hi  there\"  /' inline '/\n\n@enduml\n```\n\n\
+                   ```plantuml,render\n@startuml\nAlice -> Bob: \"Hi there\"\n@enduml\n```\n";
+    let images_with = |config: &str| {
+        let fixture = Fixture::new()
+            .with_config(config)
+            .with_chapter("Formatting", content);
+        images(&chapter(&fixture.render(), "Formatting"))
+    };
+    let images = images_with("semantic_cache = true");
+    assert_eq!(images[0], images[1]);
+    // Whitespace within strings is part of what's shown
+    assert_ne!(images[0], images[2]);
+
+    let images = images_with("semantic_cache = false");
+    assert_ne!(images[0], images[1]);
+}
+
+#[test]
+fn joins_the_blocks_of_a_group_into_one_diagram() {
+    let content = "Start with the actors:\n\n\
+                   ```plantuml,render group=arch\n@startuml\nactor Alice\n```\n\n\
+                   ```plantuml,render\nAlice -> Bob: Alone\n```\n\n\
+                   Then how they talk:\n\n\
+                   ```plantuml,render group=arch\nAlice -> Bob: Together\n@enduml\n```\n";
+    let fixture = Fixture::new().with_chapter("Grouped", content);
+    let rendered = chapter(&fixture.render(), "Grouped");
+    let images = images(&rendered);
+    assert_eq!(images.len(), 2, "{}", rendered);
+    // The group is shown where its last block was
+    assert!(
+        rendered.find("Then how they talk").unwrap() < rendered.find(&images[1]).unwrap(),
+        "{}",
+        rendered
+    );
+    let puml = fs::read_to_string(
+        fixture
+            .root
+            .join("src")
+            .join(&images[1])
+            .with_extension("puml"),
+    )
+    .unwrap();
+    assert_eq!(
+        puml,
+        "@startuml\nactor Alice\nAlice -> Bob: Together\n@enduml\n"
+    );
+}
+
+#[test]
+fn reads_diagrams_from_files_and_links_their_source() {
+    let fixture = Fixture::new()
+        .with_config("source_link_base = \"https://example.com/blob/main/\"")
+        .with_chapter(
+            "External",
+            "```plantuml,render\nfile: ../diagrams/login.puml\n```\n\n\
+             ```plantuml,render\nAlice -> Bob: Inline\n```\n",
+        );
+    fs::write(
+        fixture.root.join("diagrams/login.puml"),
+        "@startuml\nAlice -> Bob: Login\n@enduml\n",
+    )
+    .unwrap();
+    let content = chapter(&fixture.render(), "External");
+    let images = images(&content);
+    assert_eq!(images.len(), 2, "{}", content);
+    let puml = fixture
+        .root
+        .join("src")
+        .join(&images[0])
+        .with_extension("puml");
+    assert!(fs::read_to_string(puml)
+        .unwrap()
+        .contains("Alice -> Bob: Login"));
+    assert!(
+        content.contains("[source](https://example.com/blob/main/diagrams/login.puml)"),
+        "{}",
+        content
+    );
+    // Only diagrams from a file have a source to link to
+    assert_eq!(content.matches("[source]").count(), 1, "{}", content);
+
+    fs::remove_file(fixture.root.join("diagrams/login.puml")).unwrap();
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("diagrams/login.puml"), "{}", stderr);
+}
+
+#[test]
+fn warns_about_diagrams_in_files_still_to_be_included() {
+    let fixture = Fixture::new().with_chapter(
+        "Including",
+        "{{#include parts/flow.md}}\n\n{{#include parts/prose.md:intro}}\n\n\\{{#include parts/escaped.md}}\n",
+    );
+    fs::create_dir_all(fixture.root.join("src/parts")).unwrap();
+    for name in &["flow", "escaped"] {
+        fs::write(
+            fixture.root.join(format!("src/parts/{}.md", name)),
+            "```plantuml,render\nAlice -> Bob\n```\n",
+        )
+        .unwrap();
+    }
+    fs::write(fixture.root.join("src/parts/prose.md"), "Just words\n").unwrap();
+    let output = fixture.run();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("the diagrams of the included `parts/flow.md` aren't rendered"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("after = [\"links\"]"), "{}", stderr);
+    assert!(!stderr.contains("prose.md"), "{}", stderr);
+    assert!(!stderr.contains("escaped.md"), "{}", stderr);
+}
+
+#[test]
+fn handles_byte_order_marks() {
+    let fixture = Fixture::new().with_chapter(
+        "Marked",
+        "```plantuml,render\n!include marked.iuml\nAlice -> Bob\n```\n\n\
+         ```plantuml,render\nfile: marked.puml\n```\n",
+    );
+    fs::write(
+        fixture.root.join("src/marked.iuml"),
+        "\u{feff}skinparam monochrome true\n",
+    )
+    .unwrap();
+    fs::write(
+        fixture.root.join("src/marked.puml"),
+        "\u{feff}@startuml\r\nAlice -> Bob: Marked\r\n@enduml\r\n",
+    )
+    .unwrap();
+    let output = fixture.run();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("marked.iuml starts with a byte order mark"),
+        "{}",
+        stderr
+    );
+    // A file the preprocessor reads itself is cleaned up instead
+    assert!(!stderr.contains("marked.puml starts"), "{}", stderr);
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let image = images(&chapter(&book, "Marked")).remove(1);
+    let puml =
+        fs::read_to_string(fixture.root.join("src").join(image).with_extension("puml")).unwrap();
+    assert_eq!(puml, "@startuml\nAlice -> Bob: Marked\n@enduml\n");
+}
+
+#[test]
+fn shows_library_diagrams_wherever_they_are_referenced() {
+    let fixture = Fixture::new()
+        .with_chapter(
+            "Uses",
+            "Login works like this:\n\n{{#plantuml auth-flow}}\n",
+        )
+        .with_chapter(
+            "Library",
+            "```plantuml,render {#auth-flow}\nAlice -> Bob: Login\n```\n",
+        );
+    let book = fixture.render();
+    let defined = images(&chapter(&book, "Library"));
+    let referenced = images(&chapter(&book, "Uses"));
+    assert_eq!(referenced, defined);
+
+    let fixture = Fixture::new().with_chapter("Dangling", "{{#plantuml missing}}\n");
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Chapter `Dangling`: no diagram has the id `missing`"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn shows_diagrams_defined_in_the_book_toml() {
+    let fixture = Fixture::new()
+        .with_config("[preprocessor.plantuml-renderer.diagrams]\nlegend = \"Alice -> Bob\"\nunused = \"Bob -> Alice\"")
+        .with_chapter("First", "Keys:\n\n{{#plantuml legend}}\n")
+        .with_chapter("Second", "{{#plantuml legend}}\n");
+    let output = fixture.run();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("`unused`"), "{}", stderr);
+    assert!(!stderr.contains("`legend`"), "{}", stderr);
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let first = images(&chapter(&book, "First"));
+    assert_eq!(first.len(), 1);
+    assert_eq!(first, images(&chapter(&book, "Second")));
+    assert!(fixture.root.join("src").join(&first[0]).is_file());
+
+    let fixture = Fixture::new()
+        .with_config("[preprocessor.plantuml-renderer.diagrams]\nlegend = \"Alice -> Bob\"")
+        .with_chapter("Missing", "{{#plantuml key}}\n");
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no diagram has the id `key`"), "{}", stderr);
+}
+
+#[test]
+fn renders_to_the_file_named_by_out() {
+    let diagram = |message: &str| {
+        format!(
+            "```plantuml,render out=assets/login.png\nAlice -> Bob: {}\n```\n",
+            message
+        )
+    };
+    let fixture = Fixture::new().with_chapter("Named", &diagram("Hello"));
+    let content = chapter(&fixture.render(), "Named");
+    assert_eq!(images(&content), vec!["assets/login.png"]);
+    assert!(fixture.root.join("src/assets/login.png").is_file());
+
+    let log = fixture.root.join("plantuml.log");
+    let run = || {
+        let output = fixture.run_with(|command| {
+            command.env("PLANTUML_STUB_LOG", &log);
+        });
+        assert!(output.status.success());
+    };
+    run();
+    assert!(!log.exists(), "An unchanged diagram is rendered again");
+    fs::write(fixture.root.join("src/named.md"), diagram("Bye")).unwrap();
+    run();
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(log.contains("-tpng"), "{}", log);
+}
+
+#[test]
+fn stops_rendering_past_max_diagrams() {
+    let diagram = "```plantuml,render\nAlice -> Bob\n```\n";
+    let fixture = Fixture::new()
+        .with_config("max_diagrams = 100")
+        .with_chapter("Repeated", &diagram.repeat(3));
+    fixture.render();
+
+    for config in &["max_diagrams = 0", "max_diagrams = 0\nbatch = true"] {
+        let fixture = Fixture::new()
+            .with_config(config)
+            .with_chapter("Repeated", &diagram.repeat(3));
+        let log = fixture.root.join("plantuml.log");
+        let output = fixture.run_with(|command| {
+            command.env("PLANTUML_STUB_LOG", &log);
+        });
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("more than 0 different diagrams, the max_diagrams limit"),
+            "{}",
+            stderr
+        );
+        assert!(!log.exists(), "A diagram past the limit is rendered");
+    }
+}
+
+#[test]
+fn rejects_out_files_of_unknown_formats() {
+    let fixture = Fixture::new().with_chapter(
+        "Named",
+        "```plantuml,render out=assets/login.gif\nAlice -> Bob\n```\n",
+    );
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Chapter `Named`: `out=assets/login.gif` names a `.gif` file"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn scales_diagrams_with_a_directive() {
+    let fixture = Fixture::new()
+        .with_chapter(
+            "Scaled",
+            "```plantuml,render scale=\"800 width\"\nAlice -> Bob: Hello\n```\n",
+        )
+        .with_chapter("Unscaled", "```plantuml,render\nAlice -> Bob: Hello\n```\n");
+    let book = fixture.render();
+    let scaled = images(&chapter(&book, "Scaled")).remove(0);
+    assert_ne!(scaled, images(&chapter(&book, "Unscaled")).remove(0));
+    let source = fs::read_to_string(
+        fixture
+            .root
+            .join("src")
+            .join(&scaled)
+            .with_extension("puml"),
+    )
+    .unwrap();
+    assert!(
+        source.starts_with("@startuml\nscale 800 width\n"),
+        "{}",
+        source
+    );
+
+    let fixture = Fixture::new().with_chapter(
+        "Scaled",
+        "```plantuml,render scale=huge\nAlice -> Bob\n```\n",
+    );
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("`scale=huge` isn't a scale"), "{}", stderr);
+}
+
+#[test]
+fn embeds_svgs_as_objects_if_asked() {
+    let content =
+        "```plantuml,render description=\"Clickable\"\nclass Alice [[https://example.com]]\n```\n";
+    let fixture = Fixture::new()
+        .with_config("embed = \"object\"")
+        .with_chapter("Linked", content);
+    let content = chapter(&fixture.render(), "Linked");
+    let images = images(&content);
+    assert!(
+        content.contains(&format!(
+            "<object type=\"image/svg+xml\" data=\"{}\" aria-label=\"Clickable\"><img src=\"{}\"",
+            images[0], images[0]
+        )),
+        "{}",
+        content
+    );
+    assert!(content.contains("</object>"), "{}", content);
+
+    let fixture = Fixture::new()
+        .with_config("embed = \"object\"\noutput_format = \"png\"")
+        .with_chapter("Linked", "```plantuml,render\nAlice -> Bob\n```\n");
+    let content = chapter(&fixture.render(), "Linked");
+    assert!(!content.contains("<object"), "{}", content);
+}
+
+#[test]
+fn inlines_svgs_with_an_image_fallback_if_asked() {
+    let fixture = Fixture::new()
+        .with_config("embed = \"inline-with-fallback\"")
+        .with_chapter(
+            "Inline",
+            "```plantuml,render description=\"Inlined\"\nAlice -> Bob\n```\n",
+        );
+    let content = chapter(&fixture.render(), "Inline");
+    let svg_start = content.find("<svg").expect(&content);
+    let svg_end = content.find("</svg>").expect(&content) + "</svg>".len();
+    let svg = &content[svg_start..svg_end];
+    assert!(
+        svg.contains("role=\"img\" aria-label=\"Inlined\""),
+        "{}",
+        svg
+    );
+    assert!(roxmltree::Document::parse(svg).is_ok(), "{}", svg);
+    // The fallback shows the file, which is still written
+    let images = images(&content);
+    assert_eq!(images.len(), 1, "{}", content);
+    assert!(fixture.root.join("src").join(&images[0]).is_file());
+    let fallback = format!(
+        "<noscript><img src=\"{}\" alt=\"Inlined\" /></noscript>",
+        images[0]
+    );
+    assert!(
+        content[svg_end..].starts_with(&format!("\n{}</div>", fallback)),
+        "{}",
+        content
+    );
+    // One HTML block, of which markdown makes nothing
+    assert!(content.starts_with("<div><svg"), "{}", content);
+    assert!(
+        !Parser::new(&content).any(|event| matches!(
+            event,
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Paragraph)
+        )),
+        "{}",
+        content
+    );
+
+    let fixture = Fixture::new()
+        .with_config("embed = \"inline-with-fallback\"\nrewrite_svg_links = true")
+        .with_chapter("Inline", "```plantuml,render\nAlice -> Bob\n```\n");
+    assert!(!fixture.run().status.success());
+}
+
+#[test]
+fn inlines_svgs_alone_if_asked() {
+    let fixture = Fixture::new()
+        .with_config("embed = \"inline\"")
+        .with_chapter(
+            "Inline",
+            "```plantuml,render description=\"Inlined\"\nAlice -> Bob\n```\n",
+        );
+    let content = chapter(&fixture.render(), "Inline");
+    assert!(content.starts_with("<div><svg"), "{}", content);
+    assert!(content.contains("aria-label=\"Inlined\""), "{}", content);
+    assert!(!content.contains("<?xml"), "{}", content);
+    // Nothing links to the file
+    assert!(images(&content).is_empty(), "{}", content);
+    assert!(!content.contains("<noscript>"), "{}", content);
+}
+
+#[test]
+fn themes_every_diagram_of_the_book() {
+    let diagram = "```plantuml,render\n'# title: Login\nAlice -> Bob: Hello\n```\n";
+    let fixture = Fixture::new()
+        .with_config("theme = \"cerulean\"\npreamble = [\"skinparam shadowing false\"]")
+        .with_chapter("Themed", diagram)
+        .with_chapter(
+            "Own theme",
+            &format!("+++\ntheme = \"sketchy\"\n+++\n\n{}", diagram),
+        );
+    let book = fixture.render();
+    let source = |name: &str| {
+        let image = images(&chapter(&book, name)).remove(0);
+        fs::read_to_string(
+            fixture
+                .root
+                .join("src")
+                .join(image.replace(".svg", ".puml")),
+        )
+        .unwrap()
+    };
+    let themed = source("Themed");
+    assert!(
+        themed.contains("@startuml\n!theme cerulean\nskinparam shadowing false\n"),
+        "{}",
+        themed
+    );
+    // The caption stays out of it
+    assert!(!themed.contains("title:"), "{}", themed);
+    // The chapter's takes the book's place, so the preamble adjusts it alike
+    let own = source("Own theme");
+    assert!(
+        own.contains("@startuml\n!theme sketchy\nskinparam shadowing false\n"),
+        "{}",
+        own
+    );
+    assert!(!own.contains("cerulean"), "{}", own);
+
+    // Part of the hash
+    let plain = Fixture::new().with_chapter("Themed", diagram).render();
+    assert_ne!(
+        images(&chapter(&plain, "Themed")),
+        images(&chapter(&book, "Themed"))
+    );
+}
+
+#[test]
+fn overrides_options_with_a_chapter_s_front_matter() {
+    let diagram = "```plantuml,render\nAlice -> Bob: Hello\n```\n";
+    let fixture = Fixture::new()
+        .with_chapter(
+            "Raster",
+            &format!(
+                "+++\noutput_format = \"png\"\nscale = 2\n+++\n\n{}",
+                diagram
+            ),
+        )
+        .with_chapter(
+            "Themed",
+            &format!("---\ntheme: \"cerulean\"\n---\n\n{}", diagram),
+        )
+        .with_chapter(
+            "Ruled",
+            &format!("---\n\nNot front matter\n\n---\n\n{}", diagram),
+        )
+        .with_chapter("Draft", &format!("---\nNote: draft\n---\n\n{}", diagram));
+    let book = fixture.render();
+    let content = chapter(&book, "Raster");
+    assert!(!content.contains("+++"), "{}", content);
+    let raster = images(&content).remove(0);
+    assert!(raster.ends_with(".png"), "{}", raster);
+    let source = |image: &str| {
+        fs::read_to_string(fixture.root.join("src").join(image).with_extension("puml")).unwrap()
+    };
+    assert!(
+        source(&raster).contains("\nscale 2\n"),
+        "{}",
+        source(&raster)
+    );
+    let content = chapter(&book, "Themed");
+    assert!(!content.contains("cerulean"), "{}", content);
+    let themed = images(&content).remove(0);
+    assert!(themed.ends_with(".svg"), "{}", themed);
+    assert!(
+        source(&themed).contains("\n!theme cerulean\n"),
+        "{}",
+        source(&themed)
+    );
+    let content = chapter(&book, "Ruled");
+    assert!(content.contains("Not front matter"), "{}", content);
+    // Keys a chapter can't set mean it isn't front matter
+    let content = chapter(&book, "Draft");
+    assert!(content.contains("Note: draft"), "{}", content);
+
+    let fixture = Fixture::new().with_config("strict = true").with_chapter(
+        "Typo",
+        &format!("+++\noutput_fromat = \"png\"\n+++\n{}", diagram),
+    );
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("unknown front matter option `output_fromat`"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn writes_gitattributes_for_git_lfs_if_asked() {
+    let fixture = Fixture::new();
+    fixture.render();
+    let gitattributes = fixture.root.join("src/plantuml-diagrams/.gitattributes");
+    assert!(!gitattributes.exists());
+
+    let fixture = Fixture::new().with_config("lfs_gitattributes = true");
+    fixture.render();
+    let gitattributes = fixture.root.join("src/plantuml-diagrams/.gitattributes");
+    let attributes = fs::read_to_string(&gitattributes).unwrap();
+    assert!(
+        attributes.contains("\n*.png filter=lfs diff=lfs merge=lfs -text\n"),
+        "{}",
+        attributes
+    );
+    assert!(!attributes.contains("*.svg"), "{}", attributes);
+}
+
+#[test]
+fn rewrites_links_in_svgs_to_lead_from_the_chapter() {
+    let content = "```plantuml,render\nclass Alice [[other.md#usage]]\nclass Bob [[https://example.com/a.md]]\n```\n";
+    let svg = |config: &str| {
+        let fixture =
+            Fixture::new()
+                .with_config(config)
+                .with_chapter_at("Intro", "guide/intro.md", content);
+        let image = images(&chapter(&fixture.render(), "Intro")).remove(0);
+        let image = Path::new(&image).file_name().unwrap().to_owned();
+        fs::read_to_string(fixture.root.join("src/plantuml-diagrams").join(image)).unwrap()
+    };
+    let linked = svg("");
+    assert!(linked.contains(" href=\"other.md#usage\""), "{}", linked);
+    let rewritten = svg("rewrite_svg_links = true");
+    assert!(
+        rewritten.contains(" href=\"../guide/other.html#usage\"")
+            && rewritten.contains(" xlink:href=\"../guide/other.html#usage\""),
+        "{}",
+        rewritten
+    );
+    assert!(
+        rewritten.contains(" href=\"https://example.com/a.md\""),
+        "{}",
+        rewritten
+    );
+}
+
+#[test]
+fn leaves_chapters_alone_for_mdbook_test() {
+    let fixture = Fixture::new();
+    let log = fixture.root.join("plantuml.log");
+    let output = fixture.run_for("test", |command| {
+        command.env("PLANTUML_STUB_LOG", &log);
+    });
+    assert!(output.status.success());
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let content = chapter(&book, "Rendered");
+    assert!(content.contains("```plantuml,render"), "{}", content);
+    assert!(!log.exists());
+
+    let fixture = Fixture::new().with_config("skip_renderers = []");
+    let output = fixture.run_for("test", |_| {});
+    assert!(output.status.success());
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(!images(&chapter(&book, "Rendered")).is_empty());
+}
+
+#[test]
+fn links_downloadable_images_to_themselves() {
+    let book = Fixture::new()
+        .with_config("downloadable = true")
+        .with_chapter(
+            "Inline",
+            "```plantuml,render downloadable=false\nAlice -> Bob: Inline\n```\n",
+        )
+        .render();
+    let content = chapter(&book, "Rendered");
+    let image = images(&content).remove(0);
+    assert!(
+        content.contains(&format!(
+            "<a href=\"{}\" target=\"_blank\" rel=\"noopener\"><img src=\"{}\"",
+            image, image
+        )),
+        "{}",
+        content
+    );
+    let content = chapter(&book, "Inline");
+    assert!(!content.contains("<a href"), "{}", content);
+    assert!(content.contains("!["), "{}", content);
+}
+
+#[test]
+fn describes_diagrams_for_screen_readers() {
+    let content = "```plantuml,render description=\"Alice greets Bob\"\nAlice -> Bob: Hello\n```\n\n\
+                   ```plantuml,render description=Login long_description=\"Alice logs in, then Bob answers\"\n\
+                   Alice -> Bob: Login\n```\n";
+    let fixture = Fixture::new().with_chapter("Described", content);
+    let content = chapter(&fixture.render(), "Described");
+    assert!(content.contains("![Alice greets Bob]("), "{}", content);
+    assert!(content.contains("alt=\"Login\""), "{}", content);
+    assert!(
+        content.contains("aria-describedby=\"diagram-description-"),
+        "{}",
+        content
+    );
+    assert!(
+        content.contains("position: absolute;")
+            && content.contains(">Alice logs in, then Bob answers</span>"),
+        "{}",
+        content
+    );
+
+    let fixture = Fixture::new()
+        .with_config("require_alt = true")
+        .with_chapter(
+            "Described",
+            "```plantuml,render description=Described\nAlice -> Bob\n```\n",
+        );
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Chapter `Rendered`"), "{}", stderr);
+    assert!(!stderr.contains("Chapter `Described`"), "{}", stderr);
+}
+
+#[test]
+fn wraps_data_blocks_once() {
+    let fixture = Fixture::new();
+    let content = chapter(&fixture.render(), "Data");
+    let sources: Vec<String> = images(&content)
+        .iter()
+        .map(|image| {
+            let puml = fixture.root.join("src").join(image).with_extension("puml");
+            fs::read_to_string(puml).unwrap()
+        })
+        .collect();
+    assert_eq!(sources.len(), 3, "{}", content);
+    assert!(sources[0].starts_with("@startjson\n{"), "{}", sources[0]);
+    assert!(sources[0].ends_with("@endjson\n"), "{}", sources[0]);
+    assert!(sources[1].starts_with("@startyaml\nname"), "{}", sources[1]);
+    assert_eq!(
+        sources[2].matches("@startyaml").count(),
+        1,
+        "{}",
+        sources[2]
+    );
+}
+
+#[test]
+fn renders_very_long_diagrams() {
+    let mut diagram = String::from("```plantuml,render\n@startuml\n");
+    for line in 0..5000 {
+        diagram.push_str(&format!("Alice -> Bob: Message number {}\n", line));
+    }
+    diagram.push_str("@enduml\n```\n");
+    // Neither mode hands the source over as an argument, so its size isn't limited
+    for config in &["pipe = false", "pipe = true"] {
+        let fixture = Fixture::new()
+            .with_config(config)
+            .with_chapter("Long", &diagram);
+        let content = chapter(&fixture.render(), "Long");
+        let images = images(&content);
+        assert_eq!(images.len(), 1, "{}", content);
+        assert!(fixture.root.join("src").join(&images[0]).is_file());
+    }
+}
+
+#[test]
+fn renders_only_within_marked_regions() {
+    let diagram = "```plantuml,render\n@startuml\nAlice -> Bob: Region\n@enduml\n```\n\n";
+    let content = format!(
+        "{}<!-- plantuml:begin -->\n\n{}<!-- plantuml:end -->\n\n{}",
+        diagram, diagram, diagram
+    );
+    let fixture = Fixture::new().with_chapter("Regions", &content);
+    let content = chapter(&fixture.render(), "Regions");
+    assert_eq!(images(&content).len(), 1, "{}", content);
+    assert_eq!(content.matches("@startuml").count(), 2, "{}", content);
+}
+
+#[test]
+fn rejects_unmatched_region_markers() {
+    for content in &[
+        "<!-- plantuml:begin -->\n",
+        "<!-- plantuml:end -->\n",
+        "<!-- plantuml:begin -->\n\n<!-- plantuml:begin -->\n",
+    ] {
+        let fixture = Fixture::new().with_chapter("Regions", content);
+        let output = fixture.run();
+        assert!(!output.status.success(), "{}", content);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("plantuml:"), "{}", stderr);
+    }
+}
+
+#[test]
+fn picks_the_output_format_for_the_renderer() {
+    let fixture =
+        Fixture::new().with_config("output_format = \"auto\"\nauto_formats = { latex = \"svg\" }");
+    let format = |renderer: &str| {
+        let output = fixture.run_for(renderer, |_| {});
+        assert!(output.status.success());
+        let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+        let image = images(&chapter(&book, "Rendered")).remove(0);
+        Path::new(&image)
+            .extension()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    };
+    assert_eq!(format("html"), "svg");
+    assert_eq!(format("epub"), "png");
+    assert_eq!(format("latex"), "svg");
+}
+
+#[test]
+fn places_pragmas_after_the_start_directive() {
+    let content = "```plantuml,render\n@startuml\nAlice -> Bob: Written\n@enduml\n```\n\n\
+                   ```plantuml,render\nAlice -> Bob: Wrapped\n```\n\n\
+                   ```plantuml,render\n@startuml\n!pragma teoz true\nAlice -> Bob: Has it\n@enduml\n```\n";
+    let fixture = Fixture::new()
+        .with_config("pragmas = [\"teoz true\"]")
+        .with_chapter("Pragmas", content);
+    let content = chapter(&fixture.render(), "Pragmas");
+    let sources: Vec<String> = images(&content)
+        .iter()
+        .map(|image| {
+            let puml = fixture.root.join("src").join(image).with_extension("puml");
+            fs::read_to_string(puml).unwrap()
+        })
+        .collect();
+    assert_eq!(
+        sources[0],
+        "@startuml\n!pragma teoz true\nAlice -> Bob: Written\n@enduml\n"
+    );
+    assert_eq!(
+        sources[1],
+        "@startuml\n!pragma teoz true\nAlice -> Bob: Wrapped\n@enduml\n"
+    );
+    assert_eq!(sources[2].matches("!pragma teoz true").count(), 1);
+}
+
+#[test]
+fn pins_the_layout_engine_for_a_stable_layout() {
+    let puml = |fixture: &Fixture| {
+        let image = images(&chapter(&fixture.render(), "Rendered")).remove(0);
+        fs::read_to_string(fixture.root.join("src").join(image).with_extension("puml")).unwrap()
+    };
+    let fixture = Fixture::new().with_config("stable_layout = true");
+    assert!(puml(&fixture).contains("!pragma layout smetana\n"));
+
+    let fixture = Fixture::new().with_config("stable_layout = true\npragmas = [\"layout elk\"]");
+    let source = puml(&fixture);
+    assert!(source.contains("!pragma layout elk\n"), "{}", source);
+    assert!(!source.contains("smetana"), "{}", source);
+}
+
+#[test]
+fn adds_the_preamble_for_the_output_format() {
+    let rendered = |format: &str| {
+        let fixture = Fixture::new().with_config(&format!(
+            "output_format = \"{}\"\n\
+             preamble = [\"skinparam shadowing false\"]\n\
+             preamble_svg = [\"skinparam defaultFontName sans-serif\"]\n\
+             preamble_png = [\"skinparam defaultFontName DejaVu Sans\"]",
+            format
+        ));
+        let image = images(&chapter(&fixture.render(), "Rendered")).remove(0);
+        let source =
+            fs::read_to_string(fixture.root.join("src").join(&image).with_extension("puml"))
+                .unwrap();
+        (image, source)
+    };
+    let (svg, source) = rendered("svg");
+    assert!(
+        source.contains("skinparam shadowing false\nskinparam defaultFontName sans-serif\n"),
+        "{}",
+        source
+    );
+    assert!(!source.contains("DejaVu"), "{}", source);
+    let (png, source) = rendered("png");
+    assert!(
+        source.contains("skinparam shadowing false\nskinparam defaultFontName DejaVu Sans\n"),
+        "{}",
+        source
+    );
+    // A different preamble makes for a different image
+    assert_ne!(Path::new(&svg).file_stem(), Path::new(&png).file_stem());
+}
+
+#[test]
+fn defines_the_configured_values_in_every_diagram() {
+    let rendered = |version: &str| {
+        let fixture = Fixture::new().with_config(&format!(
+            "definitions = {{ VERSION = \"{}\", PRODUCT = \"Example\" }}",
+            version
+        ));
+        let image = images(&chapter(&fixture.render(), "Rendered")).remove(0);
+        let source =
+            fs::read_to_string(fixture.root.join("src").join(&image).with_extension("puml"))
+                .unwrap();
+        (image, source)
+    };
+    let (first, source) = rendered("1.2");
+    assert!(
+        source.contains("!define PRODUCT Example\n!define VERSION 1.2\n"),
+        "{}",
+        source
+    );
+    let (second, _) = rendered("1.3");
+    assert_ne!(first, second);
+
+    let output = Fixture::new()
+        .with_config("definitions = { \"NOT VALID\" = \"1\" }")
+        .run();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn shows_ascii_art_in_a_code_block() {
+    let fixture = Fixture::new()
+        .with_config("output_format = \"txt\"")
+        .with_chapter(
+            "Art",
+            "```plantuml,render {#art}\nAlice -> Bob: Drawn\n```\n\n{{#plantuml art}}\n",
+        );
+    let content = chapter(&fixture.render(), "Art");
+    assert!(images(&content).is_empty(), "{}", content);
+    assert_eq!(content.matches("```text").count(), 2, "{}", content);
+    assert_eq!(content.matches("|Alice|").count(), 2, "{}", content);
+    assert!(!content.contains("Alice -> Bob"), "{}", content);
+}
+
+#[test]
+fn comments_svgs_without_rendering_them_again() {
+    let fixture = Fixture::new().with_config("svg_comment = \"Copyright Example\"");
+    let image = images(&chapter(&fixture.render(), "Rendered")).remove(0);
+    let image = fixture.root.join("src").join(image);
+    let svg = fs::read_to_string(&image).unwrap();
+    assert!(
+        svg.starts_with("<!-- Copyright Example -->\n<svg"),
+        "{}",
+        svg
+    );
+
+    let book_toml = fixture.root.join("book.toml");
+    let config = fs::read_to_string(&book_toml)
+        .unwrap()
+        .replace("Copyright Example", "Copyright -- Other");
+    fs::write(&book_toml, config).unwrap();
+    let log = fixture.root.join("plantuml.log");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_LOG", &log);
+    });
+    assert!(output.status.success());
+    assert!(!log.exists());
+    let svg = fs::read_to_string(&image).unwrap();
+    assert!(
+        svg.starts_with("<!-- Copyright - - Other -->\n<svg"),
+        "{}",
+        svg
+    );
+    assert_eq!(svg.matches("<!--").count(), 1, "{}", svg);
+}
+
+#[test]
+fn reports_pages_which_are_not_shown() {
+    let content = "```plantuml,render\n@startuml\nAlice -> Bob: One\nnewpage\nBob -> Alice: Two\n@enduml\n```\n";
+    let fixture = Fixture::new().with_chapter("Pages", content);
+    let output = fixture.run();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("PlantUML wrote 2 pages, only the first is shown"),
+        "{}",
+        stderr
+    );
+
+    let fixture = Fixture::new()
+        .with_config("extra_pages = \"error\"")
+        .with_chapter("Pages", content);
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Chapter `Pages`"), "{}", stderr);
+    // It fails again rather than finding the image rendered
+    assert!(!fixture.run().status.success());
+}
+
+#[test]
+fn fails_on_stderr_output_in_strict_stderr_mode() {
+    let content = "```plantuml,render\n' stderr: Warning: no image in skin\nAlice -> Bob\n```\n";
+    let fixture = Fixture::new().with_chapter("Noisy", content);
+    assert!(fixture.run().status.success());
+
+    let fixture = Fixture::new()
+        .with_config("strict_stderr = true")
+        .with_chapter("Noisy", content);
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Chapter `Noisy`, diagram "), "{}", stderr);
+    assert!(stderr.contains("Warning: no image in skin"), "{}", stderr);
+    // It fails again rather than finding the image rendered
+    assert!(!fixture.run().status.success());
+
+    let fixture = Fixture::new()
+        .with_config("strict_stderr = true\nstderr_ignore = [\"no image in skin\"]")
+        .with_chapter("Noisy", content);
+    assert!(fixture.run().status.success());
+}
+
+#[test]
+fn stops_plantuml_once_it_takes_too_long() {
+    let slow = |config: &str| {
+        let fixture = Fixture::new()
+            .with_config(&format!("{}\ninclude_chapters = [\"slow.md\"]", config))
+            .with_chapter(
+                "Slow",
+                "```plantuml,render\nAlice -> Bob\n```\n\n```plantuml,render\nBob -> Alice\n```\n",
+            );
+        fixture.run_with(|command| {
+            command.env("PLANTUML_STUB_SLEEP", "3");
+        })
+    };
+    let output = slow("timeout_secs = 2");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Chapter `Slow`, diagram "), "{}", stderr);
+    assert!(
+        stderr.contains("PlantUML was stopped after 2s of rendering"),
+        "{}",
+        stderr
+    );
+
+    // A batch of two diagrams gets twice the time
+    assert!(slow("timeout_secs = 2\nbatch = true").status.success());
+    assert!(
+        !slow("timeout_secs = 2\nbatch = true\nbatch_timeout_secs = 1")
+            .status
+            .success()
+    );
+}
+
+#[test]
+fn skips_rendering_without_plantuml_if_asked() {
+    let without_plantuml = |command: &mut Command| {
+        command.env("PATH", "");
+    };
+    let fixture = Fixture::new();
+    assert!(!fixture.run_with(without_plantuml).status.success());
+
+    let fixture = Fixture::new().with_config("skip_if_missing = true");
+    let output = fixture.run_with(without_plantuml);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("PlantUML isn't installed"), "{}", stderr);
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let content = chapter(&book, "Rendered");
+    assert!(images(&content).is_empty(), "{}", content);
+    assert!(content.contains("Alice -> Bob: Hello"), "{}", content);
+
+    // References show the source of what they refer to
+    let fixture = Fixture::new()
+        .with_config("skip_if_missing = true")
+        .with_chapter(
+            "Uses",
+            "Login works like this:\n\n{{#plantuml auth-flow}}\n",
+        )
+        .with_chapter(
+            "Library",
+            "```plantuml,render {#auth-flow}\nAlice -> Bob: Login\n```\n",
+        );
+    let output = fixture.run_with(without_plantuml);
+    assert!(output.status.success());
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let content = chapter(&book, "Uses");
+    assert!(!content.contains("{{#plantuml"), "{}", content);
+    assert!(
+        content.contains("```plantuml\nAlice -> Bob: Login\n```"),
+        "{}",
+        content
+    );
+}
+
+#[test]
+fn takes_off_front_matter_when_leaving_diagrams_alone() {
+    let front_matter = "+++\ntheme = \"sketchy\"\n+++\n\n```plantuml,render\nA -> B\n```\n";
+    let without_plantuml = |command: &mut Command| {
+        command.env("PATH", "");
+    };
+    let fixture = Fixture::new()
+        .with_config("skip_if_missing = true")
+        .with_chapter("Themed", front_matter);
+    let output = fixture.run_with(without_plantuml);
+    assert!(output.status.success());
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let content = chapter(&book, "Themed");
+    assert!(!content.contains("sketchy"), "{}", content);
+    assert!(content.contains("A -> B"), "{}", content);
+
+    let fixture = Fixture::new().with_chapter("Themed", front_matter);
+    let output = fixture.run_for("test", |_| {});
+    assert!(output.status.success());
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let content = chapter(&book, "Themed");
+    assert!(!content.contains("sketchy"), "{}", content);
+    assert!(content.contains("A -> B"), "{}", content);
+}
+
+#[test]
+fn fails_without_plantuml_if_a_renderer_is_required() {
+    let without_plantuml = |command: &mut Command| {
+        command.env("PATH", "");
+    };
+    let fixture = Fixture::new().with_config("require_renderer = true");
+    let output = fixture.run_with(without_plantuml);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("The book has diagrams but PlantUML isn't installed"),
+        "{}",
+        stderr
+    );
+    assert!(fixture.run().status.success());
+
+    // Nothing needs PlantUML without a chapter to render diagrams in
+    let fixture =
+        Fixture::new().with_config("require_renderer = true\ninclude_chapters = [\"nothing.md\"]");
+    assert!(fixture.run_with(without_plantuml).status.success());
+}
+
+#[test]
+fn nocache_renders_again_on_every_build() {
+    let content = "```plantuml,render nocache\n@startuml\nAlice -> Bob: Volatile\n@enduml\n```\n\n\
+                   ```plantuml,render\n@startuml\nAlice -> Bob: Stable\n@enduml\n```\n";
+    let renders = |fixture: &Fixture| {
+        let log = fixture.root.join("plantuml.log");
+        let _ = fs::remove_file(&log);
+        let output = fixture.run_with(|command| {
+            command.env("PLANTUML_STUB_LOG", &log);
+        });
+        assert!(output.status.success());
+        fs::read_to_string(log).unwrap_or_default()
+    };
+    let fixture = Fixture::new().with_chapter("Volatile", content);
+    renders(&fixture);
+    let second = renders(&fixture);
+    assert_eq!(second.lines().count(), 1, "{}", second);
+
+    let fixture = Fixture::new()
+        .with_config("no_cache = true")
+        .with_chapter("Volatile", &content.replace("nocache", "nocache=false"));
+    let first = renders(&fixture);
+    let second = renders(&fixture);
+    assert_eq!(
+        second.lines().count(),
+        first.lines().count() - 1,
+        "{}",
+        second
+    );
+}
+
+#[test]
+fn writes_image_urls_in_the_configured_style() {
+    let diagram = "```plantuml,render\n@startuml\nAlice -> Bob: Nested\n@enduml\n```\n";
+    for (style, prefix) in &[
+        ("absolute", "plantuml-diagrams/"),
+        ("relative", "../../plantuml-diagrams/"),
+        ("root", "/plantuml-diagrams/"),
+    ] {
+        let fixture = Fixture::new()
+            .with_config(&format!("url_style = \"{}\"", style))
+            .with_chapter_at("Nested", "guide/deep/nested.md", diagram);
+        let content = chapter(&fixture.render(), "Nested");
+        let images = images(&content);
+        assert!(images[0].starts_with(prefix), "{}: {}", style, images[0]);
+        assert_eq!(images[0].matches("plantuml-diagrams").count(), 1);
+    }
+}
+
+#[test]
+fn writes_images_where_the_html_output_has_its_assets() {
+    let diagram = "```plantuml,render\nAlice -> Bob\n```\n";
+    for (style, prefix) in &[
+        ("absolute", "assets/diagrams/"),
+        ("relative", "../../assets/diagrams/"),
+        ("root", "/assets/diagrams/"),
+    ] {
+        let fixture = Fixture::new()
+            .with_config(&format!(
+                "diagram_directory = \"assets/diagrams\"\nurl_style = \"{}\"",
+                style
+            ))
+            .with_chapter_at("Nested", "guide/deep/nested.md", diagram);
+        let image = images(&chapter(&fixture.render(), "Nested")).remove(0);
+        assert!(image.starts_with(prefix), "{}: {}", style, image);
+        let src = fixture.root.join("src");
+        // Where it's served from and deployed to, the output being a copy of `src`
+        let written = match *style {
+            "absolute" => src.join(&image),
+            "relative" => src.join("guide/deep").join(&image),
+            _ => src.join(image.trim_start_matches('/')),
+        };
+        assert!(written.is_file(), "{}: {}", style, written.display());
+        assert!(!src.join("plantuml-diagrams").exists());
+    }
+
+    let fixture = Fixture::new().with_config("diagram_directory = \"../book/diagrams\"");
+    assert!(!fixture.run().status.success());
+}
+
+#[test]
+fn fits_wide_images_to_the_page() {
+    // The stub's images are 20px wide
+    let fixture = Fixture::new().with_config("fit_to_page = true\npage_width_px = 10");
+    let content = chapter(&fixture.render(), "Rendered");
+    assert!(content.contains(r#"width="10" height="10""#), "{}", content);
+
+    let fixture = Fixture::new().with_config("fit_to_page = true");
+    let content = chapter(&fixture.render(), "Rendered");
+    assert!(!content.contains("width="), "{}", content);
+}
+
+#[test]
+fn sizes_images_from_fence_attributes() {
+    let sized = |config: &str, info: &str| {
+        let fixture = Fixture::new()
+            .with_config(config)
+            .with_chapter("Sized", &format!("```{}\nAlice -> Bob\n```\n", info));
+        chapter(&fixture.render(), "Sized")
+    };
+    let content = sized("", "plantuml,render width=300px");
+    assert!(content.contains(r#"<img src="#), "{}", content);
+    assert!(content.contains(r#"width="300""#), "{}", content);
+    assert!(!content.contains("height="), "{}", content);
+
+    let content = sized("", "plantuml,render height=50%");
+    assert!(content.contains(r#"height="50%""#), "{}", content);
+    assert!(!content.contains("width="), "{}", content);
+
+    let content = sized("", "plantuml,render width=80% height=120");
+    assert!(
+        content.contains(r#"width="80%" height="120""#),
+        "{}",
+        content
+    );
+
+    // The fence's size beats the page's
+    let config = "fit_to_page = true\npage_width_px = 10";
+    let content = sized(config, "plantuml,render width=15");
+    assert!(content.contains(r#"width="15""#), "{}", content);
+    assert!(!content.contains("height="), "{}", content);
+    let content = sized(config, "plantuml,render");
+    assert!(content.contains(r#"width="10" height="10""#), "{}", content);
+
+    let fixture = Fixture::new().with_chapter(
+        "Sized",
+        "```plantuml,render width=wide\nAlice -> Bob\n```\n",
+    );
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("`width=wide`"), "{}", stderr);
+}
+
+#[test]
+fn shards_images_by_their_hash() {
+    let fixture = Fixture::new().with_config("shard = true");
+    let image = images(&chapter(&fixture.render(), "Rendered")).remove(0);
+    let mut parts = image.split('/');
+    assert_eq!(parts.next(), Some("plantuml-diagrams"));
+    let shard = parts.next().unwrap();
+    let name = parts.next().unwrap();
+    assert_eq!(shard.len(), 2);
+    assert!(name.starts_with(shard), "{}", image);
+    assert!(fixture.root.join("src").join(&image).is_file());
+}
+
+#[test]
+fn mirrors_the_chapters_in_the_output_directory() {
+    let diagram = "```plantuml,render\n@startuml\nAlice -> Bob: Mirrored\n@enduml\n```\n";
+    let fixture = Fixture::new()
+        .with_config("mirror_chapters = true")
+        .with_chapter_at("Nested", "guide/deep/nested.md", diagram);
+    let images = images(&chapter(&fixture.render(), "Nested"));
+    assert!(
+        images[0].starts_with("plantuml-diagrams/guide/deep/nested/"),
+        "{}",
+        images[0]
+    );
+    assert!(fixture.root.join("src").join(&images[0]).is_file());
+}
+
+#[test]
+fn renders_in_details_only_after_a_blank_line() {
+    let diagram = "```plantuml,render\n@startuml\nAlice -> Bob: Details\n@enduml\n```\n";
+    let content = format!(
+        "<details>\n<summary>Swallowed</summary>\n{}</details>\n\n\
+         <details>\n<summary>Separated</summary>\n\n{}\n</details>\n",
+        diagram, diagram
+    );
+    let fixture = Fixture::new().with_chapter("Details", &content);
+    let output = fixture.run();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("inside an HTML block isn't rendered"),
+        "{}",
+        stderr
+    );
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let content = chapter(&book, "Details");
+    assert_eq!(images(&content).len(), 1, "{}", content);
+    assert_eq!(content.matches("@startuml").count(), 1, "{}", content);
+}
+
+#[test]
+fn renders_diagrams_in_nested_blocks() {
+    let content = "> Quoted:\n>\n> ```plantuml,render\n> Alice -> Bob: Quoted\n> ```\n\n\
+                   - Listed:\n\n  ```plantuml,render\n  Alice -> Bob: Listed\n  ```\n\n\
+                   Noted[^note].\n\n\
+                   [^note]: Footnote:\n\n    ```plantuml,render\n    Alice -> Bob: Noted\n    ```\n";
+    let fixture = Fixture::new().with_chapter("Nested", content);
+    let content = chapter(&fixture.render(), "Nested");
+    assert_eq!(images(&content).len(), 3, "{}", content);
+    assert!(!content.contains("Alice -> Bob"), "{}", content);
+    assert!(content.contains("> "), "{}", content);
+}
+
+#[test]
+fn syntax_errors_still_build_with_error_images() {
+    let fixture = Fixture::new();
+    let output = fixture.run();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("PlantUML failure occurred! It exited with exit status: 1"),
+        "{}",
+        stderr
+    );
+    // PlantUML renders its error message in place of the diagram
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let content = chapter(&book, "Syntax error");
+    assert_eq!(images(&content).len(), 1, "{}", content);
+}
+
+#[test]
+fn fails_on_syntax_errors_without_error_images() {
+    for config in &["", "batch = true", "pipe = true"] {
+        let fixture = Fixture::new().without_error_images().with_config(config);
+        let output = fixture.run();
+        assert!(!output.status.success(), "{}", config);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("Chapter `Syntax error`, diagram "),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("PlantUML failed. It exited with exit status: 1"),
+            "{}",
+            stderr
+        );
+        assert!(!stderr.contains("PlantUML failure occurred!"), "{}", stderr);
+        // No error image is left for the next build to take as up to date
+        assert!(!fixture.run().status.success(), "{}", config);
+    }
+}
+
+#[test]
+fn strict_fails_on_syntax_errors() {
+    for config in &["strict = true", "strict = true\nbatch = true"] {
+        let fixture = Fixture::new().with_config(config);
+        // The fixture's unknown attribute is taken out, so what strict fails on
+        // is PlantUML's failure
+        fs::write(
+            fixture.root.join("src/attributes.md"),
+            "```plantuml,render\nAlice -> Bob\n```\n",
+        )
+        .unwrap();
+        let output = fixture.run();
+        assert!(!output.status.success(), "{}", config);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("Chapter `Syntax error`, diagram "),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("exit status: 1"), "{}", stderr);
+        // No error image is left for the next build to take as up to date
+        assert!(!fixture.run().status.success(), "{}", config);
+    }
+}
+
+#[test]
+fn strict_fails_on_errors_plantuml_reports_while_exiting_successfully() {
+    let reported = |config: &str, warning: &str| {
+        let fixture = Fixture::new().with_config(config).with_chapter(
+            "Reported",
+            &format!(
+                "```plantuml,render\nAlice -> Bob\n' stderr: {}\n```\n",
+                warning
+            ),
+        );
+        // Only what PlantUML reports on stderr is left to fail on
+        fs::write(
+            fixture.root.join("src/attributes.md"),
+            "```plantuml,render\nAlice -> Bob\n```\n",
+        )
+        .unwrap();
+        fs::write(fixture.root.join("src/syntax_error.md"), "# Syntax error\n").unwrap();
+        fixture.run()
+    };
+    for config in &[
+        "strict = true",
+        "strict = true\nbatch = true",
+        "strict = true\npipe = true",
+    ] {
+        let output = reported(config, "Error line 2 in file: diagram.puml");
+        assert!(!output.status.success(), "{}", config);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("Chapter `Reported`, diagram "),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("PlantUML reported on stderr: Error line 2 in file: diagram.puml"),
+            "{}",
+            stderr
+        );
+        // Warnings are left to strict_stderr
+        let output = reported(config, "Font not found");
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    assert!(reported("", "Error line 2 in file: diagram.puml")
+        .status
+        .success());
+}
+
+#[test]
+fn shows_the_placeholder_for_missing_images() {
+    let fixture = Fixture::new().with_config("placeholder = \"rendering.svg\"");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_SILENT", "1");
+    });
+    assert!(output.status.success());
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let content = chapter(&book, "Rendered");
+    assert_eq!(images(&content), vec!["rendering.svg".to_string()]);
+    assert!(content.contains("Diagram not rendered yet"), "{}", content);
+}
+
+#[test]
+fn shows_the_source_of_missing_images_without_a_placeholder() {
+    let fixture = Fixture::new()
+        .with_config("fallback_language = \"text\"")
+        .with_chapter("Missing", "```plantuml,render\nAlice -> Bob: Lost\n```\n");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_SILENT", "1");
+    });
+    assert!(output.status.success());
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let content = chapter(&book, "Missing");
+    assert!(images(&content).is_empty(), "{}", content);
+    assert!(content.contains("```text"), "{}", content);
+    assert!(content.contains("Alice -> Bob: Lost"), "{}", content);
+}
+
+#[test]
+fn searches_the_chapter_and_base_dir_for_includes() {
+    let fixture = Fixture::new().with_config("base_dir = \"diagrams\"");
+    let log = fixture.root.join("plantuml.log");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_LOG", &log);
+    });
+    assert!(output.status.success());
+    let log = fs::read_to_string(log).unwrap();
+    let base_dir = fixture.root.join("diagrams");
+    let include_path = env::join_paths(&[fixture.root.join("src"), base_dir.clone()]).unwrap();
+    for invocation in log.lines() {
+        assert!(
+            invocation.contains(&format!("cwd={} ", base_dir.display())),
+            "{}",
+            invocation
+        );
+        assert!(
+            invocation.ends_with(&format!("include_path={}", include_path.to_string_lossy())),
+            "{}",
+            invocation
+        );
+    }
+}
+
+#[test]
+fn runs_plantuml_with_the_configured_environment() {
+    let fixture = Fixture::new();
+    let log = fixture.root.join("plantuml.log");
+    // The stub logs wherever its environment tells it to
+    let fixture = fixture.with_config(&format!(
+        "env = {{ PLANTUML_STUB_LOG = \"{}\", PLANTUML_INCLUDE_PATH = \"/nowhere\" }}",
+        log.display()
+    ));
+    fixture.render();
+    let log = fs::read_to_string(log).unwrap();
+    assert!(!log.is_empty());
+    // What the preprocessor sets itself takes precedence
+    assert!(!log.contains("include_path=/nowhere"), "{}", log);
+}
+
+#[test]
+fn limits_plantuml_processes() {
+    Fixture::new().with_config("max_processes = 1").render();
+    let output = Fixture::new().with_config("max_processes = 0").run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("max_processes has to be at least 1"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn renders_a_chapter_in_one_batch() {
+    let diagram = |message: &str| {
+        format!(
+            "```plantuml,render\n@startuml\nAlice -> Bob: {}\n@enduml\n```\n\n",
+            message
+        )
+    };
+    let content = format!("{}{}{}", diagram("One"), diagram("Two"), diagram("Three"));
+    let fixture = Fixture::new()
+        .with_config("batch = true")
+        .with_chapter("Batch", &content);
+    let log = fixture.root.join("plantuml.log");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_LOG", &log);
+    });
+    assert!(output.status.success());
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let images = images(&chapter(&book, "Batch"));
+    assert_eq!(images.len(), 3);
+    for image in &images {
+        assert!(fixture.root.join("src").join(image).is_file(), "{}", image);
+    }
+    let log = fs::read_to_string(log).unwrap();
+    let batches = log
+        .lines()
+        .filter(|invocation| invocation.matches(".puml").count() == 3)
+        .count();
+    assert_eq!(batches, 1, "{}", log);
+}
+
+#[test]
+fn splits_batches_too_long_for_one_command_line() {
+    let content: String = (0..400)
+        .map(|number| format!("```plantuml,render\nAlice -> Bob: {}\n```\n\n", number))
+        .collect();
+    let fixture = Fixture::new()
+        .with_config("batch = true")
+        .with_chapter("Many", &content);
+    let log = fixture.root.join("plantuml.log");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_LOG", &log);
+    });
+    assert!(output.status.success());
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    for image in images(&chapter(&book, "Many")) {
+        assert!(fixture.root.join("src").join(&image).is_file(), "{}", image);
+    }
+    let log = fs::read_to_string(log).unwrap();
+    let runs: Vec<usize> = log
+        .lines()
+        .map(|invocation| invocation.matches(".puml").count())
+        .filter(|sources| *sources > 10)
+        .collect();
+    assert!(runs.len() > 1, "{:?}", runs);
+    assert_eq!(runs.iter().sum::<usize>(), 400);
+}
+
+#[test]
+fn explains_itself_when_not_run_by_mdbook() {
+    let fixture = Fixture::new();
+    for input in &["", "not a book"] {
+        let mut child = fixture
+            .preprocessor()
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(output.status.code(), Some(2));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("[preprocessor.plantuml-renderer]"),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn logs_nothing_but_errors_when_quiet() {
+    let fixture = Fixture::new().with_config("quiet = true");
+    let output = fixture.run();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+
+    let output = Fixture::new().run_with(|command| {
+        command.arg("--quiet");
+    });
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+
+    // The fixture has a diagram with an unknown attribute
+    let output = Fixture::new()
+        .with_config("quiet = true\nstrict = true")
+        .run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("unknown fence attribute"), "{}", stderr);
+}
+
+#[test]
+fn logs_at_the_configured_level() {
+    let stderr = |config: &str, rust_log: Option<&str>| {
+        let output = Fixture::new().with_config(config).run_with(|command| {
+            command.env_remove("RUST_LOG");
+            if let Some(rust_log) = rust_log {
+                command.env("RUST_LOG", rust_log);
+            }
+        });
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+    let default = stderr("", None);
+    assert!(default.contains("Working Chapter"), "{}", default);
+    assert!(!default.contains("SHA1 hash sum"), "{}", default);
+    let debug = stderr("log_level = \"debug\"", None);
+    assert!(debug.contains("SHA1 hash sum"), "{}", debug);
+    let warn = stderr("log_level = \"warn\"", None);
+    assert!(!warn.contains("Working Chapter"), "{}", warn);
+    // RUST_LOG beats the option, directives for other crates don't count
+    let overridden = stderr(
+        "log_level = \"warn\"",
+        Some("mdbook_plantuml_renderer=debug"),
+    );
+    assert!(overridden.contains("SHA1 hash sum"), "{}", overridden);
+    let other = stderr("log_level = \"warn\"", Some("mdbook=trace"));
+    assert!(!other.contains("Working Chapter"), "{}", other);
+}
+
+#[test]
+fn finds_images_wherever_plantuml_put_them() {
+    for behaviour in &["input", "cwd"] {
+        let fixture = Fixture::new();
+        let log = fixture.root.join("plantuml.log");
+        let run = || {
+            let output = fixture.run_with(|command| {
+                command
+                    .env("PLANTUML_STUB_OUTPUT", behaviour)
+                    .env("PLANTUML_STUB_LOG", &log);
+            });
+            assert!(output.status.success());
+            serde_json::from_slice::<Value>(&output.stdout).unwrap()
+        };
+        let images = images(&chapter(&run(), "Rendered"));
+        assert!(
+            fixture.root.join("src").join(&images[0]).is_file(),
+            "{}: {}",
+            behaviour,
+            images[0]
+        );
+        // Found where it belongs, nothing is rendered again
+        fs::remove_file(&log).unwrap();
+        run();
+        assert!(!log.exists(), "{}", behaviour);
+    }
+}
+
+#[test]
+fn rejects_a_missing_base_dir() {
+    let fixture = Fixture::new().with_config("base_dir = \"missing\"");
+    assert!(!fixture.run().status.success());
+}
+
+#[test]
+fn warns_about_unknown_attributes() {
+    let fixture = Fixture::new();
+    let output = fixture.run();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("colour"), "{}", stderr);
+}
+
+#[test]
+fn carries_fence_attributes_onto_the_image() {
+    let fixture = Fixture::new();
+    let content = chapter(&fixture.render(), "Attributes");
+    // What a preprocessor running after this one would make of it
+    let mut reserialized = String::new();
+    cmark(Parser::new(&content), &mut reserialized, None).unwrap();
+    for content in &[content, reserialized] {
+        assert!(
+            content.contains("<img src=\"plantuml-diagrams/"),
+            "{}",
+            content
+        );
+        assert!(content.contains(r#"id="architecture""#), "{}", content);
+        assert!(content.contains(r#"class="wide bordered""#), "{}", content);
+        assert!(content.contains(r#"data-zoom="2""#), "{}", content);
+    }
+}
+
+#[test]
+fn anchors_diagrams_with_unique_ids() {
+    let diagram = "```plantuml,render\n@startuml\nAlice -> Bob: Twice\n@enduml\n```\n\n";
+    let fixture = Fixture::new()
+        .with_config("anchors = true\nanchor_prefix = \"figure-\"")
+        .with_chapter("Twice", &diagram.repeat(2));
+    let content = chapter(&fixture.render(), "Twice");
+    let images = images(&content);
+    let hash = Path::new(&images[0]).file_stem().unwrap().to_str().unwrap();
+    let id = format!("figure-{}", &hash[..8]);
+    assert!(
+        content.contains(&format!("<div id=\"{}\"><img src=", id)),
+        "{}",
+        content
+    );
+    assert!(
+        content.contains(&format!("<div id=\"{}-2\"><img src=", id)),
+        "{}",
+        content
+    );
+}
+
+#[test]
+fn strict_fails_on_unknown_attributes() {
+    let fixture = Fixture::new().with_config("strict = true");
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("colour"), "{}", stderr);
+}
+
+#[test]
+fn takes_alt_text_from_titles() {
+    let diagram = |title: &str| {
+        format!(
+            "```plantuml,render\n@startuml\n{}\nAlice -> Bob: Hello\n@enduml\n```\n",
+            title
+        )
+    };
+    let book = Fixture::new()
+        .with_config("alt_from_title = true")
+        .with_chapter("Single line", &diagram("title Sales Overview 2024"))
+        .with_chapter("Quoted", &diagram("title \"Bob's <b>café</b> <U+2615>\""))
+        .with_chapter(
+            "Multiple lines",
+            &diagram("title\n  Order **flow**\n  across teams\nend title"),
+        )
+        .render();
+    let alt_texts = [
+        ("Single line", "![Sales Overview 2024]("),
+        ("Quoted", "![Bob's café ☕]("),
+        ("Multiple lines", "![Order flow across teams]("),
+    ];
+    for (name, alt_text) in alt_texts {
+        let content = chapter(&book, name);
+        assert!(content.contains(alt_text), "{}", content);
+    }
+}
+
+#[test]
+fn fills_in_the_alt_text_template() {
+    let book = Fixture::new()
+        .with_config("alt_fallback = \"Diagram: {title}\"\nrequire_alt = true")
+        .with_chapter(
+            "Titled",
+            "```plantuml,render\ntitle Checkout\nAlice -> Bob\n```\n",
+        )
+        .with_chapter("Untitled", "```plantuml,render\nBob -> Alice\n```\n")
+        .render();
+    let content = chapter(&book, "Titled");
+    assert!(content.contains("![Diagram: Checkout]("), "{}", content);
+    let content = chapter(&book, "Untitled");
+    assert!(content.contains("![Diagram: Untitled]("), "{}", content);
+}
+
+#[test]
+fn titles_diagrams_from_files_after_their_name() {
+    let fixture = Fixture::new()
+        .with_config("title_from_filename = true")
+        .with_chapter(
+            "Files",
+            "```plantuml,render\nfile: ../diagrams/login-sequence.puml\n```\n\n\
+             ```plantuml,render\nfile: ../diagrams/checkout_flow.puml\n```\n",
+        );
+    fs::write(
+        fixture.root.join("diagrams/login-sequence.puml"),
+        "@startuml\nAlice -> Bob: Login\n@enduml\n",
+    )
+    .unwrap();
+    fs::write(
+        fixture.root.join("diagrams/checkout_flow.puml"),
+        "@startuml\ntitle Paying\nAlice -> Bob: Pay\n@enduml\n",
+    )
+    .unwrap();
+    let content = chapter(&fixture.render(), "Files");
+    assert!(content.contains("![Login Sequence]("), "{}", content);
+    // A diagram's own title comes first
+    assert!(content.contains("![Paying]("), "{}", content);
+}
+
+#[test]
+fn takes_captions_from_title_comments() {
+    let fixture = Fixture::new()
+        .with_chapter(
+            "Captioned",
+            "```plantuml,render\n'# title: Login flow\nAlice -> Bob: Login\n```\n",
+        )
+        .with_chapter("Plain", "```plantuml,render\nAlice -> Bob: Login\n```\n");
+    let book = fixture.render();
+    let content = chapter(&book, "Captioned");
+    assert!(
+        content.contains("![Login flow](") && content.contains("\"Login flow\")"),
+        "{}",
+        content
+    );
+    // The caption is left out of the diagram, so it's the same image
+    let image = images(&content).remove(0);
+    assert_eq!(images(&chapter(&book, "Plain")), vec![image.clone()]);
+    let source = fs::read_to_string(
+        fixture
+            .root
+            .join("src")
+            .join(image.replace(".svg", ".puml")),
+    )
+    .unwrap();
+    assert!(!source.contains("title:"), "{}", source);
+}
+
+#[test]
+fn renders_raster_images_at_every_srcset_scale() {
+    let fixture = Fixture::new()
+        .with_config("output_format = \"png\"\nsrcset_scales = [1, 2]")
+        .with_chapter("Sharp", "```plantuml,render\nAlice -> Bob: Sharp\n```\n");
+    let content = chapter(&fixture.render(), "Sharp");
+    let image = images(&content).remove(0);
+    let variant = image.replace(".png", "@2x.png");
+    assert!(
+        content.contains(&format!("srcset=\"{} 1x, {} 2x\"", image, variant)),
+        "{}",
+        content
+    );
+    let src = fixture.root.join("src");
+    assert!(src.join(&variant).is_file());
+    let source = fs::read_to_string(src.join(variant.replace(".png", ".puml"))).unwrap();
+    assert!(source.contains("skinparam dpi 192"), "{}", source);
+    assert!(
+        !fs::read_to_string(src.join(image.replace(".png", ".puml")))
+            .unwrap()
+            .contains("skinparam dpi")
+    );
+
+    // An SVG is sharp at any size
+    let content = chapter(
+        &Fixture::new()
+            .with_config("srcset_scales = [1, 2]")
+            .with_chapter("Sharp", "```plantuml,render\nAlice -> Bob: Sharp\n```\n")
+            .render(),
+        "Sharp",
+    );
+    assert!(!content.contains("srcset"), "{}", content);
+}
+
+#[test]
+fn marks_where_diagrams_are_if_asked() {
+    let book = Fixture::new().with_config("emit_markers = true").render();
+    let content = chapter(&book, "Rendered");
+    let image = images(&content).remove(0);
+    let hash = Path::new(&image).file_stem().unwrap().to_str().unwrap();
+    let begin = content
+        .find(&format!("<!-- plantuml-renderer:begin hash={} -->", hash))
+        .expect(&content);
+    let end = content
+        .find("<!-- plantuml-renderer:end -->")
+        .expect(&content);
+    let image = content.find(&image).unwrap();
+    assert!(begin < image && image < end, "{}", content);
+    // It stays a markdown image, not part of the comment
+    assert!(
+        Parser::new(&content).any(|event| matches!(
+            event,
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Image(..))
+        )),
+        "{}",
+        content
+    );
+}
+
+#[test]
+fn appends_a_gallery_of_every_diagram() {
+    let copy = fs::read_to_string(fixtures().join("book/src/rendered.md")).unwrap();
+    let book = Fixture::new()
+        .with_config("gallery = true")
+        .with_chapter("Copy", &copy.replace("# Rendered", "# Copy"))
+        .render();
+    let gallery = chapter(&book, "Diagram Gallery");
+    let rendered = images(&chapter(&book, "Rendered")).remove(0);
+    assert_eq!(gallery.matches(&rendered).count(), 1, "{}", gallery);
+    assert!(
+        gallery.contains("Used in [Rendered](rendered.md), [Copy](copy.md)"),
+        "{}",
+        gallery
+    );
+    let sections = book["sections"].as_array().unwrap();
+    let last = &sections.last().unwrap()["Chapter"];
+    assert_eq!(last["path"].as_str(), Some("plantuml-gallery.md"));
+}
+
+#[test]
+fn fails_on_malformed_svgs_if_asked() {
+    // Rendered ahead with `parallel`, the chapter still fails on it
+    for config in &[
+        "validate_svg = true",
+        "validate_svg = true\nparallel = true",
+    ] {
+        let fixture = Fixture::new().with_config(config).with_chapter(
+            "Malformed",
+            "```plantuml,render\nAlice -> Bob: Fine\n```\n\n\
+             ```plantuml,render\n' truncated\nAlice -> Bob: Cut off\n```\n",
+        );
+        let output = fixture.run();
+        assert!(!output.status.success(), "{}", config);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("Chapter `Malformed`, diagram"),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("malformed SVG"), "{}", stderr);
+    }
+}
+
+#[test]
+fn runs_the_post_render_command_on_rendered_images() {
+    let fixture = Fixture::new()
+        .with_config("post_render = [\"sh\", \"-c\", \"echo \\\"$0\\\" >> post_render.log\"]")
+        .with_chapter("Post", "```plantuml,render\nAlice -> Bob: Optimized\n```\n");
+    let content = chapter(&fixture.render(), "Post");
+    let image = images(&content).remove(0);
+    let log = fixture.root.join("post_render.log");
+    let runs = fs::read_to_string(&log).unwrap();
+    assert!(runs.lines().any(|line| line.ends_with(&image)), "{}", runs);
+    // Cached images were already taken care of
+    fixture.render();
+    assert_eq!(fs::read_to_string(&log).unwrap(), runs);
+
+    let fixture = Fixture::new()
+        .with_config("post_render = [\"sh\", \"-c\", \"echo broken >&2; exit 3\"]")
+        .with_chapter("Post", "```plantuml,render\nAlice -> Bob: Optimized\n```\n");
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Chapter `Post`"), "{}", stderr);
+    assert!(stderr.contains("broken"), "{}", stderr);
+}
+
+/// Answers like a PlantUML `-picoweb` server on a port of its own, keeping the
+/// request line and body of every diagram posted to it. A diagram with a
+/// `' server error` line gets an error image, with the header saying why.
+fn fake_plantuml_server() -> (u16, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let received = Arc::clone(&requests);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            // Until the body is in, or the connection was only to see it's up
+            loop {
+                let read = stream.read(&mut buffer).unwrap_or(0);
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                let complete = text.find("\r\n\r\n").is_some_and(|end| {
+                    let length = text
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .and_then(|length| length.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    request.len() >= end + 4 + length
+                });
+                if read == 0 || complete {
+                    break;
+                }
+            }
+            if request.is_empty() {
+                continue;
+            }
+            received
+                .lock()
+                .unwrap()
+                .push(String::from_utf8_lossy(&request).to_string());
+            let svg = "<svg xmlns=\"http://www.w3.org/2000/svg\"><!-- served --></svg>";
+            let status = if String::from_utf8_lossy(&request).contains("' server error") {
+                "400 Bad Request\r\nX-PlantUML-Diagram-Error: Syntax Error?"
+            } else {
+                "200 OK"
+            };
+            write!(
+                stream,
+                "HTTP/1.0 {}\r\nContent-Type: image/svg+xml\r\nContent-Length: {}\r\n\r\n{}",
+                status,
+                svg.len(),
+                svg
+            )
+            .unwrap();
+        }
+    });
+    (port, requests)
+}
+
+#[test]
+fn renders_through_the_managed_server() {
+    let fixture = Fixture::new()
+        .with_config("server_mode = \"managed\"")
+        .with_chapter("Served", "```plantuml,render\nAlice -> Bob: Served\n```\n");
+    let (port, requests) = fake_plantuml_server();
+    // Left running by an earlier build
+    fs::write(
+        fixture.root.join(".plantuml-renderer-server"),
+        format!("{} {}\n", process::id(), port),
+    )
+    .unwrap();
+    let log = fixture.root.join("plantuml.log");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_LOG", &log);
+    });
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let image = images(&chapter(&book, "Served")).remove(0);
+    let svg = fs::read_to_string(fixture.root.join("src").join(image)).unwrap();
+    assert!(svg.contains("<!-- served -->"), "{}", svg);
+    let requests = requests.lock().unwrap();
+    assert!(
+        requests
+            .iter()
+            .any(|request| request.starts_with("POST /plantuml/svg ")
+                && request.contains("Alice -> Bob: Served")),
+        "{:?}",
+        requests
+    );
+    // Nothing was started, the server was there already
+    assert!(!log.exists(), "{}", fs::read_to_string(&log).unwrap());
+}
+
+#[test]
+fn renders_through_a_remote_server() {
+    let (port, requests) = fake_plantuml_server();
+    let fixture = Fixture::new()
+        .with_config(&format!(
+            "server_url = \"http://127.0.0.1:{}/plantuml\"",
+            port
+        ))
+        .with_chapter("Remote", "```plantuml,render\nAlice -> Bob: Remote\n```\n");
+    let log = fixture.root.join("plantuml.log");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_LOG", &log);
+    });
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let image = images(&chapter(&book, "Remote")).remove(0);
+    let svg = fs::read_to_string(fixture.root.join("src").join(image)).unwrap();
+    assert!(svg.contains("<!-- served -->"), "{}", svg);
+    // PlantUML isn't run here
+    assert!(!log.exists());
+    let requests = requests.lock().unwrap();
+    let path = requests
+        .iter()
+        .find_map(|request| request.strip_prefix("GET /plantuml/svg/"))
+        .and_then(|request| request.split(' ').next())
+        .expect("The diagram should be fetched");
+    // Deflated into a stored block, after the five bytes of its header
+    let alphabet = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz-_";
+    let (mut bits, mut count, mut deflated) = (0u32, 0, Vec::new());
+    for digit in path.chars() {
+        bits = (bits << 6) | alphabet.find(digit).unwrap() as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            deflated.push((bits >> count) as u8);
+        }
+    }
+    let source = String::from_utf8_lossy(&deflated[5..]);
+    assert!(source.contains("Alice -> Bob: Remote"), "{}", source);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buffer = [0; 4096];
+            let _ = stream.read(&mut buffer);
+            let _ = stream.write_all(b"HTTP/1.0 500 Internal Server Error\r\n\r\nOut of memory");
+        }
+    });
+    let fixture = Fixture::new()
+        .with_config(&format!("server_url = \"http://127.0.0.1:{}\"", port))
+        .with_chapter("Remote", "```plantuml,render\nAlice -> Bob: Failed\n```\n");
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("500 Internal Server Error"), "{}", stderr);
+    assert!(stderr.contains("Out of memory"), "{}", stderr);
+}
+
+#[test]
+fn strict_fails_on_diagrams_the_server_draws_an_error_image_for() {
+    let served = |config: &str| {
+        let (port, _) = fake_plantuml_server();
+        let fixture = Fixture::new()
+            .with_config("server_mode = \"managed\"")
+            .with_config(config)
+            .with_chapter(
+                "Served",
+                "```plantuml,render\nAlice -> Bob\n' server error\n```\n",
+            );
+        fs::write(
+            fixture.root.join(".plantuml-renderer-server"),
+            format!("{} {}\n", process::id(), port),
+        )
+        .unwrap();
+        // The fixture's unknown attribute is taken out, so what strict fails on
+        // is PlantUML's failure
+        fs::write(
+            fixture.root.join("src/attributes.md"),
+            "```plantuml,render\nAlice -> Bob\n```\n",
+        )
+        .unwrap();
+        fixture
+    };
+
+    let fixture = served("strict = true");
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Chapter `Served`, diagram "), "{}", stderr);
+    assert!(
+        stderr.contains("PlantUML failed. Syntax Error?"),
+        "{}",
+        stderr
+    );
+    // No error image is left for the next build to take as up to date
+    assert!(!fixture.run().status.success());
+
+    // The fixture's error_images shows it, with a warning
+    let fixture = served("");
+    let output = fixture.run();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("PlantUML failure occurred! Syntax Error?"),
+        "{}",
+        stderr
+    );
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(images(&chapter(&book, "Served")).len(), 1);
+}
+
+#[test]
+fn stops_the_managed_server() {
+    let fixture = Fixture::new();
+    let mut server = Command::new("sleep").arg("30").spawn().unwrap();
+    let pidfile = fixture.root.join(".plantuml-renderer-server");
+    fs::write(&pidfile, format!("{} 1\n", server.id())).unwrap();
+    let output = fixture
+        .preprocessor()
+        .arg("stop-server")
+        .arg(&fixture.root)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("Stopped the PlantUML server"),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    assert!(!server.wait().unwrap().success());
+    assert!(!pidfile.exists());
+}
+
+#[test]
+fn runs_plantuml_with_a_fixed_charset_and_locale_if_deterministic() {
+    let fixture = Fixture::new().with_config("deterministic = true");
+    let log = fixture.root.join("plantuml.log");
+    let output = fixture.run_with(|command| {
+        command
+            .env("PLANTUML_STUB_LOG", &log)
+            .env("LC_ALL", "de_DE.UTF-8");
+    });
+    assert!(output.status.success());
+    let log = fs::read_to_string(&log).unwrap();
+    for invocation in log.lines() {
+        assert!(invocation.starts_with("-charset UTF-8 "), "{}", log);
+        assert!(invocation.contains("| lc_all=C |"), "{}", log);
+    }
+    let source = fs::read_to_string(
+        fixture
+            .root
+            .join("src")
+            .join(&images(&chapter(&fixture.render(), "Rendered"))[0])
+            .with_extension("puml"),
+    )
+    .unwrap();
+    assert!(source.contains("!pragma layout smetana"), "{}", source);
+}
+
+#[test]
+fn runs_plantuml_with_the_arguments_of_its_command() {
+    let fixture = Fixture::new()
+        .with_config("plantuml_command = [\"tools/java\", \"-jar\", \"tools/plantuml.jar\"]");
+    let tools = fixture.root.join("tools");
+    fs::create_dir_all(&tools).unwrap();
+    let java = tools.join("java");
+    fs::write(
+        &java,
+        format!(
+            "#!/bin/sh\necho \"java $1 $2\" >> \"$PLANTUML_STUB_LOG\"\nshift 2\nexec {} \"$@\"\n",
+            fixtures().join("bin/plantuml").display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&java, fs::Permissions::from_mode(0o755)).unwrap();
+    let log = fixture.root.join("plantuml.log");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_LOG", &log);
+    });
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let log = fs::read_to_string(&log).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    for invocation in lines.chunks(2) {
+        assert_eq!(invocation[0], "java -jar tools/plantuml.jar", "{}", log);
+    }
+    // What the preprocessor passes comes after
+    assert!(log.contains("-tsvg"), "{}", log);
+    let image = images(&chapter(&fixture.render(), "Rendered")).remove(0);
+    assert!(fixture.root.join("src").join(image).is_file());
+}
+
+#[test]
+fn tries_plantuml_commands_in_order() {
+    let fixture = Fixture::new()
+        .with_config("commands = [\"tools/missing\", \"tools/broken\", \"tools/plantuml\"]");
+    let tools = fixture.root.join("tools");
+    fs::create_dir_all(&tools).unwrap();
+    let script = |name: &str, body: &str| {
+        let path = tools.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    };
+    script("broken", "exit 1");
+    script(
+        "plantuml",
+        &format!(
+            "echo picked >> \"$PLANTUML_STUB_LOG\"\nexec {} \"$@\"",
+            fixtures().join("bin/plantuml").display()
+        ),
+    );
+    let log = fixture.root.join("plantuml.log");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_LOG", &log);
+    });
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("Using PlantUML at"), "{}", stderr);
+    assert!(stderr.contains("tools/plantuml"), "{}", stderr);
+    let log = fs::read_to_string(&log).unwrap();
+    let mut invocations = log.lines();
+    assert_eq!(invocations.next(), Some("picked"));
+    assert!(
+        invocations.next().unwrap().starts_with("-version"),
+        "{}",
+        log
+    );
+    assert!(log.matches("picked").count() > 1, "{}", log);
+}
+
+#[test]
+fn warns_about_a_build_directory_inside_src() {
+    let build_dir = "[build]\nbuild-dir = \"src/book\"";
+    let fixture = Fixture::new().with_config(build_dir);
+    let output = fixture.run();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("is inside the source directory"),
+        "{}",
+        stderr
+    );
+
+    let fixture = Fixture::new().with_config(&format!("strict = true\n{}", build_dir));
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Move `build-dir` out of `src`"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn rejects_invalid_configuration() {
+    let fixture = Fixture::new().with_config("output_format = \"gif\"");
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Invalid [preprocessor.plantuml-renderer] configuration"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn reads_the_table_of_an_overridden_name() {
+    let fixture = Fixture::new().with_config("[preprocessor.print]\nstrict = true");
+    assert!(fixture.run().status.success());
+    let output = fixture.run_with(|command| {
+        command.args(["--name", "print"]);
+    });
+    assert!(!output.status.success());
+}
+
+#[test]
+fn prints_its_version_and_the_plantuml_version() {
+    let fixture = Fixture::new();
+    let output = fixture
+        .preprocessor()
+        .arg("version")
+        .arg(&fixture.root)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.starts_with(&format!(
+            "mdbook-plantuml-renderer {} (",
+            env!("CARGO_PKG_VERSION")
+        )),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("PlantUML version 1.2020.0 (stub)"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn renders_again_when_options_changing_images_change() {
+    let image = |config: &str| {
+        let book = Fixture::new().with_config(config).render();
+        images(&chapter(&book, "Rendered")).remove(0)
+    };
+    let default = image("");
+    assert_eq!(image("quiet = true\nmax_processes = 2"), default);
+    assert_ne!(image("trim = true"), default);
+    assert_ne!(image("env = { LANG = \"de_DE.UTF-8\" }"), default);
+}
+
+#[test]
+fn links_images_in_place_for_the_listed_renderers() {
+    let fixture = Fixture::new()
+        .with_config("images_in_place = [\"markdown\"]")
+        .with_chapter_at(
+            "Nested",
+            "guide/nested.md",
+            "```plantuml,render\nAlice -> Bob: Nested\n```\n",
+        );
+    let run = |renderer: &str| {
+        let output = fixture.run_for(renderer, |_| {});
+        assert!(output.status.success());
+        serde_json::from_slice::<Value>(&output.stdout).unwrap()
+    };
+    let markdown = run("markdown");
+    let html = run("html");
+    for (name, prefix) in [("Rendered", "../src/"), ("Nested", "../../src/")] {
+        let image = images(&chapter(&html, name)).remove(0);
+        assert_eq!(
+            images(&chapter(&markdown, name)),
+            vec![format!("{}{}", prefix, image)]
+        );
+    }
+}
+
+#[test]
+fn keeps_ids_apart_for_a_repeated_diagram() {
+    let copy =
+        "```plantuml,render long_description=\"Alice says hello\"\nAlice -> Bob: Hello\n```\n";
+    let fixture = Fixture::new().with_chapter("Copies", &format!("{}\n{}", copy, copy));
+    let log = fixture.root.join("plantuml.log");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_LOG", &log);
+    });
+    assert!(output.status.success());
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let content = chapter(&book, "Copies");
+    let images = images(&content);
+    assert_eq!(images.len(), 2, "{}", content);
+    assert_eq!(images[0], images[1]);
+    let hash = Path::new(&images[0]).file_stem().unwrap().to_string_lossy()[..8].to_string();
+    let id = format!("diagram-description-{}", hash);
+    assert!(content.contains(&format!("id=\"{}\"", id)), "{}", content);
+    assert!(content.contains(&format!("id=\"{}-2\"", id)), "{}", content);
+    let log = fs::read_to_string(&log).unwrap();
+    assert_eq!(log.matches(&hash).count(), 1, "{}", log);
+}
+
+#[test]
+fn prerenders_without_emitting_the_book() {
+    let fixture = Fixture::new();
+    let prerender = || {
+        let output = fixture
+            .preprocessor()
+            .arg("prerender")
+            .arg(&fixture.root)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let first = prerender();
+    assert!(first.starts_with("Rendered "), "{}", first);
+    assert!(!first.starts_with("Rendered 0 "), "{}", first);
+    let second = prerender();
+    assert!(second.starts_with("Rendered 0 diagrams"), "{}", second);
+    // The cache is then used by the actual build
+    let content = chapter(&fixture.render(), "Rendered");
+    assert!(fixture
+        .root
+        .join("src")
+        .join(&images(&content)[0])
+        .is_file());
+}
+
+#[test]
+fn checks_images_against_their_diagrams() {
+    let fixture = Fixture::new();
+    let check = || {
+        fixture
+            .preprocessor()
+            .arg("check")
+            .arg(&fixture.root)
+            .output()
+            .unwrap()
+    };
+    let output = check();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("missing: "));
+
+    let image = fixture
+        .root
+        .join("src")
+        .join(&images(&chapter(&fixture.render(), "Rendered"))[0]);
+    let output = check();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    fs::write(&image, "<svg/>").unwrap();
+    let output = check();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(&format!("changed: {}", image.display())),
+        "{}",
+        stdout
+    );
+    // Checking doesn't touch the book
+    assert_eq!(fs::read_to_string(&image).unwrap(), "<svg/>");
+    assert!(!fixture.root.join(".plantuml-renderer-check").exists());
+}
+
+#[test]
+fn checks_committed_images_for_ci() {
+    let fixture = Fixture::new();
+    let ci_check = |ignore_metadata: bool| {
+        let mut command = fixture.preprocessor();
+        command.arg("ci-check").arg(&fixture.root);
+        if ignore_metadata {
+            command.arg("--ignore-metadata");
+        }
+        command.output().unwrap()
+    };
+    let image = fixture
+        .root
+        .join("src")
+        .join(&images(&chapter(&fixture.render(), "Rendered"))[0]);
+    let output = ci_check(false);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    // Only the structure of an SVG counts
+    let svg = fs::read_to_string(&image).unwrap();
+    fs::write(&image, svg.replace("><", ">\n  <")).unwrap();
+    assert!(ci_check(false).status.success());
+    fs::write(&image, svg.replace("><", "><!-- edited --><")).unwrap();
+    let output = ci_check(false);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(&format!("changed: {}", image.display())),
+        "{}",
+        stdout
+    );
+    assert!(ci_check(true).status.success());
+
+    let stale = image.with_file_name(format!("{}.svg", "0".repeat(40)));
+    fs::write(&stale, &svg).unwrap();
+    let output = ci_check(true);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(&format!("removed: {}", stale.display())),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("0 missing, 0 changed, 1 removed"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn compares_cached_images_with_fresh_renders() {
+    let fixture = Fixture::new();
+    let image = fixture
+        .root
+        .join("src")
+        .join(&images(&chapter(&fixture.render(), "Rendered"))[0]);
+    fs::write(&image, "<svg/>").unwrap();
+    let report = fixture.root.join("report");
+    let output = fixture
+        .preprocessor()
+        .arg("compare")
+        .arg(&fixture.root)
+        .arg("--report")
+        .arg(&report)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(&format!("changed: {}", image.display())),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("1 diagrams render differently"),
+        "{}",
+        stdout
+    );
+    let index = fs::read_to_string(report.join("index.html")).unwrap();
+    let file_name = image.file_name().unwrap().to_str().unwrap();
+    assert!(
+        index.contains(&format!("cached/plantuml-diagrams/{}", file_name)),
+        "{}",
+        index
+    );
+    assert_eq!(
+        fs::read_to_string(report.join("cached/plantuml-diagrams").join(file_name)).unwrap(),
+        "<svg/>"
+    );
+    assert!(report
+        .join("fresh/plantuml-diagrams")
+        .join(file_name)
+        .is_file());
+    // Comparing doesn't touch the book
+    assert_eq!(fs::read_to_string(&image).unwrap(), "<svg/>");
+}
+
+#[test]
+fn validates_diagrams_without_rendering() {
+    let fixture = Fixture::new();
+    let validate = || {
+        fixture
+            .preprocessor()
+            .arg("validate")
+            .arg(&fixture.root)
+            .output()
+            .unwrap()
+    };
+    let output = validate();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // The fence of the incomplete diagram is on the third line of its chapter
+    assert!(
+        stdout.contains("Chapter `Syntax error`, line 3: Error line 1"),
+        "{}",
+        stdout
+    );
+    assert!(!stdout.contains("Chapter `Rendered`"), "{}", stdout);
+    assert!(!fixture.root.join("src/plantuml-diagrams").exists());
+    assert!(!fixture.root.join(".plantuml-renderer-validate").exists());
+
+    fs::write(fixture.root.join("src/syntax_error.md"), "# Syntax error\n").unwrap();
+    let output = validate();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn validates_diagrams_with_their_includes_resolved() {
+    let resolver = "include_resolver = [\"sh\", \"-c\", \"echo \\\"$0\\\" >> resolver.log; \
+                    echo diagrams/style.iuml\"]";
+    let fixture = Fixture::new().with_config(resolver).with_chapter(
+        "Shared",
+        "# Shared\n\n```plantuml,render\n!include @shared/style.iuml\nA -> B\n```\n",
+    );
+    fs::write(fixture.root.join("src/syntax_error.md"), "# Syntax error\n").unwrap();
+    fs::write(
+        fixture.root.join("diagrams/style.iuml"),
+        "skinparam monochrome true\n",
+    )
+    .unwrap();
+    let validate = |fixture: &Fixture| {
+        fixture
+            .preprocessor()
+            .arg("validate")
+            .arg(&fixture.root)
+            .output()
+            .unwrap()
+    };
+    let output = validate(&fixture);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    let log = fs::read_to_string(fixture.root.join("resolver.log")).unwrap();
+    assert_eq!(log, "@shared/style.iuml\n");
+
+    // An include the resolver can't resolve fails where the build would
+    let fixture = Fixture::new()
+        .with_config("include_resolver = [\"sh\", \"-c\", \"echo missing.iuml\"]")
+        .with_chapter(
+            "Shared",
+            "# Shared\n\n```plantuml,render\n!include @shared/style.iuml\nA -> B\n```\n",
+        );
+    fs::write(fixture.root.join("src/syntax_error.md"), "# Syntax error\n").unwrap();
+    let output = validate(&fixture);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Chapter `Shared`, line 3: "), "{}", stdout);
+    assert!(stdout.contains("which isn't a file"), "{}", stdout);
+}
+
+#[test]
+fn renders_a_single_diagram_outside_of_mdbook() {
+    let fixture = Fixture::new();
+    let log = fixture.root.join("plantuml.log");
+    let mut child = fixture
+        .preprocessor()
+        .arg("render-one")
+        .env("PLANTUML_STUB_LOG", &log)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"Alice -> Bob\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(output.stdout.starts_with(b"<svg"), "{:?}", output.stdout);
+    assert!(fs::read_to_string(&log).unwrap().contains("-tsvg"));
+    // Nothing is left in the book
+    assert!(!fixture.root.join("src/plantuml-diagrams").exists());
+    assert!(!fixture.root.join(".plantuml-renderer-render-one").exists());
+
+    let input = fixture.root.join("login.puml");
+    fs::write(&input, "@startuml\nAlice -> Bob\n@enduml\n").unwrap();
+    let image = fixture.root.join("login.png");
+    let output = fixture
+        .preprocessor()
+        .arg("render-one")
+        .arg(&input)
+        .arg("-o")
+        .arg(&image)
+        .args(["--format", "png"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(image.is_file());
+
+    let output = fixture
+        .preprocessor()
+        .arg("render-one")
+        .arg(&input)
+        .args(["--format", "gif"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn leaves_chapters_without_diagrams_as_they_are() {
+    // Written out again, the heading would become `# Generated`
+    let generated = format!("Generated\n=========\n\n{}", "* an item\n".repeat(5000));
+    let fixture = Fixture::new().with_chapter("Generated", &generated);
+    let output = fixture.run();
+    assert!(output.status.success());
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(chapter(&book, "Generated"), generated);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("couldn't have any and were skipped"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn emits_reference_style_images_if_asked() {
+    let content = "```plantuml,render description=\"Login [step 1]\"\nAlice -> Bob\n```\n\n\
+                   ```plantuml,render\nBob -> Alice\n```\n\n\
+                   ```plantuml,render description=\"Again\"\nAlice -> Bob\n```\n";
+    let fixture = Fixture::new()
+        .with_config("reference_links = true")
+        .with_chapter("References", content);
+    let content = chapter(&fixture.render(), "References");
+    assert!(!content.contains("]("), "{}", content);
+    let definitions: Vec<&str> = content
+        .lines()
+        .filter(|line| line.starts_with("[plantuml-"))
+        .collect();
+    // The diagram shown twice has a single label
+    assert_eq!(definitions.len(), 2, "{}", content);
+    assert!(content.trim_end().ends_with(definitions[1]), "{}", content);
+    assert!(
+        content.contains("![Login \\[step 1\\]][plantuml-"),
+        "{}",
+        content
+    );
+    // What the renderer and later preprocessors make of it
+    let images: Vec<(String, String)> = {
+        let mut images = Vec::new();
+        let mut alt = None;
+        for event in Parser::new(&content) {
+            match event {
+                pulldown_cmark::Event::Start(pulldown_cmark::Tag::Image(_, url, _)) => {
+                    alt = Some((String::new(), url.to_string()))
+                }
+                pulldown_cmark::Event::Text(text) => {
+                    if let Some((alt, _)) = alt.as_mut() {
+                        alt.push_str(&text);
+                    }
+                }
+                pulldown_cmark::Event::End(pulldown_cmark::Tag::Image(..)) => {
+                    images.extend(alt.take());
+                }
+                _ => {}
+            }
+        }
+        images
+    };
+    assert_eq!(images.len(), 3, "{}", content);
+    assert_eq!(images[0].0, "Login [step 1]");
+    assert_eq!(images[0].1, images[2].1);
+    assert_ne!(images[0].1, images[1].1);
+    assert!(fixture.root.join("src").join(&images[1].1).is_file());
+}
+
+#[test]
+fn only_warns_about_failing_diagrams_in_lenient_chapters() {
+    let broken = "```plantuml,render use=missing\nAlice -> Bob\n```\n";
+    let fixture = Fixture::new()
+        .with_config("lenient_chapters = [\"drafts/*\"]")
+        .with_chapter_at("Draft", "drafts/draft.md", broken);
+    let output = fixture.run();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("Chapter `Draft` is in lenient_chapters"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("`use=missing` names no"), "{}", stderr);
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(chapter(&book, "Draft").contains("Alice -> Bob"));
+
+    let fixture = Fixture::new()
+        .with_config("lenient_chapters = [\"drafts/*\"]")
+        .with_chapter_at("Draft", "drafts/draft.md", broken)
+        .with_chapter("Final", broken);
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Chapter `Final`"), "{}", stderr);
+}
+
+#[test]
+fn embeds_the_source_in_the_image_if_asked() {
+    let fixture = Fixture::new()
+        .with_config("embed_source_attr = true")
+        .with_chapter(
+            "Sourced",
+            "```plantuml,render\nAlice -> Bob: \"Hi\"\n\nBob -> Alice\n```\n",
+        );
+    let content = chapter(&fixture.render(), "Sourced");
+    let images = images(&content);
+    assert_eq!(images.len(), 1, "{}", content);
+    let start =
+        content.find("data-plantuml-source=\"").expect(&content) + "data-plantuml-source=\"".len();
+    let end = start + content[start..].find('"').unwrap();
+    let attribute = &content[start..end];
+    // On one line, so markdown keeps it a single piece of HTML
+    assert!(!attribute.contains('\n'), "{}", attribute);
+    let source = attribute
+        .replace("&#10;", "\n")
+        .replace("&quot;", "\"")
+        .replace("&gt;", ">")
+        .replace("&lt;", "<")
+        .replace("&amp;", "&");
+    let rendered = fs::read_to_string(
+        fixture
+            .root
+            .join("src")
+            .join(&images[0])
+            .with_extension("puml"),
+    )
+    .unwrap();
+    assert_eq!(source, rendered);
+
+    let content = chapter(&Fixture::new().render(), "Rendered");
+    assert!(!content.contains("data-plantuml-source"), "{}", content);
+}
+
+#[test]
+fn supports_renderers() {
+    let supports = |fixture: &Fixture, renderer: &str| {
+        fixture
+            .preprocessor()
+            .args(["supports", renderer])
+            .status()
+            .unwrap()
+            .success()
+    };
+    let fixture = Fixture::new();
+    assert!(supports(&fixture, "html"));
+    assert!(supports(&fixture, "epub"));
+    // Left to skip_renderers
+    assert!(!supports(&fixture, "test"));
+    let fixture = Fixture::new().with_config("skip_renderers = [\"pdf\"]");
+    assert!(supports(&fixture, "test"));
+    assert!(!supports(&fixture, "pdf"));
+    let status = fixture.preprocessor().arg("supports").status().unwrap();
+    assert!(!status.success());
+    // The book can be named when it isn't where it runs
+    let status = fixture
+        .preprocessor()
+        .current_dir(env::temp_dir())
+        .args(["supports", "pdf"])
+        .arg(&fixture.root)
+        .status()
+        .unwrap();
+    assert!(!status.success());
+
+    // Options which can't be read are reported, the build then fails on them
+    let fixture = Fixture::new().with_config("skip_renderers = 3");
+    let output = fixture
+        .preprocessor()
+        .args(["supports", "test"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Invalid [preprocessor.plantuml-renderer] configuration"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn removes_stale_images_before_the_build_if_asked() {
+    let fixture = Fixture::new().with_config("clean_before_build = true");
+    let directory = fixture.root.join("src/plantuml-diagrams");
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(directory.join("stale.svg"), "<svg/>").unwrap();
+    let image = images(&chapter(&fixture.render(), "Rendered")).remove(0);
+    assert!(!directory.join("stale.svg").exists());
+    assert!(fixture.root.join("src").join(&image).is_file(), "{}", image);
+
+    // It's kept without the option
+    let fixture = Fixture::new();
+    let directory = fixture.root.join("src/plantuml-diagrams");
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(directory.join("stale.svg"), "<svg/>").unwrap();
+    fixture.render();
+    assert!(directory.join("stale.svg").exists());
+}
+
+#[test]
+fn hashes_what_included_files_hold() {
+    let fixture = Fixture::new().with_chapter(
+        "Styled",
+        "```plantuml,render\n!include shared/style.iuml\nAlice -> Bob\n```\n",
+    );
+    let shared = fixture.root.join("src/shared");
+    fs::create_dir_all(&shared).unwrap();
+    fs::write(shared.join("style.iuml"), "!include colors.iuml\n").unwrap();
+    fs::write(
+        shared.join("colors.iuml"),
+        "skinparam backgroundColor white\n",
+    )
+    .unwrap();
+    let render = || images(&chapter(&fixture.render(), "Styled")).remove(0);
+    let first = render();
+    assert_eq!(render(), first);
+
+    // A file the included one includes counts too
+    fs::write(
+        shared.join("colors.iuml"),
+        "skinparam backgroundColor black\n",
+    )
+    .unwrap();
+    let second = render();
+    assert_ne!(second, first);
+    fs::write(shared.join("style.iuml"), "skinparam shadowing false\n").unwrap();
+    assert_ne!(render(), second);
+
+    // A named image keeps its name, so it's rendered again instead
+    let fixture = Fixture::new().with_chapter(
+        "Named",
+        "```plantuml,render out=assets/styled.svg\n!include style.iuml\nAlice -> Bob\n```\n",
+    );
+    let style = fixture.root.join("src/style.iuml");
+    fs::write(&style, "skinparam backgroundColor white\n").unwrap();
+    let log = fixture.root.join("plantuml.log");
+    let run = || {
+        let output = fixture.run_with(|command| {
+            command.env("PLANTUML_STUB_LOG", &log);
+        });
+        assert!(output.status.success());
+    };
+    run();
+    fs::remove_file(&log).unwrap();
+    run();
+    assert!(!log.exists(), "An unchanged diagram is rendered again");
+    fs::write(&style, "skinparam backgroundColor black\n").unwrap();
+    run();
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(log.contains("styled.puml"), "{}", log);
+}
+
+#[test]
+fn renders_blocks_of_the_configured_language() {
+    let source = "```puml,render\nAlice -> Bob: Short\n```\n\n\
+                  ```plantuml, render\nAlice -> Bob: Long\n```\n";
+    let book = Fixture::new()
+        .with_config("language = \"puml\"")
+        .with_chapter("Aliased", source)
+        .render();
+    let content = chapter(&book, "Aliased");
+    assert_eq!(images(&content).len(), 2, "{}", content);
+
+    // Not without the option
+    let book = Fixture::new().with_chapter("Aliased", source).render();
+    let content = chapter(&book, "Aliased");
+    assert_eq!(images(&content).len(), 1, "{}", content);
+    assert!(content.contains("puml,render"), "{}", content);
+}
+
+#[test]
+fn prunes_images_no_diagram_uses_anymore() {
+    let fixture = Fixture::new()
+        .with_config("prune = true")
+        .with_chapter("Edited", "```plantuml,render\nAlice -> Bob: Before\n```\n");
+    let src = fixture.root.join("src");
+    fs::create_dir_all(src.join("plantuml-diagrams")).unwrap();
+    fs::write(src.join("plantuml-diagrams/stale.svg"), "<svg/>").unwrap();
+    let before = images(&chapter(&fixture.render(), "Edited")).remove(0);
+    assert!(src.join(&before).is_file(), "{}", before);
+
+    fs::write(
+        src.join("edited.md"),
+        "```plantuml,render\nAlice -> Bob: After\n```\n",
+    )
+    .unwrap();
+    let book = fixture.render();
+    let after = images(&chapter(&book, "Edited")).remove(0);
+    assert!(src.join(&after).is_file(), "{}", after);
+    assert!(!src.join(&before).exists(), "{}", before);
+    assert!(!src.join(&before).with_extension("puml").exists());
+    // The other chapters' images, and files not named after a hash, stay
+    let rendered = images(&chapter(&book, "Rendered")).remove(0);
+    assert!(src.join(rendered).is_file());
+    assert!(src.join("plantuml-diagrams/stale.svg").is_file());
+
+    // Without every chapter rendered, what's used isn't known
+    let fixture = Fixture::new()
+        .with_config("prune = true\ninclude_chapters = [\"edited.md\"]")
+        .with_chapter("Edited", "```plantuml,render\nAlice -> Bob: Kept\n```\n");
+    let kept = images(&chapter(&Fixture::new().render(), "Rendered")).remove(0);
+    fs::create_dir_all(fixture.root.join("src").join(&kept).parent().unwrap()).unwrap();
+    fs::write(fixture.root.join("src").join(&kept), "<svg/>").unwrap();
+    fixture.render();
+    assert!(fixture.root.join("src").join(&kept).is_file());
+}
+
+#[test]
+fn renders_data_files_as_json_trees() {
+    let fixture = Fixture::new().with_chapter(
+        "Data",
+        "```plantuml,render\ndatafile: ../diagrams/defaults.toml\n```\n",
+    );
+    let data = fixture.root.join("diagrams/defaults.toml");
+    fs::write(&data, "name = \"book\"\n\n[limits]\npages = 3\n").unwrap();
+    let image = images(&chapter(&fixture.render(), "Data")).remove(0);
+    let puml = fixture.root.join("src").join(&image).with_extension("puml");
+    let puml = fs::read_to_string(puml).unwrap();
+    assert!(puml.starts_with("@startjson\n"), "{}", puml);
+    assert!(puml.contains("\"name\": \"book\""), "{}", puml);
+    assert!(puml.contains("\"pages\": 3"), "{}", puml);
+
+    // The data is part of the hash
+    fs::write(&data, "name = \"other\"\n").unwrap();
+    let changed = images(&chapter(&fixture.render(), "Data")).remove(0);
+    assert_ne!(image, changed);
+
+    fs::remove_file(&data).unwrap();
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("defaults.toml"), "{}", stderr);
+}
+
+#[test]
+fn shows_why_diagrams_are_not_rendered_if_asked() {
+    let notice = "Diagram not rendered: ";
+    let fixture = Fixture::new().with_config("skip_if_missing = true\nshow_skips = true");
+    let output = fixture.run_with(|command| {
+        command.env("PATH", "");
+    });
+    assert!(output.status.success());
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let content = chapter(&book, "Rendered");
+    assert!(
+        content.contains("Diagram not rendered: PlantUML isn't installed"),
+        "{}",
+        content
+    );
+    assert!(content.contains("Alice -> Bob: Hello"), "{}", content);
+
+    let diagram = "```plantuml,render\nAlice -> Bob: Region\n```\n\n";
+    let content = format!(
+        "{}<!-- plantuml:begin -->\n\n{}<!-- plantuml:end -->\n\n{}",
+        diagram, diagram, diagram
+    );
+    let fixture = Fixture::new()
+        .with_config("show_skips = true")
+        .with_chapter("Regions", &content);
+    let content = chapter(&fixture.render(), "Regions");
+    assert_eq!(images(&content).len(), 1, "{}", content);
+    assert_eq!(content.matches(notice).count(), 2, "{}", content);
+    assert!(content.contains("plantuml:begin"), "{}", content);
+
+    let fixture = Fixture::new().with_config("show_skips = true");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_SILENT", "1");
+    });
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let content = chapter(&book, "Rendered");
+    assert!(
+        content.contains("Diagram not rendered: PlantUML didn't produce an image"),
+        "{}",
+        content
+    );
+
+    // Nothing's shown without the option, or for diagrams which are rendered
+    let fixture = Fixture::new().with_chapter("Regions", &format!("{}{}", diagram, diagram));
+    let content = chapter(&fixture.render(), "Regions");
+    assert!(!content.contains(notice), "{}", content);
+    let fixture = Fixture::new().with_config("show_skips = true");
+    let content = chapter(&fixture.render(), "Rendered");
+    assert!(!content.contains(notice), "{}", content);
+}
+
+#[test]
+fn shortens_image_names_to_hash_length() {
+    let fixture = Fixture::new().with_config("hash_length = 8");
+    let image = images(&chapter(&fixture.render(), "Rendered")).remove(0);
+    let name = Path::new(&image).file_stem().unwrap().to_string_lossy();
+    assert_eq!(name.len(), 8, "{}", image);
+    assert!(fixture.root.join("src").join(&image).is_file());
+
+    // Seventeen diagrams can't all start with a different hex character
+    let content = (0..17)
+        .map(|index| format!("```plantuml,render\nAlice -> Bob: {}\n```\n\n", index))
+        .collect::<String>();
+    let fixture = Fixture::new()
+        .with_config("hash_length = 1")
+        .with_chapter("Crowded", &content);
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Raise hash_length"), "{}", stderr);
+}
+
+#[test]
+fn adds_the_configured_legend_to_diagrams() {
+    let content = "```plantuml,render legend\n@startuml\nAlice -> Bob: Asked\n@enduml\n```\n\n\
+                   ```plantuml,render\n@startuml\nAlice -> Bob: Plain\n@enduml\n```\n\n\
+                   ```plantuml,render\n@startmindmap\n* Typed\n@endmindmap\n```\n\n\
+                   ```plantuml,render legend=false\n@startmindmap\n* Opted out\n@endmindmap\n```\n";
+    let fixture = Fixture::new()
+        .with_config("legend = \"diagrams/key.puml\"\nlegend_diagram_types = [\"mindmap\"]")
+        .with_chapter("Legends", content);
+    let legend = "legend\nKey\nendlegend\n";
+    fs::write(fixture.root.join("diagrams/key.puml"), legend).unwrap();
+    let sources = |fixture: &Fixture| {
+        images(&chapter(&fixture.render(), "Legends"))
+            .iter()
+            .map(|image| {
+                let puml = fixture.root.join("src").join(image).with_extension("puml");
+                fs::read_to_string(puml).unwrap()
+            })
+            .collect::<Vec<_>>()
+    };
+    let first = sources(&fixture);
+    assert_eq!(first.len(), 4);
+    assert!(
+        first[0].ends_with(&format!("Alice -> Bob: Asked\n{}@enduml\n", legend)),
+        "{}",
+        first[0]
+    );
+    assert!(!first[1].contains("legend"), "{}", first[1]);
+    assert!(
+        first[2].contains(&format!("{}@endmindmap", legend)),
+        "{}",
+        first[2]
+    );
+    assert!(!first[3].contains("legend"), "{}", first[3]);
+
+    // The legend is part of the hash
+    fs::write(
+        fixture.root.join("diagrams/key.puml"),
+        "legend\nOther\nendlegend\n",
+    )
+    .unwrap();
+    let second = sources(&fixture);
+    assert!(second[0].contains("Other"), "{}", second[0]);
+    assert_eq!(first[1], second[1]);
+
+    fs::remove_file(fixture.root.join("diagrams/key.puml")).unwrap();
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("key.puml"), "{}", stderr);
+}
+
+#[test]
+fn renders_only_the_exact_trigger_with_strict_trigger() {
+    let content = "```plantuml,render out=assets/exact\nAlice -> Bob: Exact\n```\n\n\
+                   ```render,plantuml\nAlice -> Bob: Reversed\n```\n\n\
+                   ```plantuml render\nAlice -> Bob: Spaced\n```\n";
+    let fixture = Fixture::new().with_chapter("Triggers", content);
+    let rendered = chapter(&fixture.render(), "Triggers");
+    assert_eq!(images(&rendered).len(), 3, "{}", rendered);
+
+    let fixture = Fixture::new()
+        .with_config("strict_trigger = true")
+        .with_chapter("Triggers", content);
+    let rendered = chapter(&fixture.render(), "Triggers");
+    let images = images(&rendered);
+    assert_eq!(images.len(), 1, "{}", rendered);
+    assert!(images[0].starts_with("assets/exact/"), "{}", images[0]);
+    assert!(rendered.contains("Alice -> Bob: Reversed"), "{}", rendered);
+    assert!(rendered.contains("Alice -> Bob: Spaced"), "{}", rendered);
+}
+
+#[test]
+fn writes_the_provenance_of_rendered_images_if_asked() {
+    let fixture = Fixture::new().with_config("write_provenance = true");
+    let image = images(&chapter(&fixture.render(), "Rendered")).remove(0);
+    let image = fixture.root.join("src").join(image);
+    let hash = image.file_stem().unwrap().to_string_lossy().into_owned();
+    let meta = image.with_extension("puml.meta");
+    let provenance: Value = serde_json::from_str(&fs::read_to_string(&meta).unwrap()).unwrap();
+    assert_eq!(provenance["hash"].as_str(), Some(hash.as_str()));
+    assert_eq!(provenance["format"].as_str(), Some("svg"));
+    assert_eq!(
+        provenance["plantuml_version"].as_str(),
+        Some("PlantUML version 1.2020.0 (stub)")
+    );
+    assert!(provenance["rendered_at"].as_str().is_some());
+    assert!(provenance["options_fingerprint"].as_str().is_some());
+
+    // A cached image is left as it is, with or without its provenance
+    fs::remove_file(&meta).unwrap();
+    let log = fixture.root.join("plantuml.log");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_LOG", &log);
+    });
+    assert!(output.status.success());
+    assert!(!log.exists());
+    assert!(!meta.exists());
+
+    let fixture = Fixture::new();
+    let image = images(&chapter(&fixture.render(), "Rendered")).remove(0);
+    let image = fixture.root.join("src").join(image);
+    assert!(!image.with_extension("puml.meta").exists());
+}
+
+#[test]
+fn runs_plantuml_with_the_security_profile() {
+    let profile = |config: &str| {
+        let fixture = Fixture::new().with_config(config);
+        let log = fixture.root.join("plantuml.log");
+        let output = fixture.run_with(|command| {
+            command.env("PLANTUML_STUB_LOG", &log);
+        });
+        assert!(output.status.success());
+        fs::read_to_string(log).unwrap()
+    };
+    let log = profile("security_profile = \"SANDBOX\"");
+    assert!(log.contains("| security_profile=SANDBOX |"), "{}", log);
+    let log = profile("");
+    assert!(log.contains("| security_profile= |"), "{}", log);
+}
+
+#[test]
+fn numbers_figures_continuously_or_per_chapter() {
+    let diagram = |title: &str| {
+        format!(
+            "```plantuml,render\n@startuml\ntitle {}\nAlice -> Bob\n@enduml\n```\n\n",
+            title
+        )
+    };
+    let fixture = |numbering: &str| {
+        let fixture = Fixture::new()
+            .with_config(&format!("figure_numbering = \"{}\"", numbering))
+            .with_chapter(
+                "Figures",
+                &format!("{}{}", diagram("Login"), diagram("Logout")),
+            );
+        // Nested in the chapter before it
+        fs::write(fixture.root.join("src/sub.md"), diagram("Nested")).unwrap();
+        let mut summary = OpenOptions::new()
+            .append(true)
+            .open(fixture.root.join("src/SUMMARY.md"))
+            .unwrap();
+        writeln!(summary, "    - [Sub](sub.md)").unwrap();
+        drop(summary);
+        fixture.with_chapter("More", &diagram("Later"))
+    };
+    let captions = |content: &str| {
+        content
+            .split("<figcaption>")
+            .skip(1)
+            .map(|caption| caption[..caption.find("</figcaption>").unwrap()].to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let book = fixture("per_chapter").render();
+    let figures = captions(&chapter(&book, "Figures"));
+    assert_eq!(figures, vec!["Figure 7.1: Login", "Figure 7.2: Logout"]);
+    assert_eq!(captions(&chapter(&book, "Sub")), vec!["Figure 7.3: Nested"]);
+    assert_eq!(captions(&chapter(&book, "More")), vec!["Figure 8.1: Later"]);
+    let content = chapter(&book, "Rendered");
+    assert!(content.contains("<figure><img src="), "{}", content);
+    assert_eq!(captions(&content), vec!["Figure 1.1"]);
+
+    // Chapters nested in others come after them, as they're read
+    let book = fixture("continuous").render();
+    let number = |name: &str| {
+        let caption = captions(&chapter(&book, name)).remove(0);
+        caption["Figure ".len()..]
+            .split(':')
+            .next()
+            .unwrap()
+            .parse::<usize>()
+            .unwrap()
+    };
+    assert_eq!(number("Rendered"), 1);
+    let login = number("Figures");
+    assert_eq!(number("Sub"), login + 2);
+    assert_eq!(number("More"), login + 3);
+
+    let content = chapter(&Fixture::new().render(), "Rendered");
+    assert!(!content.contains("<figure"), "{}", content);
+}
+
+#[test]
+fn renders_every_diagram_of_a_build_with_serve_async() {
+    // The tests aren't run by `mdbook serve`, so nothing is left for later
+    let fixture = Fixture::new().with_config("serve_async = true");
+    let image = images(&chapter(&fixture.render(), "Rendered")).remove(0);
+    assert!(fixture.root.join("src").join(&image).is_file(), "{}", image);
+    assert!(!fixture.root.join(".plantuml-renderer-pending").exists());
+}
+
+#[test]
+fn resolves_includes_with_the_include_resolver() {
+    let fixture = Fixture::new()
+        .with_config(
+            "include_resolver = [\"sh\", \"-c\", \"echo \\\"$0\\\" >> resolver.log; \
+             case \\\"$0\\\" in @shared/*) echo \\\"diagrams/${0#@shared/}\\\";; esac\"]",
+        )
+        .with_chapter(
+            "Shared",
+            "```plantuml,render\n!include @shared/style.iuml\nA -> B\n```\n\n\
+             ```plantuml,render\n!include @shared/style.iuml\nB -> C\n```\n\n\
+             ```plantuml,render\n!include local.iuml\nC -> D\n```\n",
+        );
+    fs::write(
+        fixture.root.join("diagrams/style.iuml"),
+        "skinparam monochrome true\n",
+    )
+    .unwrap();
+    let content = chapter(&fixture.render(), "Shared");
+    let pumls: Vec<String> = images(&content)
+        .iter()
+        .map(|image| {
+            let puml = fixture.root.join("src").join(image).with_extension("puml");
+            fs::read_to_string(puml).unwrap()
+        })
+        .collect();
+    let resolved = fixture
+        .root
+        .join("diagrams/style.iuml")
+        .canonicalize()
+        .unwrap();
+    let resolved = format!("!include {}\n", resolved.display());
+    assert!(pumls[0].contains(&resolved), "{}", pumls[0]);
+    assert!(pumls[1].contains(&resolved), "{}", pumls[1]);
+    // Left to PlantUML, as the resolver printed nothing
+    assert!(pumls[2].contains("!include local.iuml\n"), "{}", pumls[2]);
+    // Each include is only resolved once
+    let log = fs::read_to_string(fixture.root.join("resolver.log")).unwrap();
+    assert_eq!(log, "@shared/style.iuml\nlocal.iuml\n");
+
+    let fixture = Fixture::new()
+        .with_config("include_resolver = [\"sh\", \"-c\", \"echo missing.iuml\"]")
+        .with_chapter(
+            "Shared",
+            "```plantuml,render\n!include @shared/style.iuml\n```\n",
+        );
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("which isn't a file"), "{}", stderr);
+}
+
+#[test]
+fn names_images_of_named_start_directives_after_their_hash() {
+    let named = "```plantuml,render\n@startuml Named\nA -> B\nnewpage\nB -> C\n@enduml\n```\n";
+    for config in &["", "batch = true"] {
+        let fixture = Fixture::new()
+            .with_config(config)
+            .with_chapter("Named", named);
+        let image = images(&chapter(&fixture.render(), "Named")).remove(0);
+        let image = fixture.root.join("src").join(&image);
+        assert!(image.is_file(), "{}", image.display());
+        let directory = image.parent().unwrap();
+        assert!(!directory.join("Named.svg").exists());
+        assert!(!directory.join("Named_001.svg").exists());
+        let stem = image.file_stem().unwrap().to_string_lossy();
+        assert!(directory.join(format!("{}_001.svg", stem)).is_file());
+
+        // It's found on the next build rather than rendered again
+        fs::write(&image, "<svg/>").unwrap();
+        fixture.render();
+        assert_eq!(fs::read_to_string(&image).unwrap(), "<svg/>");
+    }
+}
+
+#[test]
+fn keeps_diagrams_within_alert_block_quotes() {
+    let alert = "> [!NOTE]\n> Before the diagram\n>\n> ```plantuml,render\n> @startuml\n\
+                 > A -> B\n> @enduml\n> ```\n>\n> After the diagram\n\nOutside\n";
+    // As a markdown image and as HTML
+    for config in &["", "anchors = true"] {
+        let fixture = Fixture::new()
+            .with_config(config)
+            .with_chapter("Alert", alert);
+        let content = chapter(&fixture.render(), "Alert");
+        let image = images(&content).remove(0);
+        let lines: Vec<&str> = content.lines().map(str::trim).collect();
+        assert!(lines.contains(&"> [!NOTE]"), "{}", content);
+        let quoted = |text: &str| {
+            lines
+                .iter()
+                .any(|line| line.starts_with('>') && line.contains(text))
+        };
+        assert!(quoted(&image), "{}", content);
+        assert!(quoted("After the diagram"), "{}", content);
+        // Nothing between the marker and the end of the quote leaves the quote
+        let start = lines.iter().position(|line| *line == "> [!NOTE]").unwrap();
+        let end = lines.iter().position(|line| *line == "Outside").unwrap();
+        assert!(
+            lines[start..end]
+                .iter()
+                .filter(|line| !line.is_empty())
+                .all(|line| line.starts_with('>')),
+            "{}",
+            content
+        );
+        let last = lines[start..end]
+            .iter()
+            .rposition(|line| !line.is_empty())
+            .unwrap();
+        assert!(
+            lines[start..start + last]
+                .iter()
+                .all(|line| !line.is_empty()),
+            "{}",
+            content
+        );
+    }
+}
+
+#[test]
+fn wraps_images_in_the_wrapper_template() {
+    let fixture = Fixture::new()
+        .with_config(
+            "wrapper_template = '<a class=\"zoom\" href=\"{src}\">\
+             <img src=\"{src}\" alt=\"{alt}\" class=\"{class}\" data-x=\"{other}\"></a>'",
+        )
+        .with_chapter(
+            "Wrapped",
+            "```plantuml,render description=\"Alice & Bob\" {.wide .dark}\nAlice -> Bob\n```\n",
+        );
+    let content = chapter(&fixture.render(), "Wrapped");
+    let image = images(&content).remove(0);
+    let expected = format!(
+        "<a class=\"zoom\" href=\"{0}\"><img src=\"{0}\" alt=\"Alice &amp; Bob\" \
+         class=\"wide dark\" data-x=\"{{other}}\"></a>",
+        image
+    );
+    assert!(content.contains(&expected), "{}", content);
+}
+
+#[test]
+fn leaves_diagrams_unrendered_once_the_global_timeout_runs_out() {
+    let fixture = Fixture::new();
+    let rendered = images(&chapter(&fixture.render(), "Rendered"));
+    let fixture = fixture
+        .with_config("global_timeout_secs = 0")
+        .with_chapter("Late", "```plantuml,render\nAlice -> Bob: Late\n```\n");
+    let output = fixture.run();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("global_timeout_secs ran out"), "{}", stderr);
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    // What's cached is still shown
+    assert_eq!(images(&chapter(&book, "Rendered")), rendered);
+    let late = chapter(&book, "Late");
+    assert!(images(&late).is_empty(), "{}", late);
+    assert!(late.contains("Alice -> Bob: Late"), "{}", late);
+
+    let fixture = fixture.with_config("on_global_timeout = \"error\"");
+    assert!(!fixture.run().status.success());
+}
+
+#[test]
+fn reads_diagram_files_in_the_input_charset() {
+    let legacy = "```plantuml,render\nfile: ../diagrams/legacy.puml\n```\n";
+    let fixture = Fixture::new()
+        .with_config("input_charset = \"ISO-8859-1\"")
+        .with_chapter("Legacy", legacy);
+    fs::write(
+        fixture.root.join("diagrams/legacy.puml"),
+        b"!include legacy.iuml\nAlice -> Bob : Caf\xe9 cr\xe8me\n",
+    )
+    .unwrap();
+    fs::write(
+        fixture.root.join("diagrams/legacy.iuml"),
+        b"title R\xe9sum\xe9\n",
+    )
+    .unwrap();
+    let log = fixture.root.join("plantuml.log");
+    let output = fixture.run_with(|command| {
+        command.env("PLANTUML_STUB_LOG", &log);
+    });
+    assert!(output.status.success());
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(log.starts_with("-charset ISO-8859-1 "), "{}", log);
+    let image = images(&chapter(&fixture.render(), "Legacy")).remove(0);
+    let image = fixture.root.join("src").join(&image);
+    assert!(image.is_file(), "{}", image.display());
+    // Handed on to PlantUML as it was, with the include in the same charset
+    let source = fs::read(image.with_extension("puml")).unwrap();
+    let written = b"Alice -> Bob : Caf\xe9 cr\xe8me\n";
+    assert!(
+        source.windows(written.len()).any(|line| line == written),
+        "{}",
+        String::from_utf8_lossy(&source)
+    );
+
+    // The hash is that of the UTF-8 source
+    let fixture = Fixture::new().with_chapter("Legacy", legacy);
+    fs::write(
+        fixture.root.join("diagrams/legacy.puml"),
+        "!include legacy.iuml\nAlice -> Bob : Café crème\n",
+    )
+    .unwrap();
+    let utf8_image = images(&chapter(&fixture.render(), "Legacy")).remove(0);
+    assert_eq!(
+        fixture.root.join("src").join(&utf8_image).file_name(),
+        image.file_name()
+    );
+    // Read as UTF-8, the Latin-1 file fails to
+    fs::write(
+        fixture.root.join("diagrams/legacy.puml"),
+        b"Alice -> Bob : Caf\xe9\n",
+    )
+    .unwrap();
+    let output = fixture.run();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("isn't valid UTF-8"), "{}", stderr);
+}
+
+#[test]
+fn themes_the_colors_of_inlined_svgs_with_css_variables() {
+    let fixture = Fixture::new()
+        .with_config(
+            "embed = \"inline-with-fallback\"\ncss_theming = true\n\
+             css_theme_colors = { \"#123\" = \"--brand\" }",
+        )
+        .with_chapter("Themed", "```plantuml,render\nAlice -> Bob\n```\n");
+    let image = images(&chapter(&fixture.render(), "Themed")).remove(0);
+    let image = fixture.root.join("src").join(&image);
+    // What PlantUML draws with, which the cached image is taken for
+    fs::write(
+        &image,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" style=\"width:20px;background:#FFFFFF;\">\
+         <rect fill=\"#F1F1F1\" stroke=\"#181818\"/><text fill=\"#000000\">A</text>\
+         <line style=\"stroke:#112233;stroke-width:1\"/><rect fill=\"#ABCDEF\"/></svg>",
+    )
+    .unwrap();
+    let content = chapter(&fixture.render(), "Themed");
+    for themed in &[
+        "style=\"width:20px;background:var(--diagram-bg, #FFFFFF);\"",
+        "fill=\"var(--diagram-fill, #F1F1F1)\" stroke=\"var(--diagram-fg, #181818)\"",
+        "<text fill=\"var(--diagram-fg, #000000)\">",
+        "style=\"stroke:var(--brand, #112233);stroke-width:1\"",
+        // Colors without a variable are left alone
+        "<rect fill=\"#ABCDEF\"/>",
+    ] {
+        assert!(content.contains(themed), "{}\n{}", themed, content);
+    }
+    // Only the inlined markup is themed
+    let svg = fs::read_to_string(&image).unwrap();
+    assert!(!svg.contains("var("), "{}", svg);
+
+    let fixture = Fixture::new()
+        .with_config("css_theming = true")
+        .with_chapter("Themed", "```plantuml,render\nAlice -> Bob\n```\n");
+    assert!(!fixture.run().status.success());
+}
+
+#[test]
+fn renders_the_diagrams_of_the_book_in_parallel() {
+    let fixture = Fixture::new()
+        .with_config("parallel = true\nmax_processes = 4")
+        .with_chapter(
+            "First",
+            "```plantuml,render\nAlice -> Bob: 1\n```\n\n```plantuml,render\nAlice -> Bob: 2\n```\n",
+        )
+        .with_chapter(
+            "Second",
+            "```plantuml,render\nAlice -> Bob: 3\n```\n\n```plantuml,render\nAlice -> Bob: 1\n```\n",
+        )
+        .with_chapter(
+            "Themed",
+            "+++\ntheme = \"sketchy\"\n+++\n\n\
+             ```plantuml,render group=story\nAlice -> Bob: 4\n```\n\n\
+             ```plantuml,render group=story\nBob -> Alice: 5\n```\n",
+        );
+    let log = fixture.root.join("plantuml.log");
+    let started = std::time::Instant::now();
+    let output = fixture.run_with(|command| {
+        command
+            .env("PLANTUML_STUB_LOG", &log)
+            .env("PLANTUML_STUB_SLEEP", "1");
+    });
+    let elapsed = started.elapsed();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // One after another, the book's dozen diagrams would take twelve seconds
+    assert!(elapsed.as_secs() < 8, "{:?}", elapsed);
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    for name in &["First", "Second", "Themed"] {
+        for image in images(&chapter(&book, name)) {
+            assert!(fixture.root.join("src").join(&image).is_file(), "{}", image);
+        }
+    }
+    // Chapters are read alike for rendering ahead, so every image is shown
+    let shown: HashSet<String> = [
+        "Rendered",
+        "Empty",
+        "Syntax error",
+        "Attributes",
+        "Data",
+        "First",
+        "Second",
+        "Themed",
+    ]
+    .iter()
+    .flat_map(|name| images(&chapter(&book, name)))
+    .collect();
+    let written: HashSet<String> = fs::read_dir(fixture.root.join("src/plantuml-diagrams"))
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+            Some(format!("plantuml-diagrams/{}", name)).filter(|_| name.ends_with(".svg"))
+        })
+        .collect();
+    assert_eq!(written, shown);
+    // The diagram of both chapters is rendered once
+    let log = fs::read_to_string(&log).unwrap();
+    let renders: Vec<&str> = log
+        .lines()
+        .filter_map(|line| line.split(" | ").next())
+        .filter(|args| args.contains("-tsvg"))
+        .collect();
+    let distinct: HashSet<&str> = renders.iter().copied().collect();
+    assert_eq!(distinct.len(), renders.len(), "{}", log);
+}